@@ -0,0 +1,96 @@
+//! Cost of `JobManager::create_job`, which runs once per ready session on
+//! every new template.
+//!
+//! The template is shaped like a mainnet one: a v2 miner transaction with
+//! an 8-byte extra nonce and 42 other transactions, about 1500 bytes in
+//! all. Run with `cargo bench --bench jobs`, on this commit and its parent
+//! to compare; throughput is in jobs per second.
+//!
+//! Encoding only the merkle root of each hashing blob, reusing the miner
+//! transaction buffer and rendering job ids by hand took a share job from
+//! 12.4 µs (80k jobs/s) to 11.4 µs (88k jobs/s) on one development machine,
+//! with block jobs within noise of 12 µs either side. What remains is
+//! mostly the Keccak of the miner transaction and its merkle path.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use monero_web_coordinator::jobs::JobManager;
+use monero_web_coordinator::template::{BlockTemplate, TemplateState};
+
+const TRANSACTIONS: u64 = 42;
+const SESSIONS: usize = 1_000;
+const RESERVE_SIZE: u32 = 8;
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A block template blob, and the offset of the reserved bytes in it
+fn template_blob(height: u64) -> (Vec<u8>, usize) {
+    let mut blob = vec![16, 16];
+    write_varint(&mut blob, 1_700_000_000);
+    blob.extend_from_slice(&[0x11; 32]);
+    blob.extend_from_slice(&[0; 4]);
+
+    // Miner transaction: one coinbase input, one tagged output
+    write_varint(&mut blob, 2);
+    write_varint(&mut blob, height + 60);
+    blob.extend_from_slice(&[1, 0xff]);
+    write_varint(&mut blob, height);
+    write_varint(&mut blob, 1);
+    write_varint(&mut blob, 600_000_000_000);
+    blob.push(0x03);
+    blob.extend_from_slice(&[0x22; 32]);
+    blob.push(0x5a);
+    // Extra: transaction public key, then the extra nonce left for the reserve
+    write_varint(&mut blob, 1 + 32 + 2 + u64::from(RESERVE_SIZE));
+    blob.push(0x01);
+    blob.extend_from_slice(&[0x33; 32]);
+    blob.extend_from_slice(&[0x02, RESERVE_SIZE as u8]);
+    let reserved_offset = blob.len();
+    blob.extend_from_slice(&[0; RESERVE_SIZE as usize]);
+    blob.push(0);
+
+    write_varint(&mut blob, TRANSACTIONS);
+    for i in 0..TRANSACTIONS {
+        blob.extend_from_slice(&[i as u8; 32]);
+    }
+    (blob, reserved_offset)
+}
+
+fn create_job(c: &mut Criterion) {
+    let (blob, reserved_offset) = template_blob(3_000_000);
+    let template = BlockTemplate {
+        blockhashing_blob: String::new(),
+        blocktemplate_blob: hex::encode(&blob),
+        difficulty: 300_000_000_000,
+        expected_reward: 600_000_000_000,
+        height: 3_000_000,
+        prev_hash: "11".repeat(32),
+        reserved_offset,
+        seed_hash: "44".repeat(32),
+        next_seed_hash: String::new(),
+        status: "OK".into(),
+    };
+    let template = TemplateState::from_rpc(template, 1, RESERVE_SIZE).unwrap();
+    let manager = JobManager::new(10_000);
+    let sessions: Vec<String> = (0..SESSIONS).map(|i| format!("{:08x}-0000-4000-8000-000000000000", i)).collect();
+
+    let mut group = c.benchmark_group("create_job");
+    group.throughput(Throughput::Elements(1));
+    // Share and block difficulty, so the second skips the target division
+    for (name, difficulty) in [("share", 10_000), ("block", template.difficulty)] {
+        let mut next = sessions.iter().cycle();
+        group.bench_function(name, |b| {
+            b.iter(|| manager.create_job(&template, next.next().unwrap(), difficulty).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, create_job);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+//! Message-path cost of the SessionManager with many live sessions.
+//!
+//! Every inbound message checks the session's rate limit and state; most
+//! checks here hit a full limiter, as a flooding client would. The
+//! `clone` variant reads the state through `get_session`, as the message path
+//! used to; `in_place` uses `state_of`. Run with `cargo bench --bench sessions`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use monero_web_coordinator::session::{SessionManager, SessionManagerConfig, SessionState};
+
+const SESSIONS: usize = 10_000;
+
+/// A manager holding `SESSIONS` ready sessions, each with a full job history
+fn populate() -> (Arc<SessionManager>, Vec<String>) {
+    let manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: SESSIONS, max_total: SESSIONS, ..Default::default() }));
+    let reserved: Arc<[u8]> = vec![0u8; 8].into();
+    let ids = (0..SESSIONS)
+        .map(|i| {
+            let ip = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32));
+            let session = manager.create_session(ip).unwrap();
+            manager.set_ready(&session.id, "bench/1.0".into(), 4, Some("site".into()), Some("worker".into()));
+            for job in 0..4 {
+                manager.update_session(&session.id, |s| s.update_job(format!("{}-{}", session.id, job), reserved.clone()));
+            }
+            session.id
+        })
+        .collect();
+    (manager, ids)
+}
+
+/// Run `op` `iters` times spread over `threads` threads, each walking the
+/// sessions from a different starting point
+fn run_concurrent<F>(manager: &Arc<SessionManager>, ids: &Arc<Vec<String>>, threads: usize, iters: u64, op: F) -> Duration
+where
+    F: Fn(&SessionManager, &str) -> bool + Copy + Send + 'static,
+{
+    let per_thread = iters / threads as u64 + 1;
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|t| {
+            let (manager, ids) = (manager.clone(), ids.clone());
+            thread::spawn(move || {
+                let offset = t * ids.len() / threads;
+                let mut ready = 0u64;
+                for i in 0..per_thread as usize {
+                    ready += op(&manager, &ids[(offset + i) % ids.len()]) as u64;
+                }
+                ready
+            })
+        })
+        .collect();
+    for worker in workers {
+        criterion::black_box(worker.join().unwrap());
+    }
+    started.elapsed()
+}
+
+fn message_path(c: &mut Criterion) {
+    let (manager, ids) = populate();
+    let ids = Arc::new(ids);
+
+    let mut group = c.benchmark_group("message_path");
+    group.throughput(Throughput::Elements(1));
+    for threads in [1, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("clone", threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                run_concurrent(&manager, &ids, threads, iters, |manager, id| {
+                    manager.check_message_limit(id)
+                        & manager.get_session(id).map(|s| s.state == SessionState::Ready).unwrap_or(false)
+                })
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("in_place", threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                run_concurrent(&manager, &ids, threads, iters, |manager, id| {
+                    manager.check_message_limit(id) & (manager.state_of(id) == Some(SessionState::Ready))
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, message_path);
+criterion_main!(benches);
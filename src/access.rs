@@ -0,0 +1,129 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+use tracing::debug;
+
+use crate::auth::constant_time_eq;
+use crate::config::ProtectedRoutesConfig;
+use crate::privacy;
+use crate::proxy;
+use crate::server::AppState;
+
+/// Guard for the operational routes (/stats, /admin). A request passes if it
+/// carries the configured bearer token or comes from an allowed network; the
+/// client address is resolved through trusted proxies like the WS handler does.
+pub async fn require_access(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let policy = &state.config.server.protected_routes;
+    if !policy.is_enabled() || is_allowed(&state, policy, &request) {
+        return next.run(request).await;
+    }
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+}
+
+fn is_allowed(state: &AppState, policy: &ProtectedRoutesConfig, request: &Request) -> bool {
+    if let Some(expected) = policy.token.as_deref().filter(|t| !t.is_empty()) {
+        let provided = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if provided.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+            return true;
+        }
+    }
+
+    let peer = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip(),
+        None => return false,
+    };
+    let ip = proxy::resolve_client_ip(peer, request.headers(), &state.config.server.trusted_proxies);
+    let allowed = policy.allowed_cidrs.iter().any(|net| net.contains(&ip));
+    if !allowed {
+        debug!("Refused {} from {}", request.uri().path(), privacy::display_ip(&ip));
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{self, tests::test_state};
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    fn request(uri: &str, peer: [u8; 4], token: Option<&str>) -> Request {
+        let mut builder = Request::get(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let mut request = builder.body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from((peer, 5000))));
+        request
+    }
+
+    fn protected_state() -> AppState {
+        let (mut state, _template_tx) = test_state();
+        state.config.server.protected_routes = ProtectedRoutesConfig {
+            token: Some("ops-secret".to_string()),
+            allowed_cidrs: vec!["10.1.0.0/16".parse().unwrap()],
+        };
+        state
+    }
+
+    async fn status(state: &AppState, request: Request) -> StatusCode {
+        server::router(state.clone()).oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_allowed_network_reaches_stats() {
+        let state = protected_state();
+        assert_eq!(status(&state, request("/stats", [10, 1, 2, 3], None)).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_other_networks_are_refused() {
+        let state = protected_state();
+        assert_eq!(status(&state, request("/stats", [198, 51, 100, 9], None)).await, StatusCode::FORBIDDEN);
+        // Public routes are unaffected
+        assert_eq!(status(&state, request("/version", [198, 51, 100, 9], None)).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_token_grants_access_from_anywhere() {
+        let state = protected_state();
+        let allowed = request("/stats", [198, 51, 100, 9], Some("ops-secret"));
+        assert_eq!(status(&state, allowed).await, StatusCode::OK);
+        let wrong = request("/stats", [198, 51, 100, 9], Some("guess"));
+        assert_eq!(status(&state, wrong).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_missing_token_is_refused_on_admin_routes() {
+        let mut state = protected_state();
+        state.config.server.protected_routes.allowed_cidrs.clear();
+        state.config.admin.enable = true;
+        state.config.admin.token = "ops-secret".to_string();
+
+        let anonymous = request("/admin/sessions", [198, 51, 100, 9], None);
+        assert_eq!(status(&state, anonymous).await, StatusCode::FORBIDDEN);
+        let authorized = request("/admin/sessions", [198, 51, 100, 9], Some("ops-secret"));
+        assert_eq!(status(&state, authorized).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_address_is_checked_behind_trusted_proxy() {
+        let mut state = protected_state();
+        state.config.server.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+        let mut forwarded = request("/stats", [127, 0, 0, 1], None);
+        forwarded.headers_mut().insert("x-forwarded-for", "10.1.9.9".parse().unwrap());
+        assert_eq!(status(&state, forwarded).await, StatusCode::OK);
+
+        let mut spoofed = request("/stats", [198, 51, 100, 9], None);
+        spoofed.headers_mut().insert("x-forwarded-for", "10.1.9.9".parse().unwrap());
+        assert_eq!(status(&state, spoofed).await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,306 @@
+use axum::{
+    Json, Router,
+    routing::{delete, get, post},
+    response::{IntoResponse, Response},
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Instant;
+use tracing::info;
+
+use crate::auth::constant_time_eq;
+use crate::ban::BanInfo;
+use crate::capabilities::SessionCapabilities;
+use crate::privacy;
+use crate::protocol::{OutboundCounters, RandomxMode};
+use crate::server::AppState;
+use crate::session::{Session, SessionState};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Rendered per `privacy.anonymize_ips`
+    pub ip: String,
+    pub state: SessionState,
+    pub client_version: Option<String>,
+    pub threads: u8,
+    pub connected_secs: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub stale: u64,
+    pub accepted_shares: u64,
+    /// Seconds since the last accepted submit
+    pub last_accept_secs: Option<u64>,
+    /// Hashes per second implied by the accepted shares
+    pub estimated_hashrate: Option<f64>,
+    /// Deepest the outbound queue has been for this connection
+    pub outbound_high_watermark: usize,
+    /// Frames and bytes written to the connection, and its queue depth
+    pub outbound: OutboundCounters,
+    /// Label of the site token the miner authenticated with
+    pub site_label: Option<String>,
+    pub worker: Option<String>,
+    /// What the hello negotiated; None before it
+    pub capabilities: Option<SessionCapabilities>,
+    pub randomx_mode: RandomxMode,
+}
+
+impl From<&Session> for SessionInfo {
+    fn from(session: &Session) -> Self {
+        Self {
+            id: session.id.clone(),
+            ip: privacy::display_ip(&session.ip),
+            state: session.state,
+            client_version: session.client_version.clone(),
+            threads: session.threads,
+            connected_secs: session.connected_at.elapsed().as_secs(),
+            accepted: session.accepted_submits,
+            rejected: session.rejected_submits,
+            stale: session.stale_submits,
+            accepted_shares: session.accepted_shares,
+            last_accept_secs: session.last_accept_at.and_then(|t| t.elapsed().ok()).map(|d| d.as_secs()),
+            estimated_hashrate: session.hashrate.estimate(Instant::now()),
+            outbound_high_watermark: session.outbound.high_watermark(),
+            outbound: session.outbound.snapshot(),
+            site_label: session.site_label.clone(),
+            worker: session.worker.clone(),
+            capabilities: session.capabilities.clone(),
+            randomx_mode: session.randomx_mode,
+        }
+    }
+}
+
+/// Outcome of `POST /admin/selftest`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    /// Hashes go over monerod's calc_pow, so there is no local RandomX to test
+    pub skipped: bool,
+    pub took_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Admin routes, all guarded by the configured bearer token
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/:id", delete(kick_session))
+        .route("/bans", get(list_bans).delete(clear_bans))
+        .route("/bans/:ip", delete(unban))
+        .route("/selftest", post(run_self_test))
+        .route_layer(middleware::from_fn_with_state(state, require_token))
+}
+
+async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token) if constant_time_eq(token.as_bytes(), state.config.admin.token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
+    }
+}
+
+async fn list_sessions(State(state): State<AppState>) -> Json<Vec<SessionInfo>> {
+    let sessions = state.session_manager.list_sessions();
+    Json(sessions.iter().map(SessionInfo::from).collect())
+}
+
+async fn kick_session(State(state): State<AppState>, Path(id): Path<String>) -> StatusCode {
+    if state.session_manager.close_session(&id, "Disconnected by operator") {
+        info!("Admin kicked session {}", id);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn list_bans(State(state): State<AppState>) -> Json<Vec<BanInfo>> {
+    Json(state.bans.list())
+}
+
+async fn unban(State(state): State<AppState>, Path(ip): Path<IpAddr>) -> StatusCode {
+    if state.bans.unban(&ip) {
+        info!("Admin lifted ban on {}", privacy::display_ip(&ip));
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Hash RandomX's test vector again; 500 if the hash is wrong or cannot be made
+async fn run_self_test(State(state): State<AppState>) -> (StatusCode, Json<SelfTestReport>) {
+    let report = match state.validator.self_test(state.pow.as_ref()).await {
+        Ok(took) => SelfTestReport {
+            passed: took.is_some(),
+            skipped: took.is_none(),
+            took_ms: took.map(|took| took.as_millis() as u64),
+            error: None,
+        },
+        Err(e) => SelfTestReport { passed: false, skipped: false, took_ms: None, error: Some(e.to_string()) },
+    };
+    info!("Admin ran the RandomX self-test: {:?}", report);
+    let status = if report.error.is_some() { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK };
+    (status, Json(report))
+}
+
+async fn clear_bans(State(state): State<AppState>) -> StatusCode {
+    state.bans.clear();
+    info!("Admin cleared all bans");
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{self, tests::test_state};
+    use axum::body::Body;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tower::ServiceExt;
+
+    fn request(method: &str, uri: &str, token: Option<&str>) -> Request {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requires_token() {
+        let (state, _template_tx) = test_state();
+        let app = server::router(state);
+
+        let response = app.clone().oneshot(request("GET", "/admin/sessions", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(request("GET", "/admin/sessions", Some("wrong"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(request("DELETE", "/admin/sessions/x", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_lists_sessions() {
+        let (state, _template_tx) = test_state();
+        let a = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let b = state.session_manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        let app = server::router(state);
+
+        let response = app.oneshot(request("GET", "/admin/sessions", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let sessions: Vec<SessionInfo> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().any(|s| s.id == a.id && s.state == SessionState::Connected));
+        assert!(sessions.iter().any(|s| s.id == b.id && s.ip == b.ip.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_kick_unknown_session() {
+        let (state, _template_tx) = test_state();
+        let app = server::router(state);
+
+        let response = app.oneshot(request("DELETE", "/admin/sessions/nope", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_kicked_socket_closes() {
+        let (state, _template_tx) = test_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server::serve(listener, state.clone()));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+
+        let mut sessions = Vec::new();
+        for _ in 0..100 {
+            sessions = state.session_manager.list_sessions();
+            if !sessions.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let id = sessions.first().expect("session not registered").id.clone();
+
+        let uri = format!("/admin/sessions/{}", id);
+        let response = server::router(state.clone())
+            .oneshot(request("DELETE", &uri, Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let WsMessage::Close(_) = msg {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+        .unwrap();
+        assert!(closed);
+
+        for _ in 0..100 {
+            if state.session_manager.active_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.session_manager.active_count(), 0);
+        state.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_list_and_lift_bans() {
+        let (state, _template_tx) = test_state();
+        let ip: IpAddr = "198.51.100.9".parse().unwrap();
+        while !state.bans.record_offense(ip) {}
+        let app = server::router(state.clone());
+
+        let response = app.clone().oneshot(request("GET", "/admin/bans", Some("secret"))).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let bans: Vec<BanInfo> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].ip, ip.to_string());
+
+        let response = app.clone().oneshot(request("DELETE", "/admin/bans/198.51.100.9", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!state.bans.is_banned(&ip));
+
+        let response = app.clone().oneshot(request("DELETE", "/admin/bans/198.51.100.9", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        while !state.bans.record_offense(ip) {}
+        let response = app.oneshot(request("DELETE", "/admin/bans", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.bans.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_self_test_runs_on_demand() {
+        let (state, _template_tx) = test_state();
+        let response = server::router(state).oneshot(request("POST", "/admin/selftest", Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let report: SelfTestReport = serde_json::from_slice(&body).unwrap();
+        assert!(report.passed && !report.skipped, "{:?}", report);
+        assert!(report.took_ms.is_some());
+    }
+}
@@ -0,0 +1,171 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use percent_encoding::percent_decode_str;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// HTML entry points must be revalidated so a deploy takes effect immediately
+const HTML_CACHE_CONTROL: &str = "no-cache";
+/// Other assets (JS, WASM, images) may be cached briefly
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+
+/// Serve the miner bundle from `dir`. Meant to be mounted as the fallback of
+/// the main router, so the WebSocket path, health and stats routes win.
+pub fn router(dir: &Path) -> Router {
+    Router::new()
+        .fallback(serve_asset)
+        .with_state(Arc::new(dir.to_path_buf()))
+}
+
+async fn serve_asset(State(dir): State<Arc<PathBuf>>, request: Request) -> Response {
+    let path = request.uri().path().to_owned();
+    if escapes_root(&path) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let (parts, _) = request.into_parts();
+    let headers = parts.headers.clone();
+    let uri = parts.uri.clone();
+    let method = parts.method.clone();
+
+    let mut response = ServeDir::new(dir.as_path())
+        .oneshot(Request::from_parts(parts, Body::empty()))
+        .await
+        .map(|r| r.map(Body::new))
+        .unwrap_or_else(|e| match e {});
+
+    // Unknown extension-less paths are client-side routes; hand them index.html.
+    // Missing files with an extension stay 404 so broken asset links are visible.
+    if response.status() == StatusCode::NOT_FOUND && !has_extension(&path) {
+        let mut fallback = Request::new(Body::empty());
+        *fallback.method_mut() = method;
+        *fallback.uri_mut() = uri;
+        *fallback.headers_mut() = headers;
+        response = ServeFile::new(dir.join("index.html"))
+            .oneshot(fallback)
+            .await
+            .map(|r| r.map(Body::new))
+            .unwrap_or_else(|e| match e {});
+    }
+
+    set_cache_control(&mut response);
+    response
+}
+
+fn set_cache_control(response: &mut Response) {
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+        return;
+    }
+
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+    let value = if is_html { HTML_CACHE_CONTROL } else { ASSET_CACHE_CONTROL };
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+}
+
+/// Reject any path that could resolve outside the served directory,
+/// including percent-encoded `..` segments and backslash separators.
+fn escapes_root(path: &str) -> bool {
+    let decoded = match percent_decode_str(path).decode_utf8() {
+        Ok(p) => p,
+        Err(_) => return true,
+    };
+    decoded.contains('\\')
+        || decoded.contains('\0')
+        || decoded.split('/').any(|segment| segment == ".." || segment.contains(':'))
+}
+
+fn has_extension(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .map(|name| name.contains('.'))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+
+    fn temp_site() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("coordinator-assets-{}", std::process::id()));
+        let site = root.join("site");
+        std::fs::create_dir_all(site.join("pkg")).unwrap();
+        std::fs::write(site.join("index.html"), "<html>miner</html>").unwrap();
+        std::fs::write(site.join("pkg/miner.wasm"), b"\0asm\x01\0\0\0").unwrap();
+        std::fs::write(root.join("secret.txt"), "do not serve").unwrap();
+        site
+    }
+
+    fn app(site: &Path) -> Router {
+        Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .fallback_service(router(site))
+    }
+
+    async fn get_path(app: Router, path: &str) -> Response {
+        app.oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    async fn body_string(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_wasm_content_type_and_cache() {
+        let site = temp_site();
+        let response = get_path(app(&site), "/pkg/miner.wasm").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/wasm");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], ASSET_CACHE_CONTROL);
+    }
+
+    #[tokio::test]
+    async fn test_index_fallback() {
+        let site = temp_site();
+
+        let response = get_path(app(&site), "/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], HTML_CACHE_CONTROL);
+        assert_eq!(body_string(response).await, "<html>miner</html>");
+
+        let response = get_path(app(&site), "/dashboard/settings").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "<html>miner</html>");
+
+        let response = get_path(app(&site), "/pkg/missing.js").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_path_traversal_is_not_found() {
+        let site = temp_site();
+        for path in ["/../secret.txt", "/pkg/../../secret.txt", "/%2e%2e/secret.txt", "/..%2fsecret.txt", "/..%5csecret.txt"] {
+            let response = get_path(app(&site), path).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_take_precedence() {
+        let site = temp_site();
+        std::fs::write(site.join("health"), "shadowed").unwrap();
+        let response = get_path(app(&site), "/health").await;
+        assert_eq!(body_string(response).await, "OK");
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::AuthConfig;
+
+/// Result of checking a hello's site token
+#[derive(Debug, PartialEq, Eq)]
+pub enum SiteAuth {
+    /// Token matched; carries the configured label
+    Matched(String),
+    /// No valid token, but tokens are optional
+    Anonymous,
+    Denied,
+}
+
+/// Check the site token sent in hello against the configured tokens.
+///
+/// Every configured token is compared, in constant time, so response timing
+/// does not reveal how much of a guess was right or which entry matched.
+pub fn check_site_token(config: &AuthConfig, token: Option<&str>) -> SiteAuth {
+    let mut matched = None;
+    if let Some(token) = token {
+        for (candidate, label) in &config.tokens {
+            if constant_time_eq(token.as_bytes(), candidate.as_bytes()) {
+                matched = Some(label.clone());
+            }
+        }
+    }
+
+    match matched {
+        Some(label) => SiteAuth::Matched(label),
+        None if config.require_site_token => SiteAuth::Denied,
+        None => SiteAuth::Anonymous,
+    }
+}
+
+/// Compare secrets without short-circuiting on the first differing byte
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(require: bool) -> AuthConfig {
+        AuthConfig {
+            require_site_token: require,
+            tokens: [("tok-a".to_string(), "partner-a".to_string())].into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_required_token() {
+        assert_eq!(check_site_token(&config(true), None), SiteAuth::Denied);
+        assert_eq!(check_site_token(&config(true), Some("tok-b")), SiteAuth::Denied);
+        assert_eq!(check_site_token(&config(true), Some("tok-a")), SiteAuth::Matched("partner-a".into()));
+    }
+
+    #[test]
+    fn test_optional_token() {
+        assert_eq!(check_site_token(&config(false), None), SiteAuth::Anonymous);
+        assert_eq!(check_site_token(&config(false), Some("tok-b")), SiteAuth::Anonymous);
+        assert_eq!(check_site_token(&config(false), Some("tok-a")), SiteAuth::Matched("partner-a".into()));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+}
@@ -0,0 +1,254 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::BanConfig;
+use crate::ipkey::{IpKey, DEFAULT_IPV6_PREFIX_LEN};
+use crate::privacy;
+
+struct BanEntry {
+    /// Invalid submissions inside the current window
+    offenses: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    /// Bans served so far; each one doubles the next
+    ban_count: u32,
+}
+
+/// Snapshot of an active ban for the admin API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanInfo {
+    /// The banned address; for IPv6, the start of the banned prefix.
+    /// Rendered per `privacy.anonymize_ips`.
+    pub ip: String,
+    pub remaining_secs: u64,
+    pub ban_count: u32,
+}
+
+/// Temporarily bans IPs that keep sending work that fails validation, since
+/// every such submission may cost a full RandomX hash. IPv6 clients are
+/// tracked and banned by prefix, like the per-IP connection limits.
+pub struct BanManager {
+    entries: DashMap<IpKey, BanEntry>,
+    config: BanConfig,
+    ipv6_prefix_len: u8,
+}
+
+impl BanManager {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            entries: DashMap::new(),
+            config,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+        }
+    }
+
+    /// Track IPv6 clients by their first `len` bits
+    pub fn with_ipv6_prefix(mut self, len: u8) -> Self {
+        self.ipv6_prefix_len = len;
+        self
+    }
+
+    fn key(&self, ip: IpAddr) -> IpKey {
+        IpKey::new(ip, self.ipv6_prefix_len)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        let now = Instant::now();
+        self.entries
+            .get(&self.key(*ip))
+            .and_then(|e| e.banned_until)
+            .map(|until| until > now)
+            .unwrap_or(false)
+    }
+
+    /// Record an invalid submission. Returns true if it triggered a ban.
+    pub fn record_offense(&self, ip: IpAddr) -> bool {
+        if !self.config.enable {
+            return false;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_millis(self.config.offense_window_ms);
+        let mut entry = self.entries.entry(self.key(ip)).or_insert_with(|| BanEntry {
+            offenses: VecDeque::new(),
+            banned_until: None,
+            ban_count: 0,
+        });
+
+        if entry.banned_until.map(|until| until > now).unwrap_or(false) {
+            return false;
+        }
+
+        while entry.offenses.front().map(|&t| now.duration_since(t) > window).unwrap_or(false) {
+            entry.offenses.pop_front();
+        }
+        entry.offenses.push_back(now);
+
+        if entry.offenses.len() as u32 <= self.config.max_offenses {
+            return false;
+        }
+
+        let duration = self.ban_duration(entry.ban_count);
+        entry.ban_count += 1;
+        entry.banned_until = Some(now + duration);
+        entry.offenses.clear();
+        warn!("Banned {} for {:?} (ban #{})", privacy::display_ip(&ip), duration, entry.ban_count);
+        true
+    }
+
+    /// Base duration doubled for every earlier ban, capped at the maximum
+    fn ban_duration(&self, previous_bans: u32) -> Duration {
+        let base = self.config.ban_duration_ms;
+        let scaled = base.saturating_mul(1u64 << previous_bans.min(32));
+        Duration::from_millis(scaled.min(self.config.max_ban_duration_ms))
+    }
+
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter_map(|e| {
+                let until = e.banned_until.filter(|&until| until > now)?;
+                Some(BanInfo {
+                    ip: privacy::display_ip(&e.key().addr()),
+                    remaining_secs: until.duration_since(now).as_secs(),
+                    ban_count: e.ban_count,
+                })
+            })
+            .collect()
+    }
+
+    /// Lift a ban and forget the IP's history. Returns false if it was not banned.
+    pub fn unban(&self, ip: &IpAddr) -> bool {
+        let banned = self.is_banned(ip);
+        self.entries.remove(&self.key(*ip));
+        banned
+    }
+
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// Forget IPs with no active ban and no recent offenses. Escalation
+    /// history is kept until the longest possible ban would have lapsed.
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        let window = Duration::from_millis(self.config.offense_window_ms);
+        let memory = Duration::from_millis(self.config.max_ban_duration_ms);
+        self.entries.retain(|_, e| {
+            let recent_offense = e.offenses.back().map(|&t| now.duration_since(t) <= window).unwrap_or(false);
+            let remembered = e.banned_until.map(|until| until + memory > now).unwrap_or(false);
+            recent_offense || remembered
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BanConfig {
+        BanConfig {
+            enable: true,
+            max_offenses: 3,
+            offense_window_ms: 60_000,
+            ban_duration_ms: 1_000,
+            max_ban_duration_ms: 3_000,
+        }
+    }
+
+    fn ip() -> IpAddr {
+        "198.51.100.9".parse().unwrap()
+    }
+
+    #[test]
+    fn test_ban_after_threshold() {
+        let bans = BanManager::new(config());
+        for _ in 0..3 {
+            assert!(!bans.record_offense(ip()));
+        }
+        assert!(!bans.is_banned(&ip()));
+        assert!(bans.record_offense(ip()));
+        assert!(bans.is_banned(&ip()));
+        assert_eq!(bans.list().len(), 1);
+    }
+
+    #[test]
+    fn test_escalation_is_capped() {
+        let bans = BanManager::new(config());
+        assert_eq!(bans.ban_duration(0), Duration::from_millis(1_000));
+        assert_eq!(bans.ban_duration(1), Duration::from_millis(2_000));
+        assert_eq!(bans.ban_duration(2), Duration::from_millis(3_000));
+        assert_eq!(bans.ban_duration(40), Duration::from_millis(3_000));
+    }
+
+    #[test]
+    fn test_ban_lapses_and_escalates() {
+        let bans = BanManager::new(config());
+        for _ in 0..4 {
+            bans.record_offense(ip());
+        }
+        // Pretend the first ban has run out
+        bans.entries.get_mut(&bans.key(ip())).unwrap().banned_until = Some(Instant::now());
+        assert!(!bans.is_banned(&ip()));
+
+        for _ in 0..4 {
+            bans.record_offense(ip());
+        }
+        let info = bans.list().pop().unwrap();
+        assert_eq!(info.ban_count, 2);
+        assert!(info.remaining_secs >= 1);
+    }
+
+    #[test]
+    fn test_unban_and_disabled() {
+        let bans = BanManager::new(config());
+        for _ in 0..4 {
+            bans.record_offense(ip());
+        }
+        assert!(bans.unban(&ip()));
+        assert!(!bans.is_banned(&ip()));
+        assert!(!bans.unban(&ip()));
+
+        let disabled = BanManager::new(BanConfig { enable: false, ..config() });
+        for _ in 0..10 {
+            assert!(!disabled.record_offense(ip()));
+        }
+        assert!(!disabled.is_banned(&ip()));
+    }
+
+    #[test]
+    fn test_ipv6_bans_cover_the_prefix() {
+        let bans = BanManager::new(config());
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        // Offenses from rotating interface identifiers add up
+        for i in 1..=4 {
+            bans.record_offense(ip(&format!("2001:db8:7:1::{:x}", i)));
+        }
+        assert!(bans.is_banned(&ip("2001:db8:7:1:dead:beef::1")));
+        assert!(!bans.is_banned(&ip("2001:db8:7:2::1")));
+        assert_eq!(bans.list()[0].ip, "2001:db8:7:1::");
+
+        assert!(bans.unban(&ip("2001:db8:7:1::99")));
+        assert!(!bans.is_banned(&ip("2001:db8:7:1::1")));
+    }
+
+    #[test]
+    fn test_cleanup_keeps_active_and_recent() {
+        let bans = BanManager::new(config());
+        let other: IpAddr = "198.51.100.10".parse().unwrap();
+        for _ in 0..4 {
+            bans.record_offense(ip());
+        }
+        bans.record_offense(other);
+        bans.cleanup();
+        assert_eq!(bans.entries.len(), 2);
+
+        bans.entries.get_mut(&bans.key(other)).unwrap().offenses[0] = Instant::now() - Duration::from_secs(120);
+        bans.cleanup();
+        assert_eq!(bans.entries.len(), 1);
+    }
+}
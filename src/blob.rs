@@ -0,0 +1,397 @@
+//! Layout of a Monero block template blob, and the hashing blob miners work
+//! on: the block header, the merkle root of the block's transactions and
+//! their count. The reserved value sits in the miner transaction's extra,
+//! so every job has its own merkle root.
+
+use std::cell::RefCell;
+
+use crate::keccak::keccak256;
+
+thread_local! {
+    /// Copy of a miner transaction taking a reserved value, reused by every
+    /// job made on the thread
+    static MINER_TX: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Where the parts of a template blob are, and the merkle branch of its
+/// miner transaction, worked out once per template
+#[derive(Debug, Clone)]
+pub struct BlockLayout {
+    /// Bytes of the block header, nonce included
+    header_len: usize,
+    miner_tx_version: u64,
+    /// End of the miner transaction's prefix, which holds the reserved value
+    miner_tx_prefix_end: usize,
+    miner_tx_end: usize,
+    /// Transactions in the block, the miner transaction included
+    tx_count: u64,
+    /// Siblings of the miner transaction on its way up to the merkle root,
+    /// lowest first
+    merkle_branch: Vec<[u8; 32]>,
+    blob_len: usize,
+}
+
+impl BlockLayout {
+    pub fn parse(blob: &[u8]) -> Result<Self, String> {
+        let mut pos = 0;
+        // Major and minor version, timestamp
+        for _ in 0..3 {
+            read_varint(blob, &mut pos)?;
+        }
+        // Previous block id and nonce
+        skip(blob, &mut pos, 32 + 4)?;
+        let header_len = pos;
+
+        let miner_tx_version = read_varint(blob, &mut pos)?;
+        if miner_tx_version == 0 || miner_tx_version > 2 {
+            return Err(format!("unsupported miner transaction version {}", miner_tx_version));
+        }
+        // Unlock time
+        read_varint(blob, &mut pos)?;
+        if read_varint(blob, &mut pos)? != 1 || read_byte(blob, &mut pos)? != TXIN_GEN {
+            return Err("miner transaction must have a single coinbase input".into());
+        }
+        // Height
+        read_varint(blob, &mut pos)?;
+        let outputs = read_varint(blob, &mut pos)?;
+        for _ in 0..outputs {
+            // Amount
+            read_varint(blob, &mut pos)?;
+            match read_byte(blob, &mut pos)? {
+                TXOUT_TO_KEY => skip(blob, &mut pos, 32)?,
+                TXOUT_TO_TAGGED_KEY => skip(blob, &mut pos, 33)?,
+                tag => return Err(format!("unsupported output type {:#04x}", tag)),
+            }
+        }
+        let extra_len = read_varint(blob, &mut pos)?;
+        skip(blob, &mut pos, usize::try_from(extra_len).map_err(|_| "extra too long")?)?;
+        let miner_tx_prefix_end = pos;
+        if miner_tx_version >= 2 && read_byte(blob, &mut pos)? != RCT_TYPE_NULL {
+            return Err("miner transaction must not carry RingCT signatures".into());
+        }
+        let miner_tx_end = pos;
+
+        let tx_hashes = read_varint(blob, &mut pos)?;
+        let remaining = blob.len() - pos;
+        if tx_hashes.checked_mul(32) != Some(remaining as u64) {
+            return Err(format!("{} transaction hashes do not fill the {} bytes left", tx_hashes, remaining));
+        }
+        let hashes: Vec<[u8; 32]> = blob[pos..].chunks_exact(32).map(|h| h.try_into().unwrap()).collect();
+
+        Ok(Self {
+            header_len,
+            miner_tx_version,
+            miner_tx_prefix_end,
+            miner_tx_end,
+            tx_count: tx_hashes + 1,
+            merkle_branch: merkle_branch(&hashes),
+            blob_len: blob.len(),
+        })
+    }
+
+    /// Bytes of the block header, nonce included
+    pub fn header_len(&self) -> usize {
+        self.header_len
+    }
+
+    /// What miners hash for the template `blob` (the one this layout was
+    /// parsed from, with any reserved value written in)
+    pub fn hashing_blob(&self, blob: &[u8]) -> Vec<u8> {
+        assert_eq!(blob.len(), self.blob_len, "blob does not match its layout");
+        self.assemble(blob, &self.merkle_root(&blob[self.header_len..self.miner_tx_end]))
+    }
+
+    /// `hashing_blob` of `blob` with `reserved` written at `reserved_offset`.
+    /// When the reserved value lies in the miner transaction's prefix, as
+    /// monerod places it, only that transaction is copied.
+    pub fn hashing_blob_reserved(&self, blob: &[u8], reserved_offset: usize, reserved: &[u8]) -> Vec<u8> {
+        if let Some(root) = self.merkle_root_reserved(blob, reserved_offset, reserved) {
+            return self.assemble(blob, &root);
+        }
+        let mut whole = blob.to_vec();
+        write_clipped(&mut whole, reserved_offset, reserved);
+        self.hashing_blob(&whole)
+    }
+
+    /// The merkle root once `reserved` is written at `reserved_offset`, if
+    /// that lies in the miner transaction's prefix; the rest of the hashing
+    /// blob is the template's own
+    pub fn merkle_root_reserved(&self, blob: &[u8], reserved_offset: usize, reserved: &[u8]) -> Option<[u8; 32]> {
+        assert_eq!(blob.len(), self.blob_len, "blob does not match its layout");
+        let end = reserved_offset.checked_add(reserved.len())?;
+        if !self.in_miner_tx_prefix(reserved_offset, end) {
+            return None;
+        }
+        Some(MINER_TX.with(|tx| {
+            let mut tx = tx.borrow_mut();
+            tx.clear();
+            tx.extend_from_slice(&blob[self.header_len..self.miner_tx_end]);
+            tx[reserved_offset - self.header_len..end - self.header_len].copy_from_slice(reserved);
+            self.merkle_root(&tx)
+        }))
+    }
+
+    /// Hex of what precedes and follows the merkle root in every hashing
+    /// blob of the template `blob`: its header, and the transaction count
+    pub fn hashing_hex_parts(&self, blob: &[u8]) -> (String, String) {
+        let mut count = Vec::with_capacity(10);
+        write_varint(&mut count, self.tx_count);
+        (hex::encode(&blob[..self.header_len]), hex::encode(count))
+    }
+
+    /// Whether bytes `start..end` lie in the miner transaction's prefix,
+    /// where a reserved value changes the merkle root and nothing else
+    pub fn in_miner_tx_prefix(&self, start: usize, end: usize) -> bool {
+        start >= self.header_len && start <= end && end <= self.miner_tx_prefix_end
+    }
+
+    /// Root reached from the miner transaction `tx`
+    fn merkle_root(&self, tx: &[u8]) -> [u8; 32] {
+        let mut root = self.miner_tx_hash(tx);
+        for sibling in &self.merkle_branch {
+            root = hash_pair(&root, sibling);
+        }
+        root
+    }
+
+    /// Header from `blob`, then `root` and the transaction count
+    fn assemble(&self, blob: &[u8], root: &[u8; 32]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.header_len + 32 + 10);
+        out.extend_from_slice(&blob[..self.header_len]);
+        out.extend_from_slice(root);
+        write_varint(&mut out, self.tx_count);
+        out
+    }
+
+    fn miner_tx_hash(&self, tx: &[u8]) -> [u8; 32] {
+        if self.miner_tx_version == 1 {
+            return keccak256(tx);
+        }
+        // Version 2: prefix, RingCT base and prunable parts hashed
+        // separately; a coinbase has no prunable part, which hashes as zero
+        let prefix_len = self.miner_tx_prefix_end - self.header_len;
+        let mut parts = [0u8; 96];
+        parts[..32].copy_from_slice(&keccak256(&tx[..prefix_len]));
+        parts[32..64].copy_from_slice(&keccak256(&tx[prefix_len..]));
+        keccak256(&parts)
+    }
+}
+
+/// Copy `bytes` into `blob` at `offset`, dropping whatever runs past its end
+pub fn write_clipped(blob: &mut [u8], offset: usize, bytes: &[u8]) {
+    if offset < blob.len() {
+        let len = bytes.len().min(blob.len() - offset);
+        blob[offset..offset + len].copy_from_slice(&bytes[..len]);
+    }
+}
+
+const TXIN_GEN: u8 = 0xff;
+const TXOUT_TO_KEY: u8 = 0x02;
+const TXOUT_TO_TAGGED_KEY: u8 = 0x03;
+const RCT_TYPE_NULL: u8 = 0;
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut pair = [0u8; 64];
+    pair[..32].copy_from_slice(left);
+    pair[32..].copy_from_slice(right);
+    keccak256(&pair)
+}
+
+/// Siblings of the first leaf of Monero's transaction tree, given the
+/// leaves after it. The first leaf is always a left child, so the root is
+/// the leaf hashed with each sibling in turn.
+///
+/// Monero's tree is not padded: with `n` leaves, the first `2 * cnt - n`
+/// (`cnt` the largest power of two below `n`) are carried up a level
+/// unpaired, the rest are paired, and from there the tree is perfect.
+fn merkle_branch(rest: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let n = rest.len() + 1;
+    match n {
+        1 => return Vec::new(),
+        2 => return vec![rest[0]],
+        _ => {}
+    }
+    let mut cnt = 1;
+    while cnt * 2 < n {
+        cnt *= 2;
+    }
+    let carried = 2 * cnt - n;
+
+    let mut branch = Vec::new();
+    // The level of `cnt` nodes, minus the first one, which holds the first leaf
+    let mut level: Vec<[u8; 32]> = if carried == 0 {
+        branch.push(rest[0]);
+        rest[1..].chunks_exact(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect()
+    } else {
+        let mut level = rest[..carried - 1].to_vec();
+        level.extend(rest[carried - 1..].chunks_exact(2).map(|pair| hash_pair(&pair[0], &pair[1])));
+        level
+    };
+    while !level.is_empty() {
+        branch.push(level[0]);
+        level = level[1..].chunks_exact(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+    }
+    branch
+}
+
+fn read_byte(blob: &[u8], pos: &mut usize) -> Result<u8, String> {
+    let byte = *blob.get(*pos).ok_or("blob ends early")?;
+    *pos += 1;
+    Ok(byte)
+}
+
+fn skip(blob: &[u8], pos: &mut usize, len: usize) -> Result<(), String> {
+    match pos.checked_add(len) {
+        Some(end) if end <= blob.len() => {
+            *pos = end;
+            Ok(())
+        }
+        _ => Err("blob ends early".into()),
+    }
+}
+
+pub(crate) fn read_varint(blob: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(blob, pos)?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint too long".into())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A template in monerod's layout (versions 16/16, a v2 miner transaction
+/// with one tagged output and an 8-byte extra nonce, three other
+/// transactions), with its hashing blob computed by a separate reference
+/// implementation of Monero's `get_block_hashing_blob`
+#[cfg(test)]
+pub(crate) mod fixture {
+    pub const TEMPLATE_BLOB: &str = concat!(
+        "101080e2cfaa06101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f00000000",
+        "02fc8db70101ffc08db7010180e0a596bb1103404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f7a",
+        "2b01808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f02080000000000000000",
+        "0003",
+        "c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0",
+        "c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1c1",
+        "c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2c2",
+    );
+    /// The block the template builds on, as monerod names it
+    pub const PREV_HASH: &str = "101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f";
+    /// Header timestamp, 2023-11-14
+    pub const TIMESTAMP: u64 = 1_700_000_000;
+    /// Start of the 8 zero bytes in the miner transaction's extra
+    pub const RESERVED_OFFSET: usize = 131;
+    pub const RESERVE_SIZE: u32 = 8;
+    pub const HASHING_BLOB: &str = concat!(
+        "101080e2cfaa06101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f00000000",
+        "f0c7ee9a441fea19255c92d58afd5dc21c71fdc2ab27623356bb3540dcb49ec1",
+        "04",
+    );
+    /// The hashing blob with 0102030405060708 written at the reserved offset
+    pub const HASHING_BLOB_RESERVED: &str = concat!(
+        "101080e2cfaa06101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f00000000",
+        "5c60457c236e6aac1ddf5edfbe4962e18340b372d954e90d5198910bbdcb3fc8",
+        "04",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template() -> Vec<u8> {
+        hex::decode(fixture::TEMPLATE_BLOB).unwrap()
+    }
+
+    #[test]
+    fn test_hashing_blob_matches_reference() {
+        let blob = template();
+        let layout = BlockLayout::parse(&blob).unwrap();
+        assert_eq!(layout.header_len(), 43);
+        assert_eq!(hex::encode(layout.hashing_blob(&blob)), fixture::HASHING_BLOB);
+
+        // The reserved value changes the miner transaction, so the merkle root
+        let mut reserved = blob.clone();
+        reserved[fixture::RESERVED_OFFSET..fixture::RESERVED_OFFSET + 8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(hex::encode(layout.hashing_blob(&reserved)), fixture::HASHING_BLOB_RESERVED);
+    }
+
+    #[test]
+    fn test_hashing_blob_reserved_matches_a_full_copy() {
+        let blob = template();
+        let layout = BlockLayout::parse(&blob).unwrap();
+        let value = [1, 2, 3, 4, 5, 6, 7, 8];
+        let fast = layout.hashing_blob_reserved(&blob, fixture::RESERVED_OFFSET, &value);
+        assert_eq!(hex::encode(fast), fixture::HASHING_BLOB_RESERVED);
+
+        // Anywhere else, including past the end, the whole blob is copied
+        for offset in [0, 40, 139, blob.len() - 4, blob.len() + 10] {
+            let mut whole = blob.clone();
+            write_clipped(&mut whole, offset, &value);
+            assert_eq!(layout.hashing_blob_reserved(&blob, offset, &value), layout.hashing_blob(&whole), "offset {}", offset);
+            assert_eq!(layout.merkle_root_reserved(&blob, offset, &value), None);
+        }
+    }
+
+    #[test]
+    fn test_hex_parts_surround_the_root() {
+        let blob = template();
+        let layout = BlockLayout::parse(&blob).unwrap();
+        let root = layout.merkle_root_reserved(&blob, fixture::RESERVED_OFFSET, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let (header, count) = layout.hashing_hex_parts(&blob);
+        assert_eq!(format!("{}{}{}", header, hex::encode(root), count), fixture::HASHING_BLOB_RESERVED);
+    }
+
+    #[test]
+    fn test_merkle_branch_matches_tree_hash() {
+        // Roots of 1 to 6 leaves (0x01.., 0x02.., ...) from Monero's tree_hash
+        let roots = [
+            "0101010101010101010101010101010101010101010101010101010101010101",
+            "346d8c96a2454213fcc0daff3c96ad0398148181b9fa6488f7ae2c0af5b20aa0",
+            "404ebd91efee7b7c90d0fb00e35f81528db996fdf403b234c5a167940009bb92",
+            "99976d3b1539e7cfaca77649ac7536fec61db00fb0835634915b4d542fff06ae",
+            "db5cf6a1ea015260b8f4d2d70a1c0f2444c342fe3048e9e1bda954918bdfa895",
+            "7bfcd1212603cc3b3d36482a8bb1f3b474db3d555fe0e415f03f00f5311c5ba9",
+        ];
+        let leaves: Vec<[u8; 32]> = (1..=6u8).map(|i| [i; 32]).collect();
+        for (n, expected) in (1..=6).zip(roots) {
+            let root = merkle_branch(&leaves[1..n]).iter().fold(leaves[0], |node, sibling| hash_pair(&node, sibling));
+            assert_eq!(hex::encode(root), expected, "{} leaves", n);
+        }
+    }
+
+    #[test]
+    fn test_malformed_templates_are_refused() {
+        let blob = template();
+        assert!(BlockLayout::parse(&blob[..40]).is_err());
+        assert!(BlockLayout::parse(&blob[..blob.len() - 1]).is_err());
+        assert!(BlockLayout::parse(&[blob.clone(), vec![0]].concat()).is_err());
+
+        // A miner transaction spending a real input
+        let mut not_coinbase = blob.clone();
+        not_coinbase[43 + 6] = 0x02;
+        assert!(BlockLayout::parse(&not_coinbase).is_err());
+        assert!(BlockLayout::parse(&[0u8; 76]).is_err());
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, 1_700_000_000, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+    }
+}
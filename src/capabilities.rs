@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::ServerConfig;
+use crate::protocol::{Encoding, HelloOptions};
+
+/// Proof-of-work algorithm of every job this coordinator hands out
+pub const ALGO: &str = "rx/0";
+
+/// Shortest ping interval a client may ask for
+pub const MIN_KEEPALIVE_MS: u64 = 5_000;
+
+/// What was agreed with a miner in its hello. Worked out once, stored on
+/// the session, and consulted by the writer and by job construction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionCapabilities {
+    /// The `v` of the hello
+    pub protocol_version: u8,
+    pub encoding: Encoding,
+    /// Job targets as 8 or 16 hex digits, stratum style, instead of 64
+    pub wants_compact_target: bool,
+    pub supports_batching: bool,
+    pub algo: String,
+    /// Interval of the server's pings on this connection
+    pub keepalive_ms: u64,
+}
+
+impl SessionCapabilities {
+    /// Settle a hello's requests against what the server offers. Fails
+    /// with a reason for the client when it asks for something unsupported.
+    pub fn negotiate(protocol_version: u8, options: &HelloOptions, server: &ServerConfig) -> Result<Self, &'static str> {
+        if options.algo.as_deref().is_some_and(|algo| algo != ALGO) {
+            return Err("unsupported algo");
+        }
+        // Clients may ask for more frequent pings (to keep a NAT mapping
+        // open), never less frequent ones
+        let keepalive_ms = match options.keepalive_ms {
+            Some(requested) => requested.max(MIN_KEEPALIVE_MS).min(server.keepalive_interval_ms),
+            None => server.keepalive_interval_ms,
+        };
+        Ok(Self {
+            protocol_version,
+            encoding: options.encoding.unwrap_or_default(),
+            wants_compact_target: options.compact_target,
+            supports_batching: options.batching,
+            algo: ALGO.to_string(),
+            keepalive_ms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::test_state;
+
+    fn negotiate(options: HelloOptions) -> Result<SessionCapabilities, &'static str> {
+        let (state, _template_tx) = test_state();
+        SessionCapabilities::negotiate(1, &options, &state.config.server)
+    }
+
+    #[test]
+    fn test_plain_hello_gets_defaults() {
+        let caps = negotiate(HelloOptions::default()).unwrap();
+        assert_eq!(
+            caps,
+            SessionCapabilities {
+                protocol_version: 1,
+                encoding: Encoding::Text,
+                wants_compact_target: false,
+                supports_batching: false,
+                algo: ALGO.to_string(),
+                keepalive_ms: 30_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_flags_are_recorded() {
+        let caps = negotiate(HelloOptions {
+            encoding: Some(Encoding::Binary),
+            compact_target: true,
+            batching: true,
+            algo: Some(ALGO.into()),
+            keepalive_ms: Some(10_000),
+            randomx_mode: None,
+        })
+        .unwrap();
+        assert_eq!(caps.encoding, Encoding::Binary);
+        assert!(caps.wants_compact_target);
+        assert!(caps.supports_batching);
+        assert_eq!(caps.keepalive_ms, 10_000);
+
+        let caps = negotiate(HelloOptions { compact_target: true, ..Default::default() }).unwrap();
+        assert!(caps.wants_compact_target);
+        assert!(!caps.supports_batching);
+        assert_eq!(caps.encoding, Encoding::Text);
+    }
+
+    #[test]
+    fn test_keepalive_is_clamped() {
+        let slower = negotiate(HelloOptions { keepalive_ms: Some(600_000), ..Default::default() }).unwrap();
+        assert_eq!(slower.keepalive_ms, 30_000);
+        let too_fast = negotiate(HelloOptions { keepalive_ms: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(too_fast.keepalive_ms, MIN_KEEPALIVE_MS);
+    }
+
+    #[test]
+    fn test_unsupported_algo_is_refused() {
+        assert_eq!(negotiate(HelloOptions { algo: Some("cn/r".into()), ..Default::default() }), Err("unsupported algo"));
+    }
+}
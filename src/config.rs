@@ -1,7 +1,10 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::fs;
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 use anyhow::{Context, Result};
+use ipnet::IpNet;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -10,30 +13,257 @@ pub struct Config {
     pub jobs: JobsConfig,
     pub limits: LimitsConfig,
     pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub bans: BanConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub vardiff: VarDiffConfig,
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    #[serde(default)]
+    pub randomx: RandomXConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig {
-    pub bind_addr: String,
+    /// One listen address, or a list to listen on several at once
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind_addr: Vec<String>,
     pub ws_path: String,
     pub max_connections: usize,
     pub max_connections_per_ip: usize,
     pub max_frame_bytes: usize,
+    /// Proxies whose X-Forwarded-For / X-Real-IP headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Expect a PROXY protocol (v1/v2) header on every accepted connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Origins allowed to use the coordinator; empty allows all
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Terminate TLS in-process when set
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Offer the HTTP transports (long-polling, SSE) under /v1 for clients without WebSockets
+    #[serde(default = "default_true")]
+    pub long_polling: bool,
+    /// Serve the miner's HTML/JS/WASM bundle from this directory
+    #[serde(default)]
+    pub static_dir: Option<PathBuf>,
+    /// Interval between server-sent WebSocket pings
+    #[serde(default = "default_keepalive_interval_ms")]
+    pub keepalive_interval_ms: u64,
+    /// Close the connection if nothing is heard from the client for this long
+    #[serde(default = "default_keepalive_timeout_ms")]
+    pub keepalive_timeout_ms: u64,
+    /// Close connections that have not sent hello within this long
+    #[serde(default = "default_hello_timeout_ms")]
+    pub hello_timeout_ms: u64,
+    /// How long to wait for sessions to close on shutdown
+    #[serde(default = "default_shutdown_drain_timeout_ms")]
+    pub shutdown_drain_timeout_ms: u64,
+    /// Upper bound of the random reconnect delay sent in the goodbye message
+    #[serde(default = "default_shutdown_retry_jitter_ms")]
+    pub shutdown_retry_jitter_ms: u64,
+    /// Messages queued per connection before non-critical ones are dropped
+    #[serde(default = "default_outbound_queue_size")]
+    pub outbound_queue_size: usize,
+    /// Disconnect a client whose queue cannot take a job or result for this long
+    #[serde(default = "default_slow_client_timeout_ms")]
+    pub slow_client_timeout_ms: u64,
+    /// Interval of unsolicited stats pushes to ready miners; 0 disables them
+    #[serde(default = "default_stats_interval_ms")]
+    pub stats_interval_ms: u64,
+    /// Who may reach /stats and the admin API
+    #[serde(default)]
+    pub protected_routes: ProtectedRoutesConfig,
+}
+
+fn reserve_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let size = u32::deserialize(deserializer)?;
+    if !(1..=crate::rpc::MAX_RESERVE_SIZE).contains(&size) {
+        return Err(serde::de::Error::custom(format!(
+            "reserve_size must be between 1 and {}, got {}",
+            crate::rpc::MAX_RESERVE_SIZE,
+            size
+        )));
+    }
+    Ok(size)
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+impl ServerConfig {
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(self.keepalive_interval_ms > 0, "server.keepalive_interval_ms must be at least 1");
+        Ok(())
+    }
+}
+
+fn default_keepalive_interval_ms() -> u64 {
+    30_000
+}
+
+fn default_keepalive_timeout_ms() -> u64 {
+    90_000
+}
+
+fn default_hello_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_shutdown_drain_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_shutdown_retry_jitter_ms() -> u64 {
+    30_000
+}
+
+fn default_outbound_queue_size() -> usize {
+    64
+}
+
+fn default_slow_client_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_stats_interval_ms() -> u64 {
+    30_000
+}
+
+/// Access policy for the operational routes. With neither a token nor any
+/// CIDRs set, the routes stay open.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProtectedRoutesConfig {
+    /// Bearer token that grants access from any address
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Client networks allowed without a token
+    #[serde(default)]
+    pub allowed_cidrs: Vec<IpNet>,
+}
+
+impl ProtectedRoutesConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.token.as_deref().is_some_and(|t| !t.is_empty()) || !self.allowed_cidrs.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MonerodConfig {
     pub rpc_url: String,
     pub wallet_address: String,
-    pub reserve_size: u8,
+    /// Bytes monerod leaves in each template for reserved values, 1 to
+    /// `rpc::MAX_RESERVE_SIZE`
+    #[serde(deserialize_with = "reserve_size")]
+    pub reserve_size: u32,
     pub rpc_timeout_ms: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct JobsConfig {
-    pub job_ttl_ms: u64,
+    /// How long blocks found on a job are submitted to monerod
+    #[serde(alias = "job_ttl_ms")]
+    pub block_ttl_ms: u64,
+    /// How long shares on a job are credited; the block TTL when unset
+    #[serde(default)]
+    pub share_ttl_ms: Option<u64>,
     pub template_refresh_interval_ms: u64,
     pub stale_job_grace_ms: u64,
+    /// Share difficulty of jobs for sessions without vardiff; 0 asks for
+    /// blocks only
+    #[serde(default)]
+    pub default_share_difficulty: u64,
+    /// Live jobs across all sessions; the oldest are evicted past this
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: usize,
+    /// Live jobs a session may hold; the oldest is dropped past this
+    #[serde(default = "default_max_jobs_per_session")]
+    pub max_jobs_per_session: usize,
+    /// Nonces each job remembers across sessions to catch replays
+    #[serde(default = "default_max_nonces_per_job")]
+    pub max_nonces_per_job: usize,
+    /// Submissions hashed per job; further ones are refused with RATE_LIMIT
+    #[serde(default = "default_max_submissions_per_job")]
+    pub max_submissions_per_job: u32,
+    /// Written into each job's reserved value, to tell coordinators mining
+    /// to one wallet apart
+    #[serde(default)]
+    pub instance_id: u32,
+    /// Give each miner thread a job of its own, differing in reserved value,
+    /// instead of one job whose nonces the threads split
+    #[serde(default)]
+    pub job_per_thread: bool,
+    /// How far a submitted block's timestamp may be from the clock, in
+    /// seconds; 0 leaves it unchecked
+    #[serde(default = "default_max_timestamp_skew_secs")]
+    pub max_timestamp_skew_secs: u64,
+}
+
+fn default_max_jobs() -> usize {
+    crate::jobs::DEFAULT_MAX_JOBS
+}
+
+fn default_max_jobs_per_session() -> usize {
+    crate::jobs::DEFAULT_MAX_JOBS_PER_SESSION
+}
+
+fn default_max_nonces_per_job() -> usize {
+    crate::jobs::DEFAULT_MAX_NONCES_PER_JOB
+}
+
+/// monerod's own limit on how far ahead a block may be timestamped
+fn default_max_timestamp_skew_secs() -> u64 {
+    7200
+}
+
+impl JobsConfig {
+    /// A share TTL shorter than the block TTL would refuse shares on jobs
+    /// whose blocks are still submitted
+    pub fn validate(&self) -> Result<()> {
+        if let Some(share_ttl_ms) = self.share_ttl_ms {
+            anyhow::ensure!(
+                share_ttl_ms >= self.block_ttl_ms,
+                "jobs.share_ttl_ms ({}) must be at least jobs.block_ttl_ms ({})",
+                share_ttl_ms,
+                self.block_ttl_ms
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_max_submissions_per_job() -> u32 {
+    crate::jobs::DEFAULT_MAX_SUBMISSIONS_PER_JOB
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -41,6 +271,41 @@ pub struct LimitsConfig {
     pub submits_per_minute: u32,
     pub shares_per_minute: u32,
     pub messages_per_second: u32,
+    /// Submissions one session may have being verified at once
+    #[serde(default = "default_max_inflight_submits")]
+    pub max_inflight_submits: u32,
+    /// New connections accepted per IP per minute; 0 disables the limit
+    #[serde(default = "default_connections_per_minute")]
+    pub connections_per_minute: u32,
+    /// Sessions with no activity for this long are removed
+    #[serde(default = "default_session_idle_timeout_secs")]
+    pub session_idle_timeout_secs: u64,
+    /// Ready sessions that submit nothing for this long get no more jobs
+    /// until they send a message; 0 disables the check
+    #[serde(default)]
+    pub inactive_timeout_secs: u64,
+    /// Sessions older than this must handshake again; 0 disables the limit
+    #[serde(default)]
+    pub max_session_lifetime_secs: u64,
+    /// IPv6 clients are counted and banned by this prefix, not the full address
+    #[serde(default = "default_ipv6_prefix_len")]
+    pub ipv6_prefix_len: u8,
+}
+
+fn default_ipv6_prefix_len() -> u8 {
+    crate::ipkey::DEFAULT_IPV6_PREFIX_LEN
+}
+
+fn default_session_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_connections_per_minute() -> u32 {
+    60
+}
+
+fn default_max_inflight_submits() -> u32 {
+    2
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -48,6 +313,382 @@ pub struct MetricsConfig {
     pub enable: bool,
     pub bind_addr: String,
     pub path: String,
+    /// Worker names exported as their own label; all others count as "other"
+    #[serde(default)]
+    pub worker_labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub enable: bool,
+    /// Bearer token required on every admin request
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthConfig {
+    /// /readyz fails if the current template is older than this
+    #[serde(default = "default_max_template_age_ms")]
+    pub max_template_age_ms: u64,
+    /// /readyz fails if monerod has not answered an RPC call for this long
+    #[serde(default = "default_max_rpc_silence_ms")]
+    pub max_rpc_silence_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_template_age_ms: default_max_template_age_ms(),
+            max_rpc_silence_ms: default_max_rpc_silence_ms(),
+        }
+    }
+}
+
+fn default_max_template_age_ms() -> u64 {
+    600_000
+}
+
+fn default_max_rpc_silence_ms() -> u64 {
+    60_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    /// Refuse miners whose hello does not carry a known site token
+    #[serde(default)]
+    pub require_site_token: bool,
+    /// Accepted site tokens, mapped to a label used for accounting
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+    /// Ready connections allowed per site token label; 0 disables the cap
+    #[serde(default)]
+    pub max_connections_per_site: usize,
+    /// How long a site's rollup is kept after its last session leaves
+    #[serde(default = "default_site_stats_retention_secs")]
+    pub site_stats_retention_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            require_site_token: false,
+            tokens: HashMap::new(),
+            max_connections_per_site: 0,
+            site_stats_retention_secs: default_site_stats_retention_secs(),
+        }
+    }
+}
+
+fn default_site_stats_retention_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BanConfig {
+    #[serde(default = "default_true")]
+    pub enable: bool,
+    /// Invalid submissions tolerated per window before an IP is banned
+    #[serde(default = "default_max_offenses")]
+    pub max_offenses: u32,
+    #[serde(default = "default_offense_window_ms")]
+    pub offense_window_ms: u64,
+    /// Length of a first ban; each repeat ban doubles it
+    #[serde(default = "default_ban_duration_ms")]
+    pub ban_duration_ms: u64,
+    #[serde(default = "default_max_ban_duration_ms")]
+    pub max_ban_duration_ms: u64,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            max_offenses: default_max_offenses(),
+            offense_window_ms: default_offense_window_ms(),
+            ban_duration_ms: default_ban_duration_ms(),
+            max_ban_duration_ms: default_max_ban_duration_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    /// Append session lifecycle events here as JSON lines
+    #[serde(default)]
+    pub events_file: Option<PathBuf>,
+    /// Rotate the events file to `<events_file>.1` past this size; 0 never rotates
+    #[serde(default = "default_events_max_bytes")]
+    pub events_max_bytes: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            events_file: None,
+            events_max_bytes: default_events_max_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VarDiffConfig {
+    /// Give each session its own share difficulty instead of the block difficulty
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "default_vardiff_initial_difficulty")]
+    pub initial_difficulty: u64,
+    /// Starting difficulty for clients reporting `randomx_mode` "fast";
+    /// unset uses `initial_difficulty`
+    #[serde(default)]
+    pub initial_difficulty_fast: Option<u64>,
+    /// Starting difficulty for clients reporting `randomx_mode` "light"
+    #[serde(default)]
+    pub initial_difficulty_light: Option<u64>,
+    #[serde(default = "default_vardiff_min_difficulty")]
+    pub min_difficulty: u64,
+    #[serde(default = "default_vardiff_max_difficulty")]
+    pub max_difficulty: u64,
+    /// Seconds between accepted shares the controller aims for
+    #[serde(default = "default_vardiff_target_share_secs")]
+    pub target_share_secs: u64,
+    /// Minimum seconds between difficulty changes for one session
+    #[serde(default = "default_vardiff_retarget_secs")]
+    pub retarget_secs: u64,
+}
+
+impl Default for VarDiffConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            initial_difficulty: default_vardiff_initial_difficulty(),
+            initial_difficulty_fast: None,
+            initial_difficulty_light: None,
+            min_difficulty: default_vardiff_min_difficulty(),
+            max_difficulty: default_vardiff_max_difficulty(),
+            target_share_secs: default_vardiff_target_share_secs(),
+            retarget_secs: default_vardiff_retarget_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PersistenceConfig {
+    /// Save per-site accounting here and restore it on startup; unset keeps it in memory only
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default = "default_snapshot_interval_secs")]
+    pub snapshot_interval_secs: u64,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            snapshot_interval_secs: default_snapshot_interval_secs(),
+        }
+    }
+}
+
+/// How client addresses are written to logs, the event log and the admin API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpAnonymization {
+    #[default]
+    Off,
+    /// Zero the last octet of IPv4 and the last 80 bits of IPv6
+    Truncate,
+    /// Keyed hash of the address with `hmac_secret`
+    Hmac,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PrivacyConfig {
+    #[serde(default)]
+    pub anonymize_ips: IpAnonymization,
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+}
+
+/// How submissions are hashed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    /// From the 256 MB cache
+    #[default]
+    Light,
+    /// From a 2 GB dataset built from the cache, about ten times faster
+    Fast,
+}
+
+/// RandomX flags to force on or off. Unset ones are left to RandomX's
+/// choice for the CPU, which never includes large pages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RandomXFlags {
+    pub large_pages: Option<bool>,
+    pub jit: Option<bool>,
+    /// JIT pages never writable and executable at once, for hosts that forbid it
+    pub secure: Option<bool>,
+    pub hard_aes: Option<bool>,
+}
+
+/// What hashes submissions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifierKind {
+    /// RandomX VMs in this process
+    #[default]
+    Local,
+    /// monerod's calc_pow, a round trip per hash, without RandomX's memory
+    Rpc,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RandomXConfig {
+    /// VMs hashing submissions at once; unset is one per core, at most 4, in
+    /// light mode and one in fast mode, as each VM builds its own dataset
+    #[serde(default)]
+    pub vms: Option<usize>,
+    #[serde(default)]
+    pub mode: VerifyMode,
+    /// Percent of shares claiming a result hash that are hashed again; the
+    /// rest are credited on the claim. Claimed blocks are always hashed.
+    #[serde(default = "default_verify_share_percent")]
+    pub verify_share_percent: u8,
+    /// Hashes running or waiting for a VM; past this submits are refused as
+    /// busy. Claimed blocks are let in regardless.
+    #[serde(default = "default_verify_queue")]
+    pub verify_queue: usize,
+    #[serde(flatten)]
+    pub flags: RandomXFlags,
+    #[serde(default)]
+    pub verifier: VerifierKind,
+    /// With the local verifier, hash over calc_pow when no RandomX cache or
+    /// VM can be made
+    #[serde(default = "default_rpc_fallback")]
+    pub rpc_fallback: bool,
+    /// calc_pow calls waiting on monerod at once
+    #[serde(default = "default_rpc_max_concurrent")]
+    pub rpc_max_concurrent: usize,
+    /// calc_pow calls started per second; hashes past this are refused
+    #[serde(default = "default_rpc_per_second")]
+    pub rpc_per_second: u32,
+    /// Hash RandomX's test vector at startup, refusing to start on a wrong hash
+    #[serde(default = "default_self_test")]
+    pub self_test: bool,
+}
+
+impl Default for RandomXConfig {
+    fn default() -> Self {
+        Self {
+            vms: None,
+            mode: VerifyMode::default(),
+            verify_share_percent: default_verify_share_percent(),
+            verify_queue: default_verify_queue(),
+            flags: RandomXFlags::default(),
+            verifier: VerifierKind::default(),
+            rpc_fallback: default_rpc_fallback(),
+            rpc_max_concurrent: default_rpc_max_concurrent(),
+            rpc_per_second: default_rpc_per_second(),
+            self_test: default_self_test(),
+        }
+    }
+}
+
+fn default_verify_share_percent() -> u8 {
+    100
+}
+
+fn default_verify_queue() -> usize {
+    64
+}
+
+fn default_rpc_fallback() -> bool {
+    true
+}
+
+fn default_rpc_max_concurrent() -> usize {
+    2
+}
+
+fn default_rpc_per_second() -> u32 {
+    20
+}
+
+fn default_self_test() -> bool {
+    true
+}
+
+impl RandomXConfig {
+    pub fn vms(&self) -> usize {
+        match self.mode {
+            VerifyMode::Fast => self.vms.unwrap_or(1),
+            VerifyMode::Light => self.vms.unwrap_or_else(crate::vm_pool::default_size),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(self.vms != Some(0), "randomx.vms must be at least 1");
+        anyhow::ensure!(
+            self.mode == VerifyMode::Light || self.vms() == 1,
+            "randomx.mode = \"fast\" builds a 2 GB dataset per VM, so needs randomx.vms = 1"
+        );
+        anyhow::ensure!(self.verify_share_percent <= 100, "randomx.verify_share_percent must be at most 100");
+        anyhow::ensure!(self.verify_queue > 0, "randomx.verify_queue must be at least 1");
+        anyhow::ensure!(self.rpc_max_concurrent > 0, "randomx.rpc_max_concurrent must be at least 1");
+        anyhow::ensure!(self.rpc_per_second > 0, "randomx.rpc_per_second must be at least 1");
+        Ok(())
+    }
+}
+
+fn default_snapshot_interval_secs() -> u64 {
+    60
+}
+
+fn default_vardiff_initial_difficulty() -> u64 {
+    5_000
+}
+
+fn default_vardiff_min_difficulty() -> u64 {
+    500
+}
+
+fn default_vardiff_max_difficulty() -> u64 {
+    1_000_000_000
+}
+
+fn default_vardiff_target_share_secs() -> u64 {
+    30
+}
+
+fn default_vardiff_retarget_secs() -> u64 {
+    60
+}
+
+fn default_events_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_offenses() -> u32 {
+    20
+}
+
+fn default_offense_window_ms() -> u64 {
+    60_000
+}
+
+fn default_ban_duration_ms() -> u64 {
+    300_000
+}
+
+fn default_max_ban_duration_ms() -> u64 {
+    86_400_000
 }
 
 pub fn load_config() -> Result<Config> {
@@ -58,6 +699,86 @@ pub fn load_config() -> Result<Config> {
     
     let config: Config = toml::from_str(&config_content)
         .with_context(|| "Failed to parse configuration")?;
+    config.server.validate()?;
+    config.jobs.validate()?;
+    config.randomx.validate()?;
     
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monerod(reserve_size: u32) -> Result<MonerodConfig, toml::de::Error> {
+        toml::from_str(&format!(
+            "rpc_url = \"http://127.0.0.1:18081\"\nwallet_address = \"test\"\nreserve_size = {}\nrpc_timeout_ms = 100",
+            reserve_size
+        ))
+    }
+
+    #[test]
+    fn test_reserve_size_within_monerods_limit() {
+        assert_eq!(monerod(1).unwrap().reserve_size, 1);
+        assert_eq!(monerod(255).unwrap().reserve_size, 255);
+        assert!(monerod(0).unwrap_err().to_string().contains("between 1 and 255"));
+        assert!(monerod(256).is_err());
+    }
+
+    fn jobs(ttls: &str) -> JobsConfig {
+        toml::from_str(&format!("{}\ntemplate_refresh_interval_ms = 20000\nstale_job_grace_ms = 10000", ttls)).unwrap()
+    }
+
+    #[test]
+    fn test_keepalive_interval_is_not_zero() {
+        let server = |extra: &str| -> ServerConfig {
+            toml::from_str(&format!(
+                "bind_addr = \"0.0.0.0:8080\"\nws_path = \"/ws\"\nmax_connections = 10\nmax_connections_per_ip = 2\nmax_frame_bytes = 4096\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+        assert!(server("").validate().is_ok());
+        let err = server("keepalive_interval_ms = 0").validate().unwrap_err();
+        assert!(err.to_string().contains("server.keepalive_interval_ms"));
+    }
+
+    #[test]
+    fn test_share_ttl_is_at_least_the_block_ttl() {
+        let legacy = jobs("job_ttl_ms = 30000");
+        assert_eq!((legacy.block_ttl_ms, legacy.share_ttl_ms), (30_000, None));
+        assert!(legacy.validate().is_ok());
+        assert!(jobs("block_ttl_ms = 30000\nshare_ttl_ms = 30000").validate().is_ok());
+        assert!(jobs("block_ttl_ms = 30000\nshare_ttl_ms = 45000").validate().is_ok());
+        let err = jobs("block_ttl_ms = 30000\nshare_ttl_ms = 20000").validate().unwrap_err();
+        assert!(err.to_string().contains("at least jobs.block_ttl_ms"));
+    }
+
+    #[test]
+    fn test_randomx_vms_default_to_the_cores() {
+        let unset: RandomXConfig = toml::from_str("").unwrap();
+        assert!((1..=crate::vm_pool::MAX_DEFAULT_VMS).contains(&unset.vms()));
+        let set: RandomXConfig = toml::from_str("vms = 8").unwrap();
+        assert_eq!(set.vms(), 8);
+        assert!(set.validate().is_ok());
+        assert!(toml::from_str::<RandomXConfig>("vms = 0").unwrap().validate().is_err());
+        assert_eq!(unset.mode, VerifyMode::Light);
+        let fast: RandomXConfig = toml::from_str("mode = \"fast\"").unwrap();
+        assert_eq!((fast.mode, fast.vms()), (VerifyMode::Fast, 1));
+        assert!(fast.validate().is_ok());
+        let err = toml::from_str::<RandomXConfig>("mode = \"fast\"\nvms = 2").unwrap().validate().unwrap_err();
+        assert!(err.to_string().contains("randomx.vms = 1"));
+        assert_eq!(unset.verify_share_percent, 100);
+        assert!(toml::from_str::<RandomXConfig>("verify_share_percent = 101").unwrap().validate().is_err());
+        assert_eq!(unset.verify_queue, 64);
+        assert!(toml::from_str::<RandomXConfig>("verify_queue = 0").unwrap().validate().is_err());
+        assert_eq!(unset.flags, RandomXFlags::default());
+        let flags = toml::from_str::<RandomXConfig>("large_pages = true\njit = false").unwrap().flags;
+        assert_eq!((flags.large_pages, flags.jit, flags.secure), (Some(true), Some(false), None));
+        assert_eq!((unset.verifier, unset.rpc_fallback), (VerifierKind::Local, true));
+        assert_eq!(toml::from_str::<RandomXConfig>("verifier = \"rpc\"").unwrap().verifier, VerifierKind::Rpc);
+        assert!(toml::from_str::<RandomXConfig>("rpc_per_second = 0").unwrap().validate().is_err());
+        assert!(unset.self_test);
+        assert!(!toml::from_str::<RandomXConfig>("self_test = false").unwrap().self_test);
+    }
+}
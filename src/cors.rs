@@ -0,0 +1,120 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Build the CORS layer from the configured origin allowlist.
+/// An empty list allows every origin.
+pub fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    let layer = CorsLayer::new().allow_methods(Any).allow_headers(Any);
+
+    if allowed_origins.is_empty() {
+        return layer.allow_origin(Any);
+    }
+
+    let allowed = allowed_origins.to_vec();
+    layer.allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+        origin
+            .to_str()
+            .map(|o| origin_allowed(o, &allowed))
+            .unwrap_or(false)
+    }))
+}
+
+/// Check the Origin of a WebSocket upgrade request.
+///
+/// Browsers always send Origin on WebSocket upgrades, so a missing header means
+/// a non-browser client, which the allowlist is not meant to (and cannot) stop.
+pub fn upgrade_origin_allowed(headers: &HeaderMap, allowed_origins: &[String]) -> bool {
+    match headers.get(header::ORIGIN) {
+        Some(origin) => origin
+            .to_str()
+            .map(|o| origin_allowed(o, allowed_origins))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// Check an Origin header value against the allowlist.
+///
+/// Entries are either exact origins (`https://example.com`) or wildcard
+/// subdomains (`https://*.example.com`), which match any subdomain depth but
+/// not the bare domain itself. An empty list allows everything.
+pub fn origin_allowed(origin: &str, allowed_origins: &[String]) -> bool {
+    if allowed_origins.is_empty() {
+        return true;
+    }
+
+    let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+    allowed_origins.iter().any(|pattern| {
+        let pattern = pattern.trim_end_matches('/').to_ascii_lowercase();
+        if pattern == "*" || pattern == origin {
+            return true;
+        }
+        match pattern.split_once("://*.") {
+            Some((scheme, domain)) => origin
+                .strip_prefix(scheme)
+                .and_then(|rest| rest.strip_prefix("://"))
+                .map(|host| host.len() > domain.len() && host.ends_with(&format!(".{}", domain)))
+                .unwrap_or(false),
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> Vec<String> {
+        vec![
+            "https://miner.example.com".to_string(),
+            "https://*.partner.org".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_exact_origin_allowed() {
+        assert!(origin_allowed("https://miner.example.com", &allowlist()));
+        assert!(origin_allowed("HTTPS://Miner.Example.com", &allowlist()));
+    }
+
+    #[test]
+    fn test_disallowed_origin() {
+        assert!(!origin_allowed("https://evil.com", &allowlist()));
+        assert!(!origin_allowed("http://miner.example.com", &allowlist()));
+        assert!(!origin_allowed("https://miner.example.com.evil.com", &allowlist()));
+        assert!(!origin_allowed("null", &allowlist()));
+    }
+
+    #[test]
+    fn test_wildcard_subdomains() {
+        assert!(origin_allowed("https://a.partner.org", &allowlist()));
+        assert!(origin_allowed("https://a.b.partner.org", &allowlist()));
+        assert!(!origin_allowed("https://partner.org", &allowlist()));
+        assert!(!origin_allowed("https://evilpartner.org", &allowlist()));
+        assert!(!origin_allowed("http://a.partner.org", &allowlist()));
+    }
+
+    #[test]
+    fn test_wildcard_with_port() {
+        let allowed = vec!["https://*.partner.org:8443".to_string()];
+        assert!(origin_allowed("https://a.partner.org:8443", &allowed));
+        assert!(!origin_allowed("https://a.partner.org", &allowed));
+    }
+
+    #[test]
+    fn test_upgrade_origin_header() {
+        let mut headers = HeaderMap::new();
+        assert!(upgrade_origin_allowed(&headers, &allowlist()));
+
+        headers.insert(header::ORIGIN, "https://a.partner.org".parse().unwrap());
+        assert!(upgrade_origin_allowed(&headers, &allowlist()));
+
+        headers.insert(header::ORIGIN, "https://evil.com".parse().unwrap());
+        assert!(!upgrade_origin_allowed(&headers, &allowlist()));
+    }
+
+    #[test]
+    fn test_empty_allowlist_allows_all() {
+        assert!(origin_allowed("https://anything.example", &[]));
+    }
+}
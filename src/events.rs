@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::config::LoggingConfig;
+use crate::metrics::Metrics;
+use crate::privacy;
+use crate::protocol::{ErrorCode, SubmitStatus};
+
+/// Events buffered for the writer before new ones are dropped
+const EVENT_QUEUE_SIZE: usize = 4096;
+
+/// One line of the session event log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEvent {
+    /// Unix time in milliseconds
+    pub ts_ms: u64,
+    pub session_id: String,
+    /// Rendered per `privacy.anonymize_ips`
+    pub ip: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    Open,
+    Hello {
+        client_version: String,
+        threads: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        site: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        worker: Option<String>,
+    },
+    Submit {
+        job_id: String,
+        status: SubmitStatus,
+    },
+    /// An error reply sent to the client
+    Error {
+        code: ErrorCode,
+    },
+    Close,
+}
+
+/// Handle for recording session events. Cheap to clone; does nothing when
+/// no events file is configured.
+#[derive(Clone, Default)]
+pub struct EventLog {
+    inner: Option<(mpsc::Sender<SessionEvent>, Arc<Metrics>)>,
+}
+
+impl EventLog {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Open the configured events file and spawn its writer task
+    pub fn start(config: &LoggingConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let path = match &config.events_file {
+            Some(path) => path.clone(),
+            None => return Ok(Self::disabled()),
+        };
+        let file = open(&path)?;
+        info!("Writing session events to {}", path.display());
+
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        tokio::spawn(write_loop(rx, path, config.events_max_bytes, file));
+        Ok(Self { inner: Some((tx, metrics)) })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Queue an event without waiting; it is dropped, and counted, if the
+    /// writer has fallen behind
+    pub fn emit(&self, session_id: &str, ip: IpAddr, kind: EventKind) {
+        let (tx, metrics) = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let event = SessionEvent {
+            ts_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            session_id: session_id.to_string(),
+            ip: privacy::display_ip(&ip),
+            kind,
+        };
+        if tx.try_send(event).is_err() {
+            metrics.inc_events_dropped();
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<tokio::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open events file {}", path.display()))?;
+    Ok(tokio::fs::File::from_std(file))
+}
+
+/// Path the log is moved to when it rotates
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+async fn write_loop(mut rx: mpsc::Receiver<SessionEvent>, path: PathBuf, max_bytes: u64, file: tokio::fs::File) {
+    let mut size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    let mut out = BufWriter::new(file);
+
+    while let Some(event) = rx.recv().await {
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize session event: {}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+
+        if max_bytes > 0 && size > 0 && size + line.len() as u64 > max_bytes {
+            let _ = out.flush().await;
+            match rotate(&path) {
+                Ok(file) => {
+                    out = BufWriter::new(file);
+                    size = 0;
+                }
+                Err(e) => warn!("Failed to rotate events file: {:#}", e),
+            }
+        }
+
+        if let Err(e) = out.write_all(&line).await {
+            warn!("Failed to write session event: {}", e);
+            continue;
+        }
+        size += line.len() as u64;
+
+        // Flush once the queue is drained so a burst costs one write
+        if rx.is_empty() {
+            if let Err(e) = out.flush().await {
+                warn!("Failed to flush events file: {}", e);
+            }
+        }
+    }
+    let _ = out.flush().await;
+}
+
+/// Move the current file aside, replacing the previous rotation, and start a new one
+fn rotate(path: &Path) -> Result<tokio::fs::File> {
+    std::fs::rename(path, rotated_path(path))
+        .with_context(|| format!("Failed to rotate {}", path.display()))?;
+    open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("coordinator-events-{}-{}.jsonl", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+        path
+    }
+
+    fn read_events(path: &Path) -> Vec<SessionEvent> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    async fn wait_for_lines(path: &Path, count: usize) {
+        for _ in 0..200 {
+            if read_events(path).len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("events were not written");
+    }
+
+    #[tokio::test]
+    async fn test_events_are_written_as_json_lines() {
+        let path = temp_path("lines");
+        let config = LoggingConfig { events_file: Some(path.clone()), ..Default::default() };
+        let log = EventLog::start(&config, Arc::new(Metrics::new())).unwrap();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+
+        log.emit("s1", ip, EventKind::Open);
+        log.emit("s1", ip, EventKind::Hello { client_version: "t".into(), threads: 2, site: None, worker: Some("blog-footer".into()) });
+        log.emit("s1", ip, EventKind::Submit { job_id: "j1".into(), status: SubmitStatus::Rejected });
+        log.emit("s1", ip, EventKind::Error { code: ErrorCode::RateLimit });
+        log.emit("s1", ip, EventKind::Close);
+        wait_for_lines(&path, 5).await;
+
+        let events = read_events(&path);
+        assert!(events.iter().all(|e| e.session_id == "s1" && e.ip == ip.to_string() && e.ts_ms > 0));
+        assert!(matches!(events[0].kind, EventKind::Open));
+        assert!(matches!(&events[1].kind, EventKind::Hello { worker: Some(w), .. } if w == "blog-footer"));
+        assert!(matches!(&events[2].kind, EventKind::Submit { job_id, status: SubmitStatus::Rejected } if job_id == "j1"));
+        assert!(matches!(events[3].kind, EventKind::Error { code: ErrorCode::RateLimit }));
+        assert!(matches!(events[4].kind, EventKind::Close));
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(raw.lines().nth(2).unwrap().contains(r#""type":"submit""#));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_file_rotates_past_size_limit() {
+        let path = temp_path("rotate");
+        let config = LoggingConfig { events_file: Some(path.clone()), events_max_bytes: 200 };
+        let log = EventLog::start(&config, Arc::new(Metrics::new())).unwrap();
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+
+        for i in 0..3 {
+            log.emit(&format!("session-{}", i), ip, EventKind::Open);
+        }
+        wait_for_lines(&rotated_path(&path), 1).await;
+        wait_for_lines(&path, 1).await;
+
+        let total = read_events(&path).len() + read_events(&rotated_path(&path)).len();
+        assert_eq!(total, 3);
+        assert!(std::fs::metadata(&path).unwrap().len() <= 200);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+    }
+
+    #[tokio::test]
+    async fn test_events_dropped_when_writer_falls_behind() {
+        let metrics = Arc::new(Metrics::new());
+        let (tx, _rx) = mpsc::channel(1);
+        let log = EventLog { inner: Some((tx, metrics.clone())) };
+        let ip: IpAddr = "198.51.100.4".parse().unwrap();
+
+        log.emit("s1", ip, EventKind::Open);
+        log.emit("s1", ip, EventKind::Close);
+        assert_eq!(metrics.events_dropped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Layout of a job's reserved value, so the coinbase of a found block tells
+//! which coordinator and which session it came from:
+//!
+//! | bytes | field                          |
+//! |-------|--------------------------------|
+//! | 0..4  | instance id, big endian        |
+//! | 4..8  | session index, big endian      |
+//! | 8..   | entropy, distinct for each job |
+//!
+//! At least [`MIN_ENTROPY`] bytes are left to entropy, or a session's jobs
+//! could not differ. A reserve too small for the whole header keeps the
+//! fields that fit whole, instance id first: 8 to 11 bytes carry the
+//! instance id alone, fewer than 8 carry entropy only.
+
+use std::fmt;
+
+/// Instance id and session index
+pub const HEADER_LEN: usize = 8;
+/// Bytes kept for entropy before any header field
+pub const MIN_ENTROPY: usize = 4;
+
+const FIELD_LEN: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraNonce {
+    /// `jobs.instance_id` of the coordinator that issued the job
+    pub instance_id: Option<u32>,
+    /// Index the job manager gave the session, logged when it was given
+    pub session_index: Option<u32>,
+    pub entropy: Vec<u8>,
+}
+
+impl ExtraNonce {
+    /// Header bytes a reserve of `size` has room for
+    pub fn header_len(size: usize) -> usize {
+        (size.saturating_sub(MIN_ENTROPY) / FIELD_LEN * FIELD_LEN).min(HEADER_LEN)
+    }
+
+    /// Entropy bytes a reserve of `size` has room for
+    pub fn entropy_len(size: usize) -> usize {
+        size - Self::header_len(size)
+    }
+
+    /// The reserved value of `size` bytes. Fields without room are left
+    /// out; entropy is cut or zero padded to what remains.
+    pub fn encode(&self, size: usize) -> Vec<u8> {
+        let header_len = Self::header_len(size);
+        let mut out = Vec::with_capacity(size);
+        if header_len >= FIELD_LEN {
+            out.extend_from_slice(&self.instance_id.unwrap_or(0).to_be_bytes());
+        }
+        if header_len >= 2 * FIELD_LEN {
+            out.extend_from_slice(&self.session_index.unwrap_or(0).to_be_bytes());
+        }
+        let entropy = &self.entropy[..self.entropy.len().min(size - header_len)];
+        out.extend_from_slice(entropy);
+        out.resize(size, 0);
+        out
+    }
+
+    /// Read back a reserved value; its length is the reserve size
+    pub fn decode(reserved: &[u8]) -> Self {
+        let header_len = Self::header_len(reserved.len());
+        let field = |i: usize| {
+            (header_len >= (i + 1) * FIELD_LEN)
+                .then(|| u32::from_be_bytes(reserved[i * FIELD_LEN..(i + 1) * FIELD_LEN].try_into().unwrap()))
+        };
+        Self { instance_id: field(0), session_index: field(1), entropy: reserved[header_len..].to_vec() }
+    }
+}
+
+impl fmt::Display for ExtraNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.instance_id, self.session_index) {
+            (Some(instance), Some(session)) => write!(f, "instance {}, session index {}", instance, session),
+            (Some(instance), None) => write!(f, "instance {}", instance),
+            _ => write!(f, "no attribution"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extra_nonce(size: usize) -> ExtraNonce {
+        let entropy = (0..ExtraNonce::entropy_len(size)).map(|i| i as u8 ^ 0xa5).collect();
+        ExtraNonce { instance_id: Some(0x01020304), session_index: Some(42), entropy }
+    }
+
+    #[test]
+    fn test_round_trip_across_reserve_sizes() {
+        for size in [1, 4, 7, 8, 11, 12, 16, 32, 255, 300] {
+            let value = extra_nonce(size);
+            let encoded = value.encode(size);
+            assert_eq!(encoded.len(), size);
+            let decoded = ExtraNonce::decode(&encoded);
+            assert_eq!(decoded.entropy, value.entropy, "{} bytes", size);
+            assert!(decoded.entropy.len() >= MIN_ENTROPY.min(size));
+            match size {
+                ..=7 => assert_eq!((decoded.instance_id, decoded.session_index), (None, None)),
+                8..=11 => assert_eq!((decoded.instance_id, decoded.session_index), (Some(0x01020304), None)),
+                _ => assert_eq!((decoded.instance_id, decoded.session_index), (Some(0x01020304), Some(42))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_is_big_endian_and_first() {
+        let encoded = extra_nonce(16).encode(16);
+        assert_eq!(encoded[..HEADER_LEN], [1, 2, 3, 4, 0, 0, 0, 42]);
+        assert_eq!(ExtraNonce::decode(&encoded).to_string(), "instance 16909060, session index 42");
+        assert_eq!(ExtraNonce::decode(&[0; 4]).to_string(), "no attribution");
+    }
+
+    #[test]
+    fn test_entropy_is_fitted_to_the_reserve() {
+        let short = ExtraNonce { instance_id: Some(7), session_index: Some(9), entropy: vec![0xff; 2] };
+        assert_eq!(short.encode(12), [0, 0, 0, 7, 0, 0, 0, 9, 0xff, 0xff, 0, 0]);
+        let long = ExtraNonce { entropy: vec![0xff; 64], ..short };
+        assert_eq!(long.encode(8), [0, 0, 0, 7, 0xff, 0xff, 0xff, 0xff]);
+    }
+}
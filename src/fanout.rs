@@ -0,0 +1,299 @@
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::time::Instant;
+use tracing::{debug, info};
+
+use crate::jobs::{compact_target_hex, Job};
+use crate::outbound::JobSink;
+use crate::protocol::ServerMessage;
+use crate::server::{self, AppState};
+use crate::template::TemplateState;
+
+/// Registry of connections that receive a job on every template change.
+///
+/// A single task serves all of them, so a new template costs one pass over
+/// the registry instead of waking every connection to do the same work.
+#[derive(Default)]
+pub struct Fanout {
+    sinks: DashMap<String, JobSink>,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, session_id: &str, sink: JobSink) {
+        self.sinks.insert(session_id.to_string(), sink);
+    }
+
+    pub fn unregister(&self, session_id: &str) {
+        self.sinks.remove(session_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+}
+
+/// Push a job to every connection each time the template changes. After a
+/// new block every outstanding job is stale, so miners are moved off it at
+/// once; a refresh at the same height leaves the old jobs their grace period.
+pub async fn run(state: AppState) {
+    let mut template_rx = state.template_rx.clone();
+    let mut height = None;
+    loop {
+        tokio::select! {
+            result = template_rx.changed() => {
+                if result.is_err() {
+                    break;
+                }
+                let template = template_rx.borrow_and_update().clone();
+                if let Some(template) = template {
+                    let advanced = height.is_some_and(|h| template.height > h);
+                    height = Some(template.height);
+                    let delivered = broadcast(&state, &template);
+                    if advanced {
+                        info!("Height {}: jobs for earlier heights are stale, {} connections sent new work", template.height, delivered);
+                    }
+                }
+            }
+            _ = state.shutdown.cancelled() => break,
+        }
+    }
+}
+
+/// Create and queue a job for every ready session. Returns the number of
+/// connections the job was queued for.
+pub fn broadcast(state: &AppState, template: &TemplateState) -> usize {
+    let started = Instant::now();
+
+    // Snapshot the registry so connections can come and go meanwhile
+    let sinks: Vec<(String, JobSink)> = state
+        .fanout
+        .sinks
+        .iter()
+        .map(|e| (e.key().clone(), e.value().clone()))
+        .collect();
+
+    // Sessions still in the handshake get their first job with the hello
+    // reply; the rest are noted with the target form they asked for
+    let mut ready = HashMap::new();
+    state.session_manager.for_each_ready(|s| {
+        let compact = s.capabilities.as_ref().is_some_and(|c| c.wants_compact_target);
+        ready.insert(s.id.clone(), compact);
+    });
+
+    // Sessions at the same share difficulty and target form share the tail of the frame
+    let mut tails: HashMap<(u64, bool), String> = HashMap::new();
+    let mut delivered = 0;
+    for (session_id, sink) in sinks {
+        let compact = match ready.get(&session_id) {
+            Some(compact) => *compact,
+            None => continue,
+        };
+
+        // Every session shares the template, so one failure means all would fail
+        let jobs = match server::session_jobs(state, template, &session_id) {
+            Ok(jobs) => jobs,
+            Err(_) => break,
+        };
+        server::issue_jobs(state, &session_id, &jobs);
+
+        let frames = jobs
+            .iter()
+            .map(|job| {
+                let tail = tails.entry((job.share_difficulty, compact)).or_insert_with(|| job_tail(job, compact));
+                job_frame(job, tail)
+            })
+            .collect();
+        if sink.push_all(frames) {
+            delivered += 1;
+        }
+    }
+
+    let elapsed = started.elapsed();
+    state.metrics.observe_job_broadcast(elapsed);
+    debug!("Broadcast template {} to {} connections in {:?}", template.template_id, delivered, elapsed);
+    delivered
+}
+
+/// Create and queue a job for one registered connection, as when an idle
+/// session wakes up. Returns false if there is no template or connection,
+/// or the template cannot make jobs.
+pub fn send_job(state: &AppState, session_id: &str) -> bool {
+    let sink = match state.fanout.sinks.get(session_id) {
+        Some(sink) => sink.clone(),
+        None => return false,
+    };
+    let template = match state.template_rx.borrow().clone() {
+        Some(template) => template,
+        None => return false,
+    };
+    let compact = state.session_manager.wants_compact_target(session_id);
+    let jobs = match server::session_jobs(state, &template, session_id) {
+        Ok(jobs) => jobs,
+        Err(_) => return false,
+    };
+    server::issue_jobs(state, session_id, &jobs);
+    sink.push_all(jobs.iter().map(|job| job_frame(job, &job_tail(job, compact))).collect())
+}
+
+/// Queue jobs already issued to a session on its connection, as when the
+/// hello reply carries only the last of a set. Returns false if there is
+/// no connection.
+pub fn push_jobs(state: &AppState, session_id: &str, jobs: &[Job], compact: bool) -> bool {
+    match state.fanout.sinks.get(session_id) {
+        Some(sink) => sink.push_all(jobs.iter().map(|job| job_frame(job, &job_tail(job, compact))).collect()),
+        None => false,
+    }
+}
+
+/// Queue `msg` for every registered connection that has room for it.
+/// Returns the number of connections it was queued for.
+pub fn broadcast_message(state: &AppState, msg: &ServerMessage) -> usize {
+    let sinks: Vec<JobSink> = state.fanout.sinks.iter().map(|e| e.value().clone()).collect();
+    sinks.into_iter().filter(|sink| sink.push_message(msg.clone())).count()
+}
+
+/// Serialize the job fields every session at one share difficulty and
+/// target form shares for a template
+fn job_tail(job: &Job, compact_target: bool) -> String {
+    let target_hex = if compact_target { compact_target_hex(job.share_difficulty) } else { job.target_hex.clone() };
+    format!(
+        r#""reserved_offset":{},"target_hex":{},"height":{},"seed_hash":{}}}"#,
+        job.reserved_offset,
+        serde_json::to_string(&target_hex).unwrap(),
+        job.height,
+        serde_json::to_string(&job.seed_hash).unwrap(),
+    )
+}
+
+/// A `job` message for this session; decodes to the same `ServerMessage::Job`
+/// a serialized message would
+fn job_frame(job: &Job, tail: &str) -> String {
+    format!(
+        r#"{{"type":"job","job_id":{},"blob_hex":{},"reserved_value_hex":"{}",{}"#,
+        serde_json::to_string(&job.job_id).unwrap(),
+        serde_json::to_string(&job.blob_hex).unwrap(),
+        hex::encode(&job.reserved_value),
+        tail,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ClientMessage;
+    use crate::session::{SessionManager, SessionManagerConfig, SessionState};
+    use crate::server::tests::{client_text, next_server_message, test_state, test_template, HELLO};
+    use axum::extract::ws::Message;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_job_frame_matches_serialized_job() {
+        let (state, _template_tx) = test_state();
+        let job = state.job_manager.create_job(&test_template(), "session-1", 1).unwrap();
+        let expected = serde_json::to_value(ServerMessage::Job {
+            job_id: job.job_id.clone(),
+            blob_hex: job.blob_hex.clone(),
+            reserved_offset: job.reserved_offset,
+            reserved_value_hex: hex::encode(&job.reserved_value),
+            target_hex: job.target_hex.clone(),
+            height: job.height,
+            seed_hash: job.seed_hash.clone(),
+        })
+        .unwrap();
+
+        let frame = job_frame(&job, &job_tail(&job, false));
+        let actual: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(actual, expected);
+        assert!(serde_json::from_str::<ServerMessage>(&frame).is_ok());
+
+        let frame = job_frame(&job, &job_tail(&job, true));
+        let actual: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(actual, serde_json::to_value(crate::server::job_message(job, true)).unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_one_broadcast_serves_every_connection() {
+        const CLIENTS: usize = 1000;
+
+        let (mut state, template_tx) = test_state();
+        state.session_manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: CLIENTS, max_total: CLIENTS + 1, ..Default::default() }));
+        let mut clients: Vec<(_, UnboundedReceiver<Message>)> = Vec::new();
+        for i in 0..CLIENTS {
+            let (sink, mut outgoing) = unbounded::<Message>();
+            let (client, stream) = unbounded();
+            let ip = format!("10.0.{}.{}", i / 250, i % 250).parse().unwrap();
+            tokio::spawn(crate::server::run_connection(sink, stream, state.clone(), ip, None));
+            client.unbounded_send(client_text(HELLO)).unwrap();
+            // No template yet, so hello is answered with stats
+            assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { .. }));
+            clients.push((client, outgoing));
+        }
+        // One connection that never says hello
+        let (sink, mut silent_out) = unbounded::<Message>();
+        let (_silent, stream) = unbounded();
+        tokio::spawn(crate::server::run_connection(sink, stream, state.clone(), "10.9.0.1".parse().unwrap(), None));
+        tokio::task::yield_now().await;
+        assert_eq!(state.fanout.len(), CLIENTS + 1);
+
+        let fanout = tokio::spawn(run(state.clone()));
+        template_tx.send(Some(test_template())).unwrap();
+
+        for (_, outgoing) in clients.iter_mut() {
+            assert!(matches!(next_server_message(outgoing).await, ServerMessage::Job { .. }));
+        }
+        assert!(silent_out.try_recv().is_err());
+
+        assert_eq!(state.metrics.job_broadcast_seconds.count(), 1);
+        assert_eq!(state.metrics.jobs_created.load(Ordering::Relaxed), CLIENTS as u64);
+
+        state.shutdown.cancel();
+        fanout.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_session_gets_no_jobs_until_it_speaks() {
+        let (mut state, template_tx) = test_state();
+        let limits = SessionManagerConfig::from_config(&state.config).unwrap();
+        state.session_manager = Arc::new(SessionManager::new(limits).with_metrics(state.metrics.clone()));
+        let (sink, mut outgoing) = unbounded::<Message>();
+        let (client, stream) = unbounded();
+        tokio::spawn(crate::server::run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { .. }));
+
+        assert_eq!(state.session_manager.mark_inactive(Duration::ZERO), 1);
+        assert_eq!(state.metrics.sessions_idle.load(Ordering::Relaxed), 1);
+        for template_id in 1..=5 {
+            let mut template = test_template();
+            template.template_id = template_id;
+            assert_eq!(broadcast(&state, &template), 0);
+        }
+        assert_eq!(state.metrics.jobs_created.load(Ordering::Relaxed), 0);
+        assert!(outgoing.try_recv().is_err());
+
+        // Any message wakes it with a fresh job for the current template
+        template_tx.send(Some(test_template())).unwrap();
+        let ping = serde_json::to_string(&ClientMessage::Ping { id: "1".into() }).unwrap();
+        client.unbounded_send(client_text(&ping)).unwrap();
+        let replies = [next_server_message(&mut outgoing).await, next_server_message(&mut outgoing).await];
+        assert!(replies.iter().any(|m| matches!(m, ServerMessage::Job { .. })), "{:?}", replies);
+        assert!(replies.iter().any(|m| matches!(m, ServerMessage::Pong { .. })), "{:?}", replies);
+        assert_eq!(state.metrics.jobs_created.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.sessions_idle.load(Ordering::Relaxed), 0);
+
+        let mut ready = 0;
+        state.session_manager.for_each_ready(|s| {
+            assert_eq!(s.state, SessionState::Ready);
+            ready += 1;
+        });
+        assert_eq!(ready, 1);
+        assert_eq!(broadcast(&state, &test_template()), 1);
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Instant;
+
+/// Time constant of the moving window, in seconds. A share's weight falls to
+/// 1/e after this long.
+const TAU_SECS: f64 = 300.0;
+
+/// Server-side hashrate estimate for one session, from the difficulty of its
+/// accepted shares rather than what the miner claims.
+///
+/// Accepted difficulty is summed with exponentially decaying weights and
+/// divided by the weight the window has covered so far, so the first minutes
+/// after connecting are not underestimated and a miner that stops submitting
+/// decays towards zero.
+///
+/// A session's estimate only moves when it is read or a share lands, so
+/// idle sessions cost nothing between updates.
+#[derive(Debug, Clone)]
+pub struct EstimatedHashrate {
+    started: Instant,
+    /// Decayed sum of accepted difficulty as of `updated`
+    work: f64,
+    updated: Instant,
+    has_shares: bool,
+}
+
+impl EstimatedHashrate {
+    pub fn new(now: Instant) -> Self {
+        Self { started: now, work: 0.0, updated: now, has_shares: false }
+    }
+
+    pub fn record_share(&mut self, difficulty: u64, now: Instant) {
+        self.work = self.decayed_work(now) + difficulty as f64;
+        self.updated = now.max(self.updated);
+        self.has_shares = true;
+    }
+
+    /// Hashes per second, or None before the first accepted share
+    pub fn estimate(&self, now: Instant) -> Option<f64> {
+        if !self.has_shares {
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(self.started).as_secs_f64();
+        // Weight covered since the session started: elapsed for young
+        // sessions, approaching TAU_SECS for old ones
+        let window = TAU_SECS * -(-elapsed / TAU_SECS).exp_m1();
+        if window <= 0.0 {
+            return None;
+        }
+        Some(self.decayed_work(now) / window)
+    }
+
+    fn decayed_work(&self, now: Instant) -> f64 {
+        let since = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.work * (-since / TAU_SECS).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= expected * tolerance,
+            "estimate {} not within {}% of {}",
+            actual,
+            tolerance * 100.0,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_no_estimate_before_first_share() {
+        let start = Instant::now();
+        let hashrate = EstimatedHashrate::new(start);
+        assert_eq!(hashrate.estimate(start), None);
+        assert_eq!(hashrate.estimate(start + Duration::from_secs(600)), None);
+    }
+
+    #[test]
+    fn test_steady_miner_converges_to_its_rate() {
+        // Difficulty 3000 every 10 s is 300 H/s
+        let start = Instant::now();
+        let mut hashrate = EstimatedHashrate::new(start);
+        let mut now = start;
+        for _ in 0..360 {
+            now += Duration::from_secs(10);
+            hashrate.record_share(3000, now);
+        }
+        // Halfway between shares, past the share just counted
+        assert_close(hashrate.estimate(now + Duration::from_secs(5)).unwrap(), 300.0, 0.02);
+
+        // The window scales with the session's age, so a young session is not underestimated
+        let mut young = EstimatedHashrate::new(start);
+        for i in 1..=6 {
+            young.record_share(3000, start + Duration::from_secs(10 * i));
+        }
+        assert_close(young.estimate(start + Duration::from_secs(65)).unwrap(), 300.0, 0.1);
+    }
+
+    #[test]
+    fn test_single_share_matches_analytic_value() {
+        let start = Instant::now();
+        let mut hashrate = EstimatedHashrate::new(start);
+        hashrate.record_share(10_000, start + Duration::from_secs(100));
+
+        let t = 400.0;
+        let expected = 10_000.0 * (-(t - 100.0) / TAU_SECS).exp() / (TAU_SECS * (1.0 - (-t / TAU_SECS).exp()));
+        let actual = hashrate.estimate(start + Duration::from_secs_f64(t)).unwrap();
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_estimate_decays_after_miner_stops() {
+        let start = Instant::now();
+        let mut hashrate = EstimatedHashrate::new(start);
+        let mut now = start;
+        for _ in 0..200 {
+            now += Duration::from_secs(10);
+            hashrate.record_share(1000, now);
+        }
+        let active = hashrate.estimate(now).unwrap();
+
+        let after_tau = hashrate.estimate(now + Duration::from_secs(300)).unwrap();
+        assert_close(after_tau, active / std::f64::consts::E, 0.01);
+        assert!(hashrate.estimate(now + Duration::from_secs(3600)).unwrap() < active * 1e-4);
+    }
+}
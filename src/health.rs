@@ -0,0 +1,200 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+};
+use parking_lot::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::server::AppState;
+use crate::validator::VerifierRung;
+
+/// When monerod last answered an RPC call, shared between the template
+/// manager (writer) and the readiness probe (reader).
+#[derive(Default)]
+pub struct DaemonStatus {
+    last_ok: RwLock<Option<Instant>>,
+}
+
+impl DaemonStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mark_ok(&self) {
+        *self.last_ok.write() = Some(Instant::now());
+    }
+
+    pub fn last_ok(&self) -> Option<Instant> {
+        *self.last_ok.read()
+    }
+}
+
+/// Process is up and serving HTTP
+pub async fn livez() -> impl IntoResponse {
+    (StatusCode::OK, "OK")
+}
+
+/// Ready to issue work: a fresh template is available, monerod is responding
+/// and submissions can be hashed. Hashing on a lower rung than configured is
+/// ready, but says so.
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let failures = readiness_failures(&state);
+    if failures.is_empty() {
+        let body = match state.validator.rung(state.pow.as_ref()) {
+            VerifierRung::Configured => "OK".to_string(),
+            rung => format!("OK (verification degraded: {})", rung.as_str()),
+        };
+        (StatusCode::OK, body)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, format!("NOT READY: {}", failures.join("; ")))
+    }
+}
+
+fn readiness_failures(state: &AppState) -> Vec<String> {
+    let mut failures = Vec::new();
+    let max_template_age = Duration::from_millis(state.config.health.max_template_age_ms);
+    let max_rpc_silence = Duration::from_millis(state.config.health.max_rpc_silence_ms);
+
+    match state.template_rx.borrow().as_ref() {
+        None => failures.push("no block template available".to_string()),
+        Some(template) => {
+            let age = template.created_at.elapsed();
+            if age > max_template_age {
+                failures.push(format!(
+                    "block template is stale ({}s old, max {}s)",
+                    age.as_secs(),
+                    max_template_age.as_secs()
+                ));
+            }
+        }
+    }
+
+    match state.daemon_status.last_ok() {
+        None => failures.push("monerod has not responded yet".to_string()),
+        Some(last_ok) => {
+            let silence = last_ok.elapsed();
+            if silence > max_rpc_silence {
+                failures.push(format!(
+                    "monerod last responded {}s ago (max {}s)",
+                    silence.as_secs(),
+                    max_rpc_silence.as_secs()
+                ));
+            }
+        }
+    }
+
+    if state.validator.rung(state.pow.as_ref()) == VerifierRung::Down {
+        failures.push("hash verification is down, retrying RandomX".to_string());
+    }
+
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob;
+    use crate::rpc::BlockTemplate;
+    use crate::server::{self, tests::test_state};
+    use crate::template::TemplateState;
+    use crate::validator::SubmissionValidator;
+    use crate::vm_pool::{LocalVerifier, VmPool};
+    use std::sync::Arc;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use tower::ServiceExt;
+
+    fn template(age: Duration) -> TemplateState {
+        let mut template = TemplateState::from_rpc(
+            BlockTemplate {
+                blockhashing_blob: String::new(),
+                blocktemplate_blob: blob::fixture::TEMPLATE_BLOB.into(),
+                difficulty: 1000,
+                expected_reward: 0,
+                height: 1,
+                prev_hash: String::new(),
+                reserved_offset: blob::fixture::RESERVED_OFFSET,
+                seed_hash: String::new(),
+                next_seed_hash: String::new(),
+                status: "OK".into(),
+            },
+            1,
+            8,
+        )
+        .unwrap();
+        template.created_at = Instant::now() - age;
+        template
+    }
+
+    async fn get(state: AppState, uri: &str) -> (StatusCode, String) {
+        let response = server::router(state)
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_livez_always_ok() {
+        let (state, _template_tx) = test_state();
+        let (status, _) = get(state, "/livez").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_ready_with_fresh_template() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(template(Duration::ZERO))).unwrap();
+        state.daemon_status.mark_ok();
+
+        let (status, body) = get(state.clone(), "/readyz").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let (status, _) = get(state, "/health").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_stale_template_not_ready() {
+        let (state, template_tx) = test_state();
+        let max_age = Duration::from_millis(state.config.health.max_template_age_ms);
+        template_tx.send(Some(template(max_age + Duration::from_secs(5)))).unwrap();
+        state.daemon_status.mark_ok();
+
+        let (status, body) = get(state.clone(), "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("block template is stale"), "{}", body);
+        assert!(!body.contains("monerod"), "{}", body);
+
+        let (status, body) = get(state, "/health").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("block template is stale"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_verification_down_not_ready() {
+        let (mut state, template_tx) = test_state();
+        template_tx.send(Some(template(Duration::ZERO))).unwrap();
+        state.daemon_status.mark_ok();
+        state.validator = Arc::new(SubmissionValidator::new());
+        state.pow = Arc::new(LocalVerifier::new(VmPool::new(1).refusing(|_, _| true)));
+
+        // The first hash finds no memory for RandomX, and no RPC to fall back on
+        let ticket = state.validator.enqueue(state.pow.as_ref(), false).unwrap();
+        assert!(state.validator.verify(state.pow.as_ref(), &"00".repeat(32), 1, vec![1], ticket).await.is_err());
+        let (status, body) = get(state, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("hash verification is down"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_missing_template_and_daemon_not_ready() {
+        let (state, _template_tx) = test_state();
+        let (status, body) = get(state, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("no block template available"), "{}", body);
+        assert!(body.contains("monerod has not responded yet"), "{}", body);
+    }
+}
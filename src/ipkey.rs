@@ -0,0 +1,68 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Prefix that usually covers one IPv6 subscriber
+pub const DEFAULT_IPV6_PREFIX_LEN: u8 = 64;
+
+/// What per-client limits and bans are keyed on: the full address for IPv4,
+/// and the network prefix for IPv6, since one household typically holds a
+/// whole /64 and can rotate interface identifiers at will.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpKey(IpAddr);
+
+impl IpKey {
+    pub fn new(ip: IpAddr, ipv6_prefix_len: u8) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self(ip),
+            IpAddr::V6(v6) => {
+                // An IPv4-mapped address is an IPv4 client
+                if let Some(v4) = v6.to_ipv4_mapped() {
+                    return Self(IpAddr::V4(v4));
+                }
+                let len = u32::from(ipv6_prefix_len.min(128));
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                Self(IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask)))
+            }
+        }
+    }
+
+    /// The address with host bits cleared
+    pub fn addr(&self) -> IpAddr {
+        self.0
+    }
+}
+
+impl fmt::Display for IpKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(ip: &str) -> IpKey {
+        IpKey::new(ip.parse().unwrap(), DEFAULT_IPV6_PREFIX_LEN)
+    }
+
+    #[test]
+    fn test_addresses_in_one_prefix_share_a_key() {
+        let a = key("2001:db8:1:2::1");
+        assert_eq!(a, key("2001:db8:1:2:ffff:ffff:ffff:ffff"));
+        assert_eq!(a, key("2001:db8:1:2:abcd::9"));
+        assert_eq!(a.addr(), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+
+        assert_ne!(a, key("2001:db8:1:3::1"));
+        assert_ne!(key("198.51.100.1"), key("198.51.100.2"));
+        assert_eq!(key("::ffff:198.51.100.1"), key("198.51.100.1"));
+    }
+
+    #[test]
+    fn test_prefix_length_is_configurable() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(IpKey::new(ip("2001:db8:1:2::1"), 48), IpKey::new(ip("2001:db8:1:3::1"), 48));
+        assert_ne!(IpKey::new(ip("2001:db8::1"), 128), IpKey::new(ip("2001:db8::2"), 128));
+        assert_eq!(IpKey::new(ip("2001:db8::1"), 0), IpKey::new(ip("fe80::1"), 0));
+    }
+}
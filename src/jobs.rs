@@ -1,15 +1,49 @@
 use dashmap::DashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use num_bigint::BigUint;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use thiserror::Error;
+use rand::Rng;
+use ring::hmac;
+use tracing::info;
 
+use crate::blob;
+use crate::extranonce::ExtraNonce;
+use crate::metrics::Metrics;
 use crate::template::TemplateState;
 
-// Nonce is at byte offset 39 in the block hashing blob (standard Monero position)
+// Nonce is at byte offset 39 in the block hashing blob (standard Monero position):
+// the header's major and minor version varints (1 byte each), the timestamp
+// varint (5 bytes for any current time) and the 32-byte previous block id
 pub const NONCE_OFFSET: usize = 39;
 pub const NONCE_SIZE: usize = 4;
 
+/// How long a job's blocks are submitted unless its manager says otherwise
+pub const DEFAULT_BLOCK_TTL_MS: u64 = 30_000;
+
+/// Live jobs across all sessions unless the manager says otherwise
+pub const DEFAULT_MAX_JOBS: usize = 100_000;
+
+/// Live jobs a session may hold unless its manager says otherwise
+pub const DEFAULT_MAX_JOBS_PER_SESSION: usize = 8;
+
+/// Nonces a job remembers unless its manager says otherwise
+pub const DEFAULT_MAX_NONCES_PER_JOB: usize = 1024;
+
+/// Submissions hashed per job unless its manager says otherwise; far more
+/// than an honest browser miner finds in a job's lifetime
+pub const DEFAULT_MAX_SUBMISSIONS_PER_JOB: u32 = 32;
+
+/// Fresh sequence numbers tried when a reserved value is already live;
+/// only tiny reserve sizes ever need more than one
+const RESERVED_VALUE_ATTEMPTS: usize = 16;
+
 // Pre-compute 2^256 once for efficiency
 static MAX_TARGET: Lazy<BigUint> = Lazy::new(|| {
     let two: BigUint = 2u32.into();
@@ -19,115 +53,745 @@ static MAX_TARGET: Lazy<BigUint> = Lazy::new(|| {
 #[derive(Clone, Debug)]
 pub struct Job {
     pub job_id: String,
+    /// Session the job was created for
+    pub session_id: String,
     pub template_id: u64,
+    /// Hashing blob (header, merkle root, transaction count) the miner
+    /// works on; it carries this job's reserved value through the root
     pub blob_hex: String,
+    /// The template's block, shared by all its jobs; `block_blob` writes
+    /// this job's reserved value and nonce into a copy
+    pub template_blob: Arc<Vec<u8>>,
     pub reserved_offset: usize,
-    pub reserved_value: Vec<u8>,
+    /// Shared with the sessions the job is issued to
+    pub reserved_value: Arc<[u8]>,
+    /// Target for `share_difficulty`; what the miner must meet to submit
     pub target_hex: String,
+    /// `target_hex` decoded
+    pub share_target: [u8; 32],
+    /// Target for `difficulty`; what makes a hash a block
+    pub block_target: [u8; 32],
+    /// Block difficulty of the template
+    pub difficulty: u64,
+    /// Difficulty of the shares this job asks for; at most `difficulty`
+    pub share_difficulty: u64,
     pub height: u64,
+    /// Block the job's block would build on
+    pub prev_hash: String,
     pub seed_hash: String,
     pub created_at: Instant,
+    /// Blocks found after this are not submitted, whatever the template
+    pub expires_at: Instant,
+    /// Submissions after this are stale; never before `expires_at`
+    pub share_expires_at: Instant,
+    /// Where the manager keeps the job; also encoded in `job_id`
+    pub key: JobKey,
 }
 
 impl Job {
-    /// Reconstruct the full blob by inserting the nonce at the correct position
+    /// Reconstruct the hashing blob the miner hashed by inserting the nonce
+    /// at the correct position
     pub fn apply_nonce(&self, nonce_hex: &str) -> Result<Vec<u8>, String> {
-        let nonce_bytes = hex::decode(nonce_hex)
-            .map_err(|_| "Invalid nonce hex".to_string())?;
+        let blob = hex::decode(&self.blob_hex)
+            .map_err(|_| "Invalid stored blob".to_string())?;
+        splice_nonce(blob, nonce_hex)
+    }
+
+    /// The block to hand monerod when the nonce makes it one. The header,
+    /// nonce included, is the same in both blobs.
+    pub fn block_blob(&self, nonce_hex: &str) -> Result<Vec<u8>, String> {
+        let mut blob = self.template_blob.to_vec();
+        blob::write_clipped(&mut blob, self.reserved_offset, &self.reserved_value);
+        splice_nonce(blob, nonce_hex)
+    }
 
-        if nonce_bytes.len() != NONCE_SIZE {
-            return Err(format!("Nonce must be {} bytes", NONCE_SIZE));
+    /// Whether the job is past its block TTL
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    /// Whether the job is past its share TTL, so nothing is credited for it
+    pub fn is_share_expired(&self) -> bool {
+        Instant::now() >= self.share_expires_at
+    }
+
+    /// Which of the job's targets `hash` meets
+    pub fn classify(&self, hash: &[u8; 32]) -> HashClass {
+        if meets_target(hash, &self.block_target) {
+            HashClass::Block
+        } else if meets_target(hash, &self.share_target) {
+            HashClass::Share
+        } else {
+            HashClass::BelowTarget
         }
+    }
+}
 
-        let mut blob = hex::decode(&self.blob_hex)
-            .map_err(|_| "Invalid stored blob".to_string())?;
+/// A 256-bit unsigned integer read from little-endian bytes, as hashes and
+/// targets are. Limbs are held most significant first, so the derived
+/// ordering is the numeric one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub fn from_le_bytes(bytes: &[u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, chunk) in bytes.chunks_exact(8).enumerate() {
+            limbs[3 - i] = u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes"));
+        }
+        Self(limbs)
+    }
+}
+
+/// Whether `hash` is valid work for `target`: read as little-endian
+/// integers, the hash is at most the target. With the target at
+/// floor(2^256 / difficulty), that is Monero's check that hash times
+/// difficulty does not pass 2^256.
+pub fn meets_target(hash: &[u8; 32], target: &[u8; 32]) -> bool {
+    U256::from_le_bytes(hash) <= U256::from_le_bytes(target)
+}
+
+/// What a submitted hash is worth to its job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashClass {
+    BelowTarget,
+    /// Meets the share target only
+    Share,
+    /// Meets the block target, and so the share target too
+    Block,
+}
+
+fn splice_nonce(mut blob: Vec<u8>, nonce_hex: &str) -> Result<Vec<u8>, String> {
+    let nonce_bytes = hex::decode(nonce_hex)
+        .map_err(|_| "Invalid nonce hex".to_string())?;
+
+    if nonce_bytes.len() != NONCE_SIZE {
+        return Err(format!("Nonce must be {} bytes", NONCE_SIZE));
+    }
+
+    if NONCE_OFFSET + NONCE_SIZE > blob.len() {
+        return Err("Blob too short for nonce".to_string());
+    }
+
+    // Insert nonce at offset 39
+    blob[NONCE_OFFSET..NONCE_OFFSET + NONCE_SIZE].copy_from_slice(&nonce_bytes);
+
+    Ok(blob)
+}
+
+/// Stratum-style target for `difficulty`: the top 32 bits of the full
+/// target as little-endian hex, or the top 64 bits once 32 are too few
+pub fn compact_target_hex(difficulty: u64) -> String {
+    let difficulty = difficulty.max(1);
+    if difficulty <= u64::from(u32::MAX) {
+        hex::encode((u32::MAX / difficulty as u32).to_le_bytes())
+    } else {
+        hex::encode((u64::MAX / difficulty).to_le_bytes())
+    }
+}
+
+/// Why no job can be made from a template
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum JobError {
+    #[error("template has no reserved bytes, so every job would be the same work")]
+    ZeroReserveSize,
+    #[error("reserved bytes {offset}..{end} are not in the miner transaction's prefix")]
+    OffsetOutOfRange { offset: usize, end: usize },
+}
+
+/// Check that jobs can be made from `template`: each needs reserved bytes
+/// of its own, and they must sit where they change only the merkle root
+pub fn check_template(template: &TemplateState) -> Result<(), JobError> {
+    if template.reserve_size == 0 {
+        return Err(JobError::ZeroReserveSize);
+    }
+    let offset = template.reserved_offset;
+    match offset.checked_add(template.reserve_size as usize) {
+        Some(end) if template.layout.in_miner_tx_prefix(offset, end) => Ok(()),
+        end => Err(JobError::OffsetOutOfRange { offset, end: end.unwrap_or(usize::MAX) }),
+    }
+}
+
+/// Result of recording a submitted nonce against its job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceStatus {
+    New,
+    /// Already submitted for this job, by any session
+    Duplicate,
+    /// The job remembers as many nonces as it may; this one was not recorded
+    Full,
+    /// The job has had `max_submissions_per_job` submissions hashed already
+    Capped,
+    UnknownJob,
+}
+
+/// How the manager keys a job: the session's index and the job's sequence
+/// number, so all of a session's jobs are found from the session alone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobKey {
+    /// The session's index in its jobs' reserved values
+    pub session: u32,
+    pub seq: u64,
+}
+
+/// A job id, `{height}-{template_id:08x}-{session:08x}-{seq:08x}`. The
+/// height and template can be read from a submit without looking the job
+/// up, and show in logs after the job is gone; the last two are its
+/// [`JobKey`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobId {
+    pub height: u64,
+    pub template_id: u64,
+    pub session: u32,
+    pub seq: u64,
+}
+
+/// A 20-digit height, two 16-digit hex fields and an 8-digit one, with
+/// their dashes
+const JOB_ID_MAX_LEN: usize = 20 + 1 + 16 + 1 + 8 + 1 + 16;
+
+/// A job id rendered on the stack, so an id found taken costs no allocation
+pub struct RenderedJobId {
+    bytes: [u8; JOB_ID_MAX_LEN],
+    len: usize,
+}
+
+impl RenderedJobId {
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len]).expect("job ids are ascii")
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.bytes[self.len] = byte;
+        self.len += 1;
+    }
 
-        if NONCE_OFFSET + NONCE_SIZE > blob.len() {
-            return Err("Blob too short for nonce".to_string());
+    /// `value` in hex, at least 8 digits
+    fn push_hex(&mut self, value: u64) {
+        let digits = (16 - value.leading_zeros() as usize / 4).max(8);
+        for digit in (0..digits).rev() {
+            self.push(b"0123456789abcdef"[(value >> (digit * 4)) as usize & 0xf]);
         }
+    }
+}
 
-        // Insert nonce at offset 39
-        blob[NONCE_OFFSET..NONCE_OFFSET + NONCE_SIZE].copy_from_slice(&nonce_bytes);
+impl JobId {
+    pub fn key(&self) -> JobKey {
+        JobKey { session: self.session, seq: self.seq }
+    }
+
+    /// The id as `Display` would write it, without the formatting machinery
+    pub fn render(&self) -> RenderedJobId {
+        let mut out = RenderedJobId { bytes: [0; JOB_ID_MAX_LEN], len: 0 };
+        let mut decimal = [0u8; 20];
+        let (mut height, mut start) = (self.height, decimal.len());
+        loop {
+            start -= 1;
+            decimal[start] = b'0' + (height % 10) as u8;
+            height /= 10;
+            if height == 0 {
+                break;
+            }
+        }
+        for &digit in &decimal[start..] {
+            out.push(digit);
+        }
+        for value in [self.template_id, u64::from(self.session), self.seq] {
+            out.push(b'-');
+            out.push_hex(value);
+        }
+        out
+    }
+}
 
-        Ok(blob)
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.render().as_str())
     }
 }
 
+/// Fails on ids in the older forms too, which name no job this manager
+/// holds
+impl FromStr for JobId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("job id {:?} is not height-template-session-seq", s);
+        let mut parts = s.splitn(4, '-');
+        let (Some(height), Some(template_id), Some(session), Some(seq)) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(bad());
+        };
+        Ok(Self {
+            height: height.parse().map_err(|_| bad())?,
+            template_id: u64::from_str_radix(template_id, 16).map_err(|_| bad())?,
+            session: u32::from_str_radix(session, 16).map_err(|_| bad())?,
+            seq: u64::from_str_radix(seq, 16).map_err(|_| bad())?,
+        })
+    }
+}
+
+/// The key an id names, if it is one of this manager's
+fn parse_key(job_id: &str) -> Option<JobKey> {
+    job_id.parse::<JobId>().ok().map(|id| id.key())
+}
+
+/// What a job id stands for
+#[derive(Debug, Clone)]
+pub enum JobLookup {
+    Live(Job),
+    /// Past its block TTL but not its share TTL: shares are still credited,
+    /// blocks not submitted
+    BlockExpired(Job),
+    /// Past its share TTL but not yet cleaned up
+    Expired(Job),
+    /// Dropped early to make room, and would otherwise still be around
+    Evicted,
+    /// Never issued, or cleaned up
+    Unknown,
+}
+
+/// A job and the nonces submitted for it, which go when the job does
+struct JobEntry {
+    job: Job,
+    submitted_nonces: HashSet<u32>,
+    /// New nonces let through to be hashed
+    submissions: u32,
+}
+
 pub struct JobManager {
-    jobs: DashMap<String, Job>,
+    jobs: DashMap<JobKey, JobEntry>,
+    /// Job keys oldest first, for eviction; may still hold keys of jobs
+    /// since removed, until the next cleanup
+    order: Mutex<VecDeque<JobKey>>,
+    max_jobs: usize,
+    /// Evicted jobs, to when cleanup would have dropped them
+    evicted: DashMap<JobKey, Instant>,
+    metrics: Option<Arc<Metrics>>,
+    /// Reserved values of live jobs, to the job holding each
+    reserved_values: DashMap<Arc<[u8]>, JobKey>,
+    /// Sequence numbers of each session index's live jobs, oldest first;
+    /// with the index they make up the jobs' keys
+    session_jobs: DashMap<u32, VecDeque<u64>>,
+    max_jobs_per_session: usize,
     counter: AtomicU64,
     stale_grace_ms: u64,
+    block_ttl: Duration,
+    /// Never shorter than `block_ttl`; the same when unset
+    share_ttl: Option<Duration>,
+    max_nonces_per_job: usize,
+    max_submissions_per_job: u32,
+    /// Keys reserved values, so clients cannot predict them; new each start
+    reserved_key: hmac::Key,
+    /// Written into every reserved value with room for it
+    instance_id: u32,
+    /// Index of each session in its jobs' reserved values
+    session_indices: DashMap<String, u32>,
+    next_session_index: AtomicU32,
 }
 
 impl JobManager {
     pub fn new(stale_grace_ms: u64) -> Self {
         Self {
             jobs: DashMap::new(),
+            order: Mutex::new(VecDeque::new()),
+            max_jobs: DEFAULT_MAX_JOBS,
+            evicted: DashMap::new(),
+            metrics: None,
+            reserved_values: DashMap::new(),
+            session_jobs: DashMap::new(),
+            max_jobs_per_session: DEFAULT_MAX_JOBS_PER_SESSION,
             counter: AtomicU64::new(0),
             stale_grace_ms,
+            block_ttl: Duration::from_millis(DEFAULT_BLOCK_TTL_MS),
+            share_ttl: None,
+            max_nonces_per_job: DEFAULT_MAX_NONCES_PER_JOB,
+            max_submissions_per_job: DEFAULT_MAX_SUBMISSIONS_PER_JOB,
+            reserved_key: hmac::Key::new(hmac::HMAC_SHA256, &rand::thread_rng().gen::<[u8; 32]>()),
+            instance_id: 0,
+            session_indices: DashMap::new(),
+            next_session_index: AtomicU32::new(0),
         }
     }
 
-    pub fn create_job(&self, template: &TemplateState, session_id: &str) -> Job {
-        let seq = self.counter.fetch_add(1, Ordering::SeqCst);
-        let job_id = format!("{:016x}", seq);
-        
-        // Create unique reserved value from session + sequence
-        let mut reserved = vec![0u8; template.reserve_size as usize];
-        let session_bytes = session_id.as_bytes();
-        let seq_bytes = seq.to_le_bytes();
-        
-        for (i, byte) in session_bytes.iter().chain(seq_bytes.iter()).take(reserved.len()).enumerate() {
-            reserved[i] = *byte;
-        }
+    /// Submit blocks found on a job for `ttl_ms` after it is made
+    pub fn with_block_ttl_ms(mut self, ttl_ms: u64) -> Self {
+        self.block_ttl = Duration::from_millis(ttl_ms);
+        self
+    }
+
+    /// Credit shares on a job for `ttl_ms` after it is made, or the block
+    /// TTL if that is longer
+    pub fn with_share_ttl_ms(mut self, ttl_ms: u64) -> Self {
+        self.share_ttl = Some(Duration::from_millis(ttl_ms));
+        self
+    }
+
+    fn share_ttl(&self) -> Duration {
+        self.share_ttl.unwrap_or(self.block_ttl).max(self.block_ttl)
+    }
+
+    /// Keep at most `max` jobs in all, evicting the oldest first
+    pub fn with_max_jobs(mut self, max: usize) -> Self {
+        self.max_jobs = max.max(1);
+        self
+    }
+
+    /// Count capacity evictions in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Keep at most `max` jobs per session, dropping the oldest first
+    pub fn with_max_jobs_per_session(mut self, max: usize) -> Self {
+        self.max_jobs_per_session = max.max(1);
+        self
+    }
+
+    pub fn with_max_nonces_per_job(mut self, max: usize) -> Self {
+        self.max_nonces_per_job = max;
+        self
+    }
+
+    /// Name this coordinator in the reserved values of its jobs
+    pub fn with_instance_id(mut self, instance_id: u32) -> Self {
+        self.instance_id = instance_id;
+        self
+    }
+
+    /// Hash at most `max` submissions per job, from all sessions together
+    pub fn with_max_submissions_per_job(mut self, max: u32) -> Self {
+        self.max_submissions_per_job = max;
+        self
+    }
 
-        // Modify blob with reserved value
-        let mut blob = hex::decode(&template.blocktemplate_blob).unwrap_or_default();
+    /// Create a job for `session_id` asking for shares of `share_difficulty`,
+    /// which is capped at the block difficulty
+    pub fn create_job(&self, template: &TemplateState, session_id: &str, share_difficulty: u64) -> Result<Job, JobError> {
+        check_template(template)?;
+        let (id, reserved) = self.claim_reserved_value(template, session_id);
+        let key = id.key();
+
+        // The template stays as fetched; only the miner transaction is
+        // copied to take the reserved value, and only the merkle root is
+        // encoded afresh
         let offset = template.reserved_offset;
-        for (i, byte) in reserved.iter().enumerate() {
-            if offset + i < blob.len() {
-                blob[offset + i] = *byte;
-            }
-        }
+        let blob_hex = match template.layout.merkle_root_reserved(&template.blob, offset, &reserved) {
+            Some(root) => template.hashing_hex(&root),
+            None => hex::encode(template.layout.hashing_blob_reserved(&template.blob, offset, &reserved)),
+        };
+        let created_at = Instant::now();
 
         // Calculate target from difficulty
-        let target = difficulty_to_target(template.difficulty);
+        let share_difficulty = share_difficulty.min(template.difficulty).max(1);
+        let share_target = if share_difficulty == template.difficulty {
+            template.block_target
+        } else {
+            difficulty_to_target(share_difficulty)
+        };
 
         let job = Job {
-            job_id: job_id.clone(),
+            job_id: id.render().as_str().to_string(),
+            session_id: session_id.to_string(),
             template_id: template.template_id,
-            blob_hex: hex::encode(&blob),
+            blob_hex,
+            template_blob: template.blob.clone(),
             reserved_offset: offset,
             reserved_value: reserved,
-            target_hex: hex::encode(&target),
+            target_hex: hex::encode(share_target),
+            share_target,
+            block_target: template.block_target,
+            difficulty: template.difficulty,
+            share_difficulty,
             height: template.height,
+            prev_hash: template.prev_hash.clone(),
             seed_hash: template.seed_hash.clone(),
-            created_at: Instant::now(),
+            created_at,
+            expires_at: created_at + self.block_ttl,
+            share_expires_at: created_at + self.share_ttl(),
+            key,
         };
 
-        self.jobs.insert(job_id, job.clone());
-        job
+        self.jobs.insert(key, JobEntry { job: job.clone(), submitted_nonces: HashSet::new(), submissions: 0 });
+        let replaced = {
+            let mut seqs = self.session_jobs.entry(key.session).or_default();
+            seqs.push_back(key.seq);
+            let excess = seqs.len().saturating_sub(self.max_jobs_per_session);
+            seqs.drain(..excess).collect::<Vec<_>>()
+        };
+        for seq in replaced {
+            self.evict(&JobKey { session: key.session, seq });
+        }
+
+        let mut order = self.order.lock();
+        order.push_back(key);
+        while self.jobs.len() > self.max_jobs {
+            let Some(oldest) = order.pop_front() else { break };
+            if self.evict(&oldest) {
+                if let Some(metrics) = &self.metrics {
+                    metrics.inc_jobs_evicted();
+                }
+            }
+        }
+        drop(order);
+        self.update_live();
+        Ok(job)
     }
 
+    /// Create `count` sibling jobs for `session_id`, one per miner thread,
+    /// that differ only in their reserved value. The count is capped at
+    /// `max_jobs_per_session` so a set never evicts its own members.
+    pub fn create_job_set(
+        &self,
+        template: &TemplateState,
+        session_id: &str,
+        share_difficulty: u64,
+        count: usize,
+    ) -> Result<Vec<Job>, JobError> {
+        let count = count.clamp(1, self.max_jobs_per_session);
+        (0..count).map(|_| self.create_job(template, session_id, share_difficulty)).collect()
+    }
+
+    /// Jobs currently held
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Drop every job of `session_id`, as when the session goes away.
+    /// Only the session's own jobs are visited.
+    pub fn remove_session_jobs(&self, session_id: &str) {
+        let Some((_, session)) = self.session_indices.remove(session_id) else {
+            return;
+        };
+        if let Some((_, seqs)) = self.session_jobs.remove(&session) {
+            for seq in seqs {
+                self.drop_job(&JobKey { session, seq });
+            }
+            self.update_live();
+        }
+    }
+
+    /// Refresh the live jobs gauge
+    fn update_live(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_jobs_live(self.jobs.len());
+        }
+    }
+
+    /// Live jobs held by `session_id`
+    pub fn session_job_count(&self, session_id: &str) -> usize {
+        let Some(session) = self.session_indices.get(session_id).map(|index| *index) else {
+            return 0;
+        };
+        self.session_jobs.get(&session).map(|seqs| seqs.len()).unwrap_or(0)
+    }
+
+    fn drop_job(&self, key: &JobKey) {
+        if let Some((_, entry)) = self.jobs.remove(key) {
+            self.release_reserved_value(&entry.job);
+        }
+    }
+
+    /// Drop a job before its time, remembering it so a late submit is told
+    /// it is stale. Returns whether the job was still there.
+    fn evict(&self, key: &JobKey) -> bool {
+        let Some((_, entry)) = self.jobs.remove(key) else {
+            return false;
+        };
+        self.release_reserved_value(&entry.job);
+        self.unindex(&entry.job);
+        let forget_at = entry.job.share_expires_at + Duration::from_millis(self.stale_grace_ms);
+        self.evicted.insert(entry.job.key, forget_at);
+        true
+    }
+
+    /// Take `job` out of its session's list
+    fn unindex(&self, job: &Job) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut seqs) = self.session_jobs.entry(job.key.session) {
+            seqs.get_mut().retain(|seq| *seq != job.key.seq);
+            if seqs.get().is_empty() {
+                seqs.remove();
+            }
+        }
+    }
+
+    /// Index of `session_id` in the reserved values of its jobs, given with
+    /// its first job and logged so a found block can be traced to it
+    fn session_index(&self, session_id: &str) -> u32 {
+        if let Some(index) = self.session_indices.get(session_id) {
+            return *index;
+        }
+        *self.session_indices.entry(session_id.to_string()).or_insert_with(|| {
+            let index = self.next_session_index.fetch_add(1, Ordering::Relaxed);
+            info!("Session {} has extra nonce index {}", session_id, index);
+            index
+        })
+    }
+
+    /// A job id and a reserved value no live job holds, laid out as an
+    /// [`ExtraNonce`]. Only when the reserve is too small to avoid it is a
+    /// value shared.
+    fn claim_reserved_value(&self, template: &TemplateState, session_id: &str) -> (JobId, Arc<[u8]>) {
+        let size = template.reserve_size as usize;
+        let session = self.session_index(session_id);
+        let mut attempts = 0;
+        loop {
+            let seq = self.counter.fetch_add(1, Ordering::SeqCst);
+            let id = JobId { height: template.height, template_id: template.template_id, session, seq };
+            let entropy = reserved_value(&self.reserved_key, session_id, seq, ExtraNonce::entropy_len(size));
+            let extra_nonce = ExtraNonce { instance_id: Some(self.instance_id), session_index: Some(session), entropy };
+            let value: Arc<[u8]> = extra_nonce.encode(size).into();
+            attempts += 1;
+            if size == 0 || attempts == RESERVED_VALUE_ATTEMPTS {
+                return (id, value);
+            }
+            if let dashmap::mapref::entry::Entry::Vacant(slot) = self.reserved_values.entry(value.clone()) {
+                slot.insert(id.key());
+                return (id, value);
+            }
+        }
+    }
+
+    /// Forget `job`'s reserved value, unless another job shares it
+    fn release_reserved_value(&self, job: &Job) {
+        self.reserved_values.remove_if(&job.reserved_value, |_, holder| *holder == job.key);
+    }
+
+    /// The job `job_id` names. The key is read from the id; the rest of the
+    /// id must match too.
     pub fn get_job(&self, job_id: &str) -> Option<Job> {
-        self.jobs.get(job_id).map(|j| j.clone())
+        let entry = self.jobs.get(&parse_key(job_id)?)?;
+        (entry.job.job_id == job_id).then(|| entry.job.clone())
+    }
+
+    /// Look `job_id` up, telling a job past its TTL from one never issued
+    pub fn lookup_job(&self, job_id: &str) -> JobLookup {
+        match self.get_job(job_id) {
+            Some(job) if job.is_share_expired() => JobLookup::Expired(job),
+            Some(job) if job.is_expired() => JobLookup::BlockExpired(job),
+            Some(job) => JobLookup::Live(job),
+            None if parse_key(job_id).is_some_and(|key| self.evicted.contains_key(&key)) => JobLookup::Evicted,
+            None => JobLookup::Unknown,
+        }
+    }
+
+    /// Remember `nonce` as submitted for `job_id`, whichever session sent
+    /// it, so a replay after a reconnect is caught too
+    pub fn record_nonce(&self, job_id: &str, nonce: u32) -> NonceStatus {
+        let Some(mut entry) = parse_key(job_id).and_then(|key| self.jobs.get_mut(&key)) else {
+            return NonceStatus::UnknownJob;
+        };
+        if entry.job.job_id != job_id {
+            return NonceStatus::UnknownJob;
+        }
+        if entry.submitted_nonces.contains(&nonce) {
+            NonceStatus::Duplicate
+        } else if entry.submissions >= self.max_submissions_per_job {
+            NonceStatus::Capped
+        } else if entry.submitted_nonces.len() >= self.max_nonces_per_job {
+            NonceStatus::Full
+        } else {
+            if entry.submissions == 0 {
+                if let Some(metrics) = &self.metrics {
+                    metrics.observe_job_first_submit(entry.job.created_at.elapsed());
+                }
+            }
+            entry.submitted_nonces.insert(nonce);
+            entry.submissions += 1;
+            NonceStatus::New
+        }
+    }
+
+    /// Forget a nonce recorded for `job_id`, and the submission it counted
+    /// as, so it may be submitted again
+    pub fn forget_nonce(&self, job_id: &str, nonce: u32) {
+        let Some(mut entry) = parse_key(job_id).and_then(|key| self.jobs.get_mut(&key)) else {
+            return;
+        };
+        if entry.job.job_id == job_id && entry.submitted_nonces.remove(&nonce) {
+            entry.submissions = entry.submissions.saturating_sub(1);
+        }
     }
 
-    pub fn is_stale(&self, job: &Job, current_template_id: u64) -> bool {
-        if job.template_id == current_template_id {
+    /// A job for an earlier height than the current template is stale at
+    /// once, since its block can no longer extend the chain. One building on
+    /// the same block as the current template is fresh however often the
+    /// template was refreshed; one on another block at the same height, as
+    /// after a reorg, stays valid for the grace period.
+    pub fn is_stale(&self, job: &Job, current_height: u64, current_prev_hash: &str) -> bool {
+        if job.height < current_height {
+            return true;
+        }
+        if job.prev_hash == current_prev_hash {
             return false;
         }
         job.created_at.elapsed().as_millis() > self.stale_grace_ms as u128
     }
 
-    pub fn cleanup_old_jobs(&self, max_age_ms: u64) {
-        self.jobs.retain(|_, job| {
-            job.created_at.elapsed().as_millis() < max_age_ms as u128
+    pub fn remove_job(&self, job_id: &str) {
+        let Some(key) = parse_key(job_id) else {
+            return;
+        };
+        if let Some((_, entry)) = self.jobs.remove_if(&key, |_, entry| entry.job.job_id == job_id) {
+            self.release_reserved_value(&entry.job);
+            self.unindex(&entry.job);
+            self.update_live();
+        }
+    }
+
+    /// Drop jobs a grace period past their share TTL, the longer of the
+    /// two; until then a submit against one is told it expired rather than
+    /// that it is unknown. Returns the number dropped.
+    pub fn cleanup_old_jobs(&self) -> usize {
+        let now = Instant::now();
+        let grace = Duration::from_millis(self.stale_grace_ms);
+        let (mut removed, mut unused) = (0, 0);
+        self.jobs.retain(|_, entry| {
+            let keep = now < entry.job.share_expires_at + grace;
+            if !keep {
+                self.release_reserved_value(&entry.job);
+                self.unindex(&entry.job);
+                removed += 1;
+                if entry.submissions == 0 {
+                    unused += 1;
+                }
+            }
+            keep
         });
+        self.evicted.retain(|_, forget_at| now < *forget_at);
+        self.order.lock().retain(|key| self.jobs.contains_key(key));
+        if let Some(metrics) = &self.metrics {
+            metrics.add_jobs_expired_unused(unused);
+        }
+        self.update_live();
+        removed
+    }
+}
+
+/// `size` bytes of HMAC-SHA256 over the session, sequence number and block
+/// index, as many blocks as it takes
+fn reserved_value(key: &hmac::Key, session_id: &str, seq: u64, size: usize) -> Vec<u8> {
+    let mut value = Vec::with_capacity(size);
+    let mut block = 0u32;
+    while value.len() < size {
+        let mut context = hmac::Context::with_key(key);
+        context.update(&(session_id.len() as u64).to_le_bytes());
+        context.update(session_id.as_bytes());
+        context.update(&seq.to_le_bytes());
+        context.update(&block.to_le_bytes());
+        let tag = context.sign();
+        let take = (size - value.len()).min(tag.as_ref().len());
+        value.extend_from_slice(&tag.as_ref()[..take]);
+        block += 1;
     }
+    value
 }
 
-fn difficulty_to_target(difficulty: u64) -> [u8; 32] {
+pub(crate) fn difficulty_to_target(difficulty: u64) -> [u8; 32] {
     if difficulty <= 1 {
         return [0xff; 32];
     }
@@ -146,6 +810,24 @@ fn difficulty_to_target(difficulty: u64) -> [u8; 32] {
     target
 }
 
+/// Difficulty a hash achieves: floor(2^256 / hash), reading the hash as a
+/// little-endian integer. An all-zero hash meets any difficulty, and very
+/// small hashes saturate, so both give `u128::MAX`.
+pub fn hash_to_difficulty(hash: &[u8; 32]) -> u128 {
+    let value = BigUint::from_bytes_le(hash);
+    if value == BigUint::default() {
+        return u128::MAX;
+    }
+    let difficulty = &*MAX_TARGET / value;
+    u128::try_from(difficulty).unwrap_or(u128::MAX)
+}
+
+/// Lowest difficulty whose hashes all meet `target`; the inverse of
+/// `difficulty_to_target`
+pub fn target_to_difficulty(target: &[u8; 32]) -> u128 {
+    hash_to_difficulty(target)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,20 +876,131 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_hash_to_difficulty() {
+        assert_eq!(hash_to_difficulty(&[0; 32]), u128::MAX);
+        assert_eq!(hash_to_difficulty(&[0xff; 32]), 1);
+        let mut half = [0u8; 32];
+        half[31] = 0x80;
+        assert_eq!(hash_to_difficulty(&half), 2);
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        assert_eq!(hash_to_difficulty(&one), u128::MAX);
+        // 2^128 would be one past u128::MAX; 2^129 fits
+        let mut small = [0u8; 32];
+        small[16] = 1;
+        assert_eq!(hash_to_difficulty(&small), u128::MAX);
+        small[16] = 2;
+        assert_eq!(hash_to_difficulty(&small), 1 << 127);
+    }
+
+    #[test]
+    fn test_target_round_trips_difficulty() {
+        let mut rng = rand::thread_rng();
+        let difficulties = (0..1000).map(|_| rng.gen_range(2..u64::MAX)).chain([2, 3, 1000, 300_000_000_000, u64::MAX]);
+        for difficulty in difficulties {
+            let target = difficulty_to_target(difficulty);
+            // A hash right on the target achieves exactly the difficulty
+            assert_eq!(target_to_difficulty(&target), u128::from(difficulty), "difficulty {}", difficulty);
+            assert!(meets_target(&target, &target));
+
+            // One above it falls short
+            let above = (BigUint::from_bytes_le(&target) + 1u32).to_bytes_le();
+            let mut hash = [0u8; 32];
+            hash[..above.len()].copy_from_slice(&above);
+            assert!(!meets_target(&hash, &target));
+            assert!(hash_to_difficulty(&hash) < u128::from(difficulty));
+        }
+    }
+
+    #[test]
+    fn test_meets_target_agrees_with_biguint() {
+        let mut rng = rand::thread_rng();
+        let to_bytes = |value: BigUint| {
+            let bytes = value.to_bytes_le();
+            let mut out = [0u8; 32];
+            out[..bytes.len()].copy_from_slice(&bytes);
+            out
+        };
+        // Small, power-of-two and anywhere in u64
+        let difficulties = (0..300)
+            .map(|i| match i % 3 {
+                0 => rng.gen_range(0..=16),
+                1 => 1u64 << rng.gen_range(0..64),
+                _ => rng.gen(),
+            })
+            .chain([0, 1, 2, u64::MAX])
+            .collect::<Vec<_>>();
+        for difficulty in difficulties {
+            let target = difficulty_to_target(difficulty);
+            let target_value = BigUint::from_bytes_le(&target);
+            let mut hashes = vec![[0u8; 32], [0xff; 32], target, to_bytes(&target_value - 1u32)];
+            if target != [0xff; 32] {
+                hashes.push(to_bytes(&target_value + 1u32));
+            }
+            for _ in 0..30 {
+                hashes.push(rng.gen());
+                // Sharing the target's high bytes, so near it either side
+                let mut near = target;
+                let low = rng.gen_range(0..32);
+                rng.fill(&mut near[..low]);
+                hashes.push(near);
+            }
+            for hash in hashes {
+                let expected = BigUint::from_bytes_le(&hash) <= target_value;
+                assert_eq!(meets_target(&hash, &target), expected, "difficulty {} hash {}", difficulty, hex::encode(hash));
+            }
+        }
+    }
+
+    #[test]
+    fn test_u256_orders_by_the_most_significant_byte() {
+        let mut low = [0u8; 32];
+        low[0] = 0xff;
+        let mut high = [0u8; 32];
+        high[31] = 0x01;
+        assert!(U256::from_le_bytes(&low) < U256::from_le_bytes(&high));
+        let mut middle = [0u8; 32];
+        middle[8] = 0x01;
+        assert!(U256::from_le_bytes(&low) < U256::from_le_bytes(&middle));
+        assert!(U256::from_le_bytes(&middle) < U256::from_le_bytes(&high));
+        assert_eq!(U256::from_le_bytes(&[0xff; 32]), U256([u64::MAX; 4]));
+    }
+
+    #[test]
+    fn test_compact_target() {
+        assert_eq!(compact_target_hex(0), "ffffffff");
+        assert_eq!(compact_target_hex(1), "ffffffff");
+        // 0xffffffff / 1000 = 0x00418937
+        assert_eq!(compact_target_hex(1000), "37894100");
+        // Past 32 bits the target widens to 64
+        assert_eq!(compact_target_hex(1 << 33), "ffffff7f00000000");
+    }
+
     #[test]
     fn test_apply_nonce_success() {
         // Create a test job with a valid blob
         let blob = vec![0u8; 76]; // Minimum valid blob size
         let job = Job {
             job_id: "test_job".to_string(),
+            session_id: "session".to_string(),
             template_id: 1,
             blob_hex: hex::encode(&blob),
+            template_blob: Arc::new(blob.clone()),
             reserved_offset: 50,
-            reserved_value: vec![1, 2, 3, 4],
+            reserved_value: vec![1, 2, 3, 4].into(),
             target_hex: "ffffffff".to_string(),
+            share_target: [0xff; 32],
+            block_target: [0xff; 32],
+            difficulty: 1,
+            share_difficulty: 1,
             height: 100,
+            prev_hash: String::new(),
             seed_hash: "abcd".to_string(),
             created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(30),
+            share_expires_at: Instant::now() + Duration::from_secs(30),
+            key: JobKey::default(),
         };
 
         // Test with valid 4-byte nonce (8 hex chars)
@@ -227,14 +1020,24 @@ mod tests {
         let blob = vec![0u8; 76];
         let job = Job {
             job_id: "test_job".to_string(),
+            session_id: "session".to_string(),
             template_id: 1,
             blob_hex: hex::encode(&blob),
+            template_blob: Arc::new(blob.clone()),
             reserved_offset: 50,
-            reserved_value: vec![1, 2, 3, 4],
+            reserved_value: vec![1, 2, 3, 4].into(),
             target_hex: "ffffffff".to_string(),
+            share_target: [0xff; 32],
+            block_target: [0xff; 32],
+            difficulty: 1,
+            share_difficulty: 1,
             height: 100,
+            prev_hash: String::new(),
             seed_hash: "abcd".to_string(),
             created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(30),
+            share_expires_at: Instant::now() + Duration::from_secs(30),
+            key: JobKey::default(),
         };
 
         // Test with invalid hex
@@ -248,14 +1051,24 @@ mod tests {
         let blob = vec![0u8; 76];
         let job = Job {
             job_id: "test_job".to_string(),
+            session_id: "session".to_string(),
             template_id: 1,
             blob_hex: hex::encode(&blob),
+            template_blob: Arc::new(blob.clone()),
             reserved_offset: 50,
-            reserved_value: vec![1, 2, 3, 4],
+            reserved_value: vec![1, 2, 3, 4].into(),
             target_hex: "ffffffff".to_string(),
+            share_target: [0xff; 32],
+            block_target: [0xff; 32],
+            difficulty: 1,
+            share_difficulty: 1,
             height: 100,
+            prev_hash: String::new(),
             seed_hash: "abcd".to_string(),
             created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(30),
+            share_expires_at: Instant::now() + Duration::from_secs(30),
+            key: JobKey::default(),
         };
 
         // Test with wrong size nonce (too short)
@@ -275,18 +1088,470 @@ mod tests {
         let blob = vec![0u8; 30]; // Less than NONCE_OFFSET + NONCE_SIZE
         let job = Job {
             job_id: "test_job".to_string(),
+            session_id: "session".to_string(),
             template_id: 1,
             blob_hex: hex::encode(&blob),
+            template_blob: Arc::new(blob.clone()),
             reserved_offset: 20,
-            reserved_value: vec![1, 2, 3, 4],
+            reserved_value: vec![1, 2, 3, 4].into(),
             target_hex: "ffffffff".to_string(),
+            share_target: [0xff; 32],
+            block_target: [0xff; 32],
+            difficulty: 1,
+            share_difficulty: 1,
             height: 100,
+            prev_hash: String::new(),
             seed_hash: "abcd".to_string(),
             created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(30),
+            share_expires_at: Instant::now() + Duration::from_secs(30),
+            key: JobKey::default(),
         };
 
         let result = job.apply_nonce("12345678");
         assert!(result.is_err());
         assert_eq!(result.unwrap_err(), "Blob too short for nonce");
     }
+
+    #[test]
+    fn test_apply_nonce_touches_only_the_nonce() {
+        // A patterned blob, so a write anywhere but the nonce shows up
+        let blob: Vec<u8> = (0..76u8).map(|i| i.wrapping_mul(7) ^ 0x5a).collect();
+        let job = Job {
+            job_id: "test_job".to_string(),
+            session_id: "session".to_string(),
+            template_id: 1,
+            blob_hex: hex::encode(&blob),
+            template_blob: Arc::new(blob.clone()),
+            reserved_offset: 50,
+            reserved_value: vec![1, 2, 3, 4].into(),
+            target_hex: "ffffffff".to_string(),
+            share_target: [0xff; 32],
+            block_target: [0xff; 32],
+            difficulty: 1,
+            share_difficulty: 1,
+            height: 100,
+            prev_hash: String::new(),
+            seed_hash: "abcd".to_string(),
+            created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(30),
+            share_expires_at: Instant::now() + Duration::from_secs(30),
+            key: JobKey::default(),
+        };
+
+        let reconstructed = job.apply_nonce("deadbeef").unwrap();
+        assert_eq!(&reconstructed[39..43], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&reconstructed[..39], &blob[..39]);
+        assert_eq!(&reconstructed[43..], &blob[43..]);
+    }
+
+    #[test]
+    fn test_classify_against_both_targets() {
+        let mut template = crate::server::tests::test_template();
+        template.set_difficulty(1_000_000);
+        let job = JobManager::new(0).create_job(&template, "session", 1000).unwrap();
+        assert_eq!(hex::decode(&job.target_hex).unwrap(), job.share_target);
+        assert_eq!(job.block_target, difficulty_to_target(1_000_000));
+
+        // Meets the share target but not the block target
+        assert_eq!(job.classify(&job.share_target), HashClass::Share);
+        assert_eq!(job.classify(&job.block_target), HashClass::Block);
+        assert_eq!(job.classify(&[0; 32]), HashClass::Block);
+        assert_eq!(job.classify(&[0xff; 32]), HashClass::BelowTarget);
+
+        // At block difficulty there is no share that is not a block
+        let job = JobManager::new(0).create_job(&template, "session", 1_000_000).unwrap();
+        assert_eq!(job.classify(&job.share_target), HashClass::Block);
+    }
+
+    #[test]
+    fn test_reserved_value_is_deterministic_per_key() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let value = reserved_value(&key, "session", 7, 8);
+        assert_eq!(value, reserved_value(&key, "session", 7, 8));
+        assert_ne!(value, reserved_value(&key, "session", 8, 8));
+        assert_ne!(value, reserved_value(&key, "other", 7, 8));
+        assert_ne!(value, reserved_value(&hmac::Key::new(hmac::HMAC_SHA256, b"other"), "session", 7, 8));
+
+        // Shorter values are prefixes; longer ones run past one tag
+        assert_eq!(reserved_value(&key, "session", 7, 4), value[..4]);
+        let long = reserved_value(&key, "session", 7, 255);
+        assert_eq!(long.len(), 255);
+        assert_eq!(long[..8], value[..]);
+        // Sizes past a byte are only a question of more blocks
+        let longer = reserved_value(&key, "session", 7, 300);
+        assert_eq!(longer.len(), 300);
+        assert_eq!(longer[..255], long[..]);
+        assert!(reserved_value(&key, "session", 7, 0).is_empty());
+    }
+
+    #[test]
+    fn test_reserved_values_name_instance_and_session() {
+        let mut template = crate::server::tests::test_template();
+        template.reserve_size = 16;
+        template.reserved_offset = crate::blob::fixture::RESERVED_OFFSET + crate::blob::fixture::RESERVE_SIZE as usize - 16;
+        let manager = JobManager::new(0).with_instance_id(7);
+        let first = ExtraNonce::decode(&manager.create_job(&template, "first", 1).unwrap().reserved_value);
+        let second = ExtraNonce::decode(&manager.create_job(&template, "second", 1).unwrap().reserved_value);
+        let again = ExtraNonce::decode(&manager.create_job(&template, "first", 1).unwrap().reserved_value);
+        assert_eq!((first.instance_id, first.session_index), (Some(7), Some(0)));
+        assert_eq!((second.instance_id, second.session_index), (Some(7), Some(1)));
+        assert_eq!(again.session_index, Some(0));
+        assert_ne!(again.entropy, first.entropy);
+
+        // A session back after removal is given a new index
+        manager.remove_session_jobs("first");
+        let back = ExtraNonce::decode(&manager.create_job(&template, "first", 1).unwrap().reserved_value);
+        assert_eq!(back.session_index, Some(2));
+
+        // The fixture's 8 bytes leave room for the instance id only
+        let template = crate::server::tests::test_template();
+        let small = ExtraNonce::decode(&manager.create_job(&template, "first", 1).unwrap().reserved_value);
+        assert_eq!((small.instance_id, small.session_index, small.entropy.len()), (Some(7), None, 4));
+    }
+
+    #[test]
+    fn test_reserved_values_are_distinct_across_sessions() {
+        let mut template = crate::server::tests::test_template();
+        let extra_nonce_end = crate::blob::fixture::RESERVED_OFFSET + crate::blob::fixture::RESERVE_SIZE as usize;
+        for size in [4u32, 8, 16] {
+            // Ending where the fixture's extra nonce does, so within the prefix
+            template.reserve_size = size;
+            template.reserved_offset = extra_nonce_end - size as usize;
+            let manager = JobManager::new(0);
+            let mut seen = HashSet::new();
+            for session in 0..200 {
+                // Session ids sharing a long prefix once collided outright
+                let job = manager.create_job(&template, &format!("00000000-0000-0000-0000-{:012}", session), 1).unwrap();
+                assert_eq!(job.reserved_value.len(), size as usize);
+                assert!(seen.insert(job.reserved_value.clone()), "reserve size {}", size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_live_reserved_values_are_not_reused() {
+        // One byte has room for 256 values; clashes are retried
+        let mut template = crate::server::tests::test_template();
+        template.reserve_size = 1;
+        let manager = JobManager::new(0).with_block_ttl_ms(0);
+        let values: HashSet<_> = (0..64).map(|i| manager.create_job(&template, &format!("session-{}", i), 1).unwrap().reserved_value).collect();
+        assert_eq!(values.len(), 64);
+
+        // Cleaned up jobs give theirs back
+        manager.cleanup_old_jobs();
+        assert!(manager.reserved_values.is_empty());
+    }
+
+    #[test]
+    fn test_lookup_tells_expired_from_unknown() {
+        let template = crate::server::tests::test_template();
+        let live = JobManager::new(0);
+        let job = live.create_job(&template, "session", 1).unwrap();
+        assert!(matches!(live.lookup_job(&job.job_id), JobLookup::Live(_)));
+        assert!(matches!(live.lookup_job("missing"), JobLookup::Unknown));
+
+        // Expired jobs outlive their TTL by the grace period
+        let expiring = JobManager::new(60_000).with_block_ttl_ms(0);
+        let job = expiring.create_job(&template, "session", 1).unwrap();
+        assert!(matches!(expiring.lookup_job(&job.job_id), JobLookup::Expired(_)));
+        expiring.cleanup_old_jobs();
+        assert!(matches!(expiring.lookup_job(&job.job_id), JobLookup::Expired(_)));
+
+        let expired = JobManager::new(0).with_block_ttl_ms(0);
+        let job = expired.create_job(&template, "session", 1).unwrap();
+        expired.cleanup_old_jobs();
+        assert!(matches!(expired.lookup_job(&job.job_id), JobLookup::Unknown));
+    }
+
+    #[test]
+    fn test_jobs_are_kept_until_the_share_ttl() {
+        let template = crate::server::tests::test_template();
+        let manager = JobManager::new(0).with_block_ttl_ms(0).with_share_ttl_ms(60_000);
+        let job = manager.create_job(&template, "session", 1).unwrap();
+        assert!(job.is_expired() && !job.is_share_expired());
+        assert_eq!(manager.cleanup_old_jobs(), 0);
+        assert!(matches!(manager.lookup_job(&job.job_id), JobLookup::BlockExpired(_)));
+
+        // A share TTL shorter than the block TTL is lengthened to it
+        let manager = JobManager::new(0).with_block_ttl_ms(60_000).with_share_ttl_ms(0);
+        let job = manager.create_job(&template, "session", 1).unwrap();
+        assert_eq!(job.share_expires_at, job.expires_at);
+        assert!(matches!(manager.lookup_job(&job.job_id), JobLookup::Live(_)));
+    }
+
+    #[test]
+    fn test_job_ids_carry_height_template_and_key() {
+        let id = JobId { height: 3_000_000, template_id: 0x2a, session: 3, seq: 7 };
+        assert_eq!(id.to_string(), "3000000-0000002a-00000003-00000007");
+        assert_eq!("3000000-0000002a-00000003-00000007".parse::<JobId>(), Ok(id));
+        assert_eq!(id.key(), JobKey { session: 3, seq: 7 });
+        let wide = JobId { height: 1, template_id: u64::MAX, session: u32::MAX, seq: u64::MAX };
+        assert_eq!(wide.to_string().parse::<JobId>(), Ok(wide));
+
+        // Rendered by hand, byte for byte what the format string gives
+        let mut rng = rand::thread_rng();
+        let edges = [0, 9, 10, 0xffff_ffff, 0x1_0000_0000, 0x0fff_ffff_ffff_ffff, u64::MAX];
+        let random = (0..200).map(|_| rng.gen::<u64>() >> rng.gen_range(0..64));
+        for value in edges.into_iter().chain(random) {
+            let id = JobId { height: value, template_id: value, session: value as u32, seq: value.rotate_left(7) };
+            let formatted = format!("{}-{:08x}-{:08x}-{:08x}", id.height, id.template_id, id.session, id.seq);
+            assert_eq!(id.render().as_str(), formatted);
+        }
+
+        // Older forms and junk do not parse, so name no job
+        for bad in ["0123456789abcdef", "", "1-2", "3000000-0000002a-00000007", "x-1-1-1", "1-0000000g-1-1", "1-1-100000000-1", "1-1-1-1-1"] {
+            assert!(bad.parse::<JobId>().is_err(), "{}", bad);
+            assert!(JobManager::new(0).get_job(bad).is_none());
+        }
+
+        let template = crate::server::tests::test_template();
+        let job = JobManager::new(0).create_job(&template, "session", 1).unwrap();
+        let parsed: JobId = job.job_id.parse().unwrap();
+        assert_eq!((parsed.height, parsed.template_id), (template.height, template.template_id));
+    }
+
+    #[test]
+    fn test_job_sets_differ_only_in_reserved_value() {
+        let template = crate::server::tests::test_template();
+        let manager = JobManager::new(0).with_max_jobs_per_session(8);
+        let jobs = manager.create_job_set(&template, "session", 1, 4).unwrap();
+        assert_eq!(jobs.len(), 4);
+        let reserved: HashSet<&[u8]> = jobs.iter().map(|job| &*job.reserved_value).collect();
+        assert_eq!(reserved.len(), 4);
+        assert!(jobs.iter().all(|job| job.template_id == jobs[0].template_id && job.target_hex == jobs[0].target_hex));
+        assert_eq!(manager.session_job_count("session"), 4);
+
+        // A set never outgrows what a session may hold
+        assert_eq!(manager.create_job_set(&template, "session", 1, 255).unwrap().len(), 8);
+        assert_eq!(manager.create_job_set(&template, "session", 1, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_new_height_makes_jobs_stale_despite_grace() {
+        let mut template = crate::server::tests::test_template();
+        template.prev_hash = "11".repeat(32);
+        let graced = JobManager::new(60_000);
+        let job = graced.create_job(&template, "session", 1).unwrap();
+        assert!(!graced.is_stale(&job, template.height, &template.prev_hash));
+
+        // Another block at the same height leaves the job its grace period
+        let reorged = "22".repeat(32);
+        assert!(!graced.is_stale(&job, template.height, &reorged));
+        // A new height does not
+        assert!(graced.is_stale(&job, template.height + 1, &reorged));
+        assert!(graced.is_stale(&job, template.height + 1, &template.prev_hash));
+
+        // Without grace, only a different block makes the job stale
+        let strict = JobManager::new(0);
+        let job = strict.create_job(&template, "session", 1).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(!strict.is_stale(&job, template.height, &template.prev_hash));
+        assert!(strict.is_stale(&job, template.height, &reorged));
+    }
+
+    #[test]
+    fn test_malformed_templates_make_no_jobs() {
+        let manager = JobManager::new(0);
+        let mut template = crate::server::tests::test_template();
+        template.reserve_size = 0;
+        assert_eq!(manager.create_job(&template, "session", 1).unwrap_err(), JobError::ZeroReserveSize);
+
+        // In the header, past the miner transaction's prefix, past the blob
+        let blob_len = template.blob.len();
+        for offset in [0, 139, blob_len - 4, usize::MAX - 2] {
+            let mut template = crate::server::tests::test_template();
+            template.reserved_offset = offset;
+            let end = offset.saturating_add(8);
+            assert_eq!(manager.create_job(&template, "session", 1).unwrap_err(), JobError::OffsetOutOfRange { offset, end });
+        }
+        assert!(manager.is_empty());
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest_jobs_first() {
+        let template = crate::server::tests::test_template();
+        let metrics = Arc::new(Metrics::new());
+        let manager = JobManager::new(0).with_max_jobs(5).with_metrics(metrics.clone());
+        let jobs: Vec<Job> = (0..8).map(|i| manager.create_job(&template, &format!("session-{}", i), 1).unwrap()).collect();
+
+        assert_eq!(manager.len(), 5);
+        assert_eq!(metrics.jobs_evicted.load(Ordering::Relaxed), 3);
+        for job in &jobs[..3] {
+            assert!(matches!(manager.lookup_job(&job.job_id), JobLookup::Evicted));
+            assert_eq!(manager.session_job_count(&job.session_id), 0);
+        }
+        assert!(jobs[3..].iter().all(|job| matches!(manager.lookup_job(&job.job_id), JobLookup::Live(_))));
+
+        // Jobs removed meanwhile free their slot without an eviction
+        manager.remove_job(&jobs[3].job_id);
+        manager.create_job(&template, "session-8", 1).unwrap();
+        assert_eq!(manager.len(), 5);
+        assert_eq!(metrics.jobs_evicted.load(Ordering::Relaxed), 3);
+        manager.create_job(&template, "session-9", 1).unwrap();
+        assert!(matches!(manager.lookup_job(&jobs[4].job_id), JobLookup::Evicted));
+        assert_eq!(metrics.jobs_evicted.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_evicted_jobs_are_forgotten_with_their_expiry() {
+        let manager = JobManager::new(0).with_block_ttl_ms(0).with_max_jobs(1);
+        let template = crate::server::tests::test_template();
+        let first = manager.create_job(&template, "session", 1).unwrap();
+        manager.create_job(&template, "session", 1).unwrap();
+        assert!(matches!(manager.lookup_job(&first.job_id), JobLookup::Evicted));
+
+        manager.cleanup_old_jobs();
+        assert!(matches!(manager.lookup_job(&first.job_id), JobLookup::Unknown));
+        assert!(manager.is_empty());
+        assert!(manager.order.lock().is_empty());
+    }
+
+    #[test]
+    fn test_session_cleanup_takes_only_its_own_jobs() {
+        let template = crate::server::tests::test_template();
+        let manager = JobManager::new(0).with_max_jobs_per_session(4);
+        let leaving: Vec<Job> = (0..4).map(|_| manager.create_job(&template, "leaving", 1).unwrap()).collect();
+        let staying: Vec<Job> = (0..3).map(|_| manager.create_job(&template, "staying", 1).unwrap()).collect();
+        assert!(leaving.iter().all(|job| job.job_id.parse::<JobId>().unwrap().key() == job.key));
+        assert_ne!(leaving[0].key.session, staying[0].key.session);
+
+        manager.remove_session_jobs("leaving");
+        assert!(leaving.iter().all(|job| manager.get_job(&job.job_id).is_none()));
+        assert!(leaving.iter().all(|job| matches!(manager.lookup_job(&job.job_id), JobLookup::Unknown)));
+        assert!(!manager.session_indices.contains_key("leaving"));
+        assert!(!manager.session_jobs.contains_key(&leaving[0].key.session));
+        assert!(manager.reserved_values.iter().all(|holder| holder.value().session == staying[0].key.session));
+
+        // The other session keeps every job, and its count
+        assert!(staying.iter().all(|job| matches!(manager.lookup_job(&job.job_id), JobLookup::Live(_))));
+        assert_eq!(manager.session_job_count("staying"), 3);
+        assert_eq!(manager.len(), 3);
+
+        // An id naming a live key but another height is not that job
+        let mut forged: JobId = staying[0].job_id.parse().unwrap();
+        forged.height += 1;
+        assert!(manager.get_job(&forged.to_string()).is_none());
+        assert_eq!(manager.record_nonce(&forged.to_string(), 1), NonceStatus::UnknownJob);
+        manager.remove_job(&forged.to_string());
+        assert_eq!(manager.len(), 3);
+    }
+
+    #[test]
+    fn test_jobs_per_session_are_capped() {
+        let template = crate::server::tests::test_template();
+        let manager = JobManager::new(0).with_max_jobs_per_session(3);
+        let jobs: Vec<Job> = (0..5).map(|_| manager.create_job(&template, "session", 1).unwrap()).collect();
+        let other = manager.create_job(&template, "other", 1).unwrap();
+
+        // The oldest two made room
+        assert_eq!(manager.session_job_count("session"), 3);
+        assert!(jobs[..2].iter().all(|job| matches!(manager.lookup_job(&job.job_id), JobLookup::Evicted)));
+        assert!(jobs[2..].iter().all(|job| manager.get_job(&job.job_id).is_some()));
+        assert_eq!(manager.reserved_values.len(), 4);
+
+        manager.remove_job(&jobs[4].job_id);
+        assert_eq!(manager.session_job_count("session"), 2);
+
+        manager.remove_session_jobs("session");
+        assert_eq!(manager.session_job_count("session"), 0);
+        assert!(!manager.session_jobs.contains_key(&jobs[0].key.session));
+        assert!(jobs.iter().all(|job| manager.get_job(&job.job_id).is_none()));
+        assert!(manager.get_job(&other.job_id).is_some());
+        assert_eq!(manager.jobs.len(), 1);
+    }
+
+    #[test]
+    fn test_record_nonce() {
+        let manager = JobManager::new(0).with_block_ttl_ms(0).with_max_nonces_per_job(2);
+        let job = manager.create_job(&crate::server::tests::test_template(), "session", 1).unwrap();
+
+        assert_eq!(manager.record_nonce(&job.job_id, 1), NonceStatus::New);
+        assert_eq!(manager.record_nonce(&job.job_id, 1), NonceStatus::Duplicate);
+        assert_eq!(manager.record_nonce(&job.job_id, 2), NonceStatus::New);
+        // Full, but what it holds is still caught
+        assert_eq!(manager.record_nonce(&job.job_id, 3), NonceStatus::Full);
+        assert_eq!(manager.record_nonce(&job.job_id, 3), NonceStatus::Full);
+        assert_eq!(manager.record_nonce(&job.job_id, 2), NonceStatus::Duplicate);
+        assert_eq!(manager.record_nonce("missing", 1), NonceStatus::UnknownJob);
+        // A forgotten nonce makes room, and may be sent again
+        manager.forget_nonce(&job.job_id, 2);
+        assert_eq!(manager.record_nonce(&job.job_id, 2), NonceStatus::New);
+
+        // The nonces go with the job
+        manager.cleanup_old_jobs();
+        assert_eq!(manager.record_nonce(&job.job_id, 1), NonceStatus::UnknownJob);
+    }
+
+    #[test]
+    fn test_job_lifecycle_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let manager = JobManager::new(0).with_block_ttl_ms(0).with_metrics(metrics.clone());
+        let template = crate::server::tests::test_template();
+        let jobs: Vec<Job> = (0..4).map(|i| manager.create_job(&template, &format!("session-{}", i), 1).unwrap()).collect();
+        assert_eq!(metrics.jobs_live.load(Ordering::Relaxed), 4);
+
+        // Two get submissions; only the first of each is timed
+        for job in &jobs[..2] {
+            assert_eq!(manager.record_nonce(&job.job_id, 1), NonceStatus::New);
+            assert_eq!(manager.record_nonce(&job.job_id, 2), NonceStatus::New);
+        }
+        assert_eq!(metrics.job_first_submit_age_seconds.0.count(), 2);
+
+        manager.remove_job(&jobs[3].job_id);
+        assert_eq!(metrics.jobs_live.load(Ordering::Relaxed), 3);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(manager.cleanup_old_jobs(), 3);
+        assert_eq!(metrics.jobs_live.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.jobs_expired_unused.load(Ordering::Relaxed), 1);
+        assert_eq!(manager.cleanup_old_jobs(), 0);
+
+        let exported = metrics.format_prometheus();
+        assert!(exported.contains("coordinator_job_first_submit_age_seconds_count 2\n"));
+        assert!(exported.contains("coordinator_jobs_expired_unused 1\n"));
+    }
+
+    #[test]
+    fn test_submissions_per_job_are_capped_across_threads() {
+        let manager = JobManager::new(0).with_max_submissions_per_job(32);
+        let job = manager.create_job(&crate::server::tests::test_template(), "session", 1).unwrap();
+        let statuses: Vec<NonceStatus> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8u32)
+                .map(|t| {
+                    let (manager, job_id) = (&manager, &job.job_id);
+                    scope.spawn(move || (0..16).map(|n| manager.record_nonce(job_id, t * 16 + n)).collect::<Vec<_>>())
+                })
+                .collect();
+            workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+        });
+        assert_eq!(statuses.iter().filter(|s| **s == NonceStatus::New).count(), 32);
+        assert_eq!(statuses.iter().filter(|s| **s == NonceStatus::Capped).count(), 96);
+        // A nonce it already took is still a duplicate
+        let taken = (0..128).find(|n| statuses[*n as usize] == NonceStatus::New).unwrap();
+        assert_eq!(manager.record_nonce(&job.job_id, taken), NonceStatus::Duplicate);
+    }
+
+    #[test]
+    fn test_job_blob_is_the_hashing_blob() {
+        let template = crate::server::tests::test_template();
+        let job = JobManager::new(0).create_job(&template, "session", 1).unwrap();
+
+        // Bytes as they were when every job decoded and patched its own
+        // copy of the template
+        let mut block = hex::decode(&template.blocktemplate_blob).unwrap();
+        block[template.reserved_offset..template.reserved_offset + job.reserved_value.len()].copy_from_slice(&job.reserved_value);
+
+        // The miner gets header, merkle root and count, not the whole block
+        assert_eq!(job.blob_hex, hex::encode(template.layout.hashing_blob(&block)));
+        assert_eq!(job.blob_hex.len(), 76 * 2);
+        assert_ne!(job.blob_hex, template.blockhashing_blob, "the reserved value moves the merkle root");
+        assert!(Arc::ptr_eq(&job.template_blob, &template.blob));
+
+        // A nonce lands in the same header in both
+        let hashed = job.apply_nonce("deadbeef").unwrap();
+        let submitted = job.block_blob("deadbeef").unwrap();
+        assert_eq!(hashed[..43], submitted[..43]);
+        assert_eq!(&submitted[..39], &block[..39]);
+        assert_eq!(&submitted[43..], &block[43..]);
+    }
 }
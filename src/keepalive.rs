@@ -0,0 +1,76 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Tracks liveness of a single connection for server-driven ping/pong.
+///
+/// Any frame from the client (pong, text, application-level ping) counts as
+/// activity. The connection is considered dead once nothing has been heard
+/// for `timeout`.
+pub struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Keepalive {
+    pub fn new(interval_ms: u64, timeout_ms: u64) -> Self {
+        Self {
+            interval: Duration::from_millis(interval_ms),
+            timeout: Duration::from_millis(timeout_ms),
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// How often the server should send a protocol-level ping
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn record_activity(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_seen) > self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_expired_before_deadline() {
+        let keepalive = Keepalive::new(1_000, 5_000);
+        let start = keepalive.last_seen;
+        assert!(!keepalive.is_expired(start));
+        assert!(!keepalive.is_expired(start + Duration::from_millis(4_999)));
+        assert!(!keepalive.is_expired(start + Duration::from_millis(5_000)));
+    }
+
+    #[test]
+    fn test_expired_after_deadline() {
+        let keepalive = Keepalive::new(1_000, 5_000);
+        let start = keepalive.last_seen;
+        assert!(keepalive.is_expired(start + Duration::from_millis(5_001)));
+    }
+
+    #[test]
+    fn test_activity_extends_deadline() {
+        let mut keepalive = Keepalive::new(1_000, 5_000);
+        let start = keepalive.last_seen;
+        // Simulate a pong arriving 4s after the connection opened
+        keepalive.last_seen = start + Duration::from_millis(4_000);
+        assert!(!keepalive.is_expired(start + Duration::from_millis(8_000)));
+        assert!(keepalive.is_expired(start + Duration::from_millis(9_001)));
+
+        keepalive.record_activity();
+        let seen = keepalive.last_seen;
+        assert!(!keepalive.is_expired(seen + Duration::from_millis(5_000)));
+        assert!(keepalive.is_expired(seen + Duration::from_millis(5_001)));
+    }
+}
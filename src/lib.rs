@@ -0,0 +1,43 @@
+//! Coordinator internals, split from the binary so benchmarks can drive them.
+
+mod access;
+mod admin;
+mod assets;
+mod auth;
+pub mod ban;
+mod blob;
+mod capabilities;
+pub mod config;
+mod cors;
+mod error;
+pub mod events;
+mod extranonce;
+mod fanout;
+mod hashrate;
+mod health;
+mod ipkey;
+pub mod jobs;
+mod keepalive;
+mod keccak;
+mod longpoll;
+pub mod metrics;
+mod outbound;
+pub mod persistence;
+pub mod pow;
+pub mod privacy;
+mod protocol;
+mod proxy;
+mod proxy_protocol;
+mod ratelimit;
+mod rpc;
+pub mod rpc_verifier;
+pub mod server;
+pub mod session;
+mod sse;
+mod stats;
+pub mod template;
+mod tls;
+pub mod validator;
+mod vardiff;
+pub mod version;
+pub mod vm_pool;
@@ -0,0 +1,341 @@
+use axum::{
+    Json, Router,
+    routing::{get, post},
+    response::{IntoResponse, Response},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::info;
+
+use crate::auth::{self, constant_time_eq, SiteAuth};
+use crate::events::EventKind;
+use crate::capabilities::SessionCapabilities;
+use crate::protocol::{self, ClientMessage, HelloOptions, RandomxMode};
+use crate::proxy;
+use crate::server::{self, AppState};
+use crate::session::CloseSignal;
+
+/// Longest a job poll is held open, whatever the client asks for
+const MAX_POLL_WAIT: Duration = Duration::from_secs(30);
+
+const DEFAULT_POLL_WAIT_MS: u64 = 25_000;
+
+/// Same fields as a WebSocket hello
+#[derive(Debug, Deserialize)]
+pub struct HelloRequest {
+    pub v: u8,
+    pub client_version: String,
+    pub threads: u8,
+    #[serde(default)]
+    pub site_token: Option<String>,
+    #[serde(default)]
+    pub worker: Option<String>,
+    #[serde(flatten)]
+    pub options: HelloOptions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloResponse {
+    pub session_id: String,
+    /// Sent back as `Authorization: Bearer` on every later request
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubmitRequest {
+    pub id: String,
+    pub job_id: String,
+    pub nonce: String,
+    #[serde(default)]
+    pub result: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollQuery {
+    pub wait_ms: Option<u64>,
+}
+
+/// Long-polling transport for clients whose network breaks WebSockets.
+/// Sessions share the WebSocket session model, limits and metrics, and
+/// expire through the idle session cleanup.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/session", post(create_session))
+        .route("/session/:id/job", get(poll_job))
+        .route("/session/:id/submit", post(submit))
+}
+
+async fn create_session(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(hello): Json<HelloRequest>,
+) -> Response {
+    let ip = proxy::resolve_client_ip(addr.ip(), &headers, &state.config.server.trusted_proxies);
+    if let Some(rejection) = server::admission_rejection(&state, ip) {
+        return rejection;
+    }
+    if hello.worker.as_deref().is_some_and(|w| !protocol::is_valid_worker(w)) {
+        return (StatusCode::BAD_REQUEST, "invalid worker name").into_response();
+    }
+    let capabilities = match SessionCapabilities::negotiate(hello.v, &hello.options, &state.config.server) {
+        Ok(capabilities) => capabilities,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
+
+    let session = match state.session_manager.create_session(ip) {
+        Some(s) => s,
+        None => return (StatusCode::TOO_MANY_REQUESTS, "Connection limit exceeded").into_response(),
+    };
+
+    let site_label = match auth::check_site_token(&state.config.auth, hello.site_token.as_deref()) {
+        SiteAuth::Matched(label) => Some(label),
+        SiteAuth::Anonymous => None,
+        SiteAuth::Denied => {
+            state.session_manager.remove_session(&session.id);
+            return (StatusCode::UNAUTHORIZED, "invalid site token").into_response();
+        }
+    };
+
+    let ready = state.session_manager.set_ready(
+        &session.id,
+        hello.client_version.clone(),
+        hello.threads,
+        site_label.clone(),
+        hello.worker.clone(),
+    );
+    if !ready {
+        state.session_manager.remove_session(&session.id);
+        let error = server::site_limit_error(&state, site_label.as_deref());
+        return (StatusCode::TOO_MANY_REQUESTS, Json(error)).into_response();
+    }
+    state.events.emit(&session.id, ip, EventKind::Hello {
+        client_version: hello.client_version,
+        threads: hello.threads,
+        site: site_label,
+        worker: hello.worker,
+    });
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    state.session_manager.update_session(&session.id, |s| {
+        s.poll_token = Some(token.clone());
+        s.capabilities = Some(capabilities);
+    });
+    state.session_manager.set_randomx_mode(&session.id, RandomxMode::from_hello(hello.options.randomx_mode.as_deref()));
+    state.metrics.inc_messages();
+    info!("Long-poll session {} opened", session.id);
+
+    Json(HelloResponse { session_id: session.id, token }).into_response()
+}
+
+/// Hold the request until there is a job the session has not seen, or the
+/// wait runs out (204).
+async fn poll_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<PollQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (close_signal, last_job) = match authorize(&state, &id, &headers) {
+        Ok(session) => session,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    // A poll from an idle miner wakes it with the current job
+    let woken = state.session_manager.wake(&id);
+
+    let mut template_rx = state.template_rx.clone();
+    let current = template_rx.borrow_and_update().as_ref().map(|t| t.template_id);
+    let seen = last_job
+        .and_then(|job_id| state.job_manager.get_job(&job_id))
+        .map(|job| job.template_id);
+
+    if current.is_none() || (current == seen && !woken) {
+        let wait = Duration::from_millis(query.wait_ms.unwrap_or(DEFAULT_POLL_WAIT_MS)).min(MAX_POLL_WAIT);
+        let changed = tokio::select! {
+            result = tokio::time::timeout(wait, template_rx.changed()) => matches!(result, Ok(Ok(()))),
+            _ = close_signal.closed() => false,
+            _ = state.shutdown.cancelled() => false,
+        };
+        if !changed {
+            return StatusCode::NO_CONTENT.into_response();
+        }
+    }
+
+    let template = match template_rx.borrow().clone() {
+        Some(t) => t,
+        None => return StatusCode::NO_CONTENT.into_response(),
+    };
+    let job = match server::session_job(&state, &template, &id) {
+        Ok(job) => job,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "no job available").into_response(),
+    };
+    state.metrics.inc_jobs();
+    state.session_manager.update_session(&id, |s| {
+        s.update_job(job.job_id.clone(), job.reserved_value.clone());
+    });
+    Json(server::job_message(job, state.session_manager.wants_compact_target(&id))).into_response()
+}
+
+async fn submit(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<SubmitRequest>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &id, &headers) {
+        return rejection.into_response();
+    }
+
+    let msg = ClientMessage::Submit {
+        id: request.id,
+        job_id: request.job_id,
+        nonce: request.nonce,
+        result: request.result,
+    };
+    match server::handle_message(&state, &id, msg).await {
+        Some(response) => Json(response).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+/// Check the session's bearer token and message rate, and keep it alive.
+/// Returns the session's close signal and the id of its latest job.
+fn authorize(
+    state: &AppState,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<(CloseSignal, Option<String>), (StatusCode, &'static str)> {
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let (authorized, close_signal, last_job) = state
+        .session_manager
+        .with_session(id, |session| {
+            let authorized = match (&session.poll_token, provided) {
+                (Some(expected), Some(token)) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+                _ => false,
+            };
+            (authorized, session.close_signal.clone(), session.current_job().map(|job| job.job_id.clone()))
+        })
+        .ok_or((StatusCode::NOT_FOUND, "Unknown session"))?;
+    if !authorized {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized"));
+    }
+
+    // Kicked by an admin or banned; there is no socket to close, so end it here
+    if close_signal.is_closed() {
+        state.session_manager.remove_session(id);
+        return Err((StatusCode::GONE, "Session closed"));
+    }
+
+    if !state.session_manager.check_message_limit(id) {
+        state.metrics.inc_rate_limits();
+        return Err((StatusCode::TOO_MANY_REQUESTS, "Message rate exceeded"));
+    }
+    state.metrics.inc_messages();
+    state.session_manager.update_session(id, |s| s.touch());
+    Ok((close_signal, last_job))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ServerMessage, SubmitStatus};
+    use crate::server::tests::{test_state, test_template};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn request(method: &str, uri: &str, token: Option<&str>, body: Option<String>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if body.is_some() {
+            builder = builder.header(header::CONTENT_TYPE, "application/json");
+        }
+        let mut request = builder.body(body.map(Body::from).unwrap_or_default()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([198, 51, 100, 7], 5000))));
+        request
+    }
+
+    async fn json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    const HELLO: &str = r#"{"v":1,"client_version":"t","threads":2}"#;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hello_job_submit_cycle() {
+        let (state, template_tx) = test_state();
+        let app = server::router(state.clone());
+
+        let response = app.clone().oneshot(request("POST", "/v1/session", None, Some(HELLO.into()))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let hello: HelloResponse = json(response).await;
+        let job_uri = format!("/v1/session/{}/job?wait_ms=5000", hello.session_id);
+
+        // Nothing to hand out until a template arrives
+        let response = app.clone().oneshot(request("GET", &job_uri, Some(&hello.token), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let poll = tokio::spawn(app.clone().oneshot(request("GET", &job_uri, Some(&hello.token), None)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        template_tx.send(Some(test_template())).unwrap();
+        let response = poll.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let job_id = match json(response).await {
+            ServerMessage::Job { job_id, height, .. } => {
+                assert_eq!(height, 100);
+                job_id
+            }
+            other => panic!("expected a job, got {:?}", other),
+        };
+
+        // The same template is not handed out twice
+        let response = app.clone().oneshot(request("GET", &job_uri, Some(&hello.token), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let submit = format!(r#"{{"id":"1","job_id":"{}","nonce":"zz"}}"#, job_id);
+        let submit_uri = format!("/v1/session/{}/submit", hello.session_id);
+        let response = app.oneshot(request("POST", &submit_uri, Some(&hello.token), Some(submit))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        match json(response).await {
+            ServerMessage::SubmitResult { id, status: SubmitStatus::Rejected, .. } => assert_eq!(id, "1"),
+            other => panic!("expected a submit result, got {:?}", other),
+        }
+
+        let session = state.session_manager.get_session(&hello.session_id).unwrap();
+        assert_eq!(session.rejected_submits, 1);
+        assert_eq!(session.client_version.as_deref(), Some("t"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_the_session_token() {
+        let (state, _template_tx) = test_state();
+        let app = server::router(state.clone());
+
+        let response = app.clone().oneshot(request("POST", "/v1/session", None, Some(HELLO.into()))).await.unwrap();
+        let hello: HelloResponse = json(response).await;
+        let job_uri = format!("/v1/session/{}/job?wait_ms=0", hello.session_id);
+
+        let response = app.clone().oneshot(request("GET", &job_uri, None, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(request("GET", &job_uri, Some("wrong"), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(request("GET", "/v1/session/nope/job", Some(&hello.token), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // A kicked session is closed on its next request
+        assert!(state.session_manager.close_session(&hello.session_id, "Disconnected by operator"));
+        let response = app.oneshot(request("GET", &job_uri, Some(&hello.token), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert!(state.session_manager.get_session(&hello.session_id).is_none());
+    }
+}
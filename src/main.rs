@@ -1,24 +1,21 @@
 use anyhow::Result;
-use tracing::info;
+use tracing::{debug, info, warn};
 use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 
-mod config;
-mod error;
-mod jobs;
-mod metrics;
-mod protocol;
-mod ratelimit;
-mod rpc;
-mod server;
-mod session;
-mod template;
-mod validator;
-
-use jobs::JobManager;
-use metrics::Metrics;
-use session::SessionManager;
-use template::TemplateManager;
-use validator::SubmissionValidator;
+use monero_web_coordinator::{config, metrics, persistence, privacy, server, session, validator, version};
+
+use monero_web_coordinator::ban::BanManager;
+use monero_web_coordinator::config::VerifierKind;
+use monero_web_coordinator::events::EventLog;
+use monero_web_coordinator::jobs::JobManager;
+use monero_web_coordinator::metrics::Metrics;
+use monero_web_coordinator::pow::PowVerifier;
+use monero_web_coordinator::rpc_verifier::RpcVerifier;
+use monero_web_coordinator::session::{SessionManager, SessionManagerConfig};
+use monero_web_coordinator::template::TemplateManager;
+use monero_web_coordinator::validator::{HashError, ShareSampler, SubmissionValidator};
+use monero_web_coordinator::vm_pool::{self, LocalVerifier, VmPool};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -30,61 +27,208 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    info!("Starting Coordinator");
+    info!("Starting Coordinator {}", version::summary());
 
     let config = config::load_config()?;
     info!("Configuration loaded");
+    privacy::install(&config.privacy)?;
 
     let metrics = Arc::new(Metrics::new());
+    let events = EventLog::start(&config.logging, metrics.clone())?;
+
+    let bans = Arc::new(BanManager::new(config.bans.clone()).with_ipv6_prefix(config.limits.ipv6_prefix_len));
+    let job_manager = Arc::new(
+        JobManager::new(config.jobs.stale_job_grace_ms)
+            .with_block_ttl_ms(config.jobs.block_ttl_ms)
+            .with_share_ttl_ms(config.jobs.share_ttl_ms.unwrap_or(config.jobs.block_ttl_ms))
+            .with_max_jobs(config.jobs.max_jobs)
+            .with_max_jobs_per_session(config.jobs.max_jobs_per_session)
+            .with_max_nonces_per_job(config.jobs.max_nonces_per_job)
+            .with_max_submissions_per_job(config.jobs.max_submissions_per_job)
+            .with_instance_id(config.jobs.instance_id)
+            .with_metrics(metrics.clone()),
+    );
+    let session_manager = Arc::new(SessionManager::new(SessionManagerConfig::from_config(&config)?)
+    .with_bans(bans.clone())
+    .with_ipv6_prefix(config.limits.ipv6_prefix_len)
+    .with_connection_rate(config.limits.connections_per_minute, 60)
+    .with_site_limit(config.auth.max_connections_per_site)
+    .with_max_lifetime(std::time::Duration::from_secs(config.limits.max_session_lifetime_secs))
+    .with_vardiff(&config.vardiff)
+    .with_metrics(metrics.clone())
+    .with_jobs(job_manager.clone())
+    .with_events(events.clone()));
+    if let Some(path) = &config.persistence.path {
+        persistence::restore(&session_manager, path);
+    }
 
-    let session_manager = Arc::new(SessionManager::new(
-        config.server.max_connections_per_ip,
-        config.server.max_connections,
-        config.limits.messages_per_second,
-        config.limits.submits_per_minute,
-    ));
-    let job_manager = Arc::new(JobManager::new(config.jobs.stale_job_grace_ms));
-    let validator = Arc::new(SubmissionValidator::new());
-    
     let mut template_manager = TemplateManager::new(&config)?;
     let template_rx = template_manager.subscribe();
     let rpc_client = template_manager.client();
+    let daemon_status = template_manager.daemon_status();
+
+    let rpc_verifier = || {
+        let rpc = RpcVerifier::new(rpc_client.clone(), config.randomx.rpc_max_concurrent, config.randomx.rpc_per_second);
+        Arc::new(rpc.with_metrics(metrics.clone()))
+    };
+    let verifier: Arc<dyn PowVerifier> = match config.randomx.verifier {
+        VerifierKind::Local => {
+            let vms = VmPool::new(config.randomx.vms())
+                .with_mode(config.randomx.mode)
+                .with_previous_seed_grace(std::time::Duration::from_millis(config.jobs.stale_job_grace_ms))
+                .with_flags(config.randomx.flags)
+                .with_metrics(metrics.clone());
+            info!(
+                "RandomX verification: {} VMs in {:?} mode, each with its own cache, about {} MB once all are made; flags: {}",
+                vms.size(),
+                vms.mode(),
+                vms.memory_mb(),
+                vm_pool::flag_names(vms.flags()).join(" ")
+            );
+            Arc::new(LocalVerifier::new(vms))
+        }
+        VerifierKind::Rpc => {
+            info!(
+                "RandomX verification over monerod's calc_pow, at most {} calls at once and {} a second",
+                config.randomx.rpc_max_concurrent, config.randomx.rpc_per_second
+            );
+            rpc_verifier()
+        }
+    };
+    let mut validator = SubmissionValidator::new()
+        .with_timestamp_skew(std::time::Duration::from_secs(config.jobs.max_timestamp_skew_secs))
+        .with_share_sampling(ShareSampler::new(config.randomx.verify_share_percent))
+        .with_queue(config.randomx.verify_queue)
+        .with_metrics(metrics.clone());
+    if config.randomx.verifier == VerifierKind::Local && config.randomx.rpc_fallback {
+        validator = validator.with_fallback(rpc_verifier());
+    }
+    let validator = Arc::new(validator);
+    // A RandomX that hashes wrong would reject every share, or accept garbage
+    if config.randomx.self_test {
+        match validator.self_test(verifier.as_ref()).await {
+            Ok(Some(took)) => info!("RandomX self-test passed in {:?}", took),
+            Ok(None) => {}
+            // Short of memory: start a rung down, as a hash would step, and
+            // let recover_verifier retry RandomX on its backoff
+            Err(HashError::Init(e)) => {
+                warn!("RandomX self-test could not run: {}", e);
+                validator.step_down_from_local(verifier.as_ref(), &e);
+            }
+            Err(e @ HashError::Failed(_)) => {
+                anyhow::bail!("RandomX self-test failed, refusing to start (see randomx.self_test): {}", e)
+            }
+            Err(e) => warn!("RandomX self-test could not run, hashing on regardless: {}", e),
+        }
+    }
+
+    // Cancelled on ctrl_c; every background task and session watches it
+    let shutdown = CancellationToken::new();
+    let shutdown_trigger = shutdown.clone();
+    tokio::spawn(async move {
+        server::shutdown_signal().await;
+        shutdown_trigger.cancel();
+    });
 
     // Start metrics server
     let metrics_config = config.metrics.clone();
     let metrics_clone = metrics.clone();
+    let metrics_shutdown = shutdown.clone();
     tokio::spawn(async move {
-        metrics::run_metrics_server(metrics_config, metrics_clone).await;
+        metrics::run_metrics_server(metrics_config, metrics_clone, metrics_shutdown).await;
+    });
+
+    // Connection log, the first observer of session lifecycle events
+    let session_log = session::log_session_events(session_manager.subscribe(), metrics.clone());
+    let session_log_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = session_log => {}
+            _ = session_log_shutdown.cancelled() => {}
+        }
     });
 
     // Template manager
     let metrics_tpl = metrics.clone();
+    let template_shutdown = shutdown.clone();
     tokio::spawn(async move {
-        template_manager.run(metrics_tpl).await;
+        template_manager.run(metrics_tpl, template_shutdown).await;
     });
 
+    // Make each upcoming RandomX seed's cache before the chain switches to it
+    tokio::spawn(validator::prewarm_seeds(validator.clone(), verifier.clone(), template_rx.clone(), shutdown.clone()));
+    // Climb back up once RandomX can be made again, after running short of memory
+    tokio::spawn(validator::recover_verifier(validator.clone(), verifier.clone(), shutdown.clone()));
+
     // Periodic job cleanup
     let job_mgr_clone = job_manager.clone();
-    let job_ttl = config.jobs.job_ttl_ms;
+    let job_shutdown = shutdown.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
         loop {
-            interval.tick().await;
-            job_mgr_clone.cleanup_old_jobs(job_ttl);
+            tokio::select! {
+                _ = interval.tick() => {
+                    let removed = job_mgr_clone.cleanup_old_jobs();
+                    debug!("Job cleanup dropped {} jobs, {} live", removed, job_mgr_clone.len());
+                }
+                _ = job_shutdown.cancelled() => break,
+            }
         }
     });
 
-    // Idle session cleanup (every 60 seconds, remove sessions idle > 5 minutes)
+    // Idle session cleanup (every 60 seconds)
     let session_mgr_cleanup = session_manager.clone();
+    let idle_timeout = std::time::Duration::from_secs(config.limits.session_idle_timeout_secs);
+    let inactive_timeout = std::time::Duration::from_secs(config.limits.inactive_timeout_secs);
+    let idle_metrics = metrics.clone();
+    let site_retention = std::time::Duration::from_secs(config.auth.site_stats_retention_secs);
+    let session_shutdown = shutdown.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
         loop {
-            interval.tick().await;
-            session_mgr_cleanup.cleanup_idle(std::time::Duration::from_secs(300));
+            tokio::select! {
+                _ = interval.tick() => {
+                    let evicted = session_mgr_cleanup.cleanup_idle(idle_timeout);
+                    idle_metrics.add_idle_sessions_evicted(evicted);
+                    session_mgr_cleanup.cleanup_connection_limits();
+                    if !inactive_timeout.is_zero() {
+                        session_mgr_cleanup.mark_inactive(inactive_timeout);
+                    }
+                    session_mgr_cleanup.refresh_site_stats(site_retention);
+                    idle_metrics.set_estimated_hashrate(session_mgr_cleanup.estimated_hashrate());
+                    idle_metrics.set_sessions_by_randomx_mode(&session_mgr_cleanup.count_by_randomx_mode());
+                }
+                _ = session_shutdown.cancelled() => break,
+            }
         }
     });
 
-    server::run(config, template_rx, rpc_client, session_manager, job_manager, validator, metrics).await?;
+    // Forget IPs whose bans and offenses have lapsed
+    let bans_cleanup = bans.clone();
+    let bans_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => bans_cleanup.cleanup(),
+                _ = bans_shutdown.cancelled() => break,
+            }
+        }
+    });
+
+    // Keep per-site accounting across restarts
+    let persistence_task = config.persistence.path.clone().map(|path| {
+        let interval = std::time::Duration::from_secs(config.persistence.snapshot_interval_secs.max(1));
+        tokio::spawn(persistence::run(session_manager.clone(), path, interval, shutdown.clone()))
+    });
+
+    server::run(config, template_rx, rpc_client, session_manager, job_manager, validator, verifier, metrics, daemon_status, bans, events, shutdown).await?;
+
+    // The final snapshot is written once shutdown begins
+    if let Some(task) = persistence_task {
+        let _ = task.await;
+    }
 
+    info!("Coordinator stopped");
     Ok(())
 }
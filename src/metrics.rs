@@ -1,10 +1,100 @@
 use axum::{Router, routing::get};
+use dashmap::DashMap;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 use crate::config::MetricsConfig;
+use crate::protocol::RandomxMode;
+use crate::validator::{HashTimings, VerifierRung};
+use crate::version;
+
+/// Upper bounds, in seconds, of the histogram buckets
+const HISTOGRAM_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+/// Upper bounds, in seconds, for job ages; jobs live 30s by default
+const JOB_AGE_BUCKETS: [f64; 8] = [0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// Upper bounds, in seconds, for making RandomX caches and VMs; a light
+/// cache takes around a second
+const INIT_BUCKETS: [f64; 8] = [0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 2.5, 10.0];
+
+/// Metric label for workers without a configured name of their own
+const OTHER_WORKER: &str = "other";
+
+/// Fixed-bucket latency histogram in Prometheus layout
+pub struct Histogram {
+    bounds: &'static [f64; 8],
+    /// Per-bucket counts; made cumulative when exported
+    buckets: [AtomicU64; 8],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::with_bounds(&HISTOGRAM_BUCKETS)
+    }
+}
+
+/// Histogram of job ages, in buckets up to a minute
+pub struct JobAgeHistogram(pub Histogram);
+
+impl Default for JobAgeHistogram {
+    fn default() -> Self {
+        Self(Histogram::with_bounds(&JOB_AGE_BUCKETS))
+    }
+}
+
+/// Histogram of RandomX cache and VM making, in buckets up to 10s
+pub struct InitHistogram(pub Histogram);
+
+impl Default for InitHistogram {
+    fn default() -> Self {
+        Self(Histogram::with_bounds(&INIT_BUCKETS))
+    }
+}
+
+impl Histogram {
+    pub fn with_bounds(bounds: &'static [f64; 8]) -> Self {
+        Self { bounds, buckets: Default::default(), count: AtomicU64::new(0), sum_micros: AtomicU64::new(0) }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = self.bounds.iter().position(|&b| secs <= b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn format_prometheus(&self, name: &str, help: &str) -> String {
+        let mut out = format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name);
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            out.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, bound, cumulative));
+        }
+        let count = self.count();
+        out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, count));
+        out.push_str(&format!(
+            "{}_sum {}\n",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        ));
+        out.push_str(&format!("{}_count {}\n", name, count));
+        out
+    }
+}
 
 #[derive(Default)]
 pub struct Metrics {
@@ -15,9 +105,114 @@ pub struct Metrics {
     pub submissions_accepted: AtomicU64,
     pub submissions_rejected: AtomicU64,
     pub submissions_stale: AtomicU64,
+    /// Submissions refused for repeating a nonce already submitted for the job
+    pub submissions_duplicate: AtomicU64,
     pub jobs_created: AtomicU64,
+    /// Jobs held, kept current by the job manager
+    pub jobs_live: AtomicU64,
+    /// Jobs dropped before their time because `jobs.max_jobs` was reached
+    pub jobs_evicted: AtomicU64,
+    /// Jobs that could not be made from the current template
+    pub jobs_failed: AtomicU64,
+    /// Submits refused because their job reached `jobs.max_submissions_per_job`
+    pub job_submission_caps_hit: AtomicU64,
+    /// Submits whose claimed result hash was not the hash of their nonce
+    pub bad_pow: AtomicU64,
+    /// Shares credited on their claimed result hash without hashing them
+    pub shares_unverified: AtomicU64,
     pub templates_received: AtomicU64,
+    /// Templates dropped as unusable, the previous one kept
+    pub templates_rejected: AtomicU64,
     pub rate_limits_hit: AtomicU64,
+    pub ws_bytes_sent: AtomicU64,
+    pub ws_bytes_received: AtomicU64,
+    /// Sum of job difficulty over accepted submissions, for hashrate estimates
+    pub accepted_difficulty: AtomicU64,
+    /// Sum of the difficulty accepted hashes achieved, saturating
+    pub achieved_difficulty: AtomicU64,
+    /// Non-critical outbound messages dropped because a client's queue was full
+    pub outbound_dropped: AtomicU64,
+    pub slow_client_evictions: AtomicU64,
+    /// Connections closed for not sending hello in time
+    pub handshake_timeouts: AtomicU64,
+    pub bans_issued: AtomicU64,
+    /// Connection attempts refused because the IP is banned
+    pub banned_connections: AtomicU64,
+    /// Connection attempts refused by the per-IP connection rate limit
+    pub connections_rate_limited: AtomicU64,
+    /// Time to push a new template's jobs to every connection
+    pub job_broadcast_seconds: Histogram,
+    /// Age of jobs when their first submission arrived
+    pub job_first_submit_age_seconds: JobAgeHistogram,
+    /// Jobs dropped after expiring without a single submission
+    pub jobs_expired_unused: AtomicU64,
+    pub idle_sessions_evicted: AtomicU64,
+    /// Session events not logged because the writer fell behind
+    pub events_dropped: AtomicU64,
+    /// Session lifecycle events a slow internal subscriber missed
+    pub session_events_lagged: AtomicU64,
+    /// Sessions not sent jobs because they stopped submitting
+    pub sessions_idle: AtomicU64,
+    /// Sum of the sessions' estimated hashrates, as f64 bits
+    pub estimated_hashrate: AtomicU64,
+    /// Whether submissions are verified from a RandomX dataset (1) or cache (0)
+    pub randomx_fast: AtomicU64,
+    /// Time the last RandomX dataset took to build, as f64 bits
+    pub randomx_dataset_init_seconds: AtomicU64,
+    /// RandomX datasets that could not be built, leaving verification in light mode
+    pub randomx_dataset_failures: AtomicU64,
+    /// RandomX seed caches made ahead of the seed change
+    pub randomx_prewarms_started: AtomicU64,
+    pub randomx_prewarms_completed: AtomicU64,
+    /// RandomX seed caches made while a submission waited for one
+    pub randomx_inline_cache_inits: AtomicU64,
+    /// RandomX VMs made for a hash, and those moved onto a new seed's cache
+    pub randomx_vm_inits_new: AtomicU64,
+    pub randomx_vm_inits_rekey: AtomicU64,
+    /// Time hashes waited for a place in the verification queue and a VM
+    pub verify_queue_wait_seconds: Histogram,
+    /// Time hashes waited for a cache or VM to be made, when one was
+    pub randomx_init_seconds: InitHistogram,
+    /// Time RandomX VMs took to hash, and monerod's calc_pow to answer
+    pub verify_hash_seconds: Histogram,
+    pub rpc_pow_seconds: Histogram,
+    /// Hashes done with the current RandomX seed, and with the one before it
+    pub randomx_verifications_current: AtomicU64,
+    pub randomx_verifications_previous: AtomicU64,
+    /// Hashes answered from the validator's result cache, and those not
+    pub randomx_result_cache_hits: AtomicU64,
+    pub randomx_result_cache_misses: AtomicU64,
+    /// Hashes monerod's calc_pow returned, and calls that failed
+    pub rpc_pow_ok: AtomicU64,
+    pub rpc_pow_errors: AtomicU64,
+    /// Hashes refused rather than sent to calc_pow past its rate
+    pub rpc_pow_limited: AtomicU64,
+    /// Hashes sent to calc_pow because no local RandomX VM could be made
+    pub randomx_rpc_fallbacks: AtomicU64,
+    /// Rung of the verification ladder hashes are on; see [`VerifierRung`]
+    pub verifier_rung: AtomicU64,
+    /// Steps down the ladder, by the rung stepped to
+    pub verifier_steps_light: AtomicU64,
+    pub verifier_steps_rpc: AtomicU64,
+    pub verifier_steps_down: AtomicU64,
+    /// Climbs back up after RandomX could be made again
+    pub verifier_recoveries: AtomicU64,
+    /// Hashes running or waiting for a VM
+    pub verify_queue_depth: AtomicU64,
+    /// Submits refused because the verification queue was full
+    pub verify_queue_full: AtomicU64,
+    /// Sessions past hello per reported RandomX mode
+    pub sessions_by_randomx_mode: DashMap<String, usize>,
+    /// Ready sessions per site token label
+    pub site_connections: DashMap<String, usize>,
+    /// Total accepted difficulty per site token label
+    pub site_accepted_shares: DashMap<String, u64>,
+    /// Accepted difficulty per second per site token label, recent average
+    pub site_hashrate: DashMap<String, f64>,
+    /// Accepted submits per worker label (see `worker_label`)
+    pub worker_accepted: DashMap<String, u64>,
+    /// Submissions that failed validation, by how (see `ValidationError::label`)
+    pub submissions_rejected_by_reason: DashMap<&'static str, u64>,
 }
 
 impl Metrics {
@@ -46,6 +241,15 @@ impl Metrics {
         self.submissions_accepted.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn add_accepted_difficulty(&self, difficulty: u64) {
+        self.accepted_difficulty.fetch_add(difficulty, Ordering::Relaxed);
+    }
+
+    pub fn add_achieved_difficulty(&self, difficulty: u128) {
+        let difficulty = u64::try_from(difficulty).unwrap_or(u64::MAX);
+        let _ = self.achieved_difficulty.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| Some(sum.saturating_add(difficulty)));
+    }
+
     pub fn inc_rejected(&self) {
         self.submissions_rejected.fetch_add(1, Ordering::Relaxed);
     }
@@ -54,20 +258,246 @@ impl Metrics {
         self.submissions_stale.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn inc_duplicate(&self) {
+        self.submissions_duplicate.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn inc_jobs(&self) {
         self.jobs_created.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn set_jobs_live(&self, count: usize) {
+        self.jobs_live.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_jobs_evicted(&self) {
+        self.jobs_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_jobs_failed(&self) {
+        self.jobs_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_job_submission_caps_hit(&self) {
+        self.job_submission_caps_hit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_bad_pow(&self) {
+        self.bad_pow.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_shares_unverified(&self) {
+        self.shares_unverified.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn inc_templates(&self) {
         self.templates_received.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn inc_templates_rejected(&self) {
+        self.templates_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn inc_rate_limits(&self) {
         self.rate_limits_hit.fetch_add(1, Ordering::Relaxed);
     }
 
-    fn format_prometheus(&self) -> String {
-        format!(
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.ws_bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.ws_bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_outbound_dropped(&self) {
+        self.outbound_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_slow_client_evictions(&self) {
+        self.slow_client_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_handshake_timeouts(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_bans_issued(&self) {
+        self.bans_issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_banned_connections(&self) {
+        self.banned_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_connections_rate_limited(&self) {
+        self.connections_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_job_broadcast(&self, elapsed: Duration) {
+        self.job_broadcast_seconds.observe(elapsed);
+    }
+
+    pub fn observe_job_first_submit(&self, age: Duration) {
+        self.job_first_submit_age_seconds.0.observe(age);
+    }
+
+    pub fn add_jobs_expired_unused(&self, count: usize) {
+        self.jobs_expired_unused.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_idle_sessions_evicted(&self, count: usize) {
+        self.idle_sessions_evicted.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_events_dropped(&self) {
+        self.events_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_session_events_lagged(&self, count: u64) {
+        self.session_events_lagged.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn set_sessions_idle(&self, count: usize) {
+        self.sessions_idle.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn dec_sessions_idle(&self) {
+        let _ = self.sessions_idle.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub fn set_estimated_hashrate(&self, hashrate: f64) {
+        self.estimated_hashrate.store(hashrate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn estimated_hashrate(&self) -> f64 {
+        f64::from_bits(self.estimated_hashrate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_randomx_fast(&self, fast: bool) {
+        self.randomx_fast.store(fast as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_randomx_dataset_init(&self, took: Duration) {
+        self.randomx_dataset_init_seconds.store(took.as_secs_f64().to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_dataset_failures(&self) {
+        self.randomx_dataset_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_prewarms_started(&self) {
+        self.randomx_prewarms_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_prewarms_completed(&self) {
+        self.randomx_prewarms_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_inline_cache_inits(&self) {
+        self.randomx_inline_cache_inits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_verifications(&self, previous_seed: bool) {
+        let counter = if previous_seed { &self.randomx_verifications_previous } else { &self.randomx_verifications_current };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_result_cache(&self, hit: bool) {
+        let counter = if hit { &self.randomx_result_cache_hits } else { &self.randomx_result_cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_vm_inits(&self, rekey: bool) {
+        let counter = if rekey { &self.randomx_vm_inits_rekey } else { &self.randomx_vm_inits_new };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record where a verification's time went
+    pub fn observe_verification(&self, timings: &HashTimings) {
+        self.verify_queue_wait_seconds.observe(timings.queue);
+        if !timings.init.is_zero() {
+            self.randomx_init_seconds.0.observe(timings.init);
+        }
+        let hash = if timings.rpc { &self.rpc_pow_seconds } else { &self.verify_hash_seconds };
+        hash.observe(timings.hash);
+    }
+
+    pub fn inc_rpc_pow_calls(&self, ok: bool) {
+        let counter = if ok { &self.rpc_pow_ok } else { &self.rpc_pow_errors };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_rpc_pow_limited(&self) {
+        self.rpc_pow_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_randomx_rpc_fallbacks(&self) {
+        self.randomx_rpc_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a step down the verification ladder to `rung`
+    pub fn inc_verifier_steps(&self, rung: VerifierRung) {
+        let counter = match rung {
+            VerifierRung::Configured => return,
+            VerifierRung::Light => &self.verifier_steps_light,
+            VerifierRung::Rpc => &self.verifier_steps_rpc,
+            VerifierRung::Down => &self.verifier_steps_down,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.verifier_rung.store(rung as u64, Ordering::Relaxed);
+    }
+
+    /// Count a climb back up the verification ladder, to `rung`
+    pub fn inc_verifier_recoveries(&self, rung: VerifierRung) {
+        self.verifier_recoveries.fetch_add(1, Ordering::Relaxed);
+        self.verifier_rung.store(rung as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_verify_queue_depth(&self, depth: usize) {
+        self.verify_queue_depth.store(depth as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_verify_queue_full(&self) {
+        self.verify_queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Replace the per-mode session counts; modes missing from `counts` read 0
+    pub fn set_sessions_by_randomx_mode(&self, counts: &HashMap<RandomxMode, usize>) {
+        for mode in RandomxMode::ALL {
+            self.sessions_by_randomx_mode.insert(mode.as_str().to_string(), counts.get(&mode).copied().unwrap_or(0));
+        }
+    }
+
+    pub fn set_site_connections(&self, label: &str, count: usize) {
+        self.site_connections.insert(label.to_string(), count);
+    }
+
+    pub fn set_site_accepted_shares(&self, label: &str, shares: u64) {
+        self.site_accepted_shares.insert(label.to_string(), shares);
+    }
+
+    pub fn set_site_hashrate(&self, label: &str, hashrate: f64) {
+        self.site_hashrate.insert(label.to_string(), hashrate);
+    }
+
+    /// Drop every per-site series for a label that is no longer tracked
+    pub fn forget_site(&self, label: &str) {
+        self.site_connections.remove(label);
+        self.site_accepted_shares.remove(label);
+        self.site_hashrate.remove(label);
+    }
+
+    pub fn inc_rejected_by_reason(&self, reason: &'static str) {
+        *self.submissions_rejected_by_reason.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn inc_worker_accepted(&self, label: &str) {
+        *self.worker_accepted.entry(label.to_string()).or_insert(0) += 1;
+    }
+
+    pub(crate) fn format_prometheus(&self) -> String {
+        let mut out = format!(
             "# HELP coordinator_connections_total Total connections\n\
              # TYPE coordinator_connections_total counter\n\
              coordinator_connections_total {}\n\
@@ -89,15 +519,66 @@ impl Metrics {
              # HELP coordinator_submissions_stale Stale submissions\n\
              # TYPE coordinator_submissions_stale counter\n\
              coordinator_submissions_stale {}\n\
+             # HELP coordinator_submissions_duplicate Submissions repeating a nonce already submitted for the job\n\
+             # TYPE coordinator_submissions_duplicate counter\n\
+             coordinator_submissions_duplicate {}\n\
              # HELP coordinator_jobs_created Jobs created\n\
              # TYPE coordinator_jobs_created counter\n\
              coordinator_jobs_created {}\n\
+             # HELP coordinator_jobs_evicted Jobs dropped early because the job capacity was reached\n\
+             # TYPE coordinator_jobs_evicted counter\n\
+             coordinator_jobs_evicted {}\n\
+             # HELP coordinator_jobs_failed Jobs that could not be made from the current template\n\
+             # TYPE coordinator_jobs_failed counter\n\
+             coordinator_jobs_failed {}\n\
+             # HELP coordinator_job_submission_caps_hit Submits refused because their job had max_submissions_per_job already\n\
+             # TYPE coordinator_job_submission_caps_hit counter\n\
+             coordinator_job_submission_caps_hit {}\n\
+             # HELP coordinator_achieved_difficulty Sum of the difficulty accepted hashes achieved\n\
+             # TYPE coordinator_achieved_difficulty counter\n\
+             coordinator_achieved_difficulty {}\n\
              # HELP coordinator_templates_received Templates received\n\
              # TYPE coordinator_templates_received counter\n\
              coordinator_templates_received {}\n\
+             # HELP coordinator_templates_rejected Templates dropped as unusable, the previous one kept\n\
+             # TYPE coordinator_templates_rejected counter\n\
+             coordinator_templates_rejected {}\n\
              # HELP coordinator_rate_limits_hit Rate limits triggered\n\
              # TYPE coordinator_rate_limits_hit counter\n\
-             coordinator_rate_limits_hit {}\n",
+             coordinator_rate_limits_hit {}\n\
+             # HELP coordinator_ws_bytes_sent WebSocket payload bytes sent\n\
+             # TYPE coordinator_ws_bytes_sent counter\n\
+             coordinator_ws_bytes_sent {}\n\
+             # HELP coordinator_ws_bytes_received WebSocket payload bytes received\n\
+             # TYPE coordinator_ws_bytes_received counter\n\
+             coordinator_ws_bytes_received {}\n\
+             # HELP coordinator_outbound_dropped Outbound messages dropped from full client queues\n\
+             # TYPE coordinator_outbound_dropped counter\n\
+             coordinator_outbound_dropped {}\n\
+             # HELP coordinator_slow_client_evictions Clients disconnected for not draining their queue\n\
+             # TYPE coordinator_slow_client_evictions counter\n\
+             coordinator_slow_client_evictions {}\n\
+             # HELP coordinator_handshake_timeouts Connections closed for not sending hello in time\n\
+             # TYPE coordinator_handshake_timeouts counter\n\
+             coordinator_handshake_timeouts {}\n\
+             # HELP coordinator_bans_issued IP bans issued for invalid submissions\n\
+             # TYPE coordinator_bans_issued counter\n\
+             coordinator_bans_issued {}\n\
+             # HELP coordinator_banned_connections Connection attempts refused from banned IPs\n\
+             # TYPE coordinator_banned_connections counter\n\
+             coordinator_banned_connections {}\n\
+             # HELP coordinator_connections_rate_limited Connection attempts refused by the per-IP rate limit\n\
+             # TYPE coordinator_connections_rate_limited counter\n\
+             coordinator_connections_rate_limited {}\n\
+             # HELP coordinator_idle_sessions_evicted Sessions removed by the idle session sweep\n\
+             # TYPE coordinator_idle_sessions_evicted counter\n\
+             coordinator_idle_sessions_evicted {}\n\
+             # HELP coordinator_events_dropped Session events dropped because the event log writer fell behind\n\
+             # TYPE coordinator_events_dropped counter\n\
+             coordinator_events_dropped {}\n\
+             # HELP coordinator_session_events_lagged Session lifecycle events missed by slow internal subscribers\n\
+             # TYPE coordinator_session_events_lagged counter\n\
+             coordinator_session_events_lagged {}\n",
             self.connections_total.load(Ordering::Relaxed),
             self.connections_active.load(Ordering::Relaxed),
             self.messages_received.load(Ordering::Relaxed),
@@ -105,14 +586,282 @@ impl Metrics {
             self.submissions_accepted.load(Ordering::Relaxed),
             self.submissions_rejected.load(Ordering::Relaxed),
             self.submissions_stale.load(Ordering::Relaxed),
+            self.submissions_duplicate.load(Ordering::Relaxed),
             self.jobs_created.load(Ordering::Relaxed),
+            self.jobs_evicted.load(Ordering::Relaxed),
+            self.jobs_failed.load(Ordering::Relaxed),
+            self.job_submission_caps_hit.load(Ordering::Relaxed),
+            self.achieved_difficulty.load(Ordering::Relaxed),
             self.templates_received.load(Ordering::Relaxed),
+            self.templates_rejected.load(Ordering::Relaxed),
             self.rate_limits_hit.load(Ordering::Relaxed),
-        )
+            self.ws_bytes_sent.load(Ordering::Relaxed),
+            self.ws_bytes_received.load(Ordering::Relaxed),
+            self.outbound_dropped.load(Ordering::Relaxed),
+            self.slow_client_evictions.load(Ordering::Relaxed),
+            self.handshake_timeouts.load(Ordering::Relaxed),
+            self.bans_issued.load(Ordering::Relaxed),
+            self.banned_connections.load(Ordering::Relaxed),
+            self.connections_rate_limited.load(Ordering::Relaxed),
+            self.idle_sessions_evicted.load(Ordering::Relaxed),
+            self.events_dropped.load(Ordering::Relaxed),
+            self.session_events_lagged.load(Ordering::Relaxed),
+        );
+        out.push_str(&format!(
+            "# HELP coordinator_bad_pow Submits whose claimed result hash was not the hash of their nonce\n\
+             # TYPE coordinator_bad_pow counter\n\
+             coordinator_bad_pow {}\n\
+             # HELP coordinator_shares_unverified Shares credited on their claimed result hash without hashing them\n\
+             # TYPE coordinator_shares_unverified counter\n\
+             coordinator_shares_unverified {}\n",
+            self.bad_pow.load(Ordering::Relaxed),
+            self.shares_unverified.load(Ordering::Relaxed),
+        ));
+        out.push_str(&format!(
+            "# HELP coordinator_jobs_live Jobs held\n\
+             # TYPE coordinator_jobs_live gauge\n\
+             coordinator_jobs_live {}\n",
+            self.jobs_live.load(Ordering::Relaxed),
+        ));
+        out.push_str(&format!(
+            "# HELP coordinator_sessions_idle Sessions not sent jobs because they stopped submitting\n\
+             # TYPE coordinator_sessions_idle gauge\n\
+             coordinator_sessions_idle {}\n",
+            self.sessions_idle.load(Ordering::Relaxed),
+        ));
+        out.push_str(&format!(
+            "# HELP coordinator_estimated_hashrate Hashes per second estimated from the shares every session had accepted\n\
+             # TYPE coordinator_estimated_hashrate gauge\n\
+             coordinator_estimated_hashrate {}\n",
+            self.estimated_hashrate(),
+        ));
+        out.push_str(&self.job_broadcast_seconds.format_prometheus(
+            "coordinator_job_broadcast_seconds",
+            "Time to push a new template's jobs to every connection",
+        ));
+        out.push_str(&self.job_first_submit_age_seconds.0.format_prometheus(
+            "coordinator_job_first_submit_age_seconds",
+            "Age of jobs when their first submission arrived",
+        ));
+        out.push_str(&format!(
+            "# HELP coordinator_jobs_expired_unused Jobs that expired without a single submission\n\
+             # TYPE coordinator_jobs_expired_unused counter\n\
+             coordinator_jobs_expired_unused {}\n",
+            self.jobs_expired_unused.load(Ordering::Relaxed),
+        ));
+        let fast = self.randomx_fast.load(Ordering::Relaxed);
+        out.push_str(&format!(
+            "# HELP coordinator_randomx_mode RandomX mode submissions are verified in\n\
+             # TYPE coordinator_randomx_mode gauge\n\
+             coordinator_randomx_mode{{mode=\"fast\"}} {}\n\
+             coordinator_randomx_mode{{mode=\"light\"}} {}\n\
+             # HELP coordinator_randomx_dataset_init_seconds Time the last RandomX dataset took to build\n\
+             # TYPE coordinator_randomx_dataset_init_seconds gauge\n\
+             coordinator_randomx_dataset_init_seconds {}\n\
+             # HELP coordinator_randomx_dataset_failures RandomX datasets that could not be built, leaving verification in light mode\n\
+             # TYPE coordinator_randomx_dataset_failures counter\n\
+             coordinator_randomx_dataset_failures {}\n\
+             # HELP coordinator_randomx_prewarms_started RandomX caches for the next seed started ahead of the seed change\n\
+             # TYPE coordinator_randomx_prewarms_started counter\n\
+             coordinator_randomx_prewarms_started {}\n\
+             # HELP coordinator_randomx_prewarms_completed RandomX caches for the next seed made ahead of the seed change\n\
+             # TYPE coordinator_randomx_prewarms_completed counter\n\
+             coordinator_randomx_prewarms_completed {}\n\
+             # HELP coordinator_randomx_inline_cache_inits RandomX caches made while a submission waited for one\n\
+             # TYPE coordinator_randomx_inline_cache_inits counter\n\
+             coordinator_randomx_inline_cache_inits {}\n\
+             # HELP coordinator_randomx_vm_inits RandomX VMs made for a hash, or moved onto a new seed's cache\n\
+             # TYPE coordinator_randomx_vm_inits counter\n\
+             coordinator_randomx_vm_inits{{kind=\"new\"}} {}\n\
+             coordinator_randomx_vm_inits{{kind=\"rekey\"}} {}\n\
+             # HELP coordinator_randomx_verifications Hashes done with the current RandomX seed and the one before it\n\
+             # TYPE coordinator_randomx_verifications counter\n\
+             coordinator_randomx_verifications{{seed=\"current\"}} {}\n\
+             coordinator_randomx_verifications{{seed=\"previous\"}} {}\n\
+             # HELP coordinator_randomx_result_cache Hashes looked up in the result cache, by whether one was found\n\
+             # TYPE coordinator_randomx_result_cache counter\n\
+             coordinator_randomx_result_cache{{result=\"hit\"}} {}\n\
+             coordinator_randomx_result_cache{{result=\"miss\"}} {}\n\
+             # HELP coordinator_verify_queue_depth Hashes running or waiting for a RandomX VM\n\
+             # TYPE coordinator_verify_queue_depth gauge\n\
+             coordinator_verify_queue_depth {}\n\
+             # HELP coordinator_verify_queue_full Submits refused as busy because the verification queue was full\n\
+             # TYPE coordinator_verify_queue_full counter\n\
+             coordinator_verify_queue_full {}\n\
+             # HELP coordinator_rpc_pow_calls Hashes asked of monerod's calc_pow, by how the call went\n\
+             # TYPE coordinator_rpc_pow_calls counter\n\
+             coordinator_rpc_pow_calls{{result=\"ok\"}} {}\n\
+             coordinator_rpc_pow_calls{{result=\"error\"}} {}\n\
+             coordinator_rpc_pow_calls{{result=\"limited\"}} {}\n\
+             # HELP coordinator_randomx_rpc_fallbacks Hashes sent to calc_pow because no local RandomX VM could be made\n\
+             # TYPE coordinator_randomx_rpc_fallbacks counter\n\
+             coordinator_randomx_rpc_fallbacks {}\n\
+             # HELP coordinator_verifier_rung Verification ladder rung: 0 as configured, 1 light mode without large pages, 2 calc_pow, 3 down\n\
+             # TYPE coordinator_verifier_rung gauge\n\
+             coordinator_verifier_rung {}\n\
+             # HELP coordinator_verifier_steps Steps down the verification ladder when RandomX could not be made, by the rung stepped to\n\
+             # TYPE coordinator_verifier_steps counter\n\
+             coordinator_verifier_steps{{rung=\"light\"}} {}\n\
+             coordinator_verifier_steps{{rung=\"rpc\"}} {}\n\
+             coordinator_verifier_steps{{rung=\"down\"}} {}\n\
+             # HELP coordinator_verifier_recoveries Climbs back up the verification ladder once RandomX could be made again\n\
+             # TYPE coordinator_verifier_recoveries counter\n\
+             coordinator_verifier_recoveries {}\n",
+            fast,
+            1 - fast,
+            f64::from_bits(self.randomx_dataset_init_seconds.load(Ordering::Relaxed)),
+            self.randomx_dataset_failures.load(Ordering::Relaxed),
+            self.randomx_prewarms_started.load(Ordering::Relaxed),
+            self.randomx_prewarms_completed.load(Ordering::Relaxed),
+            self.randomx_inline_cache_inits.load(Ordering::Relaxed),
+            self.randomx_vm_inits_new.load(Ordering::Relaxed),
+            self.randomx_vm_inits_rekey.load(Ordering::Relaxed),
+            self.randomx_verifications_current.load(Ordering::Relaxed),
+            self.randomx_verifications_previous.load(Ordering::Relaxed),
+            self.randomx_result_cache_hits.load(Ordering::Relaxed),
+            self.randomx_result_cache_misses.load(Ordering::Relaxed),
+            self.verify_queue_depth.load(Ordering::Relaxed),
+            self.verify_queue_full.load(Ordering::Relaxed),
+            self.rpc_pow_ok.load(Ordering::Relaxed),
+            self.rpc_pow_errors.load(Ordering::Relaxed),
+            self.rpc_pow_limited.load(Ordering::Relaxed),
+            self.randomx_rpc_fallbacks.load(Ordering::Relaxed),
+            self.verifier_rung.load(Ordering::Relaxed),
+            self.verifier_steps_light.load(Ordering::Relaxed),
+            self.verifier_steps_rpc.load(Ordering::Relaxed),
+            self.verifier_steps_down.load(Ordering::Relaxed),
+            self.verifier_recoveries.load(Ordering::Relaxed),
+        ));
+        for (histogram, name, help) in [
+            (&self.verify_queue_wait_seconds, "coordinator_verify_queue_wait_seconds", "Time hashes waited for a place in the verification queue and a RandomX VM"),
+            (&self.randomx_init_seconds.0, "coordinator_randomx_init_seconds", "Time hashes waited for a RandomX cache or VM to be made"),
+            (&self.verify_hash_seconds, "coordinator_verify_hash_seconds", "Time RandomX VMs took to hash a submission"),
+            (&self.rpc_pow_seconds, "coordinator_rpc_pow_seconds", "Time monerod's calc_pow took to hash a submission"),
+        ] {
+            out.push_str(&histogram.format_prometheus(name, help));
+        }
+        let mut modes: Vec<(String, usize)> = self
+            .sessions_by_randomx_mode
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect();
+        modes.sort();
+        out.push_str(
+            "# HELP coordinator_sessions_by_randomx_mode Sessions past hello by the RandomX mode they reported\n\
+             # TYPE coordinator_sessions_by_randomx_mode gauge\n",
+        );
+        for (mode, count) in modes {
+            out.push_str(&format!("coordinator_sessions_by_randomx_mode{{mode=\"{}\"}} {}\n", mode, count));
+        }
+        let mut sites: Vec<(String, usize)> = self
+            .site_connections
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect();
+        sites.sort();
+        out.push_str(
+            "# HELP coordinator_site_connections_active Ready sessions per site token\n\
+             # TYPE coordinator_site_connections_active gauge\n",
+        );
+        for (site, count) in sites {
+            out.push_str(&format!(
+                "coordinator_site_connections_active{{site=\"{}\"}} {}\n",
+                escape_label(&site),
+                count
+            ));
+        }
+        let mut shares: Vec<(String, u64)> = self
+            .site_accepted_shares
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect();
+        shares.sort();
+        out.push_str(
+            "# HELP coordinator_site_accepted_shares Total accepted difficulty per site token\n\
+             # TYPE coordinator_site_accepted_shares counter\n",
+        );
+        for (site, total) in shares {
+            out.push_str(&format!(
+                "coordinator_site_accepted_shares{{site=\"{}\"}} {}\n",
+                escape_label(&site),
+                total
+            ));
+        }
+        let mut hashrates: Vec<(String, f64)> = self
+            .site_hashrate
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect();
+        hashrates.sort_by(|a, b| a.0.cmp(&b.0));
+        out.push_str(
+            "# HELP coordinator_site_hashrate Accepted difficulty per second per site token, 10 minute average\n\
+             # TYPE coordinator_site_hashrate gauge\n",
+        );
+        for (site, hashrate) in hashrates {
+            out.push_str(&format!(
+                "coordinator_site_hashrate{{site=\"{}\"}} {}\n",
+                escape_label(&site),
+                hashrate
+            ));
+        }
+        let mut reasons: Vec<(&str, u64)> = self
+            .submissions_rejected_by_reason
+            .iter()
+            .map(|e| (*e.key(), *e.value()))
+            .collect();
+        reasons.sort();
+        out.push_str(
+            "# HELP coordinator_submissions_rejected_by_reason Submissions that failed validation, by how\n\
+             # TYPE coordinator_submissions_rejected_by_reason counter\n",
+        );
+        for (reason, count) in reasons {
+            out.push_str(&format!("coordinator_submissions_rejected_by_reason{{reason=\"{}\"}} {}\n", reason, count));
+        }
+        let mut workers: Vec<(String, u64)> = self
+            .worker_accepted
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect();
+        workers.sort();
+        out.push_str(
+            "# HELP coordinator_worker_accepted_total Accepted submits per worker name\n\
+             # TYPE coordinator_worker_accepted_total counter\n",
+        );
+        for (worker, count) in workers {
+            out.push_str(&format!(
+                "coordinator_worker_accepted_total{{worker=\"{}\"}} {}\n",
+                escape_label(&worker),
+                count
+            ));
+        }
+        out.push_str(&format!(
+            "# HELP coordinator_build_info Build and protocol version of the running coordinator\n\
+             # TYPE coordinator_build_info gauge\n\
+             coordinator_build_info{{version=\"{}\",commit=\"{}\",build_timestamp=\"{}\",protocol=\"{}\"}} 1\n",
+            version::VERSION,
+            version::GIT_COMMIT,
+            version::BUILD_TIMESTAMP,
+            crate::protocol::PROTOCOL_VERSION,
+        ));
+        out
     }
 }
 
-pub async fn run_metrics_server(config: MetricsConfig, metrics: Arc<Metrics>) {
+/// Escape a Prometheus label value
+/// Label for a worker name: configured names keep their own, anything else
+/// (including no name) becomes "other" so clients cannot grow the label set
+pub fn worker_label<'a>(known: &'a [String], worker: Option<&str>) -> &'a str {
+    worker
+        .and_then(|w| known.iter().find(|k| k.as_str() == w))
+        .map(String::as_str)
+        .unwrap_or(OTHER_WORKER)
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub async fn run_metrics_server(config: MetricsConfig, metrics: Arc<Metrics>, shutdown: CancellationToken) {
     if !config.enable {
         return;
     }
@@ -135,6 +884,40 @@ pub async fn run_metrics_server(config: MetricsConfig, metrics: Arc<Metrics>) {
     info!("Metrics server listening on {}{}", addr, path);
 
     if let Ok(listener) = TcpListener::bind(addr).await {
-        let _ = axum::serve(listener, app).await;
+        let _ = axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unconfigured_workers_collapse_to_other() {
+        let known = vec!["blog-footer".to_string(), "game-page".to_string()];
+        assert_eq!(worker_label(&known, Some("game-page")), "game-page");
+        assert_eq!(worker_label(&known, Some("made-up-1")), "other");
+        assert_eq!(worker_label(&known, None), "other");
+
+        let metrics = Metrics::new();
+        for worker in ["blog-footer", "made-up-1", "made-up-2", "blog-footer"] {
+            metrics.inc_worker_accepted(worker_label(&known, Some(worker)));
+        }
+        let output = metrics.format_prometheus();
+        assert!(output.contains("coordinator_worker_accepted_total{worker=\"blog-footer\"} 2\n"));
+        assert!(output.contains("coordinator_worker_accepted_total{worker=\"other\"} 2\n"));
+        assert!(!output.contains("made-up"));
+    }
+
+    #[test]
+    fn test_randomx_modes_are_labeled() {
+        let metrics = Metrics::new();
+        metrics.set_sessions_by_randomx_mode(&HashMap::from([(RandomxMode::Light, 3), (RandomxMode::Fast, 1)]));
+        let output = metrics.format_prometheus();
+        assert!(output.contains("coordinator_sessions_by_randomx_mode{mode=\"fast\"} 1\n"));
+        assert!(output.contains("coordinator_sessions_by_randomx_mode{mode=\"light\"} 3\n"));
+        assert!(output.contains("coordinator_sessions_by_randomx_mode{mode=\"unknown\"} 0\n"));
     }
 }
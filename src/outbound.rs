@@ -0,0 +1,554 @@
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use futures::{Sink, SinkExt};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+use crate::protocol::{GoodbyeReason, OutboundCounters, ServerMessage};
+
+/// A frame queued for a connection's writer task
+#[derive(Debug)]
+pub enum Outbound {
+    Message(ServerMessage),
+    /// Job message serialized once by the fanout task
+    Job(String),
+    Ping,
+    /// Send a close frame and stop writing
+    Close(CloseFrame<'static>),
+}
+
+impl Outbound {
+    /// Critical frames are never dropped to make room; if they cannot be
+    /// queued in time the client is evicted instead.
+    fn is_critical(&self) -> bool {
+        match self {
+            Outbound::Message(msg) => matches!(
+                msg,
+                ServerMessage::Job { .. } | ServerMessage::SubmitResult { .. } | ServerMessage::Goodbye { .. }
+            ),
+            Outbound::Job(_) => true,
+            Outbound::Ping => false,
+            Outbound::Close(_) => true,
+        }
+    }
+
+    fn is_job(&self) -> bool {
+        matches!(self, Outbound::Job(_) | Outbound::Message(ServerMessage::Job { .. }))
+    }
+}
+
+/// Frame types counted per connection: every `ServerMessage` type, then
+/// the control frames
+const FRAME_KINDS: [&str; 9] = [
+    "stats", "job", "submit_result", "error", "pong", "goodbye", "policy_update", "ping", "close",
+];
+
+/// Per-connection traffic and queue statistics, kept by the writer and
+/// shared with the session for reporting
+#[derive(Debug, Default)]
+pub struct OutboundStats {
+    sent: [AtomicU64; FRAME_KINDS.len()],
+    bytes_sent: AtomicU64,
+    depth: AtomicUsize,
+    high_watermark: AtomicUsize,
+}
+
+impl OutboundStats {
+    pub fn high_watermark(&self) -> usize {
+        self.high_watermark.load(Ordering::Relaxed)
+    }
+
+    fn record_sent(&self, kind: &str, bytes: usize) {
+        if let Some(i) = FRAME_KINDS.iter().position(|k| *k == kind) {
+            self.sent[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> OutboundCounters {
+        OutboundCounters {
+            messages_sent: FRAME_KINDS
+                .iter()
+                .zip(&self.sent)
+                .map(|(kind, count)| (kind.to_string(), count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            queue_depth: self.depth.load(Ordering::Relaxed),
+            queue_high_watermark: self.high_watermark(),
+        }
+    }
+}
+
+struct Queue {
+    items: Mutex<VecDeque<Outbound>>,
+    capacity: usize,
+    item_ready: Notify,
+    space_ready: Notify,
+    sender_closed: AtomicBool,
+    writer_closed: AtomicBool,
+    /// Send messages as binary frames instead of text
+    binary: AtomicBool,
+    stats: Arc<OutboundStats>,
+}
+
+impl Queue {
+    fn push_locked(&self, items: &mut VecDeque<Outbound>, item: Outbound) {
+        items.push_back(item);
+        self.stats.depth.store(items.len(), Ordering::Relaxed);
+        self.stats.high_watermark.fetch_max(items.len(), Ordering::Relaxed);
+        self.item_ready.notify_one();
+    }
+
+    /// The client is not draining its queue. Discard what is pending and
+    /// leave only a goodbye and close frame for the writer.
+    fn evict(&self, metrics: &Metrics) {
+        metrics.inc_slow_client_evictions();
+
+        let mut items = self.items.lock();
+        items.clear();
+        self.push_locked(
+            &mut items,
+            Outbound::Message(ServerMessage::Goodbye {
+                reason: GoodbyeReason::SlowClient,
+                retry_after_ms: 0,
+            }),
+        );
+        self.push_locked(
+            &mut items,
+            Outbound::Close(CloseFrame {
+                code: close_code::POLICY,
+                reason: "Slow client".into(),
+            }),
+        );
+    }
+}
+
+enum Enqueue {
+    Queued,
+    Full,
+}
+
+/// Reader-side handle that queues frames for the writer.
+/// Every method returns false once the connection should be torn down.
+pub struct Outbox {
+    queue: Arc<Queue>,
+    slow_client_timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+/// Pushes job frames into a connection's queue without waiting, for the
+/// fanout task that serves every connection and cannot stall on one.
+#[derive(Clone)]
+pub struct JobSink {
+    queue: Arc<Queue>,
+    metrics: Arc<Metrics>,
+}
+
+/// Writer-side end of the queue
+pub struct OutboxReceiver {
+    queue: Arc<Queue>,
+}
+
+pub fn channel(
+    capacity: usize,
+    slow_client_timeout: Duration,
+    stats: Arc<OutboundStats>,
+    metrics: Arc<Metrics>,
+) -> (Outbox, OutboxReceiver) {
+    let queue = Arc::new(Queue {
+        items: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: capacity.max(1),
+        item_ready: Notify::new(),
+        space_ready: Notify::new(),
+        sender_closed: AtomicBool::new(false),
+        writer_closed: AtomicBool::new(false),
+        binary: AtomicBool::new(false),
+        stats,
+    });
+    (
+        Outbox { queue: queue.clone(), slow_client_timeout, metrics },
+        OutboxReceiver { queue },
+    )
+}
+
+impl Outbox {
+    pub async fn send(&self, msg: ServerMessage) -> bool {
+        self.enqueue(Outbound::Message(msg)).await
+    }
+
+    pub async fn ping(&self) -> bool {
+        self.enqueue(Outbound::Ping).await
+    }
+
+    pub fn job_sink(&self) -> JobSink {
+        JobSink {
+            queue: self.queue.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// At least half the queue is still waiting to be written
+    pub fn is_congested(&self) -> bool {
+        self.queue.items.lock().len() * 2 >= self.queue.capacity
+    }
+
+    /// Switch to binary frames for every message written from now on
+    pub fn use_binary(&self) {
+        self.queue.binary.store(true, Ordering::Relaxed);
+    }
+
+    /// Queue a close frame. Close frames bypass the capacity limit.
+    pub async fn close(&self, frame: CloseFrame<'static>) -> bool {
+        if self.queue.writer_closed.load(Ordering::Acquire) {
+            return false;
+        }
+        let mut items = self.queue.items.lock();
+        self.queue.push_locked(&mut items, Outbound::Close(frame));
+        true
+    }
+
+    async fn enqueue(&self, item: Outbound) -> bool {
+        let critical = item.is_critical();
+        let mut item = Some(item);
+        let deadline = tokio::time::Instant::now() + self.slow_client_timeout;
+
+        loop {
+            if self.queue.writer_closed.load(Ordering::Acquire) {
+                return false;
+            }
+
+            // Register for space notifications before checking, so a pop
+            // between the check and the wait is not missed
+            let space = self.queue.space_ready.notified();
+
+            match self.try_enqueue(&mut item, critical) {
+                Enqueue::Queued => return true,
+                Enqueue::Full => {}
+            }
+
+            if tokio::time::timeout_at(deadline, space).await.is_err() {
+                warn!("Evicting slow client: outbound queue stayed full for {:?}", self.slow_client_timeout);
+                self.queue.evict(&self.metrics);
+                return false;
+            }
+        }
+    }
+
+    fn try_enqueue(&self, item: &mut Option<Outbound>, critical: bool) -> Enqueue {
+        let mut items = self.queue.items.lock();
+
+        if items.len() >= self.queue.capacity {
+            // Make room by dropping the oldest non-critical frame
+            if let Some(pos) = items.iter().position(|i| !i.is_critical()) {
+                items.remove(pos);
+                self.metrics.inc_outbound_dropped();
+            } else if !critical {
+                // Nothing droppable queued; drop the new frame instead
+                item.take();
+                self.metrics.inc_outbound_dropped();
+                return Enqueue::Queued;
+            } else {
+                return Enqueue::Full;
+            }
+        }
+
+        if let Some(item) = item.take() {
+            self.queue.push_locked(&mut items, item);
+        }
+        Enqueue::Queued
+    }
+}
+
+impl JobSink {
+    /// Queue a job, replacing any job still waiting to be written since the
+    /// client only needs the newest. A client whose queue is full of other
+    /// critical frames is evicted rather than waited on.
+    pub fn push(&self, frame: String) -> bool {
+        self.push_all(vec![frame])
+    }
+
+    /// Queue sibling jobs, one per miner thread, in place of any job still
+    /// waiting to be written, as `push` does for one
+    pub fn push_all(&self, frames: Vec<String>) -> bool {
+        if self.queue.writer_closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let mut items = self.queue.items.lock();
+        items.retain(|i| !i.is_job());
+        for frame in frames {
+            if items.len() >= self.queue.capacity {
+                match items.iter().position(|i| !i.is_critical()) {
+                    Some(pos) => {
+                        items.remove(pos);
+                        self.metrics.inc_outbound_dropped();
+                    }
+                    None => {
+                        drop(items);
+                        warn!("Evicting slow client: no room for a new job");
+                        self.queue.evict(&self.metrics);
+                        return false;
+                    }
+                }
+            }
+            self.queue.push_locked(&mut items, Outbound::Job(frame));
+        }
+        true
+    }
+
+    /// Queue an informational message if there is room; dropped otherwise,
+    /// like stats
+    pub fn push_message(&self, msg: ServerMessage) -> bool {
+        if self.queue.writer_closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let mut items = self.queue.items.lock();
+        if items.len() >= self.queue.capacity {
+            self.metrics.inc_outbound_dropped();
+            return false;
+        }
+        self.queue.push_locked(&mut items, Outbound::Message(msg));
+        true
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.queue.sender_closed.store(true, Ordering::Release);
+        self.queue.item_ready.notify_one();
+    }
+}
+
+impl OutboxReceiver {
+    pub async fn recv(&mut self) -> Option<Outbound> {
+        loop {
+            let ready = self.queue.item_ready.notified();
+            let popped = {
+                let mut items = self.queue.items.lock();
+                let item = items.pop_front();
+                self.queue.stats.depth.store(items.len(), Ordering::Relaxed);
+                item
+            };
+            if let Some(item) = popped {
+                self.queue.space_ready.notify_one();
+                return Some(item);
+            }
+            if self.queue.sender_closed.load(Ordering::Acquire) {
+                return None;
+            }
+            ready.await;
+        }
+    }
+
+    /// A text or binary frame for `json`, with its payload size
+    fn data_frame(&self, json: String) -> (Message, usize) {
+        let bytes = json.len();
+        let frame = if self.queue.binary.load(Ordering::Relaxed) {
+            Message::Binary(json.into_bytes())
+        } else {
+            Message::Text(json)
+        };
+        (frame, bytes)
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        self.queue.writer_closed.store(true, Ordering::Release);
+        self.queue.space_ready.notify_one();
+    }
+}
+
+/// Drain the queue into the socket until the queue closes, a close frame is
+/// written, or the socket errors.
+pub async fn write_loop<W>(mut sink: W, mut rx: OutboxReceiver, metrics: Arc<Metrics>)
+where
+    W: Sink<Message> + Unpin,
+    W::Error: Display,
+{
+    while let Some(outbound) = rx.recv().await {
+        let (kind, last) = match &outbound {
+            Outbound::Message(msg) => (msg.kind(), false),
+            Outbound::Job(_) => ("job", false),
+            Outbound::Ping => ("ping", false),
+            Outbound::Close(_) => ("close", true),
+        };
+        let (frame, bytes) = match outbound {
+            Outbound::Message(msg) => rx.data_frame(serde_json::to_string(&msg).unwrap()),
+            Outbound::Job(json) => rx.data_frame(json),
+            Outbound::Ping => (Message::Ping(Vec::new()), 0),
+            Outbound::Close(frame) => (Message::Close(Some(frame)), 0),
+        };
+
+        if let Err(e) = sink.send(frame).await {
+            debug!("WebSocket write failed: {}", e);
+            return;
+        }
+        rx.queue.stats.record_sent(kind, bytes);
+        metrics.add_bytes_sent(bytes);
+        if last {
+            break;
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SubmitStatus;
+
+    fn job(n: u64) -> ServerMessage {
+        ServerMessage::Job {
+            job_id: format!("{:016x}", n),
+            blob_hex: String::new(),
+            reserved_offset: 0,
+            reserved_value_hex: String::new(),
+            target_hex: String::new(),
+            height: n,
+            seed_hash: String::new(),
+        }
+    }
+
+    fn pong(n: u64) -> ServerMessage {
+        ServerMessage::Pong { id: n.to_string() }
+    }
+
+    fn drain(rx: &OutboxReceiver) -> Vec<Outbound> {
+        rx.queue.items.lock().drain(..).collect()
+    }
+
+    fn setup(capacity: usize) -> (Outbox, OutboxReceiver, Arc<OutboundStats>, Arc<Metrics>) {
+        let stats = Arc::new(OutboundStats::default());
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = channel(capacity, Duration::from_millis(500), stats.clone(), metrics.clone());
+        (tx, rx, stats, metrics)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drops_oldest_non_critical_when_full() {
+        let (tx, rx, stats, metrics) = setup(3);
+        assert!(tx.send(pong(1)).await);
+        assert!(tx.send(job(1)).await);
+        assert!(tx.send(pong(2)).await);
+        assert!(tx.send(job(2)).await);
+
+        let queued = drain(&rx);
+        assert_eq!(queued.len(), 3);
+        assert!(matches!(&queued[0], Outbound::Message(ServerMessage::Job { height: 1, .. })));
+        assert!(matches!(&queued[1], Outbound::Message(ServerMessage::Pong { id }) if id == "2"));
+        assert!(matches!(&queued[2], Outbound::Message(ServerMessage::Job { height: 2, .. })));
+        assert_eq!(stats.high_watermark(), 3);
+        assert_eq!(metrics.outbound_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_non_critical_dropped_when_queue_all_critical() {
+        let (tx, rx, _, metrics) = setup(2);
+        assert!(tx.send(job(1)).await);
+        assert!(tx.send(job(2)).await);
+        assert!(tx.ping().await);
+
+        assert_eq!(drain(&rx).len(), 2);
+        assert_eq!(metrics.outbound_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_evicts_client_that_never_drains() {
+        let (tx, rx, _, metrics) = setup(2);
+        assert!(tx.send(job(1)).await);
+        assert!(tx.send(job(2)).await);
+
+        let start = tokio::time::Instant::now();
+        let result = tx.send(ServerMessage::SubmitResult {
+            id: "1".into(),
+            status: SubmitStatus::Accepted,
+            message: None,
+        }).await;
+        let waited = start.elapsed();
+
+        assert!(!result);
+        assert!(waited >= Duration::from_millis(500));
+        assert!(waited < Duration::from_millis(600));
+        assert_eq!(metrics.slow_client_evictions.load(Ordering::Relaxed), 1);
+
+        let queued = drain(&rx);
+        assert_eq!(queued.len(), 2);
+        assert!(matches!(
+            &queued[0],
+            Outbound::Message(ServerMessage::Goodbye { reason: GoodbyeReason::SlowClient, .. })
+        ));
+        assert!(matches!(&queued[1], Outbound::Close(f) if f.code == close_code::POLICY));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_critical_send_waits_for_space() {
+        let (tx, mut rx, _, metrics) = setup(1);
+        assert!(tx.send(job(1)).await);
+
+        let sender = tokio::spawn(async move { tx.send(job(2)).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(rx.recv().await, Some(Outbound::Message(ServerMessage::Job { height: 1, .. }))));
+
+        assert!(sender.await.unwrap());
+        assert!(matches!(rx.recv().await, Some(Outbound::Message(ServerMessage::Job { height: 2, .. }))));
+        assert_eq!(metrics.slow_client_evictions.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_job_sink_replaces_queued_job() {
+        let (tx, rx, _, _) = setup(4);
+        let sink = tx.job_sink();
+        assert!(tx.send(job(1)).await);
+        assert!(tx.send(pong(1)).await);
+        assert!(sink.push("a".into()));
+        assert!(sink.push("b".into()));
+
+        let queued = drain(&rx);
+        assert_eq!(queued.len(), 2);
+        assert!(matches!(&queued[0], Outbound::Message(ServerMessage::Pong { .. })));
+        assert!(matches!(&queued[1], Outbound::Job(frame) if frame == "b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_job_sink_evicts_instead_of_waiting() {
+        let (tx, rx, _, metrics) = setup(2);
+        let sink = tx.job_sink();
+        let result = |id: &str| ServerMessage::SubmitResult {
+            id: id.into(),
+            status: SubmitStatus::Accepted,
+            message: None,
+        };
+        assert!(tx.send(result("1")).await);
+        assert!(tx.send(result("2")).await);
+
+        assert!(!sink.push("a".into()));
+        assert_eq!(metrics.slow_client_evictions.load(Ordering::Relaxed), 1);
+        assert!(matches!(&drain(&rx)[1], Outbound::Close(_)));
+    }
+
+    #[tokio::test]
+    async fn test_receiver_ends_when_sender_dropped() {
+        let (tx, mut rx, _, _) = setup(4);
+        assert!(tx.send(pong(1)).await);
+        drop(tx);
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_after_writer_gone() {
+        let (tx, rx, _, _) = setup(4);
+        drop(rx);
+        assert!(!tx.send(job(1)).await);
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::session::{SessionManager, SiteTotals};
+
+/// Bumped when the file layout changes; other versions are not restored
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Durable aggregates saved across restarts. Live sessions are not saved;
+/// miners reconnect and hello again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    /// Unix time in milliseconds
+    pub saved_at_ms: u64,
+    pub sites: Vec<SiteTotals>,
+}
+
+impl Snapshot {
+    pub fn collect(manager: &SessionManager) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            saved_at_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            sites: manager.site_totals(),
+        }
+    }
+}
+
+/// Write `snapshot` to a temporary file next to `path` and rename it over
+/// `path`, so a crash mid-write leaves the previous snapshot intact
+pub fn save(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let temp = with_suffix(path, "tmp");
+    let json = serde_json::to_vec_pretty(snapshot)?;
+    let mut file = File::create(&temp)?;
+    file.write_all(&json)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, path)
+}
+
+/// The saved snapshot, if there is a usable one. A corrupt file is moved
+/// to `<path>.corrupt` and the coordinator starts from a clean slate.
+pub fn load(path: &Path) -> Option<Snapshot> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Cannot read saved state {}: {}; starting from a clean slate", path.display(), e);
+            return None;
+        }
+    };
+
+    let problem = match serde_json::from_slice::<Snapshot>(&data) {
+        Ok(snapshot) if snapshot.version == SNAPSHOT_VERSION => return Some(snapshot),
+        Ok(snapshot) => format!("unsupported version {}", snapshot.version),
+        Err(e) => e.to_string(),
+    };
+    let aside = with_suffix(path, "corrupt");
+    warn!(
+        "Saved state {} is unusable ({}); moved to {} and starting from a clean slate",
+        path.display(),
+        problem,
+        aside.display()
+    );
+    if let Err(e) = fs::rename(path, &aside) {
+        warn!("Cannot move {} aside: {}", path.display(), e);
+    }
+    None
+}
+
+/// Restore the per-site totals saved at `path`. Returns how many sites were restored.
+pub fn restore(manager: &SessionManager, path: &Path) -> usize {
+    let snapshot = match load(path) {
+        Some(s) => s,
+        None => return 0,
+    };
+    manager.restore_site_totals(&snapshot.sites);
+    info!("Restored accounting for {} sites from {}", snapshot.sites.len(), path.display());
+    snapshot.sites.len()
+}
+
+/// Save a snapshot every `interval` and once more on shutdown
+pub async fn run(manager: Arc<SessionManager>, path: PathBuf, interval: Duration, shutdown: CancellationToken) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        let stopping = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = shutdown.cancelled() => true,
+        };
+        let snapshot = Snapshot::collect(&manager);
+        let target = path.clone();
+        match tokio::task::spawn_blocking(move || save(&target, &snapshot)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to save state to {}: {}", path.display(), e),
+            Err(e) => warn!("State snapshot task failed: {}", e),
+        }
+        if stopping {
+            break;
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionManagerConfig;
+    use crate::metrics::Metrics;
+    use crate::protocol::SubmitStatus;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("coordinator-state-{}-{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(with_suffix(&path, "corrupt"));
+        path
+    }
+
+    /// A manager with one ready session on `site` that has had `accepted` shares of difficulty 100
+    fn manager_with(site: &str, accepted: u32) -> (SessionManager, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new());
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_metrics(metrics.clone());
+        let session = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(manager.set_ready(&session.id, "t".into(), 1, Some(site.into()), None));
+        for _ in 0..accepted {
+            manager.record_submit_result(&session.id, &SubmitStatus::Accepted, 100);
+        }
+        (manager, metrics)
+    }
+
+    #[test]
+    fn test_totals_continue_after_restart() {
+        let path = temp_path("restart");
+        let (before, _) = manager_with("site-a", 3);
+        save(&path, &Snapshot::collect(&before)).unwrap();
+        assert!(!with_suffix(&path, "tmp").exists());
+        drop(before);
+
+        // A fresh process restores the totals, and new shares add to them
+        let (after, metrics) = manager_with("site-a", 0);
+        assert_eq!(restore(&after, &path), 1);
+        let id = after.list_sessions().pop().unwrap().id;
+        after.record_submit_result(&id, &SubmitStatus::Accepted, 100);
+
+        let stats = after.site_stats();
+        assert_eq!((stats[0].accepted_submits, stats[0].accepted_shares, stats[0].sessions), (4, 400, 1));
+        assert!(metrics.format_prometheus().contains(r#"coordinator_site_accepted_shares{site="site-a"} 400"#));
+
+        // Saving again replaces the file in place
+        save(&path, &Snapshot::collect(&after)).unwrap();
+        assert_eq!(load(&path).unwrap().sites[0].accepted_shares, 400);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_corrupt_state_falls_back_to_clean_slate() {
+        let path = temp_path("corrupt");
+        assert!(load(&path).is_none(), "no file is not an error");
+
+        fs::write(&path, b"{\"version\":1,\"sites\":[{\"si").unwrap();
+        let (manager, _) = manager_with("site-a", 0);
+        assert_eq!(restore(&manager, &path), 0);
+        assert_eq!(manager.site_stats()[0].accepted_submits, 0);
+        assert!(!path.exists());
+        assert!(with_suffix(&path, "corrupt").exists(), "the bad file is kept for inspection");
+
+        fs::write(&path, br#"{"version":99,"saved_at_ms":0,"sites":[]}"#).unwrap();
+        assert!(load(&path).is_none());
+        let _ = fs::remove_file(with_suffix(&path, "corrupt"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_saves_on_shutdown() {
+        let path = temp_path("run");
+        let (manager, _) = manager_with("site-b", 2);
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(run(Arc::new(manager), path.clone(), Duration::from_secs(3600), shutdown.clone()));
+
+        tokio::task::yield_now().await;
+        assert!(!path.exists());
+        shutdown.cancel();
+        task.await.unwrap();
+        let saved = load(&path).unwrap();
+        assert_eq!(saved.sites, vec![SiteTotals { site: "site-b".into(), accepted_submits: 2, accepted_shares: 200 }]);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,140 @@
+//! The proof-of-work hash behind a submission, apart from the checks, the
+//! result cache, queueing and stepping down around it.
+//!
+//! The server hashes with a [`PowVerifier`]: RandomX's VMs as a
+//! [`LocalVerifier`](crate::vm_pool::LocalVerifier), monerod's calc_pow as an
+//! [`RpcVerifier`](crate::rpc_verifier::RpcVerifier), or in tests a
+//! [`FakeVerifier`], whose hash they can work out themselves, so the submit
+//! path can be driven end to end without RandomX's cache. Whichever it is,
+//! [`SubmissionValidator`](crate::validator::SubmissionValidator) puts the
+//! same result cache, queue and stepping down around it.
+
+use futures::future::BoxFuture;
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::error::CoordinatorError;
+use crate::validator::{HashError, HashTimings, VerifierRung};
+
+/// Hashes a block's hashing blob for a RandomX seed
+pub trait PowVerifier: Send + Sync {
+    /// Hash `blob`, the hashing blob of a block at `height`, with the
+    /// RandomX key `seed_hash`, adding where the time went to `timings`
+    fn verify<'a>(
+        &'a self,
+        blob: &'a [u8],
+        seed_hash: &'a str,
+        height: u64,
+        timings: &'a mut HashTimings,
+    ) -> BoxFuture<'a, Result<[u8; 32], HashError>>;
+
+    /// Hashes it runs at once; the verification queue holds the rest
+    fn concurrency(&self) -> usize;
+
+    /// The rung it hashes on, below the configured one once it stepped down
+    /// by itself
+    fn rung(&self) -> VerifierRung {
+        VerifierRung::Configured
+    }
+
+    /// Try again what it stepped down from, or failed to make
+    fn recover(&self) -> BoxFuture<'_, Result<(), CoordinatorError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Check it hashes right, and say how long that took; None if there is
+    /// nothing of its own to check
+    fn self_test(&self) -> BoxFuture<'_, Result<Option<Duration>, HashError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Get ready to hash for `seed_hash` before the chain switches to it
+    fn prewarm<'a>(&'a self, _seed_hash: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// Names of the RandomX flags it hashes with, if it runs RandomX
+    fn randomx_flags(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+}
+
+/// Hashes with BLAKE2s of the seed and blob: deterministic, cheap, and
+/// nothing like RandomX. Each hash can be made to take a while, to hold
+/// submissions in flight.
+#[cfg(test)]
+pub(crate) struct FakeVerifier {
+    delay: Duration,
+    concurrency: usize,
+    hashes: AtomicU64,
+    in_flight: AtomicU64,
+    peak: AtomicU64,
+}
+
+#[cfg(test)]
+impl FakeVerifier {
+    pub(crate) fn new() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            concurrency: 4,
+            hashes: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+        }
+    }
+
+    /// Take `delay` over each hash
+    pub(crate) fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Run at most `concurrency` hashes at once, as that many VMs would
+    pub(crate) fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// The hash [`PowVerifier::verify`] answers for `blob` and `seed_hash`
+    pub(crate) fn hash(blob: &[u8], seed_hash: &str) -> [u8; 32] {
+        use blake2::{Blake2s256, Digest};
+        Blake2s256::new().chain_update(seed_hash.as_bytes()).chain_update(blob).finalize().into()
+    }
+
+    /// Hashes it was asked for
+    pub(crate) fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// The most hashes it had in flight at once
+    pub(crate) fn peak(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl PowVerifier for FakeVerifier {
+    fn verify<'a>(
+        &'a self,
+        blob: &'a [u8],
+        seed_hash: &'a str,
+        _height: u64,
+        timings: &'a mut HashTimings,
+    ) -> BoxFuture<'a, Result<[u8; 32], HashError>> {
+        self.hashes.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async move {
+            let hashing = std::time::Instant::now();
+            let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+            self.peak.fetch_max(in_flight, Ordering::Relaxed);
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            timings.hash += hashing.elapsed();
+            Ok(Self::hash(blob, seed_hash))
+        })
+    }
+
+    fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+}
@@ -0,0 +1,122 @@
+use once_cell::sync::OnceCell;
+use ring::hmac;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::config::{IpAnonymization, PrivacyConfig};
+use crate::error::CoordinatorError;
+
+/// Hex digits of the keyed hash shown in place of an address
+const HASH_HEX_LEN: usize = 16;
+
+static ANONYMIZER: OnceCell<IpAnonymizer> = OnceCell::new();
+
+/// Renders client addresses for logs, the event log and the admin API.
+/// Limits and bans never see its output; they keep the real address.
+pub enum IpAnonymizer {
+    Off,
+    /// IPv4 /24, IPv6 /48
+    Truncate,
+    Hmac(hmac::Key),
+}
+
+impl IpAnonymizer {
+    pub fn from_config(config: &PrivacyConfig) -> Result<Self, CoordinatorError> {
+        match config.anonymize_ips {
+            IpAnonymization::Off => Ok(Self::Off),
+            IpAnonymization::Truncate => Ok(Self::Truncate),
+            IpAnonymization::Hmac => match config.hmac_secret.as_deref() {
+                Some(secret) if !secret.is_empty() => Ok(Self::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))),
+                _ => Err(CoordinatorError::Config("privacy.hmac_secret is required with anonymize_ips = \"hmac\"".into())),
+            },
+        }
+    }
+
+    pub fn display(&self, ip: &IpAddr) -> String {
+        // An IPv4-mapped address is an IPv4 client
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match self {
+            Self::Off => ip.to_string(),
+            Self::Truncate => match ip {
+                IpAddr::V4(v4) => Ipv4Addr::from(u32::from(v4) & !0xff).to_string(),
+                IpAddr::V6(v6) => Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1)).to_string(),
+            },
+            Self::Hmac(key) => {
+                let tag = hmac::sign(key, ip.to_string().as_bytes());
+                let mut hashed = hex::encode(tag.as_ref());
+                hashed.truncate(HASH_HEX_LEN);
+                hashed
+            }
+        }
+    }
+}
+
+/// Make `display_ip` follow the configured mode. Only the first call takes effect.
+pub fn install(config: &PrivacyConfig) -> Result<(), CoordinatorError> {
+    let anonymizer = IpAnonymizer::from_config(config)?;
+    let _ = ANONYMIZER.set(anonymizer);
+    Ok(())
+}
+
+/// A client address as it may be written anywhere outside the process
+pub fn display_ip(ip: &IpAddr) -> String {
+    ANONYMIZER.get().unwrap_or(&IpAnonymizer::Off).display(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{SessionManager, SessionManagerConfig};
+
+    fn anonymizer(mode: IpAnonymization, secret: Option<&str>) -> IpAnonymizer {
+        IpAnonymizer::from_config(&PrivacyConfig { anonymize_ips: mode, hmac_secret: secret.map(String::from) }).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_off_shows_the_address() {
+        let off = anonymizer(IpAnonymization::Off, None);
+        assert_eq!(off.display(&ip("198.51.100.7")), "198.51.100.7");
+        assert_eq!(off.display(&ip("2001:db8::1")), "2001:db8::1");
+        assert_eq!(display_ip(&ip("198.51.100.7")), "198.51.100.7");
+    }
+
+    #[test]
+    fn test_truncate_zeroes_host_bits() {
+        let truncate = anonymizer(IpAnonymization::Truncate, None);
+        assert_eq!(truncate.display(&ip("198.51.100.7")), "198.51.100.0");
+        assert_eq!(truncate.display(&ip("::ffff:198.51.100.7")), "198.51.100.0");
+        assert_eq!(truncate.display(&ip("2001:db8:1:2:3:4:5:6")), "2001:db8:1::");
+    }
+
+    #[test]
+    fn test_hmac_is_stable_per_secret() {
+        let hmac = anonymizer(IpAnonymization::Hmac, Some("secret"));
+        let hashed = hmac.display(&ip("198.51.100.7"));
+        assert_eq!(hashed.len(), HASH_HEX_LEN);
+        assert!(hashed.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hmac.display(&ip("198.51.100.7")), hashed);
+        assert_ne!(hmac.display(&ip("198.51.100.8")), hashed);
+        assert_ne!(anonymizer(IpAnonymization::Hmac, Some("other")).display(&ip("198.51.100.7")), hashed);
+
+        let missing = PrivacyConfig { anonymize_ips: IpAnonymization::Hmac, hmac_secret: None };
+        assert!(IpAnonymizer::from_config(&missing).is_err());
+    }
+
+    #[test]
+    fn test_limits_key_on_the_real_address() {
+        // Both addresses render the same when truncated, but each gets its own slot
+        let truncate = anonymizer(IpAnonymization::Truncate, None);
+        assert_eq!(truncate.display(&ip("198.51.100.1")), truncate.display(&ip("198.51.100.2")));
+
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 10, ..Default::default() });
+        assert!(manager.create_session(ip("198.51.100.1")).is_some());
+        assert!(manager.create_session(ip("198.51.100.2")).is_some());
+        assert!(manager.create_session(ip("198.51.100.1")).is_none());
+    }
+}
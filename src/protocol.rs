@@ -1,4 +1,86 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Protocol version spoken by this coordinator (the `v` in hello)
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// WebSocket subprotocol for the current major protocol version
+pub const SUBPROTOCOL_V1: &str = "mwc.v1";
+
+/// Longest worker name a hello may carry
+pub const MAX_WORKER_LEN: usize = 64;
+
+/// Worker names are short printable ASCII, so they are safe in logs and labels
+pub fn is_valid_worker(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_WORKER_LEN && name.bytes().all(|b| (0x20..=0x7e).contains(&b))
+}
+
+/// How messages are framed on a WebSocket; both carry the same JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Text,
+    Binary,
+}
+
+/// How the client runs RandomX: native miners use the fast mode's full
+/// dataset, browser miners usually the much slower light mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RandomxMode {
+    Fast,
+    Light,
+    #[default]
+    Unknown,
+}
+
+impl RandomxMode {
+    pub const ALL: [RandomxMode; 3] = [RandomxMode::Fast, RandomxMode::Light, RandomxMode::Unknown];
+
+    /// Missing and unrecognised values are Unknown
+    pub fn from_hello(value: Option<&str>) -> Self {
+        match value {
+            Some("fast") => Self::Fast,
+            Some("light") => Self::Light,
+            _ => Self::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fast => "fast",
+            Self::Light => "light",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Optional features a client asks for in hello
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HelloOptions {
+    /// A hello sent as a binary frame asks for binary without saying so
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    /// Job targets as 8 or 16 hex digits, stratum style
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub compact_target: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub batching: bool,
+    /// Proof-of-work algorithm the client mines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algo: Option<String>,
+    /// Preferred interval of server pings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_ms: Option<u64>,
+    /// "fast" or "light"; anything else is recorded as unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub randomx_mode: Option<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -9,11 +91,19 @@ pub enum ClientMessage {
         threads: u8,
         #[serde(skip_serializing_if = "Option::is_none")]
         site_token: Option<String>,
+        /// Operator-chosen name for the embedding page, e.g. "blog-footer"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        worker: Option<String>,
+        #[serde(flatten)]
+        options: HelloOptions,
     },
     Submit {
         id: String,
         job_id: String,
         nonce: String,  // 4-byte nonce as hex (8 chars)
+        /// Hash the miner got for the nonce, as 32 bytes of hex
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<String>,
     },
     Ping {
         id: String,
@@ -28,6 +118,24 @@ pub enum ServerMessage {
         session_id: String,
         submits_per_minute: u32,
         messages_per_second: u32,
+        /// Interval of unsolicited stats pushes; 0 when the server sends none
+        stats_interval_ms: u64,
+        accepted: u64,
+        rejected: u64,
+        stale: u64,
+        /// Total difficulty of the session's accepted submits
+        accepted_shares: u64,
+        /// Unix time in milliseconds of the last accepted submit
+        last_accept_ms: Option<u64>,
+        /// Hashes per second implied by the accepted shares; null before the first
+        #[serde(default)]
+        estimated_hashrate: Option<f64>,
+        /// Height of the current template, if any
+        tip_height: Option<u64>,
+        server_time_ms: u64,
+        /// What the connection has been sent so far
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        outbound: Option<OutboundCounters>,
     },
     Job {
         job_id: String,
@@ -48,13 +156,38 @@ pub enum ServerMessage {
         id: Option<String>,
         code: ErrorCode,
         message: String,
+        /// Machine-readable context, such as which limit was hit
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        details: Option<serde_json::Value>,
     },
     Pong {
         id: String,
     },
+    Goodbye {
+        reason: GoodbyeReason,
+        retry_after_ms: u64,
+    },
+    /// The session's limits changed, e.g. on a configuration reload
+    PolicyUpdate {
+        submits_per_minute: u32,
+        messages_per_second: u32,
+        max_inflight_submits: u32,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Frames written to one connection and the state of its outbound queue
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutboundCounters {
+    /// Frames written, by message type; control frames count as `ping` and `close`
+    pub messages_sent: BTreeMap<String, u64>,
+    /// Payload bytes of the data frames written
+    pub bytes_sent: u64,
+    pub queue_depth: usize,
+    /// Deepest the queue has been
+    pub queue_high_watermark: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum SubmitStatus {
     Accepted,
@@ -63,7 +196,7 @@ pub enum SubmitStatus {
     Error,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ErrorCode {
     BadFormat,
@@ -72,14 +205,64 @@ pub enum ErrorCode {
     InvalidData,
     InternalError,
     NotReady,
+    HandshakeTimeout,
+    Unauthorized,
+    /// A submit named a job that was issued to another session
+    BadJob,
+    /// A submit's result hash is not the hash of its nonce
+    BadPow,
+    /// The verification queue is full; send the submit again later
+    Busy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GoodbyeReason {
+    Shutdown,
+    SlowClient,
+    /// The session reached `max_session_lifetime_secs`; reconnect and hello again
+    SessionExpired,
+}
+
+impl ClientMessage {
+    /// Message type as it appears in the `type` tag
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Hello { .. } => "hello",
+            ClientMessage::Submit { .. } => "submit",
+            ClientMessage::Ping { .. } => "ping",
+        }
+    }
 }
 
 impl ServerMessage {
+    /// Message type as it appears in the `type` tag
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerMessage::Stats { .. } => "stats",
+            ServerMessage::Job { .. } => "job",
+            ServerMessage::SubmitResult { .. } => "submit_result",
+            ServerMessage::Error { .. } => "error",
+            ServerMessage::Pong { .. } => "pong",
+            ServerMessage::Goodbye { .. } => "goodbye",
+            ServerMessage::PolicyUpdate { .. } => "policy_update",
+        }
+    }
+
     pub fn error(id: Option<String>, code: ErrorCode, message: impl Into<String>) -> Self {
         Self::Error {
             id,
             code,
             message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Attach details to an `Error`; other messages are returned unchanged
+    pub fn with_details(mut self, value: serde_json::Value) -> Self {
+        if let Self::Error { details, .. } = &mut self {
+            *details = Some(value);
         }
+        self
     }
 }
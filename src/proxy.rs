@@ -0,0 +1,182 @@
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// Resolve the real client IP for a connection.
+///
+/// Forwarding headers are only honoured when the direct peer is a trusted proxy;
+/// otherwise they are ignored so untrusted clients cannot spoof their address.
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    if !is_trusted(peer, trusted_proxies) {
+        return peer;
+    }
+
+    if let Some(ip) = from_forwarded_for(peer, headers, trusted_proxies) {
+        return ip;
+    }
+
+    headers
+        .get(X_REAL_IP)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_ip)
+        .unwrap_or(peer)
+}
+
+pub(crate) fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    let ip = canonical(ip);
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// Walk X-Forwarded-For from right to left and return the first hop that is
+/// not a trusted proxy. A malformed hop stops the walk, and the last hop we
+/// could verify is used instead.
+fn from_forwarded_for(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let mut hops: Vec<&str> = Vec::new();
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        let value = value.to_str().ok()?;
+        hops.extend(value.split(',').map(str::trim).filter(|h| !h.is_empty()));
+    }
+
+    if hops.is_empty() {
+        return None;
+    }
+
+    let mut last = peer;
+    for hop in hops.iter().rev() {
+        let ip = match parse_ip(hop) {
+            Some(ip) => ip,
+            None => return Some(last),
+        };
+        if !is_trusted(ip, trusted_proxies) {
+            return Some(ip);
+        }
+        last = ip;
+    }
+
+    // Every hop was a trusted proxy; the leftmost one is the best we have
+    Some(last)
+}
+
+/// Parse a header hop, accepting bare addresses as well as `ip:port` and `[ipv6]:port`
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()));
+    }
+    value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse::<IpAddr>().ok())
+        .map(canonical)
+}
+
+/// Collapse IPv4-mapped IPv6 addresses so dual-stack listeners count them as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "fd00::/8".parse().unwrap(),
+        ]
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_headers() {
+        let peer: IpAddr = "203.0.113.5".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "5.6.7.8")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), peer);
+    }
+
+    #[test]
+    fn test_trusted_peer_uses_forwarded_for() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "198.51.100.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_nested_proxies_pick_rightmost_untrusted() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        // Client spoofs a leading hop; the real client is the rightmost untrusted entry
+        let h = headers(&[("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.1.2.3")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "198.51.100.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_multiple_forwarded_for_headers() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-forwarded-for", "10.9.9.9")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "198.51.100.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_all_hops_trusted_uses_leftmost() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "10.3.3.3, 10.2.2.2")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "10.3.3.3".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_malformed_hop_falls_back_to_last_verified() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "not-an-ip, 10.2.2.2")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "10.2.2.2".parse::<IpAddr>().unwrap());
+
+        let h = headers(&[("x-forwarded-for", "garbage")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), peer);
+    }
+
+    #[test]
+    fn test_malformed_real_ip_falls_back_to_peer() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-real-ip", "999.1.1.1")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), peer);
+    }
+
+    #[test]
+    fn test_real_ip_used_without_forwarded_for() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-real-ip", "198.51.100.7")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "198.51.100.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_ipv6_hops_and_ports() {
+        let peer: IpAddr = "fd00::1".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "[2001:db8::5]:4711, fd12::2")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "2001:db8::5".parse::<IpAddr>().unwrap());
+
+        let h = headers(&[("x-forwarded-for", "2001:db8::9")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "2001:db8::9".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_ipv4_mapped_peer_is_trusted() {
+        let peer: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "198.51.100.7:1234")]);
+        assert_eq!(resolve_client_ip(peer, &h, &trusted()), "198.51.100.7".parse::<IpAddr>().unwrap());
+    }
+}
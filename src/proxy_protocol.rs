@@ -0,0 +1,337 @@
+use axum::extract::ConnectInfo;
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use ipnet::IpNet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::TcpStream;
+use tower::Layer;
+
+/// Give up on a connection that has not sent its PROXY header by then
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest possible v1 header, including the trailing CRLF
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Acceptor that strips a PROXY protocol (v1 or v2) header from each
+/// connection and exposes the original client address as `ConnectInfo`.
+///
+/// Only peers in `trusted_proxies` may speak for a client; connections from
+/// anyone else, and connections without a valid header, are dropped. Serve
+/// with `into_make_service()` so the peer address does not override ours.
+#[derive(Clone, Debug)]
+pub struct ProxyProtocolAcceptor {
+    trusted_proxies: Arc<[IpNet]>,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new(trusted_proxies: &[IpNet]) -> Self {
+        Self {
+            trusted_proxies: trusted_proxies.into(),
+        }
+    }
+}
+
+impl<S> Accept<TcpStream, S> for ProxyProtocolAcceptor
+where
+    S: Send + 'static,
+{
+    type Stream = BufReader<TcpStream>;
+    type Service = AddExtension<S, ConnectInfo<SocketAddr>>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let trusted_proxies = self.trusted_proxies.clone();
+        Box::pin(async move {
+            let peer = stream.peer_addr()?;
+            // Anyone can write a PROXY header, so only believe the ones our balancer sends
+            if !crate::proxy::is_trusted(peer.ip(), &trusted_proxies) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("PROXY header from untrusted peer {}", peer.ip()),
+                ));
+            }
+            // Bytes after the header stay buffered and are read by the HTTP parser
+            let mut stream = BufReader::new(stream);
+            let source = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timeout"))??;
+
+            let client = source.unwrap_or(peer);
+            Ok((stream, Extension(ConnectInfo(client)).layer(service)))
+        })
+    }
+}
+
+/// Read and consume a PROXY protocol header.
+///
+/// Returns the original source address, or `None` when the header carries no
+/// address (v1 `UNKNOWN`, v2 `LOCAL` or a non-IP family) and the peer address
+/// should be used instead.
+pub async fn read_header<R>(reader: &mut BufReader<R>) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 12];
+    reader.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if prefix.starts_with(b"PROXY ") {
+        let mut line = prefix.to_vec();
+        (&mut *reader)
+            .take((V1_MAX_LEN - prefix.len()) as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        parse_v1(&line)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| invalid("PROXY v1 header not terminated"))?;
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+
+    let mut fields = line.split(' ');
+    fields.next(); // "PROXY"
+    match fields.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") | Some("TCP6") => {}
+        _ => return Err(invalid("unsupported PROXY v1 protocol")),
+    }
+
+    let src: IpAddr = parse_field(fields.next())?;
+    let _dst: IpAddr = parse_field(fields.next())?;
+    let src_port: u16 = parse_field(fields.next())?;
+    let _dst_port: u16 = parse_field(fields.next())?;
+    if fields.next().is_some() {
+        return Err(invalid("trailing data in PROXY v1 header"));
+    }
+
+    Ok(Some(SocketAddr::new(src, src_port)))
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&str>) -> io::Result<T> {
+    field
+        .and_then(|f| f.parse().ok())
+        .ok_or_else(|| invalid("malformed PROXY v1 address"))
+}
+
+async fn read_v2<R>(reader: &mut BufReader<R>) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut fixed = [0u8; 4];
+    reader.read_exact(&mut fixed).await?;
+    let [ver_cmd, family, len_hi, len_lo] = fixed;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY v2 version"));
+    }
+
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    reader.read_exact(&mut body).await?;
+
+    match ver_cmd & 0x0f {
+        // LOCAL: health checks from the proxy itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    match family >> 4 {
+        0x1 => {
+            if body.len() < 12 {
+                return Err(invalid("truncated PROXY v2 IPv4 address"));
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 => {
+            if body.len() < 36 {
+                return Err(invalid("truncated PROXY v2 IPv6 address"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // UNSPEC or UNIX: no usable client address
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server;
+    use tokio::io::AsyncWriteExt;
+
+    async fn parse(bytes: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let mut reader = BufReader::new(bytes);
+        read_header(&mut reader).await
+    }
+
+    fn v2_header(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(ver_cmd);
+        header.push(family);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    fn v2_ipv4(src: [u8; 4], port: u16) -> Vec<u8> {
+        let mut body = src.to_vec();
+        body.extend_from_slice(&[10, 0, 0, 1]);
+        body.extend_from_slice(&port.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        v2_header(0x21, 0x11, &body)
+    }
+
+    #[tokio::test]
+    async fn test_v1_tcp4_and_tcp6() {
+        let addr = parse(b"PROXY TCP4 198.51.100.7 10.0.0.1 51234 443\r\n").await.unwrap();
+        assert_eq!(addr, Some("198.51.100.7:51234".parse().unwrap()));
+
+        let addr = parse(b"PROXY TCP6 2001:db8::5 2001:db8::1 4711 443\r\n").await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::5]:4711".parse().unwrap()));
+
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_v1_malformed() {
+        assert!(parse(b"PROXY TCP4 not-an-ip 10.0.0.1 1 2\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 198.51.100.7 10.0.0.1 51234 443\n").await.is_err());
+        assert!(parse(b"PROXY UDP4 198.51.100.7 10.0.0.1 1 2\r\n").await.is_err());
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(120));
+        assert!(parse(long.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_addresses() {
+        let addr = parse(&v2_ipv4([198, 51, 100, 7], 51234)).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.7:51234".parse().unwrap()));
+
+        let mut body = "2001:db8::5".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&4711u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        let addr = parse(&v2_header(0x21, 0x21, &body)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::5]:4711".parse().unwrap()));
+
+        assert_eq!(parse(&v2_header(0x20, 0x00, &[])).await.unwrap(), None);
+        assert!(parse(&v2_header(0x21, 0x11, &[1, 2, 3])).await.is_err());
+        assert!(parse(&v2_header(0x11, 0x11, &[0; 12])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_header_rejected() {
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_leaves_following_bytes_buffered() {
+        let mut input = v2_ipv4([198, 51, 100, 7], 1).to_vec();
+        input.extend_from_slice(b"GET /");
+        let mut reader = BufReader::new(&input[..]);
+        read_header(&mut reader).await.unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET /");
+    }
+
+    async fn spawn_server_trusting(
+        trusted_proxies: &[&str],
+    ) -> (SocketAddr, std::sync::Arc<crate::session::SessionManager>) {
+        let (mut state, template_tx) = server::tests::test_state();
+        state.config.server.proxy_protocol = true;
+        state.config.server.trusted_proxies = trusted_proxies.iter().map(|net| net.parse().unwrap()).collect();
+        let sessions = state.session_manager.clone();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _template_tx = template_tx;
+            server::serve(listener, state).await.unwrap();
+        });
+        (addr, sessions)
+    }
+
+    async fn spawn_server() -> (SocketAddr, std::sync::Arc<crate::session::SessionManager>) {
+        spawn_server_trusting(&["127.0.0.1/32"]).await
+    }
+
+    #[tokio::test]
+    async fn test_serves_http_after_v1_header() {
+        let (addr, _) = spawn_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PROXY TCP4 198.51.100.7 10.0.0.1 51234 443\r\nGET /livez HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_sees_v2_client_address() {
+        let (addr, sessions) = spawn_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&v2_ipv4([198, 51, 100, 7], 51234)).await.unwrap();
+        let (_ws, _) = tokio_tungstenite::client_async(format!("ws://{}/ws", addr), stream)
+            .await
+            .unwrap();
+
+        let client: IpAddr = "198.51.100.7".parse().unwrap();
+        for _ in 0..50 {
+            if sessions.list_sessions().iter().any(|s| s.ip == client) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("session was not registered under the PROXY source address");
+    }
+
+    #[tokio::test]
+    async fn test_connection_without_header_is_closed() {
+        let (addr, _) = spawn_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /livez HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_header_from_untrusted_peer_is_refused() {
+        let (addr, _) = spawn_server_trusting(&["10.0.0.0/8"]).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"PROXY TCP4 198.51.100.7 10.0.0.1 51234 443\r\nGET /livez HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+}
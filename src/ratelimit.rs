@@ -33,9 +33,31 @@ impl RateLimiter {
         true
     }
 
+    /// Time until the oldest counted event leaves the window
+    pub fn retry_after(&self) -> Duration {
+        self.timestamps
+            .front()
+            .map(|&t| (t + self.window).saturating_duration_since(Instant::now()))
+            .unwrap_or_default()
+    }
+
+    /// True once every counted event has left the window
+    pub fn is_idle(&self) -> bool {
+        self.timestamps
+            .back()
+            .map(|&t| t.elapsed() >= self.window)
+            .unwrap_or(true)
+    }
+
     pub fn remaining(&self) -> u32 {
         self.max_count.saturating_sub(self.timestamps.len() as u32)
     }
+
+    /// Change the limit, keeping the events already counted in the window:
+    /// a lower limit grants no fresh burst, a higher one applies at once
+    pub fn set_max_count(&mut self, max_count: u32) {
+        self.max_count = max_count;
+    }
 }
 
 pub struct SessionLimits {
@@ -50,4 +72,10 @@ impl SessionLimits {
             submits: RateLimiter::new(submits_per_minute, 60),
         }
     }
+
+    /// Switch to new limits without forgetting recent messages and submits
+    pub fn apply(&mut self, messages_per_second: u32, submits_per_minute: u32) {
+        self.messages.set_max_count(messages_per_second);
+        self.submits.set_max_count(submits_per_minute);
+    }
 }
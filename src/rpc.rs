@@ -38,11 +38,14 @@ struct RpcErrorResponse {
     message: String,
 }
 
+/// Largest reserve `get_block_template` grants; monerod refuses more
+pub const MAX_RESERVE_SIZE: u32 = 255;
+
 // get_block_template request/response
 #[derive(Serialize)]
 pub struct GetBlockTemplateParams {
     pub wallet_address: String,
-    pub reserve_size: u8,
+    pub reserve_size: u32,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -55,6 +58,9 @@ pub struct BlockTemplate {
     pub prev_hash: String,
     pub reserved_offset: usize,
     pub seed_hash: String,
+    /// Seed for the next epoch, set by monerod close to the switch height
+    #[serde(default)]
+    pub next_seed_hash: String,
     pub status: String,
 }
 
@@ -113,7 +119,7 @@ impl MonerodClient {
     pub async fn get_block_template(
         &self,
         wallet_address: &str,
-        reserve_size: u8,
+        reserve_size: u32,
     ) -> Result<BlockTemplate, RpcError> {
         self.call(
             "get_block_template",
@@ -138,6 +144,25 @@ impl MonerodClient {
         Ok(result.status)
     }
 
+    /// Hash `block_blob_hex`, the hashing blob of a block of `major_version`
+    /// at `height`, with the RandomX key `seed_hash`
+    pub async fn calc_pow(
+        &self,
+        major_version: u8,
+        height: u64,
+        block_blob_hex: &str,
+        seed_hash: &str,
+    ) -> Result<String, RpcError> {
+        #[derive(Serialize)]
+        struct CalcPowParams<'a> {
+            major_version: u8,
+            height: u64,
+            block_blob: &'a str,
+            seed_hash: &'a str,
+        }
+        self.call("calc_pow", CalcPowParams { major_version, height, block_blob: block_blob_hex, seed_hash }).await
+    }
+
     pub async fn get_info(&self) -> Result<DaemonInfo, RpcError> {
         #[derive(Serialize)]
         struct Empty {}
@@ -0,0 +1,94 @@
+//! Hashing over monerod's `calc_pow`, for hosts that cannot spare the memory
+//! RandomX takes, or as a fallback when its cache or VMs cannot be made.
+//!
+//! Every hash is a round trip to the daemon, and a costly one for it, so
+//! calls are capped both in how many run at once and in how many start per
+//! second. A hash past the rate is refused rather than queued.
+
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+
+use crate::error::CoordinatorError;
+use crate::metrics::Metrics;
+use crate::pow::PowVerifier;
+use crate::ratelimit::RateLimiter;
+use crate::rpc::MonerodClient;
+use crate::validator::{HashError, HashTimings};
+
+pub struct RpcVerifier {
+    client: Arc<MonerodClient>,
+    /// Calls that may be waiting on monerod at once
+    max_concurrent: usize,
+    calls: Semaphore,
+    rate: Mutex<RateLimiter>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl RpcVerifier {
+    pub fn new(client: Arc<MonerodClient>, max_concurrent: usize, per_second: u32) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+        Self {
+            client,
+            max_concurrent,
+            calls: Semaphore::new(max_concurrent),
+            rate: Mutex::new(RateLimiter::new(per_second, 1)),
+            metrics: None,
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Have monerod hash `blob`, the hashing blob of a block at `height`,
+    /// with the RandomX key `seed_hash`
+    async fn hash(&self, seed_hash: &str, height: u64, blob: &[u8]) -> Result<[u8; 32], HashError> {
+        let metrics = self.metrics.as_deref();
+        if !self.rate.lock().check() {
+            if let Some(metrics) = metrics {
+                metrics.inc_rpc_pow_limited();
+            }
+            return Err(HashError::Unavailable(CoordinatorError::Validation("monerod calc_pow rate limit reached".into())));
+        }
+        let _call = self.calls.acquire().await.expect("the semaphore is never closed");
+
+        // The block's major version leads the hashing blob, one varint byte
+        // for any version monerod knows
+        let major_version = blob.first().copied().unwrap_or_default();
+        let result = self.client.calc_pow(major_version, height, &hex::encode(blob), seed_hash).await;
+        if let Some(metrics) = metrics {
+            metrics.inc_rpc_pow_calls(result.is_ok());
+        }
+        let hash = result.map_err(|e| HashError::Unavailable(CoordinatorError::Validation(format!("monerod calc_pow failed: {}", e))))?;
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(&hash, &mut bytes)
+            .map_err(|_| HashError::Failed(CoordinatorError::Validation(format!("monerod calc_pow returned {:?}", hash))))?;
+        Ok(bytes)
+    }
+}
+
+impl PowVerifier for RpcVerifier {
+    fn verify<'a>(
+        &'a self,
+        blob: &'a [u8],
+        seed_hash: &'a str,
+        height: u64,
+        timings: &'a mut HashTimings,
+    ) -> BoxFuture<'a, Result<[u8; 32], HashError>> {
+        Box::pin(async move {
+            let hashing = Instant::now();
+            let hash = self.hash(seed_hash, height, blob).await;
+            timings.hash += hashing.elapsed();
+            timings.rpc = true;
+            hash
+        })
+    }
+
+    fn concurrency(&self) -> usize {
+        self.max_concurrent
+    }
+}
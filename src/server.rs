@@ -1,29 +1,76 @@
 use axum::{
-    Router,
+    Json, Router,
     routing::get,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     extract::{
-        ws::{WebSocket, WebSocketUpgrade, Message},
+        ws::{close_code, CloseFrame, WebSocket, WebSocketUpgrade, Message},
         State, ConnectInfo,
     },
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
+    middleware,
 };
-use tower_http::cors::{CorsLayer, Any};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use tower_http::trace::TraceLayer;
-use tracing::{info, warn};
+use tracing::{debug, error, info, instrument, warn, Instrument, Span};
+use futures::{FutureExt, Sink, Stream, StreamExt};
+use std::fmt::Display;
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
-use anyhow::Result;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
 use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use rand::Rng;
 
-use crate::config::Config;
-use crate::jobs::JobManager;
-use crate::metrics::Metrics;
-use crate::protocol::{ClientMessage, ServerMessage, ErrorCode, SubmitStatus};
+use crate::access;
+use crate::admin;
+use crate::assets;
+use crate::auth::{self, SiteAuth};
+use crate::ban::BanManager;
+use crate::capabilities::SessionCapabilities;
+use crate::config::{Config, LimitsConfig};
+use crate::error::CoordinatorError;
+use crate::cors;
+use crate::events::{EventKind, EventLog};
+use crate::extranonce::ExtraNonce;
+use crate::fanout::{self, Fanout};
+use crate::health::{self, DaemonStatus};
+use crate::jobs::{compact_target_hex, hash_to_difficulty, HashClass, Job, JobError, JobId, JobLookup, JobManager, NonceStatus};
+use crate::keepalive::Keepalive;
+use crate::longpoll;
+use crate::metrics::{self, Metrics};
+use crate::outbound::{self, Outbox};
+use crate::pow::PowVerifier;
+use crate::privacy;
+use crate::protocol::{self, ClientMessage, Encoding, RandomxMode, ServerMessage, ErrorCode, GoodbyeReason, SubmitStatus, SUBPROTOCOL_V1};
+use crate::proxy;
+use crate::proxy_protocol::ProxyProtocolAcceptor;
 use crate::rpc::MonerodClient;
-use crate::session::{SessionManager, SessionState};
+use crate::session::{SessionManager, SessionState, SiteSummary};
+use crate::sse;
+use crate::stats::CoordinatorStats;
 use crate::template::TemplateState;
-use crate::validator::SubmissionValidator;
+use crate::tls;
+use crate::validator::{SubmissionValidator, ValidationError, ValidationOutcome, VerifierRung};
+use crate::version;
+
+/// Out-of-state messages tolerated before the connection is closed
+const MAX_PROTOCOL_VIOLATIONS: u32 = 3;
+
+/// How long a closing connection waits for its writer to flush
+const WRITER_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// After closing on a bad message, how long to keep the socket open so the
+/// client reads the close frame before its unread input makes the OS reset it
+const CLOSE_LINGER: Duration = Duration::from_secs(1);
+
+/// Longest reason a close frame can carry (125-byte control payload less the code)
+const MAX_CLOSE_REASON_BYTES: usize = 123;
+
+/// Retry hint for a submit refused because the session's verification slots are taken
+const SUBMIT_BUSY_RETRY_MS: u64 = 250;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -32,10 +79,20 @@ pub struct AppState {
     pub session_manager: Arc<SessionManager>,
     pub job_manager: Arc<JobManager>,
     pub validator: Arc<SubmissionValidator>,
+    /// Hashes submissions, under the validator's cache, queue and step down
+    pub pow: Arc<dyn PowVerifier>,
     pub metrics: Arc<Metrics>,
+    pub daemon_status: Arc<DaemonStatus>,
+    pub bans: Arc<BanManager>,
+    pub fanout: Arc<Fanout>,
+    pub events: EventLog,
     pub config: Config,
+    pub shutdown: CancellationToken,
+    pub connections: TaskTracker,
+    pub started_at: Instant,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     config: Config,
     template_rx: watch::Receiver<Option<TemplateState>>,
@@ -43,315 +100,3306 @@ pub async fn run(
     session_manager: Arc<SessionManager>,
     job_manager: Arc<JobManager>,
     validator: Arc<SubmissionValidator>,
+    pow: Arc<dyn PowVerifier>,
     metrics: Arc<Metrics>,
+    daemon_status: Arc<DaemonStatus>,
+    bans: Arc<BanManager>,
+    events: EventLog,
+    shutdown: CancellationToken,
 ) -> Result<()> {
     let state = AppState {
-        template_rx, rpc_client, session_manager, job_manager, validator, metrics,
+        template_rx, rpc_client, session_manager, job_manager, validator, pow, metrics, daemon_status, bans, events,
+        fanout: Arc::new(Fanout::new()),
         config: config.clone(),
+        shutdown,
+        connections: TaskTracker::new(),
+        started_at: Instant::now(),
     };
 
-    let ws_path = config.server.ws_path.clone();
-    
-    let app = Router::new()
-        .route("/health", get(health_check))
+    tokio::spawn(fanout::run(state.clone()));
+
+    let listeners = bind_listeners(&config.server.bind_addr).await?;
+
+    let tls = match &config.server.tls {
+        Some(tls_config) => {
+            let rustls = tls::load_rustls_config(tls_config).await?;
+            tls::spawn_reload_on_sighup(rustls.clone(), tls_config.clone());
+            Some(rustls)
+        }
+        None => None,
+    };
+
+    serve_all(listeners, state, tls).await
+}
+
+/// Bind every configured address before serving on any, so one that is
+/// unusable stops startup and is named in the error.
+pub(crate) async fn bind_listeners(addrs: &[String]) -> Result<Vec<tokio::net::TcpListener>> {
+    if addrs.is_empty() {
+        anyhow::bail!("server.bind_addr lists no addresses");
+    }
+
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let parsed: SocketAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid bind address '{}'", addr))?;
+        let listener = tokio::net::TcpListener::bind(parsed)
+            .await
+            .with_context(|| format!("Failed to bind {}", addr))?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+pub(crate) fn router(state: AppState) -> Router {
+    let ws_path = state.config.server.ws_path.clone();
+
+    let mut app = Router::new()
+        .route("/health", get(health::readyz))
+        .route("/livez", get(health::livez))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(version::version_handler))
+        .route(&ws_path, get(ws_handler));
+
+    if state.config.server.long_polling {
+        app = app.nest("/v1", longpoll::router().merge(sse::router()));
+    }
+
+    // Operational routes, behind server.protected_routes
+    let mut protected = Router::new()
         .route("/stats", get(stats_handler))
-        .route(&ws_path, get(ws_handler))
+        .route("/stats/tokens", get(site_stats_handler));
+    if state.config.admin.enable {
+        if state.config.admin.token.is_empty() {
+            warn!("admin.enable is set but admin.token is empty; admin API disabled");
+        } else {
+            protected = protected.nest("/admin", admin::router(state.clone()));
+        }
+    }
+    app = app.merge(protected.route_layer(middleware::from_fn_with_state(state.clone(), access::require_access)));
+
+    if let Some(dir) = &state.config.server.static_dir {
+        app = app.fallback_service(assets::router(dir));
+    }
+
+    app
         .layer(TraceLayer::new_for_http())
-        .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
-        .with_state(state);
+        .layer(cors::cors_layer(&state.config.server.allowed_origins))
+        .with_state(state)
+}
 
-    let addr: SocketAddr = config.server.bind_addr.parse()?;
-    info!("Server listening on {}", addr);
+/// Serve plain HTTP/WebSocket on an already-bound listener until shutdown,
+/// then wait for open sessions to say goodbye.
+#[cfg(test)]
+pub(crate) async fn serve(listener: tokio::net::TcpListener, state: AppState) -> Result<()> {
+    serve_all(vec![listener], state, None).await
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal())
-    .await?;
+/// Run one accept loop per listener, all sharing the same state, until
+/// shutdown; then drain the sessions opened on any of them.
+pub(crate) async fn serve_all(
+    listeners: Vec<tokio::net::TcpListener>,
+    state: AppState,
+    tls: Option<RustlsConfig>,
+) -> Result<()> {
+    let accept_loops = listeners
+        .into_iter()
+        .map(|listener| serve_listener(listener, state.clone(), tls.clone()));
+    let result = futures::future::try_join_all(accept_loops).await;
+
+    drain_connections(&state).await;
+    result.map(|_| ())
+}
+
+async fn serve_listener(
+    listener: tokio::net::TcpListener,
+    state: AppState,
+    tls: Option<RustlsConfig>,
+) -> Result<()> {
+    let addr = listener.local_addr()?;
+    let proxy_protocol = state.config.server.proxy_protocol;
+    let proxy_acceptor = ProxyProtocolAcceptor::new(&state.config.server.trusted_proxies);
 
+    match tls {
+        Some(rustls) => {
+            info!("Server listening on {} (TLS)", addr);
+            let listener = listener.into_std()?;
+            if proxy_protocol {
+                let acceptor = RustlsAcceptor::new(rustls).acceptor(proxy_acceptor);
+                axum_server::from_tcp(listener)
+                    .acceptor(acceptor)
+                    .handle(shutdown_handle(&state))
+                    .serve(router(state).into_make_service())
+                    .await?;
+            } else {
+                axum_server::from_tcp_rustls(listener, rustls)
+                    .handle(shutdown_handle(&state))
+                    .serve(router(state).into_make_service_with_connect_info::<SocketAddr>())
+                    .await?;
+            }
+        }
+        None if proxy_protocol => {
+            info!("Server listening on {}", addr);
+            // axum::serve cannot strip the PROXY header, so use axum-server's acceptor hook
+            axum_server::from_tcp(listener.into_std()?)
+                .acceptor(proxy_acceptor)
+                .handle(shutdown_handle(&state))
+                .serve(router(state).into_make_service())
+                .await?;
+        }
+        None => {
+            info!("Server listening on {}", addr);
+            let shutdown = state.shutdown.clone();
+            axum::serve(listener, router(state).into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await?;
+        }
+    }
     Ok(())
 }
 
-async fn health_check() -> impl IntoResponse {
-    (StatusCode::OK, "OK")
+/// An axum-server handle that starts a graceful shutdown when the coordinator stops
+fn shutdown_handle(state: &AppState) -> axum_server::Handle {
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+    handle
+}
+
+/// Upgraded WebSockets outlive the HTTP server, so wait for them separately
+async fn drain_connections(state: &AppState) {
+    state.connections.close();
+    let drain = Duration::from_millis(state.config.server.shutdown_drain_timeout_ms);
+    info!("Draining {} open connections", state.connections.len());
+
+    if tokio::time::timeout(drain, state.connections.wait()).await.is_err() {
+        warn!(
+            "Drain timeout elapsed with {} connections still open",
+            state.connections.len()
+        );
+    }
+}
+
+async fn stats_handler(State(state): State<AppState>) -> Json<CoordinatorStats> {
+    Json(CoordinatorStats::collect(&state))
 }
 
-async fn stats_handler(State(state): State<AppState>) -> impl IntoResponse {
-    let count = state.session_manager.active_count();
-    (StatusCode::OK, format!("{{\"active_sessions\":{}}}", count))
+async fn site_stats_handler(State(state): State<AppState>) -> Json<Vec<SiteSummary>> {
+    Json(state.session_manager.site_stats())
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
-    let ip = addr.ip();
-    ws.on_upgrade(move |socket| handle_socket(socket, state, ip))
+    headers: HeaderMap,
+) -> Response {
+    if !cors::upgrade_origin_allowed(&headers, &state.config.server.allowed_origins) {
+        warn!("Rejected WebSocket upgrade from {} with disallowed origin", privacy::display_ip(&addr.ip()));
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+
+    let subprotocol = match negotiate_subprotocol(&headers) {
+        Ok(p) => p,
+        Err(()) => {
+            return (StatusCode::BAD_REQUEST, "Unsupported WebSocket subprotocol").into_response();
+        }
+    };
+
+    let ip = proxy::resolve_client_ip(addr.ip(), &headers, &state.config.server.trusted_proxies);
+    if let Some(rejection) = admission_rejection(&state, ip) {
+        return rejection;
+    }
+    let connections = state.connections.clone();
+    let max_frame_bytes = state.config.server.max_frame_bytes;
+    let ws = ws.max_frame_size(max_frame_bytes).max_message_size(max_frame_bytes);
+    let ws = match subprotocol {
+        Some(p) => ws.protocols([p]),
+        None => ws,
+    };
+    ws.on_upgrade(move |socket| connections.track_future(handle_socket(socket, state, ip, subprotocol)))
+        .into_response()
+}
+
+/// Refuse a new session from a banned IP (403) or one over its connection
+/// rate (429 with Retry-After). Shared by every transport.
+pub(crate) fn admission_rejection(state: &AppState, ip: IpAddr) -> Option<Response> {
+    if state.bans.is_banned(&ip) {
+        state.metrics.inc_banned_connections();
+        return Some((StatusCode::FORBIDDEN, "Banned").into_response());
+    }
+    if let Err(retry_after) = state.session_manager.check_connection_rate(ip) {
+        state.metrics.inc_connections_rate_limited();
+        let retry_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        return Some(
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_secs.to_string())],
+                "Too many connection attempts",
+            )
+                .into_response(),
+        );
+    }
+    None
+}
+
+/// Pick the subprotocol to echo in the upgrade response.
+///
+/// Clients that offer none are accepted for backward compatibility; clients
+/// that offer only protocols we don't speak are refused.
+fn negotiate_subprotocol(headers: &HeaderMap) -> Result<Option<&'static str>, ()> {
+    let mut offered = headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .peekable();
+
+    if offered.peek().is_none() {
+        return Ok(None);
+    }
+    if offered.any(|p| p == SUBPROTOCOL_V1) {
+        Ok(Some(SUBPROTOCOL_V1))
+    } else {
+        Err(())
+    }
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, ip: IpAddr, subprotocol: Option<&'static str>) {
+    let (sink, stream) = socket.split();
+    run_connection(sink, stream, state, ip, subprotocol).await;
 }
 
-async fn handle_socket(mut socket: WebSocket, state: AppState, ip: IpAddr) {
-    let session = match state.session_manager.create_session(ip) {
+/// Drive one connection. Incoming frames, template updates and timers are
+/// handled here; everything outbound goes through a queue to a separate
+/// writer task so a slow client cannot stall message processing.
+///
+/// Everything logged while the connection is open, including from the
+/// writer task, carries the session's ip, id and client version.
+#[instrument(name = "session", skip_all, fields(ip = %privacy::display_ip(&ip), session_id = tracing::field::Empty, client_version = tracing::field::Empty))]
+pub(crate) async fn run_connection<W, R>(
+    sink: W,
+    mut stream: R,
+    state: AppState,
+    ip: IpAddr,
+    subprotocol: Option<&'static str>,
+)
+where
+    W: Sink<Message> + Unpin + Send + 'static,
+    W::Error: Display,
+    R: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let session = state.session_manager.create_session(ip);
+    let stats = session.as_ref().map(|s| s.outbound.clone()).unwrap_or_default();
+    let (outbox, outbox_rx) = outbound::channel(
+        state.config.server.outbound_queue_size,
+        Duration::from_millis(state.config.server.slow_client_timeout_ms),
+        stats,
+        state.metrics.clone(),
+    );
+    let mut writer = tokio::spawn(outbound::write_loop(sink, outbox_rx, state.metrics.clone()).in_current_span());
+
+    let session = match session {
         Some(s) => s,
         None => {
-            warn!("Connection rejected for IP: {} (limit exceeded)", ip);
+            warn!("Connection rejected for IP: {} (limit exceeded)", privacy::display_ip(&ip));
             let msg = ServerMessage::error(None, ErrorCode::RateLimit, "Connection limit exceeded");
-            let _ = socket.send(Message::Text(serde_json::to_string(&msg).unwrap())).await;
+            outbox.send(msg).await;
+            outbox.close(CloseFrame {
+                code: close_code::POLICY,
+                reason: "Connection limit exceeded".into(),
+            }).await;
+            drop(outbox);
+            finish_writer(writer).await;
             return;
         }
     };
 
     let session_id = session.id.clone();
-    info!("Session created: {} from {}", session_id, ip);
+    let close_signal = session.close_signal.clone();
+    Span::current().record("session_id", session_id.as_str());
+    state.fanout.register(&session_id, outbox.job_sink());
+    if let Some(p) = subprotocol {
+        state.session_manager.update_session(&session_id, |s| s.subprotocol = Some(p.to_string()));
+    }
 
     state.metrics.inc_connections();
+    let guard = SessionGuard { state: state.clone(), session_id: session_id.clone() };
+
+    let mut keepalive = Keepalive::new(
+        state.config.server.keepalive_interval_ms,
+        state.config.server.keepalive_timeout_ms,
+    );
+    let mut ping_ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + keepalive.interval(),
+        keepalive.interval(),
+    );
 
-    let mut template_rx = state.template_rx.clone();
+    // Unsolicited stats for dashboards next to the miner; 0 disables them
+    let stats_interval = Duration::from_millis(state.config.server.stats_interval_ms);
+    let push_stats = !stats_interval.is_zero();
+    let mut stats_ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + stats_interval,
+        stats_interval.max(Duration::from_millis(1)),
+    );
+
+    // Disarmed once the client says hello; until then the session holds a slot for free
+    let hello_deadline = tokio::time::sleep(Duration::from_millis(state.config.server.hello_timeout_ms));
+    tokio::pin!(hello_deadline);
+    let mut awaiting_hello = true;
+
+    // Forces a fresh handshake now and then; 0 disables it
+    let max_lifetime = Duration::from_secs(state.config.limits.max_session_lifetime_secs);
+    let expires = !max_lifetime.is_zero();
+    let lifetime_deadline = tokio::time::sleep(max_lifetime.saturating_sub(session.connected_at.elapsed()));
+    tokio::pin!(lifetime_deadline);
+
+    let mut writer_done = false;
+    let mut linger = false;
 
     loop {
         tokio::select! {
-            result = template_rx.changed() => {
-                if result.is_err() {
+            _ = &mut writer => {
+                // Socket is no longer writable
+                writer_done = true;
+                break;
+            }
+            _ = state.shutdown.cancelled() => {
+                say_goodbye(&outbox, &state, GoodbyeReason::Shutdown).await;
+                break;
+            }
+            _ = &mut lifetime_deadline, if expires => {
+                info!("Session {} reached its maximum lifetime", session_id);
+                // Submits that arrived alongside the deadline still get their result
+                while let Some(Some(Ok(frame))) = stream.next().now_or_never() {
+                    let payload = match frame {
+                        Message::Text(text) => text.into_bytes(),
+                        Message::Binary(data) => data,
+                        _ => continue,
+                    };
+                    if let Ok(submit @ ClientMessage::Submit { .. }) = serde_json::from_slice(&payload) {
+                        if let Some(response) = handle_message(&state, &session_id, submit).await {
+                            outbox.send(response).await;
+                        }
+                    }
+                }
+                say_goodbye(&outbox, &state, GoodbyeReason::SessionExpired).await;
+                break;
+            }
+            _ = close_signal.closed() => {
+                let reason = close_frame_reason(close_signal.reason().unwrap_or("Disconnected"));
+                info!("Session {} closed by the server: {}", session_id, reason);
+                outbox.close(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: reason.into(),
+                }).await;
+                break;
+            }
+            _ = &mut hello_deadline, if awaiting_hello => {
+                info!("Session {} sent no hello in time", session_id);
+                state.metrics.inc_handshake_timeouts();
+                let msg = ServerMessage::error(None, ErrorCode::HandshakeTimeout, "No hello received");
+                outbox.send(msg).await;
+                outbox.close(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Hello timeout".into(),
+                }).await;
+                break;
+            }
+            _ = ping_ticker.tick() => {
+                if keepalive.is_expired(tokio::time::Instant::now()) {
+                    info!("Session {} timed out waiting for pong", session_id);
+                    outbox.close(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "Keepalive timeout".into(),
+                    }).await;
                     break;
                 }
-                
-                // Send new job when template updates
-                let template_opt = template_rx.borrow().clone();
-                if let Some(template) = template_opt {
-                    if let Some(sess) = state.session_manager.get_session(&session_id) {
-                        if sess.state == SessionState::Ready {
-                            let job = state.job_manager.create_job(&template, &session_id);
-                            state.metrics.inc_jobs();
-                            state.session_manager.update_session(&session_id, |s| {
-                                s.update_job(job.job_id.clone(), job.reserved_value.clone());
-                            });
-                            
-                            let msg = ServerMessage::Job {
-                                job_id: job.job_id,
-                                blob_hex: job.blob_hex,
-                                reserved_offset: job.reserved_offset,
-                                reserved_value_hex: hex::encode(&job.reserved_value),
-                                target_hex: job.target_hex,
-                                height: job.height,
-                                seed_hash: job.seed_hash,
-                            };
-                            if socket.send(Message::Text(serde_json::to_string(&msg).unwrap())).await.is_err() {
-                                break;
-                            }
+                if !outbox.ping().await {
+                    break;
+                }
+            }
+            _ = stats_ticker.tick(), if push_stats && !awaiting_hello => {
+                // Stats are a convenience; a client that is behind gets its jobs first
+                if outbox.is_congested() {
+                    continue;
+                }
+                if !outbox.send(stats_message(&state, &session_id, None)).await {
+                    break;
+                }
+            }
+            msg = stream.next() => {
+                if let Some(Ok(_)) = &msg {
+                    keepalive.record_activity();
+                    // Any frame, pongs included, keeps the session clear of the idle sweep
+                    state.session_manager.update_session(&session_id, |s| s.touch());
+                }
+                let (payload, binary) = match msg {
+                    Some(Ok(Message::Text(text))) => (text.into_bytes(), false),
+                    Some(Ok(Message::Binary(data))) => {
+                        // Reply in kind from now on
+                        outbox.use_binary();
+                        (data, true)
+                    }
+                    Some(Ok(Message::Close(_))) => {
+                        outbox.close(CloseFrame {
+                            code: close_code::NORMAL,
+                            reason: "Normal closure".into(),
+                        }).await;
+                        break;
+                    }
+                    None => break,
+                    Some(Err(e)) => {
+                        warn!("WebSocket error: {}", e);
+                        if let Some(frame) = read_error_close(&e) {
+                            outbox.close(frame).await;
+                            linger = true;
                         }
+                        break;
                     }
+                    _ => continue,
+                };
+                state.metrics.add_bytes_received(payload.len());
+
+                // Check message rate limit
+                if !state.session_manager.check_message_limit(&session_id) {
+                    state.metrics.inc_rate_limits();
+                    let msg = ServerMessage::error(None, ErrorCode::RateLimit, "Message rate exceeded");
+                    outbox.send(msg).await;
+                    continue;
                 }
-            }
-            msg = socket.recv() => {
-                match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        // Check message rate limit
-                        if !state.session_manager.check_message_limit(&session_id) {
-                            state.metrics.inc_rate_limits();
-                            let msg = ServerMessage::error(None, ErrorCode::RateLimit, "Message rate exceeded");
-                            let _ = socket.send(Message::Text(serde_json::to_string(&msg).unwrap())).await;
-                            continue;
+                state.metrics.inc_messages();
+
+                // Binary frames carry the same JSON encoding as text frames
+                match serde_json::from_slice::<ClientMessage>(&payload) {
+                    Ok(mut client_msg) => {
+                        let is_hello = matches!(client_msg, ClientMessage::Hello { .. });
+                        // A hello in a binary frame asks for binary encoding
+                        if let ClientMessage::Hello { options, .. } = &mut client_msg {
+                            if binary {
+                                options.encoding.get_or_insert(Encoding::Binary);
+                            }
+                        }
+                        let response = handle_message(&state, &session_id, client_msg).await;
+                        let violation = matches!(response, Some(ServerMessage::Error { code: ErrorCode::Unauthorized, .. }));
+                        let site_full = matches!(response, Some(ServerMessage::Error { code: ErrorCode::RateLimit, .. }));
+                        // Before the handshake, a refused hello means a bad site token or a full site
+                        let denied = awaiting_hello && is_hello && (violation || site_full);
+                        if is_hello && !denied {
+                            awaiting_hello = false;
                         }
-                        state.metrics.inc_messages();
-
-                        match serde_json::from_str::<ClientMessage>(&text) {
-                            Ok(client_msg) => {
-                                if let Some(response) = handle_message(&state, &session_id, client_msg).await {
-                                    let json = serde_json::to_string(&response).unwrap();
-                                    if socket.send(Message::Text(json)).await.is_err() {
-                                        break;
-                                    }
+                        if is_hello && !violation {
+                            if let Some(version) = state.session_manager.with_session(&session_id, |s| s.client_version.clone()).flatten() {
+                                Span::current().record("client_version", version.as_str());
+                            }
+                            // Frame replies and ping as the hello negotiated, starting with the reply to it
+                            let capabilities = state.session_manager.with_session(&session_id, |s| s.capabilities.clone()).flatten();
+                            if let Some(capabilities) = capabilities {
+                                if capabilities.encoding == Encoding::Binary {
+                                    outbox.use_binary();
+                                }
+                                let interval = Duration::from_millis(capabilities.keepalive_ms);
+                                if interval != keepalive.interval() {
+                                    keepalive.set_interval(interval);
+                                    ping_ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
                                 }
                             }
-                            Err(e) => {
-                                warn!("Invalid message: {}", e);
-                                let msg = ServerMessage::error(None, ErrorCode::BadFormat, "Invalid message format");
-                                let _ = socket.send(Message::Text(serde_json::to_string(&msg).unwrap())).await;
+                        }
+                        if let Some(response) = response {
+                            if !outbox.send(response).await {
+                                break;
                             }
                         }
+                        if denied {
+                            let reason = if site_full { "Site connection limit exceeded" } else { "Unauthorized" };
+                            info!("Session {} rejected: {}", session_id, reason);
+                            outbox.close(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: reason.into(),
+                            }).await;
+                            break;
+                        }
+                        if violation && too_many_violations(&state, &session_id) {
+                            warn!("Session {} closed after repeated protocol violations", session_id);
+                            outbox.close(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "Protocol violation".into(),
+                            }).await;
+                            break;
+                        }
                     }
-                    Some(Ok(Message::Close(_))) | None => break,
-                    Some(Err(e)) => {
-                        warn!("WebSocket error: {}", e);
-                        break;
+                    Err(e) => {
+                        warn!("Invalid message: {}", e);
+                        let msg = ServerMessage::error(None, ErrorCode::BadFormat, "Invalid message format");
+                        outbox.send(msg).await;
                     }
-                    _ => {}
                 }
             }
         }
     }
 
-    state.metrics.dec_connections();
-    state.session_manager.remove_session(&session_id);
-    info!("Session closed: {}", session_id);
+    // Release the session now rather than after the writer has flushed
+    drop(guard);
+
+    // Let the writer flush anything still queued (goodbye, close frame)
+    drop(outbox);
+    if !writer_done {
+        finish_writer(writer).await;
+    }
+    if linger {
+        tokio::time::sleep(CLOSE_LINGER).await;
+    }
+}
+
+/// The session's counters with the limits and stats interval it runs under
+pub(crate) fn stats_message(state: &AppState, session_id: &str, id: Option<String>) -> ServerMessage {
+    let counters = state.session_manager.with_session(session_id, |s| {
+        let submits = (s.accepted_submits, s.rejected_submits, s.stale_submits, s.accepted_shares, s.last_accept_at.map(unix_ms));
+        (submits, s.hashrate.estimate(Instant::now()), s.outbound.snapshot())
+    });
+    let (submits, estimated_hashrate, outbound) = match counters {
+        Some((submits, hashrate, outbound)) => (submits, hashrate, Some(outbound)),
+        None => (Default::default(), None, None),
+    };
+    let (accepted, rejected, stale, accepted_shares, last_accept_ms) = submits;
+    // The session manager's limits, which follow reloads
+    let limits = state.session_manager.config();
+    ServerMessage::Stats {
+        id,
+        session_id: session_id.to_string(),
+        submits_per_minute: limits.submits_per_minute,
+        messages_per_second: limits.messages_per_second,
+        stats_interval_ms: state.config.server.stats_interval_ms,
+        accepted,
+        rejected,
+        stale,
+        accepted_shares,
+        last_accept_ms,
+        estimated_hashrate,
+        tip_height: state.template_rx.borrow().as_ref().map(|t| t.height),
+        server_time_ms: unix_ms(SystemTime::now()),
+        outbound,
+    }
+}
+
+/// Apply reloaded rate limits to every session and tell the connected
+/// miners with a `policy_update`. Returns how many connections were told.
+pub fn apply_limits(state: &AppState, limits: &LimitsConfig) -> Result<usize, CoordinatorError> {
+    state.session_manager.apply_limits(limits)?;
+    let config = state.session_manager.config();
+    let update = ServerMessage::PolicyUpdate {
+        submits_per_minute: config.submits_per_minute,
+        messages_per_second: config.messages_per_second,
+        max_inflight_submits: config.max_inflight_submits,
+    };
+    Ok(fanout::broadcast_message(state, &update))
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Releases a connection's session, fanout slot and gauges when dropped, so
+/// a handler that panics or is cancelled cannot leak its per-IP or per-site slot
+struct SessionGuard {
+    state: AppState,
+    session_id: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.state.metrics.dec_connections();
+        self.state.fanout.unregister(&self.session_id);
+        self.state.session_manager.remove_session(&self.session_id);
+    }
+}
+
+/// Hello refused because the miner's site already holds its share of connections
+pub(crate) fn site_limit_error(state: &AppState, site_label: Option<&str>) -> ServerMessage {
+    let max = state.session_manager.max_per_site();
+    ServerMessage::error(None, ErrorCode::RateLimit, "Site connection limit exceeded").with_details(serde_json::json!({
+        "limit": "max_connections_per_site",
+        "max": max,
+        "site": site_label,
+    }))
+}
+
+/// Close frame for a failed read the client should hear about, such as a
+/// message over the size limit. Transport failures get none; the socket is gone.
+fn read_error_close(error: &axum::Error) -> Option<CloseFrame<'static>> {
+    use std::error::Error;
+    use tungstenite::Error as WsError;
+
+    let (code, reason) = match error.source()?.downcast_ref::<WsError>()? {
+        WsError::Capacity(_) => (close_code::SIZE, "Message too big"),
+        WsError::Utf8 => (close_code::INVALID, "Invalid UTF-8"),
+        WsError::Protocol(_) => (close_code::PROTOCOL, "Protocol error"),
+        _ => return None,
+    };
+    Some(CloseFrame { code, reason: reason.into() })
+}
+
+/// `reason`, cut at a character boundary to fit in a close frame
+fn close_frame_reason(reason: &str) -> String {
+    let mut end = reason.len().min(MAX_CLOSE_REASON_BYTES);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    reason[..end].to_string()
+}
+
+/// Wait briefly for the writer to flush its queue, then give up on it
+async fn finish_writer(mut writer: JoinHandle<()>) {
+    if tokio::time::timeout(WRITER_FLUSH_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
+    }
+}
+
+/// Reject a submission carrying invalid work. These count towards an IP ban,
+/// and a session whose IP just got banned is disconnected.
+fn reject_invalid(state: &AppState, session_id: &str, id: String, message: String) -> Option<ServerMessage> {
+    debug!("Rejected invalid submission: {}", message);
+    state.metrics.inc_rejected();
+    record_offense(state, session_id);
+    Some(ServerMessage::SubmitResult {
+        id, status: SubmitStatus::Rejected,
+        message: Some(message),
+    })
+}
+
+/// Refuse a submission that failed validation, answering as the failure
+/// says and counting it by how it failed
+fn reject(state: &AppState, session_id: &str, id: String, e: ValidationError) -> Option<ServerMessage> {
+    match &e {
+        ValidationError::VmUnavailable(reason) => warn!("Failed to init RandomX VM: {}", reason),
+        _ => debug!("Rejected invalid submission: {}", e),
+    }
+    state.metrics.inc_rejected();
+    state.metrics.inc_rejected_by_reason(e.label());
+    if e == ValidationError::BadPow {
+        state.metrics.inc_bad_pow();
+    }
+    if e.is_offense() {
+        record_offense(state, session_id);
+    }
+    Some(match e.status() {
+        Some(status) => ServerMessage::SubmitResult { id, status, message: Some(e.to_string()) },
+        None => {
+            let error = ServerMessage::error(Some(id), e.code(), e.to_string());
+            match &e {
+                ValidationError::Header { field, .. } => error.with_details(serde_json::json!({ "field": field })),
+                ValidationError::VerifierDown { retry_after_ms } => {
+                    error.with_details(serde_json::json!({ "retry_after_ms": retry_after_ms }))
+                }
+                _ => error,
+            }
+        }
+    })
+}
+
+/// Count an offense against the session's address, closing it if that earns a ban
+fn record_offense(state: &AppState, session_id: &str) {
+    if let Some(ip) = state.session_manager.with_session(session_id, |s| s.ip) {
+        if state.bans.record_offense(ip) {
+            state.metrics.inc_bans_issued();
+            state.session_manager.close_session(session_id, "Banned");
+        }
+    }
+}
+
+/// Submit refused because the session already has its share being verified
+fn submits_in_flight_error(state: &AppState, id: String) -> ServerMessage {
+    let max = state.session_manager.config().max_inflight_submits;
+    ServerMessage::error(Some(id), ErrorCode::RateLimit, "Too many submissions in flight").with_details(serde_json::json!({
+        "limit": "max_inflight_submits",
+        "max": max,
+        "retry_after_ms": SUBMIT_BUSY_RETRY_MS,
+    }))
+}
+
+/// A nonce as the 4 bytes it stands for; None for anything malformed,
+/// which `Job::apply_nonce` rejects with the reason
+fn parse_nonce(nonce_hex: &str) -> Option<[u8; 4]> {
+    let mut nonce = [0u8; 4];
+    hex::decode_to_slice(nonce_hex, &mut nonce).ok()?;
+    Some(nonce)
+}
+
+/// A 32-byte hash from hex; None for anything else
+fn parse_hash(hash_hex: &str) -> Option<[u8; 32]> {
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(hash_hex, &mut hash).ok()?;
+    Some(hash)
+}
+
+fn too_many_violations(state: &AppState, session_id: &str) -> bool {
+    state
+        .session_manager
+        .with_session(session_id, |s| s.protocol_violations >= MAX_PROTOCOL_VIOLATIONS)
+        .unwrap_or(true)
+}
+
+/// Tell the client why we are closing and when to come back, then close.
+/// The retry delay is jittered so miners don't reconnect as a stampede.
+async fn say_goodbye(outbox: &Outbox, state: &AppState, reason: GoodbyeReason) {
+    let jitter = rand::thread_rng().gen_range(0..=state.config.server.shutdown_retry_jitter_ms);
+    let (retry_after_ms, code, close_reason) = match reason {
+        // An expired session may hello again right away
+        GoodbyeReason::SessionExpired => (jitter, close_code::NORMAL, "Session expired"),
+        _ => (1_000 + jitter, close_code::AWAY, "Server shutting down"),
+    };
+    outbox.send(ServerMessage::Goodbye { reason, retry_after_ms }).await;
+    outbox.close(CloseFrame {
+        code,
+        reason: close_reason.into(),
+    }).await;
+}
+
+#[instrument(name = "message", skip_all, fields(kind = msg.kind()))]
+pub(crate) async fn handle_message(
+    state: &AppState,
+    session_id: &str,
+    msg: ClientMessage,
+) -> Option<ServerMessage> {
+    if !state.events.is_enabled() {
+        return dispatch_message(state, session_id, msg).await;
+    }
+
+    let is_hello = matches!(msg, ClientMessage::Hello { .. });
+    let submitted_job = match &msg {
+        ClientMessage::Submit { job_id, .. } => Some(job_id.clone()),
+        _ => None,
+    };
+    let response = dispatch_message(state, session_id, msg).await;
+    record_event(state, session_id, is_hello, submitted_job, response.as_ref());
+    response
+}
+
+/// Add a handled message to the session event log
+fn record_event(
+    state: &AppState,
+    session_id: &str,
+    is_hello: bool,
+    submitted_job: Option<String>,
+    response: Option<&ServerMessage>,
+) {
+    let event = state.session_manager.with_session(session_id, |session| {
+        let kind = match (response, submitted_job) {
+            (Some(ServerMessage::Error { code, .. }), _) => EventKind::Error { code: code.clone() },
+            (Some(ServerMessage::SubmitResult { status, .. }), Some(job_id)) => {
+                EventKind::Submit { job_id, status: status.clone() }
+            }
+            _ if is_hello => EventKind::Hello {
+                client_version: session.client_version.clone().unwrap_or_default(),
+                threads: session.threads,
+                site: session.site_label.clone(),
+                worker: session.worker.clone(),
+            },
+            _ => return None,
+        };
+        Some((session.ip, kind))
+    });
+    if let Some((ip, kind)) = event.flatten() {
+        state.events.emit(session_id, ip, kind);
+    }
 }
 
-async fn handle_message(
+async fn dispatch_message(
     state: &AppState,
     session_id: &str,
     msg: ClientMessage,
 ) -> Option<ServerMessage> {
+    // Any message from an idle miner means it is back; give it work at once
+    if state.session_manager.wake(session_id) {
+        fanout::send_job(state, session_id);
+    }
+
+    // Only hello is valid before the handshake, and only once
+    let ready = state.session_manager.state_of(session_id) == Some(SessionState::Ready);
+    let is_hello = matches!(msg, ClientMessage::Hello { .. });
+    if ready == is_hello {
+        let message = if is_hello { "hello already received" } else { "hello required" };
+        state.session_manager.update_session(session_id, |s| s.protocol_violations += 1);
+        return Some(ServerMessage::error(None, ErrorCode::Unauthorized, message));
+    }
+
     match msg {
-        ClientMessage::Hello { client_version, threads, .. } => {
-            state.session_manager.update_session(session_id, |s| {
-                s.set_ready(client_version.clone(), threads);
-            });
+        ClientMessage::Hello { v, client_version, threads, site_token, worker, options } => {
+            if worker.as_deref().is_some_and(|w| !protocol::is_valid_worker(w)) {
+                return Some(ServerMessage::error(None, ErrorCode::BadFormat, "invalid worker name"));
+            }
+            let capabilities = match SessionCapabilities::negotiate(v, &options, &state.config.server) {
+                Ok(capabilities) => capabilities,
+                Err(reason) => return Some(ServerMessage::error(None, ErrorCode::BadFormat, reason)),
+            };
+            let compact_target = capabilities.wants_compact_target;
+            let site_label = match auth::check_site_token(&state.config.auth, site_token.as_deref()) {
+                SiteAuth::Matched(label) => Some(label),
+                SiteAuth::Anonymous => None,
+                SiteAuth::Denied => {
+                    return Some(ServerMessage::error(None, ErrorCode::Unauthorized, "invalid site token"));
+                }
+            };
+            if !state.session_manager.set_ready(session_id, client_version, threads, site_label.clone(), worker) {
+                return Some(site_limit_error(state, site_label.as_deref()));
+            }
+            state.session_manager.update_session(session_id, |s| s.capabilities = Some(capabilities));
+            state.session_manager.set_randomx_mode(session_id, RandomxMode::from_hello(options.randomx_mode.as_deref()));
             
             // Send initial job if template available
             let template_opt = state.template_rx.borrow().clone();
             if let Some(template) = template_opt {
-                let job = state.job_manager.create_job(&template, session_id);
-                state.metrics.inc_jobs();
-                state.session_manager.update_session(session_id, |s| {
-                    s.update_job(job.job_id.clone(), job.reserved_value.clone());
-                });
-                return Some(ServerMessage::Job {
-                    job_id: job.job_id,
-                    blob_hex: job.blob_hex,
-                    reserved_offset: job.reserved_offset,
-                    reserved_value_hex: hex::encode(&job.reserved_value),
-                    target_hex: job.target_hex,
-                    height: job.height,
-                    seed_hash: job.seed_hash,
-                });
+                let mut jobs = match session_jobs(state, &template, session_id) {
+                    Ok(jobs) => jobs,
+                    Err(_) => return Some(ServerMessage::error(None, ErrorCode::InternalError, "no job available")),
+                };
+                issue_jobs(state, session_id, &jobs);
+                // The reply carries the last of a per-thread set; the rest go
+                // ahead of it on the connection
+                let last = jobs.pop().expect("a job set is never empty");
+                if !jobs.is_empty() {
+                    fanout::push_jobs(state, session_id, &jobs, compact_target);
+                }
+                return Some(job_message(last, compact_target));
             }
             
-            Some(ServerMessage::Stats {
-                id: None,
-                session_id: session_id.to_string(),
-                submits_per_minute: state.config.limits.submits_per_minute,
-                messages_per_second: state.config.limits.messages_per_second,
-            })
+            Some(stats_message(state, session_id, None))
         }
         ClientMessage::Ping { id } => {
             state.session_manager.update_session(session_id, |s| s.touch());
             Some(ServerMessage::Pong { id })
         }
-        ClientMessage::Submit { id, job_id, nonce } => {
-            // Rate limit check (unchanged)
-            if !state.session_manager.check_submit_limit(session_id) {
-                state.metrics.inc_rate_limits();
-                return Some(ServerMessage::SubmitResult {
-                    id, status: SubmitStatus::Error,
-                    message: Some("Submit rate exceeded".into()),
-                });
-            }
-            state.metrics.inc_submissions();
-
-            // Get job
-            let job = match state.job_manager.get_job(&job_id) {
-                Some(j) => j,
-                None => {
-                    state.metrics.inc_rejected();
-                    return Some(ServerMessage::SubmitResult {
-                        id, status: SubmitStatus::Rejected,
-                        message: Some("Unknown job".into()),
-                    });
+        ClientMessage::Submit { id, job_id, nonce, result } => {
+            let response = handle_submit(state, session_id, id, job_id.clone(), nonce, result).await;
+            // Counted here, once per reply, rather than in each branch of handle_submit
+            if let Some(ServerMessage::SubmitResult { status, .. }) = &response {
+                let difficulty = state.job_manager.get_job(&job_id).map(|j| j.share_difficulty).unwrap_or(0);
+                let summary = state.session_manager.record_submit_result(session_id, status, difficulty);
+                if let (SubmitStatus::Accepted, Some(summary)) = (status, summary) {
+                    let label = metrics::worker_label(&state.config.metrics.worker_labels, summary.worker.as_deref());
+                    state.metrics.inc_worker_accepted(label);
                 }
-            };
-
-            // Check stale
-            let current_template_id = {
-                let template_ref = state.template_rx.borrow();
-                template_ref.as_ref().map(|t| t.template_id).unwrap_or(0)
-            };
-            
-            if state.job_manager.is_stale(&job, current_template_id) {
-                state.metrics.inc_stale();
-                return Some(ServerMessage::SubmitResult {
-                    id, status: SubmitStatus::Stale,
-                    message: Some("Job expired".into()),
-                });
             }
+            response
+        }
+    }
+}
 
-            // Reconstruct blob with nonce
-            let blob = match job.apply_nonce(&nonce) {
-                Ok(b) => b,
-                Err(e) => {
-                    state.metrics.inc_rejected();
-                    return Some(ServerMessage::SubmitResult {
-                        id, status: SubmitStatus::Rejected,
-                        message: Some(e),
-                    });
-                }
-            };
+/// Create a job for a session at its current share difficulty. Without
+/// vardiff that is `jobs.default_share_difficulty`, or the block difficulty
+/// when that is 0. A template that cannot make jobs is logged and counted
+/// here, so callers only need to give up.
+pub(crate) fn session_job(state: &AppState, template: &TemplateState, session_id: &str) -> Result<Job, JobError> {
+    let difficulty = share_difficulty(state, template, session_id);
+    state.job_manager.create_job(template, session_id, difficulty).map_err(|e| job_failed(state, template, e))
+}
 
-            // Validate reconstructed blob
-            if let Err(e) = state.validator.validate_submission(&blob, &job) {
-                state.metrics.inc_rejected();
-                return Some(ServerMessage::SubmitResult {
-                    id, status: SubmitStatus::Rejected,
-                    message: Some(e.to_string()),
-                });
-            }
+/// The jobs for a session's next piece of work: one, or with
+/// `jobs.job_per_thread` one per miner thread the hello announced
+pub(crate) fn session_jobs(state: &AppState, template: &TemplateState, session_id: &str) -> Result<Vec<Job>, JobError> {
+    if !state.config.jobs.job_per_thread {
+        return session_job(state, template, session_id).map(|job| vec![job]);
+    }
+    let threads = state.session_manager.with_session(session_id, |s| s.threads).unwrap_or(1);
+    let difficulty = share_difficulty(state, template, session_id);
+    state
+        .job_manager
+        .create_job_set(template, session_id, difficulty, threads as usize)
+        .map_err(|e| job_failed(state, template, e))
+}
 
-            // Init RandomX VM if needed
-            if let Err(e) = state.validator.init_vm(&job.seed_hash) {
-                warn!("Failed to init RandomX VM: {}", e);
-                state.metrics.inc_rejected();
-                return Some(ServerMessage::SubmitResult {
-                    id, status: SubmitStatus::Rejected,
-                    message: Some("Hash verification unavailable".into()),
-                });
-            }
+/// Count new jobs and make them the session's current work
+pub(crate) fn issue_jobs(state: &AppState, session_id: &str, jobs: &[Job]) {
+    for _ in jobs {
+        state.metrics.inc_jobs();
+    }
+    state.session_manager.update_session(session_id, |s| s.update_jobs(jobs));
+}
 
-            // Compute hash
-            let hash = match state.validator.compute_hash(&blob) {
-                Ok(h) => h,
-                Err(e) => {
-                    state.metrics.inc_rejected();
-                    return Some(ServerMessage::SubmitResult {
-                        id, status: SubmitStatus::Rejected,
-                        message: Some(e.to_string()),
-                    });
-                }
-            };
+fn share_difficulty(state: &AppState, template: &TemplateState, session_id: &str) -> u64 {
+    let default = match state.config.jobs.default_share_difficulty {
+        0 => template.difficulty,
+        difficulty => difficulty,
+    };
+    state.session_manager.share_difficulty(session_id).unwrap_or(default)
+}
 
-            // Check target
-            let target = hex::decode(&job.target_hex).unwrap_or_default();
-            let mut target_arr = [0u8; 32];
-            if target.len() == 32 {
-                target_arr.copy_from_slice(&target);
-            }
+fn job_failed(state: &AppState, template: &TemplateState, e: JobError) -> JobError {
+    error!("Template {} at height {} cannot make jobs: {}", template.template_id, template.height, e);
+    state.metrics.inc_jobs_failed();
+    e
+}
 
-            if !state.validator.check_meets_target(&hash, &target_arr) {
-                state.metrics.inc_rejected();
-                return Some(ServerMessage::SubmitResult {
-                    id, status: SubmitStatus::Rejected,
-                    message: Some("Hash does not meet target".into()),
-                });
-            }
+/// Account the difficulty an accepted hash achieved, beyond the share
+/// difficulty it was asked for
+fn record_achieved(state: &AppState, session_id: &str, hash: &[u8; 32]) {
+    let difficulty = hash_to_difficulty(hash);
+    state.metrics.add_achieved_difficulty(difficulty);
+    state.session_manager.update_session(session_id, |s| s.record_achieved(difficulty));
+}
 
-            info!("Valid submission for job {}", job_id);
-            
-            // Submit to monerod using reconstructed blob
-            let blob_hex = hex::encode(&blob);
-            match state.rpc_client.submit_block(&blob_hex).await {
-                Ok(status) => {
-                    info!("Block submitted: {}", status);
-                    state.metrics.inc_accepted();
-                    Some(ServerMessage::SubmitResult {
-                        id, status: SubmitStatus::Accepted,
-                        message: Some(format!("Block submitted: {}", status)),
-                    })
-                }
-                Err(e) => {
-                    warn!("Block submission failed: {}", e);
-                    state.metrics.inc_rejected();
-                    Some(ServerMessage::SubmitResult {
-                        id, status: SubmitStatus::Rejected,
-                        message: Some(format!("Submission failed: {}", e)),
-                    })
-                }
-            }
-        }
+/// A `job` message, with the target in the form the session negotiated
+pub(crate) fn job_message(job: Job, compact_target: bool) -> ServerMessage {
+    ServerMessage::Job {
+        job_id: job.job_id,
+        blob_hex: job.blob_hex,
+        reserved_offset: job.reserved_offset,
+        reserved_value_hex: hex::encode(&job.reserved_value),
+        target_hex: if compact_target { compact_target_hex(job.share_difficulty) } else { job.target_hex },
+        height: job.height,
+        seed_hash: job.seed_hash,
     }
 }
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c().await.expect("Failed to install signal handler");
-    info!("Shutdown signal received");
+async fn handle_submit(
+    state: &AppState,
+    session_id: &str,
+    id: String,
+    job_id: String,
+    nonce: String,
+    result: Option<String>,
+) -> Option<ServerMessage> {
+    // Verification is expensive, so one session may only have a few running.
+    // The slot is released when this returns or unwinds.
+    let _slot = match state.session_manager.try_begin_submit(session_id) {
+        Some(slot) => slot,
+        None => {
+            state.metrics.inc_rate_limits();
+            return Some(submits_in_flight_error(state, id));
+        }
+    };
+
+    // Rate limit check (unchanged)
+    if !state.session_manager.check_submit_limit(session_id) {
+        state.metrics.inc_rate_limits();
+        return Some(ServerMessage::SubmitResult {
+            id, status: SubmitStatus::Error,
+            message: Some("Submit rate exceeded".into()),
+        });
+    }
+    state.metrics.inc_submissions();
+
+    let claimed = match result.as_deref().map(parse_hash) {
+        None => None,
+        Some(Some(hash)) => Some(hash),
+        Some(None) => {
+            state.metrics.inc_rejected();
+            return Some(ServerMessage::error(Some(id), ErrorCode::BadFormat, "result must be 32 bytes of hex"));
+        }
+    };
+
+    let current_height = state.template_rx.borrow().as_ref().map(|t| t.height).unwrap_or(0);
+
+    // The id tells a job for an earlier block without a lookup. Ids that do
+    // not parse name no job, which the lookup reports.
+    if job_id.parse::<JobId>().is_ok_and(|parsed| parsed.height < current_height) {
+        state.metrics.inc_stale();
+        return Some(ServerMessage::SubmitResult {
+            id, status: SubmitStatus::Stale,
+            message: Some("Job is for an earlier block".into()),
+        });
+    }
+
+    // Get job. Past its block TTL a job still earns shares, if it asks for
+    // any, but its blocks are no longer submitted.
+    let (job, block_expired) = match state.job_manager.lookup_job(&job_id) {
+        JobLookup::Live(j) => (j, false),
+        JobLookup::BlockExpired(j) if j.share_difficulty < j.difficulty => (j, true),
+        JobLookup::BlockExpired(_) => {
+            state.metrics.inc_stale();
+            return Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Stale,
+                message: Some("Job expired: older than block_ttl_ms".into()),
+            });
+        }
+        JobLookup::Expired(_) => {
+            state.metrics.inc_stale();
+            return Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Stale,
+                message: Some("Job expired: older than share_ttl_ms".into()),
+            });
+        }
+        JobLookup::Evicted => {
+            state.metrics.inc_stale();
+            return Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Stale,
+                message: Some("Job dropped to make room for newer jobs".into()),
+            });
+        }
+        JobLookup::Unknown => {
+            state.metrics.inc_rejected();
+            return Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Rejected,
+                message: Some("Unknown job".into()),
+            });
+        }
+    };
+
+    // Jobs are only valid for the session they were issued to, which keeps
+    // its id across a resume, and while it still counts them as recent
+    if job.session_id != session_id || !state.session_manager.owns_job(session_id, &job_id) {
+        debug!("Session {} submitted against job {} of session {}", session_id, job_id, job.session_id);
+        state.metrics.inc_rejected();
+        return Some(ServerMessage::error(Some(id), ErrorCode::BadJob, "Job was not issued to this session"));
+    }
+
+    // Check stale
+    let stale = {
+        let template_ref = state.template_rx.borrow();
+        let (height, prev_hash) = template_ref.as_ref().map(|t| (t.height, t.prev_hash.as_str())).unwrap_or((0, ""));
+        state.job_manager.is_stale(&job, height, prev_hash)
+    };
+    if stale {
+        state.metrics.inc_stale();
+        return Some(ServerMessage::SubmitResult {
+            id, status: SubmitStatus::Stale,
+            message: Some("Job expired".into()),
+        });
+    }
+
+    // A claimed share may be credited without hashing, if sampling says so;
+    // a claimed block never is
+    let claims_block = claimed.is_some_and(|claimed| job.classify(&claimed) == HashClass::Block);
+    let trusted = claimed.filter(|_| !state.validator.should_verify(claims_block));
+
+    // The rest take a place in the verification queue, before the nonce is
+    // recorded so a submit refused as busy, or while nothing can hash, may
+    // be sent again
+    if trusted.is_none() && state.validator.rung(state.pow.as_ref()) == VerifierRung::Down {
+        let retry_after_ms = state.validator.retry_after().as_millis() as u64;
+        return reject(state, session_id, id, ValidationError::VerifierDown { retry_after_ms });
+    }
+    let ticket = match trusted {
+        Some(_) => None,
+        None => match state.validator.enqueue(state.pow.as_ref(), claims_block) {
+            Some(ticket) => Some(ticket),
+            None => {
+                return Some(ServerMessage::error(Some(id), ErrorCode::Busy, "Verification queue full").with_details(
+                    serde_json::json!({ "retry_after_ms": SUBMIT_BUSY_RETRY_MS }),
+                ));
+            }
+        },
+    };
+
+    // A repeated nonce would cost another hash for nothing, and counts towards a ban
+    if let Some(nonce) = parse_nonce(&nonce) {
+        if !state.session_manager.record_nonce(session_id, &job_id, nonce) {
+            state.metrics.inc_duplicate();
+            return reject_invalid(state, session_id, id, "duplicate".into());
+        }
+        // The job remembers nonces too, so replays from another session are caught
+        match state.job_manager.record_nonce(&job_id, u32::from_le_bytes(nonce)) {
+            NonceStatus::New | NonceStatus::UnknownJob => {}
+            NonceStatus::Duplicate => {
+                state.metrics.inc_duplicate();
+                return reject_invalid(state, session_id, id, "duplicate nonce".into());
+            }
+            NonceStatus::Full => {
+                state.metrics.inc_rejected();
+                return Some(ServerMessage::SubmitResult {
+                    id, status: SubmitStatus::Rejected,
+                    message: Some("Too many submissions for job".into()),
+                });
+            }
+            // No honest miner gets near the cap, so spraying a job counts towards a ban
+            NonceStatus::Capped => {
+                state.metrics.inc_rate_limits();
+                state.metrics.inc_job_submission_caps_hit();
+                record_offense(state, session_id);
+                return Some(ServerMessage::error(Some(id), ErrorCode::RateLimit, "Too many submissions for job").with_details(serde_json::json!({
+                    "limit": "max_submissions_per_job",
+                    "max": state.config.jobs.max_submissions_per_job,
+                })));
+            }
+        }
+    }
+
+    // Rebuild and check the block, then hash it off the executor, making
+    // the RandomX VM first if the seed changed
+    let valid = match state.validator.validate_and_verify(state.pow.as_ref(), &job, &nonce, claimed, ticket).await {
+        Ok(valid) => valid,
+        Err(e) => {
+            // Refused on the server's account, not the miner's: the nonce
+            // may be sent again, and must not come back as a duplicate
+            if let Some(nonce) = parse_nonce(&nonce).filter(|_| !e.is_offense()) {
+                state.session_manager.forget_nonce(session_id, &job_id, nonce);
+                state.job_manager.forget_nonce(&job_id, u32::from_le_bytes(nonce));
+            }
+            return reject(state, session_id, id, e);
+        }
+    };
+    if valid.timings.is_none() {
+        state.metrics.inc_shares_unverified();
+    }
+
+    // A share that is not also a block stops here
+    let is_share_job = job.share_difficulty < job.difficulty;
+    let hash = valid.hash;
+    match valid.outcome {
+        ValidationOutcome::Share => {
+            state.metrics.inc_accepted();
+            state.metrics.add_accepted_difficulty(job.share_difficulty);
+            record_achieved(state, session_id, &hash);
+            return Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Accepted,
+                message: Some("Share accepted".into()),
+            });
+        }
+        // Too late for monerod, in time for the share
+        ValidationOutcome::Block if block_expired => {
+            state.metrics.inc_accepted();
+            state.metrics.add_accepted_difficulty(job.share_difficulty);
+            record_achieved(state, session_id, &hash);
+            return Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Accepted,
+                message: Some("Share credited, block stale".into()),
+            });
+        }
+        ValidationOutcome::Block => {}
+    }
+
+    match valid.timings {
+        Some(timings) => info!("Valid submission for job {} ({}), verified in {}", job_id, ExtraNonce::decode(&job.reserved_value), timings),
+        None => info!("Valid submission for job {} ({}), unverified", job_id, ExtraNonce::decode(&job.reserved_value)),
+    }
+    
+    // Submit to monerod using reconstructed block
+    let blob_hex = hex::encode(&valid.block);
+    match state.rpc_client.submit_block(&blob_hex).await {
+        Ok(status) => {
+            info!("Block submitted: {}", status);
+            state.metrics.inc_accepted();
+            state.metrics.add_accepted_difficulty(job.share_difficulty);
+            record_achieved(state, session_id, &hash);
+            Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Accepted,
+                message: Some(format!("Block submitted: {}", status)),
+            })
+        }
+        Err(e) if is_share_job => {
+            // The share is still valid work even though the block was refused
+            warn!("Block submission failed: {}", e);
+            state.metrics.inc_accepted();
+            state.metrics.add_accepted_difficulty(job.share_difficulty);
+            record_achieved(state, session_id, &hash);
+            Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Accepted,
+                message: Some(format!("Share accepted; block submission failed: {}", e)),
+            })
+        }
+        Err(e) => {
+            warn!("Block submission failed: {}", e);
+            state.metrics.inc_rejected();
+            Some(ServerMessage::SubmitResult {
+                id, status: SubmitStatus::Rejected,
+                message: Some(format!("Submission failed: {}", e)),
+            })
+        }
+    }
+}
+
+pub async fn shutdown_signal() {
+    tokio::signal::ctrl_c().await.expect("Failed to install signal handler");
+    info!("Shutdown signal received");
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::blob;
+    use crate::pow::FakeVerifier;
+    use crate::rpc_verifier::RpcVerifier;
+    use crate::template::BlockTemplate;
+    use crate::session::SessionManagerConfig;
+    use crate::validator::ShareSampler;
+    use crate::vm_pool::{LocalVerifier, VmPool};
+    use futures::{SinkExt, StreamExt};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    const TEST_CONFIG: &str = r#"
+        [server]
+        bind_addr = "127.0.0.1:0"
+        ws_path = "/ws"
+        max_connections = 100
+        max_connections_per_ip = 100
+        max_frame_bytes = 32768
+        shutdown_drain_timeout_ms = 3000
+
+        [monerod]
+        rpc_url = "http://127.0.0.1:1"
+        wallet_address = "test"
+        reserve_size = 8
+        rpc_timeout_ms = 100
+
+        [jobs]
+        block_ttl_ms = 30000
+        template_refresh_interval_ms = 20000
+        stale_job_grace_ms = 10000
+
+        [limits]
+        submits_per_minute = 10
+        shares_per_minute = 120
+        messages_per_second = 20
+        connections_per_minute = 20
+
+        [admin]
+        enable = true
+        token = "secret"
+
+        [bans]
+        max_offenses = 2
+        ban_duration_ms = 500
+        max_ban_duration_ms = 2000
+
+        [metrics]
+        enable = false
+        bind_addr = "127.0.0.1:0"
+        path = "/metrics"
+    "#;
+
+    /// The template sender is returned so the channel stays open for the test's lifetime
+    pub(crate) fn test_state() -> (AppState, watch::Sender<Option<TemplateState>>) {
+        let config: Config = toml::from_str(TEST_CONFIG).unwrap();
+        let (template_tx, template_rx) = watch::channel(None);
+        let bans = Arc::new(BanManager::new(config.bans.clone()));
+        let state = AppState {
+            template_rx,
+            rpc_client: Arc::new(MonerodClient::new(config.monerod.rpc_url.clone(), 100).unwrap()),
+            session_manager: Arc::new(
+                SessionManager::new(SessionManagerConfig::from_config(&config).unwrap())
+                    .with_bans(bans.clone())
+                    .with_connection_rate(config.limits.connections_per_minute, 60),
+            ),
+            job_manager: Arc::new(
+                JobManager::new(config.jobs.stale_job_grace_ms)
+                    .with_block_ttl_ms(config.jobs.block_ttl_ms)
+                    .with_max_jobs(config.jobs.max_jobs)
+                    .with_max_jobs_per_session(config.jobs.max_jobs_per_session)
+                    .with_max_nonces_per_job(config.jobs.max_nonces_per_job)
+                    .with_max_submissions_per_job(config.jobs.max_submissions_per_job),
+            ),
+            validator: Arc::new(SubmissionValidator::new()),
+            pow: Arc::new(LocalVerifier::new(VmPool::new(4))),
+            metrics: Arc::new(Metrics::new()),
+            daemon_status: Arc::new(DaemonStatus::new()),
+            bans,
+            fanout: Arc::new(Fanout::new()),
+            events: EventLog::disabled(),
+            config,
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            started_at: Instant::now(),
+        };
+        (state, template_tx)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sends_goodbye_to_all_clients() {
+        const CLIENTS: usize = 5;
+
+        let (state, _template_tx) = test_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, state.clone()));
+
+        let mut clients = Vec::new();
+        for _ in 0..CLIENTS {
+            let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap();
+            clients.push(ws);
+        }
+
+        // Wait until every connection has registered a session
+        for _ in 0..100 {
+            if state.session_manager.active_count() == CLIENTS {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.session_manager.active_count(), CLIENTS);
+
+        state.shutdown.cancel();
+
+        let drain = Duration::from_millis(state.config.server.shutdown_drain_timeout_ms);
+        for mut ws in clients {
+            let (goodbye, closed) = tokio::time::timeout(drain, async {
+                let mut goodbye = None;
+                while let Some(Ok(msg)) = ws.next().await {
+                    match msg {
+                        WsMessage::Text(text) => {
+                            let parsed: ServerMessage = serde_json::from_str(&text).unwrap();
+                            if let ServerMessage::Goodbye { retry_after_ms, .. } = parsed {
+                                goodbye = Some(retry_after_ms);
+                            }
+                        }
+                        WsMessage::Close(frame) => {
+                            let _ = ws.close(None).await;
+                            return (goodbye, frame.map(|f| u16::from(f.code)));
+                        }
+                        _ => {}
+                    }
+                }
+                (goodbye, None)
+            })
+            .await
+            .expect("client did not receive goodbye within the drain window");
+
+            let retry_after_ms = goodbye.expect("missing goodbye message");
+            assert!(retry_after_ms >= 1_000);
+            assert_eq!(closed, Some(close_code::AWAY));
+        }
+
+        tokio::time::timeout(drain, server)
+            .await
+            .expect("server did not finish draining")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.session_manager.active_count(), 0);
+    }
+
+    pub(crate) fn client_text(json: &str) -> Result<Message, axum::Error> {
+        Ok(Message::Text(json.to_string()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wedged_writer_does_not_stall_reader() {
+        let (state, _template_tx) = test_state();
+        // A sink that never accepts a frame
+        let wedged = Box::pin(futures::sink::unfold((), |_, _: Message| async {
+            std::future::pending::<Result<(), axum::Error>>().await
+        }));
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(wedged, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        for i in 0..9 {
+            client
+                .unbounded_send(client_text(&format!(r#"{{"type":"ping","id":"{}"}}"#, i)))
+                .unwrap();
+        }
+
+        for _ in 0..100 {
+            if state.metrics.messages_received.load(Ordering::Relaxed) == 10 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.metrics.messages_received.load(Ordering::Relaxed), 10);
+
+        drop(client);
+        tokio::time::timeout(WRITER_FLUSH_TIMEOUT * 2, conn).await.unwrap().unwrap();
+        assert_eq!(state.session_manager.active_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_writer_failure_cleans_up_once() {
+        let (state, _template_tx) = test_state();
+        let failing = Box::pin(futures::sink::unfold((), |_, _: Message| async {
+            Err::<(), axum::Error>(axum::Error::new("broken pipe"))
+        }));
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(failing, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        // The hello response fails to write, which must tear down the reader too
+        client.unbounded_send(client_text(HELLO)).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), conn).await.unwrap().unwrap();
+        assert_eq!(state.metrics.connections_total.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.connections_active.load(Ordering::Relaxed), 0);
+        assert_eq!(state.session_manager.active_count(), 0);
+        drop(client);
+    }
+
+    pub(crate) const HELLO: &str = r#"{"type":"hello","v":1,"client_version":"t","threads":1}"#;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_connection_closed_after_hello_timeout() {
+        let (state, _template_tx) = test_state();
+        let timeout = Duration::from_millis(state.config.server.hello_timeout_ms);
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (_client, stream) = futures::channel::mpsc::unbounded();
+        let start = tokio::time::Instant::now();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        let error = outgoing.next().await.unwrap();
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < timeout + Duration::from_secs(1));
+        match error {
+            Message::Text(text) => assert!(text.contains("HANDSHAKE_TIMEOUT")),
+            other => panic!("expected error message, got {:?}", other),
+        }
+        assert!(matches!(outgoing.next().await, Some(Message::Close(Some(f))) if f.code == close_code::POLICY));
+
+        conn.await.unwrap();
+        assert_eq!(state.metrics.handshake_timeouts.load(Ordering::Relaxed), 1);
+        assert_eq!(state.session_manager.active_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_connection_closed_after_keepalive_timeout() {
+        let (mut state, _template_tx) = test_state();
+        state.config.server.keepalive_interval_ms = 1_000;
+        state.config.server.keepalive_timeout_ms = 3_000;
+        let interval = Duration::from_millis(1_000);
+        let timeout = Duration::from_millis(3_000);
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let start = tokio::time::Instant::now();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+        client.unbounded_send(client_text(HELLO)).unwrap();
+
+        // Pings go unanswered; the first one due past the timeout closes instead
+        let mut pings = 0;
+        let close = loop {
+            match outgoing.next().await.unwrap() {
+                Message::Ping(_) => pings += 1,
+                Message::Close(frame) => break frame,
+                _ => {}
+            }
+        };
+        assert_eq!(pings, 3);
+        assert!(start.elapsed() > timeout);
+        assert!(start.elapsed() <= timeout + interval);
+        assert!(matches!(close, Some(f) if f.code == close_code::POLICY && f.reason == "Keepalive timeout"));
+
+        conn.await.unwrap();
+        assert_eq!(state.session_manager.active_count(), 0);
+        drop(client);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prompt_hello_disarms_timeout() {
+        let (state, _template_tx) = test_state();
+        let timeout = Duration::from_millis(state.config.server.hello_timeout_ms);
+        let (sink, _outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        tokio::time::sleep(timeout * 2).await;
+
+        assert!(!conn.is_finished());
+        assert_eq!(state.session_manager.active_count(), 1);
+        assert_eq!(state.metrics.handshake_timeouts.load(Ordering::Relaxed), 0);
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session_expires_after_max_lifetime() {
+        let (mut state, _template_tx) = test_state();
+        state.config.limits.max_session_lifetime_secs = 1;
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let start = tokio::time::Instant::now();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { .. }));
+
+        // A submit that lands together with the deadline is answered before the goodbye
+        tokio::time::sleep(Duration::from_millis(900)).await;
+        client
+            .unbounded_send(client_text(r#"{"type":"submit","id":"7","job_id":"nope","nonce":"00000000"}"#))
+            .unwrap();
+        tokio::time::advance(Duration::from_millis(200)).await;
+
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::SubmitResult { id, .. } if id == "7"));
+        match next_server_message(&mut outgoing).await {
+            ServerMessage::Goodbye { reason: GoodbyeReason::SessionExpired, retry_after_ms } => {
+                assert!(retry_after_ms <= state.config.server.shutdown_retry_jitter_ms);
+            }
+            other => panic!("expected goodbye, got {:?}", other),
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(matches!(outgoing.next().await, Some(Message::Close(Some(f))) if f.code == close_code::NORMAL));
+
+        conn.await.unwrap();
+        assert_eq!(state.session_manager.active_count(), 0);
+        drop(client);
+    }
+
+    pub(crate) async fn next_server_message(outgoing: &mut futures::channel::mpsc::UnboundedReceiver<Message>) -> ServerMessage {
+        match outgoing.next().await {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    fn assert_unauthorized(msg: ServerMessage, expected: &str) {
+        match msg {
+            ServerMessage::Error { code: ErrorCode::Unauthorized, message, .. } => assert_eq!(message, expected),
+            other => panic!("expected UNAUTHORIZED, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_messages_before_hello_are_rejected() {
+        let (state, _template_tx) = test_state();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(r#"{"type":"ping","id":"1"}"#)).unwrap();
+        assert_unauthorized(next_server_message(&mut outgoing).await, "hello required");
+
+        client
+            .unbounded_send(client_text(r#"{"type":"submit","id":"2","job_id":"abc","nonce":"00000000"}"#))
+            .unwrap();
+        assert_unauthorized(next_server_message(&mut outgoing).await, "hello required");
+        assert_eq!(state.metrics.submissions_total.load(Ordering::Relaxed), 0);
+
+        // The session is still usable once it says hello
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { .. }));
+        client.unbounded_send(client_text(r#"{"type":"ping","id":"3"}"#)).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Pong { id } if id == "3"));
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_repeat_hello_is_rejected() {
+        let (state, _template_tx) = test_state();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        next_server_message(&mut outgoing).await;
+
+        client
+            .unbounded_send(client_text(r#"{"type":"hello","v":1,"client_version":"other","threads":8}"#))
+            .unwrap();
+        assert_unauthorized(next_server_message(&mut outgoing).await, "hello already received");
+
+        let session = state.session_manager.list_sessions().pop().unwrap();
+        assert_eq!(session.client_version.as_deref(), Some("t"));
+        assert_eq!(session.threads, 1);
+        assert_eq!(session.protocol_violations, 1);
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_repeated_violations_disconnect() {
+        let (state, _template_tx) = test_state();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        for i in 0..MAX_PROTOCOL_VIOLATIONS {
+            client
+                .unbounded_send(client_text(&format!(r#"{{"type":"ping","id":"{}"}}"#, i)))
+                .unwrap();
+            assert_unauthorized(next_server_message(&mut outgoing).await, "hello required");
+        }
+        assert!(matches!(outgoing.next().await, Some(Message::Close(Some(f))) if f.code == close_code::POLICY));
+
+        conn.await.unwrap();
+        assert_eq!(state.session_manager.active_count(), 0);
+    }
+
+    pub(crate) fn test_template() -> TemplateState {
+        let template = BlockTemplate {
+            blockhashing_blob: blob::fixture::HASHING_BLOB.into(),
+            blocktemplate_blob: blob::fixture::TEMPLATE_BLOB.into(),
+            difficulty: 1,
+            expected_reward: 0,
+            height: 100,
+            prev_hash: blob::fixture::PREV_HASH.into(),
+            reserved_offset: blob::fixture::RESERVED_OFFSET,
+            seed_hash: "00".repeat(32),
+            next_seed_hash: String::new(),
+            status: "OK".into(),
+        };
+        TemplateState::from_rpc(template, 1, blob::fixture::RESERVE_SIZE).unwrap()
+    }
+
+    async fn next_ws_message<S>(ws: &mut S) -> ServerMessage
+    where
+        S: Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        loop {
+            match ws.next().await {
+                Some(Ok(WsMessage::Text(text))) => return serde_json::from_str(&text).unwrap(),
+                Some(Ok(_)) => continue,
+                other => panic!("expected a text frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_submissions_ban_ip_until_lapse() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+        let url = format!("ws://{}/ws", addr);
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        ws.send(WsMessage::Text(HELLO.into())).await.unwrap();
+        let job_id = match next_ws_message(&mut ws).await {
+            ServerMessage::Job { job_id, .. } => job_id,
+            other => panic!("expected a job, got {:?}", other),
+        };
+
+        // One more than max_offenses invalid nonces trips the ban
+        for i in 0..3 {
+            let submit = format!(r#"{{"type":"submit","id":"{}","job_id":"{}","nonce":"zz"}}"#, i, job_id);
+            ws.send(WsMessage::Text(submit)).await.unwrap();
+            assert!(matches!(
+                next_ws_message(&mut ws).await,
+                ServerMessage::SubmitResult { status: SubmitStatus::Rejected, .. }
+            ));
+        }
+        assert_eq!(state.metrics.bans_issued.load(Ordering::Relaxed), 1);
+
+        // The offending session is dropped and reconnects are refused pre-upgrade
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let WsMessage::Close(_) = msg {
+                    return true;
+                }
+            }
+            true
+        })
+        .await
+        .unwrap();
+        assert!(closed);
+
+        match tokio_tungstenite::connect_async(&url).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::FORBIDDEN)
+            }
+            other => panic!("expected 403, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(state.metrics.banned_connections.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(tokio_tungstenite::connect_async(&url).await.is_ok());
+        state.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_connection_churn_gets_429() {
+        let (state, _template_tx) = test_state();
+        let limit = state.config.limits.connections_per_minute;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+        let url = format!("ws://{}/ws", addr);
+
+        for _ in 0..limit {
+            let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+            ws.close(None).await.unwrap();
+        }
+
+        match tokio_tungstenite::connect_async(&url).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+                let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+                assert!((1..=60).contains(&retry_after));
+            }
+            other => panic!("expected 429, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(state.metrics.connections_rate_limited.load(Ordering::Relaxed), 1);
+        state.shutdown.cancel();
+    }
+
+    fn hello_with_token(token: Option<&str>) -> String {
+        let mut hello: serde_json::Value = serde_json::from_str(HELLO).unwrap();
+        if let Some(token) = token {
+            hello["site_token"] = token.into();
+        }
+        hello.to_string()
+    }
+
+    fn auth_state(require: bool) -> (AppState, watch::Sender<Option<TemplateState>>) {
+        let (mut state, template_tx) = test_state();
+        state.config.auth = crate::config::AuthConfig {
+            require_site_token: require,
+            tokens: [("tok-a".to_string(), "partner-a".to_string())].into_iter().collect(),
+            ..Default::default()
+        };
+        (state, template_tx)
+    }
+
+    /// Send a hello and return the reply and whether the server closed the socket
+    async fn hello_outcome(require: bool, token: Option<&str>) -> (ServerMessage, bool) {
+        let (state, _template_tx) = auth_state(require);
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state, "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(&hello_with_token(token))).unwrap();
+        let reply = next_server_message(&mut outgoing).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let closed = conn.is_finished();
+
+        drop(client);
+        conn.await.unwrap();
+        (reply, closed)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_site_token_required() {
+        for token in [None, Some("wrong")] {
+            let (reply, closed) = hello_outcome(true, token).await;
+            assert_unauthorized(reply, "invalid site token");
+            assert!(closed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_valid_site_token_labels_session() {
+        let (state, _template_tx) = auth_state(true);
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(&hello_with_token(Some("tok-a")))).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { .. }));
+        let session = state.session_manager.list_sessions().pop().unwrap();
+        assert_eq!(session.site_label.as_deref(), Some("partner-a"));
+        assert_eq!(session.state, SessionState::Ready);
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_site_token_optional_passthrough() {
+        for token in [None, Some("wrong")] {
+            let (reply, closed) = hello_outcome(false, token).await;
+            assert!(matches!(reply, ServerMessage::Stats { .. }));
+            assert!(!closed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_pushed_periodically_after_hello() {
+        let (mut state, _template_tx) = test_state();
+        state.config.server.stats_interval_ms = 20;
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state, "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        let mut times = Vec::new();
+        while times.len() < 3 {
+            match next_server_message(&mut outgoing).await {
+                ServerMessage::Stats { stats_interval_ms, server_time_ms, tip_height, outbound, .. } => {
+                    assert_eq!(stats_interval_ms, 20);
+                    assert_eq!(tip_height, None);
+                    assert!(outbound.is_some());
+                    times.push(server_time_ms);
+                }
+                other => panic!("expected stats, got {:?}", other),
+            }
+        }
+        // The first is the hello reply, the rest were pushed
+        assert!(times.windows(2).all(|w| w[0] < w[1]), "{:?}", times);
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outbound_counters_match_frames_written() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        for frame in [
+            HELLO,
+            r#"{"type":"ping","id":"1"}"#,
+            r#"{"type":"ping","id":"2"}"#,
+            r#"{"type":"submit","id":"3","job_id":"nope","nonce":"00000000"}"#,
+        ] {
+            client.unbounded_send(client_text(frame)).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let stats = state.session_manager.list_sessions().pop().unwrap().outbound;
+        assert!(stats.snapshot().messages_sent.contains_key("job"));
+        client.unbounded_send(Ok(Message::Close(None))).unwrap();
+        conn.await.unwrap();
+
+        let mut written = std::collections::BTreeMap::new();
+        let mut bytes = 0;
+        while let Ok(frame) = outgoing.try_recv() {
+            let kind = match &frame {
+                Message::Text(text) => {
+                    bytes += text.len() as u64;
+                    serde_json::from_str::<ServerMessage>(text).unwrap().kind()
+                }
+                Message::Ping(_) => "ping",
+                Message::Close(_) => "close",
+                other => panic!("unexpected frame {:?}", other),
+            };
+            *written.entry(kind.to_string()).or_insert(0u64) += 1;
+        }
+
+        let counters = stats.snapshot();
+        assert_eq!(counters.messages_sent, written);
+        assert_eq!(written.get("pong"), Some(&2));
+        assert_eq!(written.get("close"), Some(&1));
+        assert_eq!(counters.bytes_sent, bytes);
+        assert_eq!(state.metrics.ws_bytes_sent.load(Ordering::Relaxed), bytes);
+        assert_eq!(counters.queue_depth, 0);
+        assert!(counters.queue_high_watermark >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_hello_worker_name_is_validated_and_stored() {
+        let (state, _template_tx) = test_state();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let hello = |worker: &str| ClientMessage::Hello {
+            v: 1,
+            client_version: "t".into(),
+            threads: 1,
+            site_token: None,
+            worker: Some(worker.to_string()),
+            options: Default::default(),
+        };
+
+        for bad in ["", "tab\tname", "caf\u{e9}", &"w".repeat(protocol::MAX_WORKER_LEN + 1)] {
+            match handle_message(&state, &session.id, hello(bad)).await {
+                Some(ServerMessage::Error { code: ErrorCode::BadFormat, .. }) => {}
+                other => panic!("expected BAD_FORMAT for {:?}, got {:?}", bad, other),
+            }
+        }
+        assert_eq!(state.session_manager.get_session(&session.id).unwrap().state, SessionState::Connected);
+
+        assert!(handle_message(&state, &session.id, hello("blog-footer")).await.is_some());
+        let session = state.session_manager.get_session(&session.id).unwrap();
+        assert_eq!(session.state, SessionState::Ready);
+        assert_eq!(session.worker.as_deref(), Some("blog-footer"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hello_flags_shape_the_session_and_its_jobs() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+
+        let mut negotiated = Vec::new();
+        for hello in [
+            HELLO,
+            r#"{"type":"hello","v":1,"client_version":"t","threads":1,"compact_target":true,"batching":true,"keepalive_ms":10000}"#,
+        ] {
+            let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+            let (client, stream) = futures::channel::mpsc::unbounded();
+            let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+            client.unbounded_send(client_text(hello)).unwrap();
+            let target_hex = match next_server_message(&mut outgoing).await {
+                ServerMessage::Job { target_hex, .. } => target_hex,
+                other => panic!("expected a job, got {:?}", other),
+            };
+            let session = state.session_manager.list_sessions().pop().unwrap();
+            negotiated.push((target_hex, session.capabilities.unwrap()));
+            drop(client);
+            conn.await.unwrap();
+        }
+
+        let (target_hex, plain) = &negotiated[0];
+        assert_eq!(target_hex.len(), 64);
+        assert!(!plain.wants_compact_target && !plain.supports_batching);
+        assert_eq!(plain.keepalive_ms, state.config.server.keepalive_interval_ms);
+
+        let (target_hex, flagged) = &negotiated[1];
+        assert_eq!(*target_hex, compact_target_hex(test_template().difficulty));
+        assert_eq!(target_hex.len(), 8);
+        assert!(flagged.wants_compact_target && flagged.supports_batching);
+        assert_eq!(flagged.keepalive_ms, 10_000);
+        assert_eq!(flagged.encoding, protocol::Encoding::Text);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hello_can_ask_for_binary_replies() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client
+            .unbounded_send(client_text(r#"{"type":"hello","v":1,"client_version":"t","threads":1,"encoding":"binary"}"#))
+            .unwrap();
+        assert!(matches!(next_binary_message(&mut outgoing).await, ServerMessage::Job { .. }));
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hello_randomx_mode_is_recorded() {
+        let (mut state, _template_tx) = test_state();
+        state.config.vardiff.enable = true;
+        state.config.vardiff.initial_difficulty_light = Some(800);
+        let limits = SessionManagerConfig { max_per_ip: 100, max_total: 100, ..Default::default() };
+        state.session_manager = Arc::new(SessionManager::new(limits).with_vardiff(&state.config.vardiff));
+
+        for (field, expected) in [
+            (r#","randomx_mode":"fast""#, RandomxMode::Fast),
+            (r#","randomx_mode":"light""#, RandomxMode::Light),
+            (r#","randomx_mode":"turbo""#, RandomxMode::Unknown),
+            ("", RandomxMode::Unknown),
+        ] {
+            let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+            let hello = format!(r#"{{"type":"hello","v":1,"client_version":"t","threads":1{}}}"#, field);
+            handle_message(&state, &session.id, serde_json::from_str(&hello).unwrap()).await;
+            assert_eq!(state.session_manager.get_session(&session.id).unwrap().randomx_mode, expected);
+
+            let initial = if expected == RandomxMode::Light { 800 } else { state.config.vardiff.initial_difficulty };
+            assert_eq!(state.session_manager.share_difficulty(&session.id), Some(initial));
+        }
+
+        // Sessions that have not said hello yet are not counted
+        state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let counts = state.session_manager.count_by_randomx_mode();
+        assert_eq!(counts, std::collections::HashMap::from([(RandomxMode::Fast, 1), (RandomxMode::Light, 1), (RandomxMode::Unknown, 2)]));
+    }
+
+    #[tokio::test]
+    async fn test_hello_for_another_algo_is_refused() {
+        let (state, _template_tx) = test_state();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let hello: ClientMessage =
+            serde_json::from_str(r#"{"type":"hello","v":1,"client_version":"t","threads":1,"algo":"cn/r"}"#).unwrap();
+        match handle_message(&state, &session.id, hello).await {
+            Some(ServerMessage::Error { code: ErrorCode::BadFormat, message, .. }) => assert_eq!(message, "unsupported algo"),
+            other => panic!("expected BAD_FORMAT, got {:?}", other),
+        }
+        let session = state.session_manager.get_session(&session.id).unwrap();
+        assert_eq!(session.state, SessionState::Connected);
+        assert!(session.capabilities.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_jobs_carry_the_session_share_difficulty() {
+        let (mut state, template_tx) = test_state();
+        state.config.vardiff.enable = true;
+        let limits = SessionManagerConfig { max_per_ip: 100, max_total: 100, ..Default::default() };
+        state.session_manager = Arc::new(SessionManager::new(limits).with_vardiff(&state.config.vardiff));
+        let mut template = test_template();
+        template.set_difficulty(1_000_000);
+        template_tx.send(Some(template.clone())).unwrap();
+
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let hello = ClientMessage::Hello {
+            v: 1,
+            client_version: "t".into(),
+            threads: 1,
+            site_token: None,
+            worker: None,
+            options: Default::default(),
+        };
+        let job_id = match handle_message(&state, &session.id, hello).await {
+            Some(ServerMessage::Job { job_id, .. }) => job_id,
+            other => panic!("expected a job, got {:?}", other),
+        };
+        let job = state.job_manager.get_job(&job_id).unwrap();
+        assert_eq!(job.share_difficulty, state.config.vardiff.initial_difficulty);
+        assert_eq!(job.difficulty, 1_000_000);
+        assert_ne!(job.share_target, job.block_target);
+
+        // Share difficulty never exceeds the block's
+        template.set_difficulty(100);
+        let job = session_job(&state, &template, &session.id).unwrap();
+        assert_eq!(job.share_difficulty, 100);
+        assert_eq!(job.share_target, job.block_target);
+    }
+
+    #[test]
+    fn test_default_share_difficulty_without_vardiff() {
+        let (mut state, _template_tx) = test_state();
+        let mut template = test_template();
+        template.set_difficulty(1_000_000);
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+
+        assert_eq!(session_job(&state, &template, &session.id).unwrap().share_difficulty, 1_000_000);
+        state.config.jobs.default_share_difficulty = 5000;
+        let job = session_job(&state, &template, &session.id).unwrap();
+        assert_eq!(job.share_difficulty, 5000);
+        assert_eq!(job.difficulty, 1_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_submits_only_count_for_the_session_that_got_the_job() {
+        let (mut state, template_tx) = test_state();
+        state.job_manager = Arc::new(JobManager::new(0));
+        let ip = "198.51.100.1".parse().unwrap();
+        let session = state.session_manager.create_session(ip).unwrap();
+        let other = state.session_manager.create_session(ip).unwrap();
+        for id in [&session.id, &other.id] {
+            assert!(state.session_manager.set_ready(id, "t".into(), 1, None, None));
+        }
+        template_tx.send(Some(test_template())).unwrap();
+
+        let issue = |session_id: &str| {
+            let job = session_job(&state, &test_template(), session_id).unwrap();
+            state.session_manager.update_session(session_id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+            job
+        };
+        let older = issue(&session.id);
+        let newest = issue(&session.id);
+        let foreign = issue(&other.id);
+        assert!(state.session_manager.owns_job(&session.id, &newest.job_id));
+
+        // The second-newest job is still the session's; it fails only on the
+        // (stubbed) proof of work, not on ownership
+        let submit = ClientMessage::Submit { id: "1".into(), job_id: older.job_id.clone(), nonce: "00000000".into(), result: None };
+        assert!(matches!(handle_message(&state, &session.id, submit).await, Some(ServerMessage::SubmitResult { .. })));
+
+        let submit = ClientMessage::Submit { id: "2".into(), job_id: foreign.job_id.clone(), nonce: "00000000".into(), result: None };
+        match handle_message(&state, &session.id, submit).await {
+            Some(ServerMessage::Error { id, code: ErrorCode::BadJob, .. }) => assert_eq!(id.as_deref(), Some("2")),
+            other => panic!("expected BAD_JOB, got {:?}", other),
+        }
+
+        // Only the last few jobs are remembered
+        for _ in 0..crate::session::RECENT_JOBS {
+            issue(&session.id);
+        }
+        assert!(!state.session_manager.owns_job(&session.id, &older.job_id));
+    }
+
+    #[tokio::test]
+    async fn test_submit_outcomes_counted_once_per_reply() {
+        let (mut state, template_tx) = test_state();
+        state.session_manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: 100, max_total: 100, submits_per_minute: 3, ..Default::default() }));
+        state.job_manager = Arc::new(JobManager::new(0));
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+
+        template_tx.send(Some(test_template())).unwrap();
+        let job = state.job_manager.create_job(&test_template(), &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        // A template on another block, so the job is stale once the (zero) grace passes
+        let mut next = test_template();
+        next.template_id = 2;
+        next.prev_hash = "22".repeat(32);
+        template_tx.send(Some(next)).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+
+        let script = [
+            ("nope", SubmitStatus::Rejected),
+            (job.job_id.as_str(), SubmitStatus::Stale),
+            (job.job_id.as_str(), SubmitStatus::Stale),
+            // Over submits_per_minute
+            (job.job_id.as_str(), SubmitStatus::Error),
+        ];
+        for (i, (job_id, expected)) in script.iter().enumerate() {
+            let submit = ClientMessage::Submit { id: i.to_string(), job_id: job_id.to_string(), nonce: "00000000".into(), result: None };
+            match handle_message(&state, &session.id, submit).await {
+                Some(ServerMessage::SubmitResult { status, .. }) => {
+                    assert_eq!(std::mem::discriminant(&status), std::mem::discriminant(expected));
+                }
+                other => panic!("expected a submit result, got {:?}", other),
+            }
+        }
+
+        let session = state.session_manager.get_session(&session.id).unwrap();
+        assert_eq!(session.accepted_submits, 0);
+        assert_eq!(session.rejected_submits, 1);
+        assert_eq!(session.stale_submits, 2);
+        assert_eq!(session.last_accept_at, None);
+        match stats_message(&state, &session.id, None) {
+            ServerMessage::Stats { rejected, stale, .. } => assert_eq!((rejected, stale), (1, 2)),
+            other => panic!("expected stats, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_nonce_never_reaches_the_validator() {
+        let (state, template_tx) = test_state();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        template_tx.send(Some(test_template())).unwrap();
+        let job = state.job_manager.create_job(&test_template(), &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+
+        let submit = |id: &str, nonce: &str| ClientMessage::Submit { id: id.into(), job_id: job.job_id.clone(), nonce: nonce.into(), result: None };
+        handle_message(&state, &session.id, submit("1", "00000000")).await.unwrap();
+        assert_eq!(state.validator.validations(), 1);
+
+        match handle_message(&state, &session.id, submit("2", "00000000")).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Rejected, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("duplicate"));
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 1);
+        assert_eq!(state.metrics.submissions_duplicate.load(Ordering::Relaxed), 1);
+
+        // Another nonce for the same job is still validated
+        handle_message(&state, &session.id, submit("3", "01000000")).await.unwrap();
+        assert_eq!(state.validator.validations(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_jobs_are_stale_and_unknown_ones_rejected() {
+        let (mut state, template_tx) = test_state();
+        state.job_manager = Arc::new(JobManager::new(60_000).with_block_ttl_ms(0));
+        template_tx.send(Some(test_template())).unwrap();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        // From the current template, so only its TTL makes it stale
+        let job = state.job_manager.create_job(&test_template(), &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+
+        for (job_id, expected) in [(job.job_id.as_str(), SubmitStatus::Stale), ("0123456789abcdef", SubmitStatus::Rejected)] {
+            let submit = ClientMessage::Submit { id: "1".into(), job_id: job_id.into(), nonce: "00000000".into(), result: None };
+            match handle_message(&state, &session.id, submit).await {
+                Some(ServerMessage::SubmitResult { status, .. }) => {
+                    assert_eq!(std::mem::discriminant(&status), std::mem::discriminant(&expected), "{}", job_id);
+                }
+                other => panic!("expected a submit result, got {:?}", other),
+            }
+        }
+        assert_eq!(state.validator.validations(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shares_outlive_the_block_ttl() {
+        let (mut state, template_tx) = test_state();
+        state.job_manager = Arc::new(JobManager::new(60_000).with_block_ttl_ms(0).with_share_ttl_ms(60_000));
+        let mut template = test_template();
+        template.difficulty = 1_000;
+        template_tx.send(Some(template.clone())).unwrap();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let share_job = state.job_manager.create_job(&template, &session.id, 10).unwrap();
+        let block_job = state.job_manager.create_job(&template, &session.id, 1_000).unwrap();
+        issue_jobs(&state, &session.id, &[share_job.clone(), block_job.clone()]);
+
+        // Between the two TTLs a share job is still hashed, a block-only one is stale
+        let submit = |job: &Job| ClientMessage::Submit { id: "1".into(), job_id: job.job_id.clone(), nonce: "00000000".into(), result: None };
+        assert!(matches!(state.job_manager.lookup_job(&share_job.job_id), JobLookup::BlockExpired(_)));
+        handle_message(&state, &session.id, submit(&share_job)).await.unwrap();
+        assert_eq!(state.validator.validations(), 1);
+        match handle_message(&state, &session.id, submit(&block_job)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Stale, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("Job expired: older than block_ttl_ms"));
+            }
+            other => panic!("expected a stale result, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 1);
+
+        // Past the share TTL too, the share job is stale as well
+        state.job_manager = Arc::new(JobManager::new(60_000).with_block_ttl_ms(0).with_share_ttl_ms(0));
+        let share_job = state.job_manager.create_job(&template, &session.id, 10).unwrap();
+        issue_jobs(&state, &session.id, std::slice::from_ref(&share_job));
+        match handle_message(&state, &session.id, submit(&share_job)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Stale, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("Job expired: older than share_ttl_ms"));
+            }
+            other => panic!("expected a stale result, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_new_height_stales_jobs_but_a_refresh_does_not() {
+        let (mut state, template_tx) = test_state();
+        // No grace, so only the block the job builds on decides
+        state.job_manager = Arc::new(JobManager::new(0));
+        let mut template = test_template();
+        template.prev_hash = "11".repeat(32);
+        template_tx.send(Some(template.clone())).unwrap();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let job = state.job_manager.create_job(&template, &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        let submit = |nonce: &str| ClientMessage::Submit { id: "1".into(), job_id: job.job_id.clone(), nonce: nonce.into(), result: None };
+
+        // A refresh on the same block leaves the job fresh, so the submit is validated
+        let mut refresh = template.clone();
+        refresh.template_id += 1;
+        template_tx.send(Some(refresh.clone())).unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        handle_message(&state, &session.id, submit("01000000")).await;
+        assert_eq!(state.validator.validations(), 1);
+
+        // Another block at the same height, or a new height, does not
+        let mut reorg = refresh.clone();
+        reorg.prev_hash = "22".repeat(32);
+        let mut next_block = refresh;
+        next_block.height += 1;
+        for (nonce, current) in [("02000000", reorg), ("03000000", next_block)] {
+            template_tx.send(Some(current)).unwrap();
+            match handle_message(&state, &session.id, submit(nonce)).await {
+                Some(ServerMessage::SubmitResult { status: SubmitStatus::Stale, .. }) => {}
+                other => panic!("expected stale, got {:?}", other),
+            }
+        }
+        assert_eq!(state.validator.validations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_earlier_height_in_job_id_is_stale_without_lookup() {
+        let (state, template_tx) = test_state();
+        let template = test_template();
+        template_tx.send(Some(template.clone())).unwrap();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+
+        // Neither job was ever issued; only the id's height tells them apart
+        let old = JobId { height: template.height - 1, template_id: 1, session: 0, seq: 1 }.to_string();
+        let current = JobId { height: template.height, template_id: template.template_id, session: 0, seq: 1 }.to_string();
+        for (job_id, expected) in [(old, SubmitStatus::Stale), (current, SubmitStatus::Rejected), ("0123456789abcdef".into(), SubmitStatus::Rejected)] {
+            let submit = ClientMessage::Submit { id: "1".into(), job_id: job_id.clone(), nonce: "00000000".into(), result: None };
+            match handle_message(&state, &session.id, submit).await {
+                Some(ServerMessage::SubmitResult { status, .. }) => {
+                    assert_eq!(std::mem::discriminant(&status), std::mem::discriminant(&expected), "{}", job_id);
+                }
+                other => panic!("expected a submit result, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_each_thread_gets_its_own_job() {
+        let (mut state, template_tx) = test_state();
+        state.config.jobs.job_per_thread = true;
+        template_tx.send(Some(test_template())).unwrap();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(r#"{"type":"hello","v":1,"client_version":"t","threads":4}"#)).unwrap();
+        let mut jobs = Vec::new();
+        for _ in 0..4 {
+            match next_server_message(&mut outgoing).await {
+                ServerMessage::Job { job_id, reserved_value_hex, blob_hex, .. } => jobs.push((job_id, reserved_value_hex, blob_hex)),
+                other => panic!("expected a job, got {:?}", other),
+            }
+        }
+        let reserved: std::collections::HashSet<&String> = jobs.iter().map(|(_, reserved, _)| reserved).collect();
+        assert_eq!(reserved.len(), 4);
+        let blobs: std::collections::HashSet<&String> = jobs.iter().map(|(_, _, blob)| blob).collect();
+        assert_eq!(blobs.len(), 4);
+
+        // A submit against any sibling is the session's own work, and is hashed
+        for (i, (job_id, _, _)) in jobs.iter().enumerate() {
+            let submit = format!(r#"{{"type":"submit","id":"{}","job_id":"{}","nonce":"00000000"}}"#, i, job_id);
+            client.unbounded_send(client_text(&submit)).unwrap();
+            match next_server_message(&mut outgoing).await {
+                ServerMessage::SubmitResult { status, .. } => assert!(!matches!(status, SubmitStatus::Stale)),
+                other => panic!("expected a submit result, got {:?}", other),
+            }
+        }
+        assert_eq!(state.validator.validations(), 4);
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_submissions_past_the_job_cap_are_not_hashed() {
+        const CAP: u32 = 3;
+        let (mut state, template_tx) = test_state();
+        state.config.jobs.max_submissions_per_job = CAP;
+        state.job_manager = Arc::new(JobManager::new(0).with_max_submissions_per_job(CAP));
+        template_tx.send(Some(test_template())).unwrap();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let job = state.job_manager.create_job(&test_template(), &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+
+        for n in 0..=CAP {
+            let submit = ClientMessage::Submit { id: n.to_string(), job_id: job.job_id.clone(), nonce: hex::encode(n.to_le_bytes()), result: None };
+            let response = handle_message(&state, &session.id, submit).await;
+            if n < CAP {
+                assert!(matches!(response, Some(ServerMessage::SubmitResult { .. })), "{:?}", response);
+            } else {
+                assert!(matches!(response, Some(ServerMessage::Error { code: ErrorCode::RateLimit, .. })), "{:?}", response);
+            }
+        }
+        assert_eq!(state.validator.validations(), u64::from(CAP));
+        assert_eq!(state.metrics.job_submission_caps_hit.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_job_listed_by_another_session_is_refused() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let first = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let job = state.job_manager.create_job(&test_template(), &first.id, 1).unwrap();
+        // The same job in a second session's list; the job names its owner
+        let second = state.session_manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        for session in [&first, &second] {
+            assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+            state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        }
+
+        let submit = || ClientMessage::Submit { id: "1".into(), job_id: job.job_id.clone(), nonce: "0a000000".into(), result: None };
+        match handle_message(&state, &second.id, submit()).await {
+            Some(ServerMessage::Error { code: ErrorCode::BadJob, message, .. }) => {
+                assert_eq!(message, "Job was not issued to this session");
+            }
+            other => panic!("expected BAD_JOB, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 0);
+
+        handle_message(&state, &first.id, submit()).await.unwrap();
+        assert_eq!(state.validator.validations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_jobs_stay_owned_across_a_resume() {
+        let (state, template_tx) = test_state();
+        let state = AppState {
+            session_manager: Arc::new(
+                SessionManager::new(SessionManagerConfig::from_config(&state.config).unwrap()).with_resume(Duration::from_secs(60), true),
+            ),
+            ..state
+        };
+        template_tx.send(Some(test_template())).unwrap();
+        let ip = "198.51.100.1".parse().unwrap();
+        let session = state.session_manager.create_session(ip).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let job = session_job(&state, &test_template(), &session.id).unwrap();
+        issue_jobs(&state, &session.id, std::slice::from_ref(&job));
+
+        let token = state.session_manager.detach_session(&session.id).unwrap();
+        let resumed = state.session_manager.resume_session(&token, ip).unwrap();
+        assert_eq!(resumed.id, session.id);
+
+        // Hashed, so past the ownership check; a replay is still caught
+        let submit = || ClientMessage::Submit { id: "1".into(), job_id: job.job_id.clone(), nonce: "0a000000".into(), result: None };
+        handle_message(&state, &resumed.id, submit()).await.unwrap();
+        assert_eq!(state.validator.validations(), 1);
+        match handle_message(&state, &resumed.id, submit()).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Rejected, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("duplicate"));
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_submits_beyond_inflight_limit_are_refused_fast() {
+        let (mut state, template_tx) = test_state();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        template_tx.send(Some(test_template())).unwrap();
+        let job = state.job_manager.create_job(&test_template(), &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        let fake = Arc::new(FakeVerifier::new().with_delay(Duration::from_millis(500)));
+        state.pow = fake.clone();
+
+        let submit = |i: u32| ClientMessage::Submit { id: i.to_string(), job_id: job.job_id.clone(), nonce: format!("{:08x}", i), result: None };
+        let slow: Vec<_> = (0..2)
+            .map(|i| {
+                let (state, session_id, msg) = (state.clone(), session.id.clone(), submit(i));
+                tokio::spawn(async move { handle_message(&state, &session_id, msg).await })
+            })
+            .collect();
+        while state.validator.validations() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        match handle_message(&state, &session.id, submit(2)).await {
+            Some(ServerMessage::Error { code: ErrorCode::RateLimit, details: Some(details), .. }) => {
+                assert_eq!(details["limit"], "max_inflight_submits");
+                assert_eq!(details["max"], 2);
+                assert_eq!(details["retry_after_ms"], SUBMIT_BUSY_RETRY_MS);
+            }
+            other => panic!("expected a rate limit error, got {:?}", other),
+        }
+        // Refused while both are still hashing, without waiting on either
+        assert!(slow.iter().all(|task| !task.is_finished()));
+
+        for task in slow {
+            assert!(matches!(task.await.unwrap(), Some(ServerMessage::SubmitResult { .. })));
+        }
+        assert_eq!(state.validator.validations(), 2);
+        assert_eq!(fake.hashes(), 2);
+        // Both slots were given back
+        let slots: Vec<_> = (0..2).filter_map(|_| state.session_manager.try_begin_submit(&session.id)).collect();
+        assert_eq!(slots.len(), 2);
+    }
+
+    /// A ready session at `ip` holding a fresh job, and a submit for it
+    fn session_with_job(state: &AppState, ip: &str) -> (String, ClientMessage) {
+        let session = state.session_manager.create_session(ip.parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let job = state.job_manager.create_job(&test_template(), &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        (session.id, ClientMessage::Submit { id: "1".into(), job_id: job.job_id, nonce: "00000001".into(), result: None })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_hashes_from_different_sessions_overlap() {
+        let (mut state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let fake = Arc::new(FakeVerifier::new().with_delay(Duration::from_millis(400)));
+        state.pow = fake.clone();
+
+        let submits: Vec<_> = ["198.51.100.1", "198.51.100.2"]
+            .into_iter()
+            .map(|ip| {
+                let (state, (session_id, msg)) = (state.clone(), session_with_job(&state, ip));
+                tokio::spawn(async move { handle_message(&state, &session_id, msg).await })
+            })
+            .collect();
+        // Each is answered, whatever its hash is worth
+        for task in submits {
+            assert!(matches!(task.await.unwrap(), Some(ServerMessage::SubmitResult { .. })));
+        }
+        // Run one after the other, only one would have been in flight at a time
+        assert_eq!(fake.hashes(), 2);
+        assert_eq!(fake.peak(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_many_nonces_are_hashed_across_the_vm_pool() {
+        let (mut state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let fake = Arc::new(FakeVerifier::new().with_delay(Duration::from_millis(150)));
+        state.pow = fake.clone();
+        let submits = 12;
+
+        let tasks: Vec<_> = (0..submits)
+            .map(|i| {
+                let (state, (session_id, msg)) = (state.clone(), session_with_job(&state, &format!("198.51.100.{}", i + 1)));
+                tokio::spawn(async move { handle_message(&state, &session_id, msg).await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(matches!(task.await.unwrap(), Some(ServerMessage::SubmitResult { .. })));
+        }
+        assert_eq!(state.validator.validations(), submits);
+
+        // Four VMs: every hash made, never more than four at once
+        assert_eq!(fake.hashes(), submits);
+        assert_eq!(fake.peak(), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_next_seed_is_made_before_the_switch() {
+        let metrics = Arc::new(Metrics::new());
+        let vms: Arc<dyn PowVerifier> = Arc::new(LocalVerifier::new(VmPool::new(1).with_metrics(metrics.clone())));
+        let validator = Arc::new(SubmissionValidator::new().with_metrics(metrics.clone()));
+        let (template_tx, template_rx) = watch::channel(None);
+        let shutdown = CancellationToken::new();
+        tokio::spawn(crate::validator::prewarm_seeds(validator.clone(), vms.clone(), template_rx, shutdown.clone()));
+
+        // Near the boundary monerod announces the next seed
+        let mut template = test_template();
+        template.next_seed_hash = "11".repeat(32);
+        template_tx.send(Some(template.clone())).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while metrics.randomx_prewarms_completed.load(Ordering::Relaxed) == 0 {
+            assert!(Instant::now() < deadline, "prewarm did not finish");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // The chain switches; the first hash for the new seed finds its cache made
+        template.seed_hash = std::mem::take(&mut template.next_seed_hash);
+        template_tx.send(Some(template.clone())).unwrap();
+        let blob = hex::decode(&template.blockhashing_blob).unwrap();
+        let ticket = validator.enqueue(vms.as_ref(), false).unwrap();
+        assert!(validator.verify(vms.as_ref(), &template.seed_hash, template.height, blob, ticket).await.is_ok());
+        assert_eq!(metrics.randomx_inline_cache_inits.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.randomx_prewarms_started.load(Ordering::Relaxed), 1);
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_claimed_result_hash_must_match() {
+        let (state, _template_tx, session_id, job) = fake_verifier_session("198.51.100.1").await;
+        let submit = |nonce: &str, result: &str| ClientMessage::Submit {
+            id: nonce.into(),
+            job_id: job.job_id.clone(),
+            nonce: nonce.into(),
+            result: Some(result.into()),
+        };
+
+        let hash = FakeVerifier::hash(&job.apply_nonce("00000001").unwrap(), &job.seed_hash);
+        let matching = handle_message(&state, &session_id, submit("00000001", &hex::encode(hash))).await;
+        assert!(matches!(matching, Some(ServerMessage::SubmitResult { .. })), "got {:?}", matching);
+        match handle_message(&state, &session_id, submit("00000002", &"11".repeat(32))).await {
+            Some(ServerMessage::Error { id, code: ErrorCode::BadPow, .. }) => assert_eq!(id.as_deref(), Some("00000002")),
+            other => panic!("expected BAD_POW, got {:?}", other),
+        }
+        assert_eq!(state.metrics.bad_pow.load(Ordering::Relaxed), 1);
+        assert_eq!(state.validator.validations(), 2);
+
+        match handle_message(&state, &session_id, submit("00000003", "zz")).await {
+            Some(ServerMessage::Error { code: ErrorCode::BadFormat, .. }) => {}
+            other => panic!("expected BAD_FORMAT, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unsampled_share_claims_are_credited_without_hashing() {
+        let (mut state, template_tx) = test_state();
+        state.validator = Arc::new(SubmissionValidator::new().with_share_sampling(ShareSampler::seeded(0, 1)));
+        state.pow = Arc::new(FakeVerifier::new());
+        let mut template = test_template();
+        template.set_difficulty(u64::MAX);
+        template_tx.send(Some(template.clone())).unwrap();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let job = state.job_manager.create_job(&template, &session.id, 1).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        let submit = |nonce: &str, result: [u8; 32]| ClientMessage::Submit {
+            id: nonce.into(),
+            job_id: job.job_id.clone(),
+            nonce: nonce.into(),
+            result: Some(hex::encode(result)),
+        };
+
+        // Meets the share target but not the block's, so it is taken on trust
+        let mut share = [0u8; 32];
+        share[31] = 0x10;
+        match handle_message(&state, &session.id, submit("00000001", share)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Accepted, .. }) => {}
+            other => panic!("expected the share credited, got {:?}", other),
+        }
+        assert_eq!(state.metrics.shares_unverified.load(Ordering::Relaxed), 1);
+
+        // A claimed block is always hashed; a wrong claim is caught
+        share[31] = 0;
+        share[0] = 1;
+        match handle_message(&state, &session.id, submit("00000002", share)).await {
+            Some(ServerMessage::Error { code: ErrorCode::BadPow, .. }) => {}
+            other => panic!("expected BAD_POW, got {:?}", other),
+        }
+        assert_eq!(state.metrics.shares_unverified.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ping_is_not_held_behind_a_hash() {
+        // One executor thread, shared with the submit waiting on its hash
+        let (mut state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        state.pow = Arc::new(FakeVerifier::new().with_delay(Duration::from_millis(500)));
+        let (session_id, submit) = session_with_job(&state, "198.51.100.1");
+
+        let submitting = {
+            let (state, session_id) = (state.clone(), session_id.clone());
+            tokio::spawn(async move { handle_message(&state, &session_id, submit).await })
+        };
+        while state.validator.validations() < 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let started = Instant::now();
+        let ping = ClientMessage::Ping { id: "p".into() };
+        assert!(matches!(handle_message(&state, &session_id, ping).await, Some(ServerMessage::Pong { .. })));
+        assert!(started.elapsed() < Duration::from_millis(250), "pong after {:?}", started.elapsed());
+        assert!(!submitting.is_finished());
+        assert!(matches!(submitting.await.unwrap(), Some(ServerMessage::SubmitResult { .. })));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_submits_past_a_full_verification_queue_are_refused_as_busy() {
+        let (mut state, template_tx) = test_state();
+        state.validator = Arc::new(SubmissionValidator::new().with_queue(1).with_metrics(state.metrics.clone()));
+        let fake = Arc::new(FakeVerifier::new().with_delay(Duration::from_millis(400)).with_concurrency(1));
+        state.pow = fake.clone();
+        template_tx.send(Some(test_template())).unwrap();
+        let (first_id, first) = session_with_job(&state, "198.51.100.1");
+        let (second_id, second) = session_with_job(&state, "198.51.100.2");
+
+        let hashing = {
+            let state = state.clone();
+            tokio::spawn(async move { handle_message(&state, &first_id, first).await })
+        };
+        while state.validator.validations() < 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        match handle_message(&state, &second_id, second.clone()).await {
+            Some(ServerMessage::Error { code: ErrorCode::Busy, details: Some(details), .. }) => {
+                assert_eq!(details["retry_after_ms"], SUBMIT_BUSY_RETRY_MS);
+            }
+            other => panic!("expected BUSY, got {:?}", other),
+        }
+        // Refused while the first still hashes, and without a hash of its own
+        assert!(!hashing.is_finished());
+        assert_eq!(fake.hashes(), 1);
+        assert_eq!(state.validator.validations(), 1);
+        assert_eq!(state.metrics.verify_queue_full.load(Ordering::Relaxed), 1);
+
+        // Once the queue drains the same nonce is taken, not called a duplicate
+        assert!(matches!(hashing.await.unwrap(), Some(ServerMessage::SubmitResult { .. })));
+        assert!(matches!(handle_message(&state, &second_id, second).await, Some(ServerMessage::SubmitResult { .. })));
+        assert_eq!(state.metrics.submissions_duplicate.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_header_far_from_the_clock_is_a_bad_job() {
+        let (mut state, template_tx) = test_state();
+        // The fixture template is from 2023
+        state.validator = Arc::new(SubmissionValidator::new().with_timestamp_skew(Duration::from_secs(7200)));
+        template_tx.send(Some(test_template())).unwrap();
+        let (session_id, submit) = session_with_job(&state, "198.51.100.1");
+
+        match handle_message(&state, &session_id, submit).await {
+            Some(ServerMessage::Error { code: ErrorCode::BadJob, message, details: Some(details), .. }) => {
+                assert_eq!(details["field"], "timestamp");
+                assert!(message.contains("timestamp"), "{}", message);
+            }
+            other => panic!("expected BAD_JOB, got {:?}", other),
+        }
+        assert_eq!(state.metrics.submissions_rejected.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.submissions_rejected_by_reason.get("header").map(|count| *count), Some(1));
+        assert!(state.metrics.format_prometheus().contains("coordinator_submissions_rejected_by_reason{reason=\"header\"} 1\n"));
+    }
+
+    /// State whose validator hashes with [`FakeVerifier`], a monerod that
+    /// takes every block, and a ready session at `ip` holding a job of
+    /// share difficulty 4 on a template of difficulty 16
+    async fn fake_verifier_session(ip: &str) -> (AppState, watch::Sender<Option<TemplateState>>, String, Job) {
+        let (url, _) = fake_monerod([0; 32]).await;
+        let (mut state, template_tx) = test_state();
+        state.rpc_client = Arc::new(MonerodClient::new(url, 1_000).unwrap());
+        state.validator = Arc::new(SubmissionValidator::new().with_metrics(state.metrics.clone()));
+        state.pow = Arc::new(FakeVerifier::new());
+        let mut template = test_template();
+        template.set_difficulty(16);
+        template_tx.send(Some(template.clone())).unwrap();
+        let session = state.session_manager.create_session(ip.parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, None, None));
+        let job = state.job_manager.create_job(&template, &session.id, 4).unwrap();
+        state.session_manager.update_session(&session.id, |s| s.update_job(job.job_id.clone(), job.reserved_value.clone()));
+        (state, template_tx, session.id, job)
+    }
+
+    /// The first nonce whose fake hash for `job` is worth `class`, and the hash
+    fn fake_nonce(job: &Job, class: HashClass, after: u32) -> (String, [u8; 32]) {
+        (after..)
+            .map(|n| hex::encode(n.to_le_bytes()))
+            .map(|nonce| {
+                let hash = FakeVerifier::hash(&job.apply_nonce(&nonce).unwrap(), &job.seed_hash);
+                (nonce, hash)
+            })
+            .find(|(_, hash)| job.classify(hash) == class)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_submits_are_classed_by_their_hash_end_to_end() {
+        let (state, _template_tx, session_id, job) = fake_verifier_session("198.51.100.1").await;
+        let submit = |nonce: String| ClientMessage::Submit { id: nonce.clone(), job_id: job.job_id.clone(), nonce, result: None };
+
+        let (below, _) = fake_nonce(&job, HashClass::BelowTarget, 0);
+        match handle_message(&state, &session_id, submit(below)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Rejected, message, .. }) => {
+                assert_eq!(message.as_deref(), Some(ValidationError::BelowShareTarget.to_string().as_str()));
+            }
+            other => panic!("expected the share rejected, got {:?}", other),
+        }
+        let (share, _) = fake_nonce(&job, HashClass::Share, 0);
+        match handle_message(&state, &session_id, submit(share)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Accepted, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("Share accepted"));
+            }
+            other => panic!("expected the share accepted, got {:?}", other),
+        }
+        let (block, _) = fake_nonce(&job, HashClass::Block, 0);
+        match handle_message(&state, &session_id, submit(block)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Accepted, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("Block submitted: OK"));
+            }
+            other => panic!("expected the block submitted, got {:?}", other),
+        }
+
+        assert_eq!(state.validator.validations(), 3);
+        assert_eq!(state.metrics.submissions_rejected_by_reason.get("below_share_target").map(|count| *count), Some(1));
+        assert_eq!(state.metrics.randomx_inline_cache_inits.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_claimed_hashes_are_checked_against_the_verifier() {
+        let (state, _template_tx, session_id, job) = fake_verifier_session("198.51.100.1").await;
+        let submit = |nonce: &str, result: [u8; 32]| ClientMessage::Submit {
+            id: nonce.into(),
+            job_id: job.job_id.clone(),
+            nonce: nonce.into(),
+            result: Some(hex::encode(result)),
+        };
+
+        let (first, first_hash) = fake_nonce(&job, HashClass::Share, 0);
+        let after_first = u32::from_le_bytes(hex::decode(&first).unwrap().try_into().unwrap()) + 1;
+        let (second, second_hash) = fake_nonce(&job, HashClass::Share, after_first);
+        match handle_message(&state, &session_id, submit(&first, first_hash)).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Accepted, .. }) => {}
+            other => panic!("expected the share accepted, got {:?}", other),
+        }
+        // A hash that meets the target, but is another nonce's
+        match handle_message(&state, &session_id, submit(&second, first_hash)).await {
+            Some(ServerMessage::Error { code: ErrorCode::BadPow, .. }) => {}
+            other => panic!("expected BAD_POW, got {:?}", other),
+        }
+        assert_ne!(first_hash, second_hash);
+        assert_eq!(state.metrics.bad_pow.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.shares_unverified.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_submits_are_not_ready_while_verification_is_down() {
+        let (mut state, template_tx) = test_state();
+        // No memory for RandomX, and no RPC to fall back on
+        let refuse = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let refusing = refuse.clone();
+        state.validator = Arc::new(SubmissionValidator::new().with_metrics(state.metrics.clone()));
+        state.pow = Arc::new(LocalVerifier::new(VmPool::new(1).refusing(move |_, _| refusing.load(Ordering::Relaxed))));
+        template_tx.send(Some(test_template())).unwrap();
+        let (session_id, submit) = session_with_job(&state, "198.51.100.1");
+
+        match handle_message(&state, &session_id, submit.clone()).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Rejected, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("Hash verification unavailable"));
+            }
+            other => panic!("expected the submit rejected, got {:?}", other),
+        }
+        // The same nonce again, as the miner is told to send it
+        match handle_message(&state, &session_id, submit.clone()).await {
+            Some(ServerMessage::Error { code: ErrorCode::NotReady, details: Some(details), .. }) => {
+                assert!(details["retry_after_ms"].as_u64().is_some_and(|ms| ms > 0), "{}", details);
+            }
+            other => panic!("expected NOT_READY, got {:?}", other),
+        }
+        assert_eq!(state.metrics.verifier_steps_down.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.submissions_rejected_by_reason.get("verifier_down").map(|count| *count), Some(1));
+        // Refused before it was validated, let alone hashed
+        assert_eq!(state.validator.validations(), 1);
+
+        // Once RandomX can be made again the nonce is hashed, not a duplicate
+        refuse.store(false, Ordering::Relaxed);
+        assert_eq!(state.validator.retry_local(state.pow.as_ref()).await, VerifierRung::Configured);
+        match handle_message(&state, &session_id, submit).await {
+            Some(ServerMessage::SubmitResult { message, .. }) => {
+                assert!(!message.as_deref().is_some_and(|m| m.contains("duplicate")), "{:?}", message);
+            }
+            other => panic!("expected the submit hashed, got {:?}", other),
+        }
+        assert_eq!(state.validator.validations(), 2);
+        assert_eq!(state.metrics.submissions_duplicate.load(Ordering::Relaxed), 0);
+        // None of it is the miner's fault
+        assert!(!state.bans.is_banned(&"198.51.100.1".parse().unwrap()));
+    }
+
+    /// A monerod answering calc_pow with `hash` and taking every block;
+    /// its URL, and the calc_pow calls it had
+    pub(crate) async fn fake_monerod(hash: [u8; 32]) -> (String, Arc<AtomicU64>) {
+        let calls = Arc::new(AtomicU64::new(0));
+        let counted = calls.clone();
+        let app = Router::new().route(
+            "/json_rpc",
+            axum::routing::post(move |Json(request): Json<serde_json::Value>| {
+                let result = if request["method"] == "calc_pow" {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                    serde_json::json!(hex::encode(hash))
+                } else {
+                    serde_json::json!({ "status": "OK" })
+                };
+                async move { Json(serde_json::json!({ "jsonrpc": "2.0", "id": "0", "result": result })) }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, calls)
+    }
+
+    #[tokio::test]
+    async fn test_rpc_verifier_hashes_submits_without_randomx() {
+        let (url, calls) = fake_monerod([0; 32]).await;
+        let (mut state, template_tx) = test_state();
+        let client = Arc::new(MonerodClient::new(url, 1_000).unwrap());
+        state.rpc_client = client.clone();
+        state.validator = Arc::new(SubmissionValidator::new().with_metrics(state.metrics.clone()));
+        state.pow = Arc::new(RpcVerifier::new(client, 2, 100).with_metrics(state.metrics.clone()));
+        template_tx.send(Some(test_template())).unwrap();
+        let (session_id, submit) = session_with_job(&state, "198.51.100.1");
+
+        // The zero hash makes a block, which the fake monerod takes
+        match handle_message(&state, &session_id, submit).await {
+            Some(ServerMessage::SubmitResult { status: SubmitStatus::Accepted, message, .. }) => {
+                assert_eq!(message.as_deref(), Some("Block submitted: OK"));
+            }
+            other => panic!("expected the block accepted, got {:?}", other),
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.rpc_pow_ok.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.randomx_inline_cache_inits.load(Ordering::Relaxed), 0);
+        assert_eq!(state.metrics.randomx_verifications_current.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reloaded_limits_reach_connected_miners() {
+        let (state, _template_tx) = test_state();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { submits_per_minute: 10, .. }));
+
+        let mut limits = state.config.limits.clone();
+        limits.submits_per_minute = 3;
+        assert_eq!(apply_limits(&state, &limits).unwrap(), 1);
+        match next_server_message(&mut outgoing).await {
+            ServerMessage::PolicyUpdate { submits_per_minute, messages_per_second, max_inflight_submits } => {
+                assert_eq!((submits_per_minute, messages_per_second, max_inflight_submits), (3, 20, 2));
+            }
+            other => panic!("expected a policy update, got {:?}", other),
+        }
+        let session = state.session_manager.list_sessions().pop().unwrap();
+        assert!(matches!(stats_message(&state, &session.id, None), ServerMessage::Stats { submits_per_minute: 3, .. }));
+
+        // An invalid reload changes nothing and tells nobody
+        limits.submits_per_minute = 0;
+        assert!(apply_limits(&state, &limits).is_err());
+        assert_eq!(state.session_manager.config().submits_per_minute, 3);
+    }
+
+    /// Field names of a serialized reply, `type` included
+    fn wire_fields(msg: &ServerMessage) -> Vec<String> {
+        let value = serde_json::to_value(msg).unwrap();
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn test_replies_match_protocol_wire_format() {
+        let (state, template_tx) = test_state();
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let hello: ClientMessage = serde_json::from_str(HELLO).unwrap();
+
+        // Hello before any template is answered with stats
+        let stats = handle_message(&state, &session.id, hello.clone()).await.unwrap();
+        assert_eq!(
+            wire_fields(&stats),
+            [
+                "accepted", "accepted_shares", "estimated_hashrate", "id", "last_accept_ms", "messages_per_second", "outbound", "rejected",
+                "server_time_ms", "session_id", "stale", "stats_interval_ms", "submits_per_minute", "tip_height",
+                "type",
+            ]
+        );
+        assert_eq!(serde_json::to_value(&stats).unwrap()["session_id"], session.id.as_str());
+
+        let ping = ClientMessage::Ping { id: "7".into() };
+        let pong = handle_message(&state, &session.id, ping).await.unwrap();
+        assert_eq!(serde_json::to_value(&pong).unwrap(), serde_json::json!({"type": "pong", "id": "7"}));
+
+        let submit = ClientMessage::Submit { id: "8".into(), job_id: "nope".into(), nonce: "00000000".into(), result: None };
+        let result = handle_message(&state, &session.id, submit).await.unwrap();
+        let value = serde_json::to_value(&result).unwrap();
+        assert_eq!(value["type"], "submit_result");
+        assert_eq!(value["id"], "8");
+
+        let error = handle_message(&state, &session.id, hello).await.unwrap();
+        assert_eq!(wire_fields(&error), ["code", "id", "message", "type"]);
+        assert_eq!(serde_json::to_value(&error).unwrap()["code"], "UNAUTHORIZED");
+
+        // With a template, hello is answered with the session's first job
+        template_tx.send(Some(test_template())).unwrap();
+        let fresh = state.session_manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        let job = handle_message(&state, &fresh.id, serde_json::from_str(HELLO).unwrap()).await.unwrap();
+        assert_eq!(
+            wire_fields(&job),
+            ["blob_hex", "height", "job_id", "reserved_offset", "reserved_value_hex", "seed_hash", "target_hex", "type"]
+        );
+        for msg in [stats, pong, result, error, job] {
+            let json = serde_json::to_string(&msg).unwrap();
+            let decoded: ServerMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_site_connection_cap_is_per_token() {
+        let (mut state, _template_tx) = auth_state(true);
+        state.config.auth.tokens.insert("tok-b".to_string(), "partner-b".to_string());
+        state.metrics = Arc::new(Metrics::new());
+        state.session_manager = Arc::new(
+            SessionManager::new(SessionManagerConfig { max_per_ip: 100, max_total: 100, ..Default::default() }).with_site_limit(2).with_metrics(state.metrics.clone()),
+        );
+
+        let connect = |token: &'static str| {
+            let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+            let (client, stream) = futures::channel::mpsc::unbounded();
+            let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+            client.unbounded_send(client_text(&hello_with_token(Some(token)))).unwrap();
+            async move { (next_server_message(&mut outgoing).await, client, conn) }
+        };
+
+        let (reply, first, first_conn) = connect("tok-a").await;
+        assert!(matches!(reply, ServerMessage::Stats { .. }));
+        let (reply, _second, _) = connect("tok-a").await;
+        assert!(matches!(reply, ServerMessage::Stats { .. }));
+
+        // partner-a is full
+        let (reply, _third, third_conn) = connect("tok-a").await;
+        match reply {
+            ServerMessage::Error { code: ErrorCode::RateLimit, details: Some(details), .. } => {
+                assert_eq!(details["limit"], "max_connections_per_site");
+                assert_eq!(details["max"], 2);
+                assert_eq!(details["site"], "partner-a");
+            }
+            other => panic!("expected a rate limit error, got {:?}", other),
+        }
+        third_conn.await.unwrap();
+
+        // partner-b is unaffected
+        let (reply, _other, _) = connect("tok-b").await;
+        assert!(matches!(reply, ServerMessage::Stats { .. }));
+        assert_eq!(state.session_manager.site_count("partner-a"), 2);
+        assert_eq!(state.session_manager.site_count("partner-b"), 1);
+        assert!(state.metrics.format_prometheus().contains("coordinator_site_connections_active{site=\"partner-a\"} 2"));
+
+        // A disconnect frees the slot
+        drop(first);
+        first_conn.await.unwrap();
+        assert_eq!(state.session_manager.site_count("partner-a"), 1);
+        let (reply, _again, _) = connect("tok-a").await;
+        assert!(matches!(reply, ServerMessage::Stats { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_session_released_when_handler_panics() {
+        let (mut state, _template_tx) = test_state();
+        state.session_manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: 100, max_total: 100, ..Default::default() }).with_site_limit(1));
+        let session = state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert!(state.session_manager.set_ready(&session.id, "t".into(), 1, Some("partner-a".into()), None));
+
+        let guard = SessionGuard { state: state.clone(), session_id: session.id.clone() };
+        let handler = tokio::spawn(async move {
+            let _guard = guard;
+            panic!("handler bug");
+        });
+        assert!(handler.await.unwrap_err().is_panic());
+
+        assert_eq!(state.session_manager.active_count(), 0);
+        assert_eq!(state.session_manager.site_count("partner-a"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subprotocol_negotiation() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (state, _template_tx) = test_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+        let url = format!("ws://{}/ws", addr);
+
+        let offer = |protocols: &str| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, protocols.parse().unwrap());
+            request
+        };
+
+        // Offered and matched
+        let (_ws, response) = tokio_tungstenite::connect_async(offer("mqtt, mwc.v1")).await.unwrap();
+        assert_eq!(response.headers()[header::SEC_WEBSOCKET_PROTOCOL], SUBPROTOCOL_V1);
+        for _ in 0..100 {
+            if !state.session_manager.list_sessions().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let session = state.session_manager.list_sessions().pop().expect("session not registered");
+        assert_eq!(session.subprotocol.as_deref(), Some(SUBPROTOCOL_V1));
+
+        // Offered and unmatched
+        match tokio_tungstenite::connect_async(offer("mqtt, mwc.v9")).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST)
+            }
+            other => panic!("expected 400, got {:?}", other.map(|_| ())),
+        }
+
+        // Not offered
+        let (_ws, response) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert!(response.headers().get(header::SEC_WEBSOCKET_PROTOCOL).is_none());
+        state.shutdown.cancel();
+    }
+
+    fn client_binary(json: &str) -> Result<Message, axum::Error> {
+        Ok(Message::Binary(json.as_bytes().to_vec()))
+    }
+
+    async fn next_binary_message(outgoing: &mut futures::channel::mpsc::UnboundedReceiver<Message>) -> ServerMessage {
+        match outgoing.next().await {
+            Some(Message::Binary(data)) => serde_json::from_slice(&data).unwrap(),
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_binary_frames_get_binary_replies() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_binary(HELLO)).unwrap();
+        let job_id = match next_binary_message(&mut outgoing).await {
+            ServerMessage::Job { job_id, .. } => job_id,
+            other => panic!("expected a job, got {:?}", other),
+        };
+
+        let submit = format!(r#"{{"type":"submit","id":"7","job_id":"{}","nonce":"zz"}}"#, job_id);
+        client.unbounded_send(client_binary(&submit)).unwrap();
+        match next_binary_message(&mut outgoing).await {
+            ServerMessage::SubmitResult { id, status: SubmitStatus::Rejected, .. } => assert_eq!(id, "7"),
+            other => panic!("expected a submit result, got {:?}", other),
+        }
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_undecodable_binary_is_bad_format() {
+        let (state, _template_tx) = test_state();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state, "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(Ok(Message::Binary(vec![0x82, 0xa4, 0xff, 0x00]))).unwrap();
+        assert!(matches!(
+            next_binary_message(&mut outgoing).await,
+            ServerMessage::Error { code: ErrorCode::BadFormat, .. }
+        ));
+
+        drop(client);
+        conn.await.unwrap();
+    }
+
+    /// Collects formatted log output so tests can inspect it
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_submit_logs_carry_session_fields() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state, "198.51.100.1".parse().unwrap(), None));
+
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        let job_id = match next_server_message(&mut outgoing).await {
+            ServerMessage::Job { job_id, .. } => job_id,
+            other => panic!("expected a job, got {:?}", other),
+        };
+        let submit = format!(r#"{{"type":"submit","id":"7","job_id":"{}","nonce":"zz"}}"#, job_id);
+        client.unbounded_send(client_text(&submit)).unwrap();
+        next_server_message(&mut outgoing).await;
+        drop(client);
+        conn.await.unwrap();
+
+        let output = String::from_utf8(logs.0.lock().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|l| l.contains("Rejected invalid submission"))
+            .unwrap_or_else(|| panic!("no submit log line in:\n{}", output));
+        assert!(line.contains("ip=198.51.100.1"), "{}", line);
+        assert!(line.contains("session_id="), "{}", line);
+        assert!(line.contains("client_version=\"t\""), "{}", line);
+        assert!(line.contains("kind=\"submit\""), "{}", line);
+    }
+
+    async fn http_status(addr: SocketAddr, path: &str) -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_every_listener_serves_until_shutdown() {
+        let (state, template_tx) = test_state();
+        template_tx.send(Some(test_template())).unwrap();
+        state.daemon_status.mark_ok();
+
+        let addrs = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let listeners = bind_listeners(&addrs).await.unwrap();
+        let bound: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_ne!(bound[0], bound[1]);
+        let server = tokio::spawn(serve_all(listeners, state.clone(), None));
+
+        for addr in &bound {
+            assert_eq!(http_status(*addr, "/health").await, 200);
+        }
+
+        state.shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        for addr in &bound {
+            assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure_names_the_address() {
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken_addr = taken.local_addr().unwrap().to_string();
+        let addrs = vec!["127.0.0.1:0".to_string(), taken_addr.clone()];
+
+        let err = bind_listeners(&addrs).await.unwrap_err();
+        assert!(err.to_string().contains(&taken_addr), "{}", err);
+
+        let err = bind_listeners(&["not-an-address".to_string()]).await.unwrap_err();
+        assert!(err.to_string().contains("not-an-address"), "{}", err);
+    }
+
+    #[test]
+    fn test_bind_addr_accepts_one_or_many() {
+        let config: Config = toml::from_str(TEST_CONFIG).unwrap();
+        assert_eq!(config.server.bind_addr, vec!["127.0.0.1:0"]);
+
+        let listed = TEST_CONFIG.replacen(
+            r#"bind_addr = "127.0.0.1:0""#,
+            r#"bind_addr = ["0.0.0.0:8080", "[::]:8080"]"#,
+            1,
+        );
+        let config: Config = toml::from_str(&listed).unwrap();
+        assert_eq!(config.server.bind_addr, vec!["0.0.0.0:8080", "[::]:8080"]);
+    }
+
+    /// Skip messages until the server's close frame and return its code
+    async fn next_close_code<S>(ws: &mut S) -> u16
+    where
+        S: Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        loop {
+            match ws.next().await {
+                Some(Ok(WsMessage::Close(Some(frame)))) => return frame.code.into(),
+                Some(Ok(_)) => continue,
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_violations_close_with_policy_code() {
+        let (state, _template_tx) = test_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, state));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        for i in 0..MAX_PROTOCOL_VIOLATIONS {
+            let ping = format!(r#"{{"type":"ping","id":"{}"}}"#, i);
+            ws.send(WsMessage::Text(ping)).await.unwrap();
+        }
+        assert_eq!(next_close_code(&mut ws).await, 1008);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_closes_with_too_big_code() {
+        let (state, _template_tx) = test_state();
+        let limit = state.config.server.max_frame_bytes;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, state));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        ws.send(WsMessage::Text(HELLO.into())).await.unwrap();
+        ws.send(WsMessage::Text("x".repeat(limit + 1))).await.unwrap();
+        assert_eq!(next_close_code(&mut ws).await, 1009);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session_limit_rejection_sends_close() {
+        let (mut state, _template_tx) = test_state();
+        state.session_manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: 0, max_total: 100, ..Default::default() }));
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (_client, stream) = futures::channel::mpsc::unbounded();
+        run_connection(sink, stream, state, "198.51.100.1".parse().unwrap(), None).await;
+
+        assert!(matches!(
+            next_server_message(&mut outgoing).await,
+            ServerMessage::Error { code: ErrorCode::RateLimit, .. }
+        ));
+        match outgoing.next().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, close_code::POLICY),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_session_closes_socket_with_reason() {
+        let (state, _template_tx) = test_state();
+        let (sink, mut outgoing) = futures::channel::mpsc::unbounded::<Message>();
+        let (client, stream) = futures::channel::mpsc::unbounded();
+        let conn = tokio::spawn(run_connection(sink, stream, state.clone(), "198.51.100.1".parse().unwrap(), None));
+        client.unbounded_send(client_text(HELLO)).unwrap();
+        assert!(matches!(next_server_message(&mut outgoing).await, ServerMessage::Stats { .. }));
+
+        let id = state.session_manager.list_sessions().pop().unwrap().id;
+        assert!(state.session_manager.close_session(&id, "Policy update"));
+        assert!(!state.session_manager.close_session(&id, "Again"), "a second close is a no-op");
+        match outgoing.next().await {
+            Some(Message::Close(Some(frame))) => {
+                assert_eq!(frame.code, close_code::POLICY);
+                assert_eq!(frame.reason, "Policy update");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        conn.await.unwrap();
+
+        // Gone with its socket; closing it now does nothing
+        assert!(state.session_manager.get_session(&id).is_none());
+        assert!(!state.session_manager.close_session(&id, "Late"));
+    }
+
+    #[test]
+    fn test_close_frame_reason_fits_the_frame() {
+        assert_eq!(close_frame_reason("Banned"), "Banned");
+        let long = "é".repeat(100);
+        let cut = close_frame_reason(&long);
+        assert_eq!(cut.len(), 122);
+        assert!(long.starts_with(&cut));
+    }
 }
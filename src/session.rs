@@ -1,34 +1,254 @@
 use dashmap::DashMap;
+use parking_lot::RwLock;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::net::IpAddr;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
 use uuid::Uuid;
 
-use crate::ratelimit::SessionLimits;
+use crate::ban::BanManager;
+use crate::capabilities::SessionCapabilities;
+use crate::config::{Config, LimitsConfig, VarDiffConfig};
+use crate::error::CoordinatorError;
+use crate::events::{EventKind, EventLog};
+use crate::hashrate::EstimatedHashrate;
+use crate::ipkey::{IpKey, DEFAULT_IPV6_PREFIX_LEN};
+use crate::jobs::{Job, JobManager};
+use crate::metrics::Metrics;
+use crate::outbound::OutboundStats;
+use crate::privacy;
+use crate::protocol::{RandomxMode, SubmitStatus};
+use crate::ratelimit::{RateLimiter, SessionLimits};
+use crate::vardiff::VarDiff;
+
+/// Jobs remembered per session, so a submit against a job that was just
+/// replaced can still be matched to the session that received it
+pub const RECENT_JOBS: usize = 4;
+
+/// Submissions a session may have in flight unless its manager says otherwise
+const DEFAULT_INFLIGHT_SUBMITS: usize = 2;
+
+/// Nonces remembered per issued job for duplicate detection; the oldest
+/// is forgotten when a miner submits more
+pub const MAX_NONCES_PER_JOB: usize = 256;
+
+/// A job handed to a session
+#[derive(Debug, Clone)]
+pub struct IssuedJob {
+    pub job_id: String,
+    pub reserved_value: Arc<[u8]>,
+    pub issued_at: Instant,
+    /// Nonces submitted for this job, oldest first
+    pub nonces: VecDeque<[u8; 4]>,
+}
+
+/// Period over which per-site hashrate is averaged
+const SITE_HASHRATE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Running totals for the sessions of one site token label
+#[derive(Debug, Default)]
+struct SiteStats {
+    /// Ready sessions; also what the per-site cap counts
+    sessions: usize,
+    accepted_submits: u64,
+    accepted_shares: u64,
+    /// Difficulty accepted within the hashrate window, oldest first
+    recent: VecDeque<(Instant, u64)>,
+    /// When the last session left, for pruning
+    empty_since: Option<Instant>,
+}
+
+impl SiteStats {
+    fn trim(&mut self, now: Instant) {
+        while self.recent.front().is_some_and(|(at, _)| now.duration_since(*at) > SITE_HASHRATE_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Accepted difficulty per second over the hashrate window
+    fn hashrate(&self) -> f64 {
+        let work: u64 = self.recent.iter().map(|(_, difficulty)| difficulty).sum();
+        work as f64 / SITE_HASHRATE_WINDOW.as_secs_f64()
+    }
+}
+
+/// Per-site rollup served by `/stats/tokens`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SiteSummary {
+    pub site: String,
+    pub sessions: usize,
+    pub accepted_submits: u64,
+    /// Total difficulty of accepted submits
+    pub accepted_shares: u64,
+    /// Accepted difficulty per second over the last 10 minutes
+    pub hashrate: f64,
+}
+
+/// Cumulative accounting of one site, the part that survives a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SiteTotals {
+    pub site: String,
+    pub accepted_submits: u64,
+    pub accepted_shares: u64,
+}
+
+/// Lifecycle events buffered per subscriber before the slowest starts to lag
+const SESSION_EVENT_CAPACITY: usize = 1024;
+
+/// Session lifecycle, broadcast to internal observers (see `SessionManager::subscribe`)
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Created {
+        session_id: String,
+        ip: IpAddr,
+    },
+    Ready {
+        session_id: String,
+        client_version: String,
+        site_label: Option<String>,
+        worker: Option<String>,
+    },
+    Submitted {
+        session_id: String,
+        status: SubmitStatus,
+    },
+    Closed {
+        session_id: String,
+        reason: CloseReason,
+    },
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection ended, for whatever reason, or the session was removed
+    Disconnected,
+    /// Removed by the idle sweep
+    Idle,
+    /// Detached and not resumed within the grace period or lifetime
+    Expired,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Disconnected => "disconnected",
+            CloseReason::Idle => "idle",
+            CloseReason::Expired => "expired",
+        })
+    }
+}
+
+/// Tells a session's transport task to close the connection, and why.
+/// Clones share the signal; only the first reason given is kept.
+#[derive(Debug, Clone, Default)]
+pub struct CloseSignal {
+    token: CancellationToken,
+    reason: Arc<OnceLock<String>>,
+}
+
+impl CloseSignal {
+    /// Fire the signal. Returns false if it had already fired.
+    pub fn close(&self, reason: &str) -> bool {
+        let first = self.reason.set(reason.to_string()).is_ok();
+        self.token.cancel();
+        first
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once the signal fires
+    pub async fn closed(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.get().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SessionState {
     Connected,
     Ready,
+    /// Handshake done, but the miner has stopped submitting (a sleeping
+    /// tab); it gets no jobs until it sends a message
+    Idle,
     Closed,
 }
 
+/// A session's state. Clones are snapshots; rate limiters live only in the
+/// SessionManager, so reading a session never copies or resets them.
+#[derive(Clone)]
 pub struct Session {
     pub id: String,
     pub ip: IpAddr,
     pub state: SessionState,
     pub client_version: Option<String>,
     pub threads: u8,
-    pub current_job_id: Option<String>,
-    pub current_reserved_value: Option<Vec<u8>>,
+    /// Label of the site token presented in hello, if any
+    pub site_label: Option<String>,
+    /// Worker name from the hello; several sessions may share one
+    pub worker: Option<String>,
+    /// WebSocket subprotocol agreed during the upgrade
+    pub subprotocol: Option<String>,
+    /// What the hello negotiated; None until then
+    pub capabilities: Option<SessionCapabilities>,
+    /// RandomX mode the hello reported
+    pub randomx_mode: RandomxMode,
+    /// Bearer token of a long-polling session; None for WebSocket sessions
+    pub poll_token: Option<String>,
+    /// Secret a new socket presents to take over this session after a disconnect
+    pub resume_token: String,
+    /// When a detached session stops being resumable
+    pub resume_deadline: Option<Instant>,
+    /// The last `RECENT_JOBS` jobs, or sets of sibling jobs, issued to this
+    /// session, newest last
+    pub recent_jobs: VecDeque<IssuedJob>,
+    /// Jobs issued together as one piece of work, one per miner thread
+    pub job_set_size: usize,
     pub connected_at: Instant,
     pub last_activity: Instant,
-    pub limits: SessionLimits,
-    messages_per_second: u32,
-    submits_per_minute: u32,
+    pub accepted_submits: u64,
+    pub rejected_submits: u64,
+    pub stale_submits: u64,
+    /// Total difficulty of accepted submits
+    pub accepted_shares: u64,
+    /// Total difficulty the accepted hashes actually achieved, which is at
+    /// least `accepted_shares`
+    pub achieved_difficulty: u128,
+    /// Highest difficulty achieved by one accepted hash
+    pub best_difficulty: u128,
+    pub last_accept_at: Option<SystemTime>,
+    /// Last submit of any outcome
+    pub last_submit_at: Option<Instant>,
+    /// When the session last became Ready, by hello, resume or waking
+    pub active_since: Instant,
+    /// Share difficulty controller; None when vardiff is disabled
+    pub vardiff: Option<VarDiff>,
+    /// Hashrate implied by the session's accepted shares
+    pub hashrate: EstimatedHashrate,
+    /// Messages that were not valid in the session's current state
+    pub protocol_violations: u32,
+    /// Fired to make the session's transport task close the connection
+    /// (admin kick, IP ban, idle sweep)
+    pub close_signal: CloseSignal,
+    /// Statistics of the connection's outbound queue
+    pub outbound: Arc<OutboundStats>,
+    /// One permit per submission that may be verified at once
+    pub submit_slots: Arc<Semaphore>,
 }
 
 impl Session {
-    pub fn new(ip: IpAddr, messages_per_second: u32, submits_per_minute: u32) -> Self {
+    pub fn new(ip: IpAddr) -> Self {
         let now = Instant::now();
         Self {
             id: Uuid::new_v4().to_string(),
@@ -36,103 +256,627 @@ impl Session {
             state: SessionState::Connected,
             client_version: None,
             threads: 1,
-            current_job_id: None,
-            current_reserved_value: None,
+            site_label: None,
+            worker: None,
+            subprotocol: None,
+            capabilities: None,
+            randomx_mode: RandomxMode::Unknown,
+            poll_token: None,
+            resume_token: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+            resume_deadline: None,
+            recent_jobs: VecDeque::with_capacity(RECENT_JOBS),
+            job_set_size: 1,
             connected_at: now,
             last_activity: now,
-            limits: SessionLimits::new(messages_per_second, submits_per_minute),
-            messages_per_second,
-            submits_per_minute,
+            accepted_submits: 0,
+            rejected_submits: 0,
+            stale_submits: 0,
+            accepted_shares: 0,
+            achieved_difficulty: 0,
+            best_difficulty: 0,
+            last_accept_at: None,
+            last_submit_at: None,
+            active_since: now,
+            vardiff: None,
+            hashrate: EstimatedHashrate::new(now),
+            protocol_violations: 0,
+            close_signal: CloseSignal::default(),
+            outbound: Arc::default(),
+            submit_slots: Arc::new(Semaphore::new(DEFAULT_INFLIGHT_SUBMITS)),
         }
     }
 
-    pub fn set_ready(&mut self, client_version: String, threads: u8) {
+    pub fn set_ready(&mut self, client_version: String, threads: u8, site_label: Option<String>, worker: Option<String>) {
         self.client_version = Some(client_version);
         self.threads = threads;
+        self.site_label = site_label;
+        self.worker = worker;
         self.state = SessionState::Ready;
+        self.active_since = Instant::now();
     }
 
-    pub fn update_job(&mut self, job_id: String, reserved_value: Vec<u8>) {
-        self.current_job_id = Some(job_id);
-        self.current_reserved_value = Some(reserved_value);
-        self.last_activity = Instant::now();
+    /// Whether a Ready session has gone `window` without submitting
+    pub fn is_inactive(&self, window: Duration, now: Instant) -> bool {
+        let last_work = self.last_submit_at.map_or(self.active_since, |at| at.max(self.active_since));
+        self.state == SessionState::Ready && now.saturating_duration_since(last_work) >= window
+    }
+
+    pub fn update_job(&mut self, job_id: String, reserved_value: Arc<[u8]>) {
+        let now = Instant::now();
+        while self.recent_jobs.len() >= RECENT_JOBS * self.job_set_size {
+            self.recent_jobs.pop_front();
+        }
+        self.recent_jobs.push_back(IssuedJob { job_id, reserved_value, issued_at: now, nonces: VecDeque::new() });
+        self.last_activity = now;
+    }
+
+    /// Record sibling jobs issued as one piece of work; any of them may be
+    /// submitted against
+    pub fn update_jobs(&mut self, jobs: &[Job]) {
+        self.job_set_size = jobs.len().max(1);
+        for job in jobs {
+            self.update_job(job.job_id.clone(), job.reserved_value.clone());
+        }
+    }
+
+    /// The job most recently issued to this session
+    pub fn current_job(&self) -> Option<&IssuedJob> {
+        self.recent_jobs.back()
+    }
+
+    /// Whether `job_id` is one of the session's recent jobs
+    pub fn owns_job(&self, job_id: &str) -> bool {
+        self.recent_jobs.iter().any(|job| job.job_id == job_id)
+    }
+
+    /// Remember a nonce submitted for one of the session's jobs. Returns
+    /// false if it was already submitted for that job.
+    pub fn record_nonce(&mut self, job_id: &str, nonce: [u8; 4]) -> bool {
+        let job = match self.recent_jobs.iter_mut().find(|job| job.job_id == job_id) {
+            Some(job) => job,
+            None => return true,
+        };
+        if job.nonces.contains(&nonce) {
+            return false;
+        }
+        if job.nonces.len() == MAX_NONCES_PER_JOB {
+            job.nonces.pop_front();
+        }
+        job.nonces.push_back(nonce);
+        true
+    }
+
+    /// Forget a nonce recorded for `job_id`, so it may be submitted again
+    pub fn forget_nonce(&mut self, job_id: &str, nonce: [u8; 4]) {
+        if let Some(job) = self.recent_jobs.iter_mut().find(|job| job.job_id == job_id) {
+            job.nonces.retain(|n| *n != nonce);
+        }
     }
 
     pub fn touch(&mut self) {
         self.last_activity = Instant::now();
     }
 
-    pub fn check_message_limit(&mut self) -> bool {
-        self.limits.messages.check()
+    /// Count one submit outcome; `difficulty` is that of the submitted job.
+    /// Submits refused before validation (`Error`) are not counted.
+    pub fn record_submit_result(&mut self, status: &SubmitStatus, difficulty: u64) {
+        self.last_submit_at = Some(Instant::now());
+        match status {
+            SubmitStatus::Accepted => {
+                self.accepted_submits += 1;
+                self.accepted_shares = self.accepted_shares.saturating_add(difficulty);
+                self.last_accept_at = Some(SystemTime::now());
+                let now = Instant::now();
+                self.hashrate.record_share(difficulty, now);
+                if let Some(vardiff) = &mut self.vardiff {
+                    vardiff.record_share(now);
+                }
+            }
+            SubmitStatus::Rejected => self.rejected_submits += 1,
+            SubmitStatus::Stale => self.stale_submits += 1,
+            SubmitStatus::Error => {}
+        }
     }
 
-    pub fn check_submit_limit(&mut self) -> bool {
-        self.limits.submits.check()
+    /// Account the difficulty an accepted hash achieved
+    pub fn record_achieved(&mut self, difficulty: u128) {
+        self.achieved_difficulty = self.achieved_difficulty.saturating_add(difficulty);
+        self.best_difficulty = self.best_difficulty.max(difficulty);
     }
 }
 
-impl Clone for Session {
-    fn clone(&self) -> Self {
+/// Lightweight copy of a session for listings and aggregation
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub id: String,
+    pub ip: IpAddr,
+    pub state: SessionState,
+    pub site_label: Option<String>,
+    pub worker: Option<String>,
+    pub accepted_submits: u64,
+    pub rejected_submits: u64,
+    pub stale_submits: u64,
+    pub accepted_shares: u64,
+    /// Time since the session's last activity
+    pub idle: Duration,
+}
+
+impl From<&Session> for SessionSummary {
+    fn from(session: &Session) -> Self {
         Self {
-            id: self.id.clone(),
-            ip: self.ip,
-            state: self.state,
-            client_version: self.client_version.clone(),
-            threads: self.threads,
-            current_job_id: self.current_job_id.clone(),
-            current_reserved_value: self.current_reserved_value.clone(),
-            connected_at: self.connected_at,
-            last_activity: self.last_activity,
-            limits: SessionLimits::new(self.messages_per_second, self.submits_per_minute),
-            messages_per_second: self.messages_per_second,
-            submits_per_minute: self.submits_per_minute,
+            id: session.id.clone(),
+            ip: session.ip,
+            state: session.state,
+            site_label: session.site_label.clone(),
+            worker: session.worker.clone(),
+            accepted_submits: session.accepted_submits,
+            rejected_submits: session.rejected_submits,
+            stale_submits: session.stale_submits,
+            accepted_shares: session.accepted_shares,
+            idle: session.last_activity.elapsed(),
+        }
+    }
+}
+
+/// Limits a `SessionManager` applies to sessions it creates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionManagerConfig {
+    /// Sessions per client, IPv6 clients counted by prefix
+    pub max_per_ip: usize,
+    /// Sessions in total
+    pub max_total: usize,
+    pub messages_per_second: u32,
+    pub submits_per_minute: u32,
+    /// Submissions one session may have being verified at once
+    pub max_inflight_submits: u32,
+}
+
+impl SessionManagerConfig {
+    /// The limits set in `config`, validated
+    pub fn from_config(config: &Config) -> Result<Self, CoordinatorError> {
+        let limits = Self {
+            max_per_ip: config.server.max_connections_per_ip,
+            max_total: config.server.max_connections,
+            messages_per_second: config.limits.messages_per_second,
+            submits_per_minute: config.limits.submits_per_minute,
+            max_inflight_submits: config.limits.max_inflight_submits,
+        };
+        limits.validate()?;
+        Ok(limits)
+    }
+
+    /// Refuse limits that would turn every client away
+    pub fn validate(&self) -> Result<(), CoordinatorError> {
+        let zero = [
+            ("server.max_connections", self.max_total == 0),
+            ("server.max_connections_per_ip", self.max_per_ip == 0),
+            ("limits.messages_per_second", self.messages_per_second == 0),
+            ("limits.submits_per_minute", self.submits_per_minute == 0),
+            ("limits.max_inflight_submits", self.max_inflight_submits == 0),
+        ];
+        if let Some((name, _)) = zero.iter().find(|(_, is_zero)| *is_zero) {
+            return Err(CoordinatorError::Config(format!("{} must be greater than 0", name)));
+        }
+        if self.max_per_ip > self.max_total {
+            return Err(CoordinatorError::Config(format!(
+                "server.max_connections_per_ip ({}) exceeds server.max_connections ({})",
+                self.max_per_ip, self.max_total
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SessionManagerConfig {
+    fn default() -> Self {
+        Self {
+            max_per_ip: 20,
+            max_total: 5000,
+            messages_per_second: 20,
+            submits_per_minute: 10,
+            max_inflight_submits: DEFAULT_INFLIGHT_SUBMITS as u32,
         }
     }
 }
 
 pub struct SessionManager {
     sessions: DashMap<String, Session>,
-    ip_counts: DashMap<IpAddr, usize>,
-    max_per_ip: usize,
-    max_total: usize,
-    messages_per_second: u32,
-    submits_per_minute: u32,
+    /// The only copy of each session's rate limiters
+    limits: DashMap<String, SessionLimits>,
+    /// Sessions per client, keyed on the IPv6 prefix rather than the full address
+    ip_counts: DashMap<IpKey, usize>,
+    ipv6_prefix_len: u8,
+    /// Consulted as each session is created or resumed; see `reconfigure`
+    config: RwLock<SessionManagerConfig>,
+    bans: Option<Arc<BanManager>>,
+    /// New-connection attempts per IP, so open/close churn is bounded too
+    connect_limits: DashMap<IpKey, RateLimiter>,
+    connect_limit: Option<(u32, u64)>,
+    /// Ready sessions per site token label, so one site cannot take every slot
+    sites: DashMap<String, SiteStats>,
+    /// 0 means no per-site cap
+    max_per_site: usize,
+    metrics: Option<Arc<Metrics>>,
+    /// Jobs of sessions that go away are dropped here at once
+    jobs: Option<Arc<JobManager>>,
+    events: EventLog,
+    /// Disconnected sessions awaiting resumption, by resume token
+    detached: DashMap<String, Session>,
+    /// How long a detached session stays resumable; zero disables resumption
+    resume_grace: Duration,
+    /// Whether detached sessions keep their slot in the per-IP limit
+    resume_holds_ip_slot: bool,
+    /// Per-session share difficulty; None hands out the block difficulty
+    vardiff: Option<VarDiffConfig>,
+    /// Age past which a session may not continue or resume; zero disables the limit
+    max_lifetime: Duration,
+    /// Lifecycle events for internal observers; sending never waits
+    notices: broadcast::Sender<SessionEvent>,
 }
 
 impl SessionManager {
-    pub fn new(max_per_ip: usize, max_total: usize, messages_per_second: u32, submits_per_minute: u32) -> Self {
+    pub fn new(config: SessionManagerConfig) -> Self {
         Self {
             sessions: DashMap::new(),
+            limits: DashMap::new(),
             ip_counts: DashMap::new(),
-            max_per_ip,
-            max_total,
-            messages_per_second,
-            submits_per_minute,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            config: RwLock::new(config),
+            bans: None,
+            connect_limits: DashMap::new(),
+            connect_limit: None,
+            sites: DashMap::new(),
+            max_per_site: 0,
+            metrics: None,
+            jobs: None,
+            events: EventLog::disabled(),
+            detached: DashMap::new(),
+            resume_grace: Duration::ZERO,
+            resume_holds_ip_slot: true,
+            vardiff: None,
+            max_lifetime: Duration::ZERO,
+            notices: broadcast::channel(SESSION_EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Replace the limits applied to sessions created or resumed from now on,
+    /// as on a configuration reload. Existing sessions keep theirs.
+    pub fn reconfigure(&self, config: SessionManagerConfig) -> Result<(), CoordinatorError> {
+        config.validate()?;
+        *self.config.write() = config;
+        Ok(())
+    }
+
+    /// Apply reloaded rate limits to every session, including those already
+    /// connected. Recent messages and submits stay counted, so a tighter
+    /// limit grants no burst and a looser one applies at once. In-flight
+    /// submission slots change for new sessions only.
+    pub fn apply_limits(&self, limits: &LimitsConfig) -> Result<(), CoordinatorError> {
+        let config = SessionManagerConfig {
+            messages_per_second: limits.messages_per_second,
+            submits_per_minute: limits.submits_per_minute,
+            max_inflight_submits: limits.max_inflight_submits,
+            ..self.config()
+        };
+        self.reconfigure(config)?;
+        for mut session_limits in self.limits.iter_mut() {
+            session_limits.apply(config.messages_per_second, config.submits_per_minute);
         }
+        Ok(())
+    }
+
+    pub fn config(&self) -> SessionManagerConfig {
+        *self.config.read()
+    }
+
+    /// Receive every session lifecycle event from now on. A subscriber that
+    /// falls behind loses the oldest events (`RecvError::Lagged`) rather than
+    /// slowing sessions down.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.notices.subscribe()
+    }
+
+    fn notify(&self, event: SessionEvent) {
+        // Fails only when nobody is subscribed
+        let _ = self.notices.send(event);
+    }
+
+    /// Count IPv6 clients by their first `len` bits for the per-IP and
+    /// connection-rate limits
+    pub fn with_ipv6_prefix(mut self, len: u8) -> Self {
+        self.ipv6_prefix_len = len;
+        self
+    }
+
+    fn ip_key(&self, ip: IpAddr) -> IpKey {
+        IpKey::new(ip, self.ipv6_prefix_len)
+    }
+
+    /// Drop detached sessions, and refuse to resume them, once they are
+    /// `max_lifetime` old; zero disables the limit
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Whether `session` has outlived the maximum session lifetime
+    pub fn is_expired(&self, session: &Session) -> bool {
+        !self.max_lifetime.is_zero() && session.connected_at.elapsed() >= self.max_lifetime
+    }
+
+    /// Give every new session a share difficulty controller, if `config` enables one
+    pub fn with_vardiff(mut self, config: &VarDiffConfig) -> Self {
+        self.vardiff = config.enable.then(|| config.clone());
+        self
+    }
+
+    /// Keep disconnected sessions resumable for `grace`; `holds_ip_slot`
+    /// keeps them counted against their IP's limit meanwhile. No message
+    /// carries a resume token yet, so connections still remove their
+    /// sessions on close and nothing outside the tests resumes one.
+    pub fn with_resume(mut self, grace: Duration, holds_ip_slot: bool) -> Self {
+        self.resume_grace = grace;
+        self.resume_holds_ip_slot = holds_ip_slot;
+        self
+    }
+
+    /// Record session opens and closes in `events`
+    pub fn with_events(mut self, events: EventLog) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Allow at most `max_per_site` ready sessions per site token label; 0 disables the cap
+    pub fn with_site_limit(mut self, max_per_site: usize) -> Self {
+        self.max_per_site = max_per_site;
+        self
+    }
+
+    /// Report per-site connection counts to `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Drop a session's jobs from `jobs` once the session is gone for good;
+    /// a detached session keeps them until it resumes or expires
+    pub fn with_jobs(mut self, jobs: Arc<JobManager>) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Allow at most `max_attempts` new connections per IP every `window_secs`
+    pub fn with_connection_rate(mut self, max_attempts: u32, window_secs: u64) -> Self {
+        if max_attempts > 0 {
+            self.connect_limit = Some((max_attempts, window_secs));
+        }
+        self
+    }
+
+    /// Count a connection attempt from `ip`. When over the limit, returns how
+    /// long the client should wait before retrying.
+    pub fn check_connection_rate(&self, ip: IpAddr) -> Result<(), Duration> {
+        let (max_attempts, window_secs) = match self.connect_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut limiter = self
+            .connect_limits
+            .entry(self.ip_key(ip))
+            .or_insert_with(|| RateLimiter::new(max_attempts, window_secs));
+        if limiter.check() {
+            Ok(())
+        } else {
+            Err(limiter.retry_after())
+        }
+    }
+
+    /// Drop connection-rate entries for IPs with no attempts left in their window
+    pub fn cleanup_connection_limits(&self) {
+        self.connect_limits.retain(|_, limiter| !limiter.is_idle());
+    }
+
+    /// Refuse sessions from IPs banned by `bans`
+    pub fn with_bans(mut self, bans: Arc<BanManager>) -> Self {
+        self.bans = Some(bans);
+        self
     }
 
     pub fn create_session(&self, ip: IpAddr) -> Option<Session> {
+        if self.bans.as_ref().map(|b| b.is_banned(&ip)).unwrap_or(false) {
+            return None;
+        }
+
+        let config = self.config();
+
         // Check global limit FIRST
-        if self.sessions.len() >= self.max_total {
+        if self.sessions.len() >= config.max_total {
             return None;
         }
         
         // Then check per-IP limit
-        let mut count = self.ip_counts.entry(ip).or_insert(0);
-        if *count >= self.max_per_ip {
+        let mut count = self.ip_counts.entry(self.ip_key(ip)).or_insert(0);
+        if *count >= config.max_per_ip {
             return None;
         }
         *count += 1;
         
-        let session = Session::new(ip, self.messages_per_second, self.submits_per_minute);
+        let mut session = Session::new(ip);
+        session.submit_slots = Arc::new(Semaphore::new(config.max_inflight_submits as usize));
+        session.vardiff = self.vardiff.as_ref().map(|config| VarDiff::new(config, Instant::now()));
+        self.limits.insert(session.id.clone(), SessionLimits::new(config.messages_per_second, config.submits_per_minute));
         self.sessions.insert(session.id.clone(), session.clone());
+        self.events.emit(&session.id, ip, EventKind::Open);
+        self.notify(SessionEvent::Created { session_id: session.id.clone(), ip });
         Some(session)
     }
 
+    /// Complete a session's hello, taking a slot in its site's cap. Returns
+    /// false, leaving the session as it was, if the site is at its cap.
+    pub fn set_ready(
+        &self,
+        id: &str,
+        client_version: String,
+        threads: u8,
+        site_label: Option<String>,
+        worker: Option<String>,
+    ) -> bool {
+        let mut session = match self.sessions.get_mut(id) {
+            Some(s) => s,
+            None => return false,
+        };
+        if let (Some(label), None) = (&site_label, &session.site_label) {
+            let mut site = self.sites.entry(label.clone()).or_default();
+            if self.max_per_site > 0 && site.sessions >= self.max_per_site {
+                return false;
+            }
+            site.sessions += 1;
+            site.empty_since = None;
+            self.report_site(label, site.sessions);
+        }
+        let event = SessionEvent::Ready {
+            session_id: id.to_string(),
+            client_version: client_version.clone(),
+            site_label: site_label.clone(),
+            worker: worker.clone(),
+        };
+        session.set_ready(client_version, threads, site_label, worker);
+        drop(session);
+        self.notify(event);
+        true
+    }
+
+    /// Count a submit outcome on the session and tell observers. Returns the
+    /// updated session's summary, or None if it is gone.
+    pub fn record_submit_result(&self, id: &str, status: &SubmitStatus, difficulty: u64) -> Option<SessionSummary> {
+        let summary = {
+            let mut session = self.sessions.get_mut(id)?;
+            session.record_submit_result(status, difficulty);
+            SessionSummary::from(&*session)
+        };
+        if let (SubmitStatus::Accepted, Some(label)) = (status, &summary.site_label) {
+            if let Some(mut site) = self.sites.get_mut(label) {
+                let now = Instant::now();
+                site.accepted_submits += 1;
+                site.accepted_shares = site.accepted_shares.saturating_add(difficulty);
+                site.recent.push_back((now, difficulty));
+                site.trim(now);
+                if let Some(metrics) = &self.metrics {
+                    metrics.set_site_accepted_shares(label, site.accepted_shares);
+                }
+            }
+        }
+        self.notify(SessionEvent::Submitted { session_id: id.to_string(), status: status.clone() });
+        Some(summary)
+    }
+
+    /// Ready sessions holding a slot for the given site label
+    pub fn site_count(&self, label: &str) -> usize {
+        self.sites.get(label).map(|s| s.sessions).unwrap_or(0)
+    }
+
+    /// Rollups for every site seen within the retention period, by label
+    pub fn site_stats(&self) -> Vec<SiteSummary> {
+        let now = Instant::now();
+        let mut summaries: Vec<SiteSummary> = self
+            .sites
+            .iter_mut()
+            .map(|mut entry| {
+                entry.trim(now);
+                SiteSummary {
+                    site: entry.key().clone(),
+                    sessions: entry.sessions,
+                    accepted_submits: entry.accepted_submits,
+                    accepted_shares: entry.accepted_shares,
+                    hashrate: entry.hashrate(),
+                }
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.site.cmp(&b.site));
+        summaries
+    }
+
+    /// Cumulative counters of every site, for persisting
+    pub fn site_totals(&self) -> Vec<SiteTotals> {
+        self.sites
+            .iter()
+            .map(|entry| SiteTotals {
+                site: entry.key().clone(),
+                accepted_submits: entry.accepted_submits,
+                accepted_shares: entry.accepted_shares,
+            })
+            .collect()
+    }
+
+    /// Add saved totals to the per-site counters, e.g. after a restart.
+    /// Sites without sessions age out after the retention period as usual.
+    pub fn restore_site_totals(&self, totals: &[SiteTotals]) {
+        let now = Instant::now();
+        for saved in totals {
+            let mut site = self.sites.entry(saved.site.clone()).or_default();
+            site.accepted_submits = site.accepted_submits.saturating_add(saved.accepted_submits);
+            site.accepted_shares = site.accepted_shares.saturating_add(saved.accepted_shares);
+            if site.sessions == 0 && site.empty_since.is_none() {
+                site.empty_since = Some(now);
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.set_site_accepted_shares(&saved.site, site.accepted_shares);
+            }
+        }
+    }
+
+    /// Forget sites that have had no sessions for `retention`, and refresh
+    /// the per-site hashrate gauges of the rest. Returns how many were pruned.
+    pub fn refresh_site_stats(&self, retention: Duration) -> usize {
+        let now = Instant::now();
+        let before = self.sites.len();
+        self.sites.retain(|label, site| {
+            let expired = site.empty_since.is_some_and(|since| now.duration_since(since) >= retention);
+            if let Some(metrics) = &self.metrics {
+                if expired {
+                    metrics.forget_site(label);
+                } else {
+                    site.trim(now);
+                    metrics.set_site_hashrate(label, site.hashrate());
+                }
+            }
+            !expired
+        });
+        before - self.sites.len()
+    }
+
+    /// Sum of every session's share-based hashrate estimate
+    pub fn estimated_hashrate(&self) -> f64 {
+        let now = Instant::now();
+        self.sessions.iter().filter_map(|s| s.hashrate.estimate(now)).sum()
+    }
+
+    pub fn max_per_site(&self) -> usize {
+        self.max_per_site
+    }
+
+    fn report_site(&self, label: &str, count: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_site_connections(label, count);
+        }
+    }
+
+    /// A full copy of the session. Hot paths should prefer `with_session`
+    /// or `state_of`, which read it in place.
     pub fn get_session(&self, id: &str) -> Option<Session> {
         self.sessions.get(id).map(|s| s.clone())
     }
 
+    /// Run `f` on the session in place, under its shard's read lock. Like
+    /// `for_each_ready`, `f` must not call back into this SessionManager.
+    pub fn with_session<R>(&self, id: &str, f: impl FnOnce(&Session) -> R) -> Option<R> {
+        self.sessions.get(id).map(|s| f(&s))
+    }
+
+    pub fn state_of(&self, id: &str) -> Option<SessionState> {
+        self.with_session(id, |s| s.state)
+    }
+
     pub fn update_session<F>(&self, id: &str, f: F)
     where
         F: FnOnce(&mut Session),
@@ -142,36 +886,311 @@ impl SessionManager {
         }
     }
 
-    pub fn check_message_limit(&self, id: &str) -> bool {
+    /// Share difficulty for the session's next job, retargeting first if one
+    /// is due. None when vardiff is disabled or the session is unknown.
+    pub fn share_difficulty(&self, id: &str) -> Option<u64> {
+        let config = self.vardiff.as_ref()?;
+        let mut session = self.sessions.get_mut(id)?;
+        let vardiff = session.vardiff.as_mut()?;
+        vardiff.retarget(config, Instant::now());
+        Some(vardiff.difficulty())
+    }
+
+    /// Whether `job_id` was recently issued to session `id`
+    pub fn owns_job(&self, id: &str, job_id: &str) -> bool {
+        self.with_session(id, |s| s.owns_job(job_id)).unwrap_or(false)
+    }
+
+    /// Take one of the session's in-flight submission slots, held until the
+    /// permit is dropped. None when all are taken or the session is gone.
+    pub fn try_begin_submit(&self, id: &str) -> Option<OwnedSemaphorePermit> {
+        let slots = self.sessions.get(id)?.submit_slots.clone();
+        slots.try_acquire_owned().ok()
+    }
+
+    /// Record the RandomX mode from the hello, restarting vardiff at the
+    /// difficulty configured for that mode
+    pub fn set_randomx_mode(&self, id: &str, mode: RandomxMode) {
         if let Some(mut session) = self.sessions.get_mut(id) {
-            return session.check_message_limit();
+            session.randomx_mode = mode;
+            if let Some(config) = &self.vardiff {
+                session.vardiff = Some(VarDiff::for_mode(config, mode, Instant::now()));
+            }
         }
-        false
     }
 
-    pub fn check_submit_limit(&self, id: &str) -> bool {
+    /// Sessions past hello, by the RandomX mode they reported
+    pub fn count_by_randomx_mode(&self) -> HashMap<RandomxMode, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.sessions.iter() {
+            if entry.client_version.is_some() {
+                *counts.entry(entry.randomx_mode).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Whether session `id` asked for stratum-style compact job targets
+    pub fn wants_compact_target(&self, id: &str) -> bool {
+        self.with_session(id, |s| s.capabilities.as_ref().is_some_and(|c| c.wants_compact_target))
+            .unwrap_or(false)
+    }
+
+    /// Remember a nonce submitted by session `id`; false if it is a repeat
+    pub fn record_nonce(&self, id: &str, job_id: &str, nonce: [u8; 4]) -> bool {
+        match self.sessions.get_mut(id) {
+            Some(mut session) => session.record_nonce(job_id, nonce),
+            None => true,
+        }
+    }
+
+    /// Forget a nonce session `id` submitted, as for a submit refused on the
+    /// server's account
+    pub fn forget_nonce(&self, id: &str, job_id: &str, nonce: [u8; 4]) {
         if let Some(mut session) = self.sessions.get_mut(id) {
-            return session.check_submit_limit();
+            session.forget_nonce(job_id, nonce);
+        }
+    }
+
+    pub fn check_message_limit(&self, id: &str) -> bool {
+        match self.limits.get_mut(id) {
+            Some(mut limits) => limits.messages.check(),
+            None => false,
+        }
+    }
+
+    pub fn check_submit_limit(&self, id: &str) -> bool {
+        match self.limits.get_mut(id) {
+            Some(mut limits) => limits.submits.check(),
+            None => false,
         }
-        false
     }
 
     pub fn remove_session(&self, id: &str) {
+        self.remove_with_reason(id, CloseReason::Disconnected);
+    }
+
+    fn remove_with_reason(&self, id: &str, reason: CloseReason) {
         if let Some((_, session)) = self.sessions.remove(id) {
-            let mut count = self.ip_counts.entry(session.ip).or_insert(0);
-            *count = count.saturating_sub(1);
-            if *count == 0 {
+            self.release_ip_slot(session.ip);
+            self.release(&session, reason);
+        }
+    }
+
+    /// Take a session off its socket but keep it, with its counters and job,
+    /// for the resume grace period. Returns the resume token, or None if the
+    /// session was removed outright because resumption is disabled.
+    pub fn detach_session(&self, id: &str) -> Option<String> {
+        if self.resume_grace.is_zero() {
+            self.remove_session(id);
+            return None;
+        }
+        let (_, mut session) = self.sessions.remove(id)?;
+        if !self.resume_holds_ip_slot {
+            self.release_ip_slot(session.ip);
+        }
+        session.state = SessionState::Closed;
+        session.resume_deadline = Some(Instant::now() + self.resume_grace);
+        let token = session.resume_token.clone();
+        self.detached.insert(token.clone(), session);
+        Some(token)
+    }
+
+    /// Reattach a detached session to a new socket from `ip`. Fails if the
+    /// token is unknown or expired, or the limits have no room for it; of
+    /// several sockets presenting the same token, only one gets the session.
+    pub fn resume_session(&self, token: &str, ip: IpAddr) -> Option<Session> {
+        let (_, mut session) = self.detached.remove(token)?;
+        if session.resume_deadline.map(|d| Instant::now() > d).unwrap_or(true) || self.is_expired(&session) {
+            self.release_detached(session);
+            return None;
+        }
+        let config = self.config();
+        if self.sessions.len() >= config.max_total {
+            self.detached.insert(token.to_string(), session);
+            return None;
+        }
+
+        // Move the IP slot to the address the client came back from
+        let held = self.resume_holds_ip_slot.then_some(session.ip);
+        if held.map(|old| self.ip_key(old)) != Some(self.ip_key(ip)) {
+            let mut count = self.ip_counts.entry(self.ip_key(ip)).or_insert(0);
+            if *count >= config.max_per_ip {
                 drop(count);
-                self.ip_counts.remove(&session.ip);
+                self.detached.insert(token.to_string(), session);
+                return None;
+            }
+            *count += 1;
+            drop(count);
+            if let Some(old) = held {
+                self.release_ip_slot(old);
             }
         }
+
+        session.ip = ip;
+        session.state = if session.client_version.is_some() { SessionState::Ready } else { SessionState::Connected };
+        session.active_since = Instant::now();
+        session.resume_deadline = None;
+        // The old socket's task is gone; give the new one its own signal
+        session.close_signal = CloseSignal::default();
+        session.touch();
+        self.sessions.insert(session.id.clone(), session.clone());
+        Some(session)
+    }
+
+    /// Drop detached sessions whose grace period or lifetime has run out
+    pub fn sweep_detached(&self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .detached
+            .iter()
+            .filter(|entry| {
+                let session = entry.value();
+                session.resume_deadline.map(|d| now > d).unwrap_or(true) || self.is_expired(session)
+            })
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut removed = 0;
+        for token in expired {
+            if let Some((_, session)) = self.detached.remove(&token) {
+                self.release_detached(session);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    pub fn detached_count(&self) -> usize {
+        self.detached.len()
+    }
+
+    fn release_detached(&self, session: Session) {
+        if self.resume_holds_ip_slot {
+            self.release_ip_slot(session.ip);
+        }
+        self.release(&session, CloseReason::Expired);
+    }
+
+    fn release_ip_slot(&self, ip: IpAddr) {
+        let key = self.ip_key(ip);
+        let mut count = self.ip_counts.entry(key).or_insert(0);
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            drop(count);
+            self.ip_counts.remove(&key);
+        }
+    }
+
+    /// Give back a session's rate limiters and site slot, and log its end
+    fn release(&self, session: &Session, reason: CloseReason) {
+        self.limits.remove(&session.id);
+        if let Some(jobs) = &self.jobs {
+            jobs.remove_session_jobs(&session.id);
+        }
+        if let Some(label) = &session.site_label {
+            if let Some(mut site) = self.sites.get_mut(label) {
+                site.sessions = site.sessions.saturating_sub(1);
+                if site.sessions == 0 {
+                    site.empty_since = Some(Instant::now());
+                }
+                self.report_site(label, site.sessions);
+            }
+        }
+        self.events.emit(&session.id, session.ip, EventKind::Close);
+        self.notify(SessionEvent::Closed { session_id: session.id.clone(), reason });
+    }
+
+    /// Snapshot of every active session
+    pub fn list_sessions(&self) -> Vec<Session> {
+        self.sessions.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    /// Summaries of every active session. Each shard is read-locked only
+    /// while its entries are copied, so sessions can be created and removed
+    /// meanwhile; those may or may not appear.
+    pub fn snapshot(&self) -> Vec<SessionSummary> {
+        self.sessions.iter().map(|entry| SessionSummary::from(entry.value())).collect()
+    }
+
+    /// Visit every Ready session in place, without copying it.
+    ///
+    /// `f` runs while the session's shard is read-locked: it must not call
+    /// back into this SessionManager to create, update or remove sessions,
+    /// which would deadlock on that shard. Collect ids and act afterwards.
+    pub fn for_each_ready<F>(&self, mut f: F)
+    where
+        F: FnMut(&Session),
+    {
+        for entry in self.sessions.iter() {
+            if entry.value().state == SessionState::Ready {
+                f(entry.value());
+            }
+        }
+    }
+
+    /// Make a session's transport task close its connection with `reason`
+    /// and exit. Returns false if there is no such session or it is
+    /// already closing, so closing twice is harmless.
+    pub fn close_session(&self, id: &str, reason: &str) -> bool {
+        self.with_session(id, |s| s.close_signal.close(reason)).unwrap_or(false)
+    }
+
+    pub fn count_by_state(&self) -> HashMap<SessionState, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.sessions.iter() {
+            *counts.entry(entry.value().state).or_insert(0) += 1;
+        }
+        counts
     }
 
     pub fn active_count(&self) -> usize {
         self.sessions.len()
     }
 
-    /// Remove sessions that have been idle for longer than the specified duration
+    /// Mark Ready sessions that have not submitted for `window` as Idle, so
+    /// template changes stop creating jobs for them. Returns how many were
+    /// marked.
+    pub fn mark_inactive(&self, window: Duration) -> usize {
+        let now = Instant::now();
+        let mut marked = 0;
+        let mut idle = 0;
+        for mut entry in self.sessions.iter_mut() {
+            if entry.is_inactive(window, now) {
+                entry.state = SessionState::Idle;
+                marked += 1;
+            }
+            if entry.state == SessionState::Idle {
+                idle += 1;
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.set_sessions_idle(idle);
+        }
+        if marked > 0 {
+            debug!("Marked {} sessions idle", marked);
+        }
+        marked
+    }
+
+    /// Make an Idle session Ready again. Returns false if it was not Idle.
+    pub fn wake(&self, id: &str) -> bool {
+        let woken = match self.sessions.get_mut(id) {
+            Some(mut session) if session.state == SessionState::Idle => {
+                session.state = SessionState::Ready;
+                session.active_since = Instant::now();
+                true
+            }
+            _ => false,
+        };
+        if let (true, Some(metrics)) = (woken, &self.metrics) {
+            metrics.dec_sessions_idle();
+        }
+        woken
+    }
+
+    /// Remove sessions that have been idle for longer than the specified
+    /// duration. A socket still attached to one is told to close.
     pub fn cleanup_idle(&self, max_idle: Duration) -> usize {
         let now = Instant::now();
         let mut removed = 0;
@@ -184,8 +1203,11 @@ impl SessionManager {
             .collect();
         
         for id in to_remove {
-            self.remove_session(&id);
-            removed += 1;
+            if let Some(signal) = self.with_session(&id, |s| s.close_signal.clone()) {
+                self.remove_with_reason(&id, CloseReason::Idle);
+                signal.close("Idle timeout");
+                removed += 1;
+            }
         }
         
         if removed > 0 {
@@ -195,3 +1217,637 @@ impl SessionManager {
         removed
     }
 }
+
+/// Log session lifecycle events as they happen. Runs until the manager is
+/// dropped; events missed by falling behind are counted, not waited for.
+pub async fn log_session_events(mut events: broadcast::Receiver<SessionEvent>, metrics: Arc<Metrics>) {
+    loop {
+        match events.recv().await {
+            Ok(SessionEvent::Created { session_id, ip }) => {
+                info!("Session created: {} from {}", session_id, privacy::display_ip(&ip))
+            }
+            Ok(SessionEvent::Ready { session_id, client_version, .. }) => {
+                info!("Session ready: {} ({})", session_id, client_version)
+            }
+            Ok(SessionEvent::Submitted { session_id, status }) => {
+                debug!("Session {} submit: {:?}", session_id, status)
+            }
+            Ok(SessionEvent::Closed { session_id, reason }) => info!("Session closed: {} ({})", session_id, reason),
+            Err(broadcast::error::RecvError::Lagged(missed)) => metrics.add_session_events_lagged(missed),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(submits_per_minute: u32) -> LimitsConfig {
+        let toml = format!("submits_per_minute = {}\nshares_per_minute = 120\nmessages_per_second = 20", submits_per_minute);
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn test_applied_limits_keep_recent_submits() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, submits_per_minute: 5, ..Default::default() });
+        let tightened = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let loosened = manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        for _ in 0..3 {
+            assert!(manager.check_submit_limit(&tightened.id));
+        }
+        for _ in 0..5 {
+            assert!(manager.check_submit_limit(&loosened.id));
+        }
+        assert!(!manager.check_submit_limit(&loosened.id));
+
+        manager.apply_limits(&limits(2)).unwrap();
+        // Three submits already counted against a limit of two: no burst
+        assert!(!manager.check_submit_limit(&tightened.id));
+        // New sessions start with the new limit
+        let fresh = manager.create_session("198.51.100.3".parse().unwrap()).unwrap();
+        assert!(manager.check_submit_limit(&fresh.id));
+        assert!(manager.check_submit_limit(&fresh.id));
+        assert!(!manager.check_submit_limit(&fresh.id));
+
+        manager.apply_limits(&limits(8)).unwrap();
+        // Loosening applies at once: five counted, three more allowed
+        for _ in 0..3 {
+            assert!(manager.check_submit_limit(&loosened.id));
+        }
+        assert!(!manager.check_submit_limit(&loosened.id));
+
+        assert!(manager.apply_limits(&limits(0)).is_err());
+        assert_eq!(manager.config().submits_per_minute, 8);
+    }
+
+    #[test]
+    fn test_sessions_without_submits_go_idle_and_wake() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let quiet = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let busy = manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        let handshaking = manager.create_session("198.51.100.3".parse().unwrap()).unwrap();
+        for id in [&quiet.id, &busy.id] {
+            assert!(manager.set_ready(id, "t".into(), 1, None, None));
+        }
+
+        let window = Duration::from_millis(20);
+        assert_eq!(manager.mark_inactive(window), 0, "just became ready");
+        std::thread::sleep(window);
+        manager.record_submit_result(&busy.id, &SubmitStatus::Rejected, 0);
+        assert_eq!(manager.mark_inactive(window), 1);
+        assert_eq!(manager.state_of(&quiet.id), Some(SessionState::Idle));
+        assert_eq!(manager.state_of(&busy.id), Some(SessionState::Ready));
+        assert_eq!(manager.state_of(&handshaking.id), Some(SessionState::Connected));
+
+        assert!(manager.wake(&quiet.id));
+        assert!(!manager.wake(&quiet.id), "already awake");
+        assert!(!manager.wake(&busy.id));
+        assert_eq!(manager.state_of(&quiet.id), Some(SessionState::Ready));
+        // Waking restarts the window
+        assert_eq!(manager.mark_inactive(window), 0);
+    }
+
+    #[test]
+    fn test_submit_slots_are_released_on_every_exit() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let session = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+
+        let first = manager.try_begin_submit(&session.id).unwrap();
+        let second = manager.try_begin_submit(&session.id).unwrap();
+        assert!(manager.try_begin_submit(&session.id).is_none());
+        drop(first);
+
+        // A verification that panics still gives its slot back
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _slot = manager.try_begin_submit(&session.id).unwrap();
+            panic!("validator blew up");
+        }));
+        assert!(result.is_err());
+        assert!(manager.try_begin_submit(&session.id).is_some());
+
+        drop(second);
+        let slots: Vec<_> = (0..3).filter_map(|_| manager.try_begin_submit(&session.id)).collect();
+        assert_eq!(slots.len(), 2);
+        assert!(manager.try_begin_submit("missing").is_none());
+    }
+
+    #[test]
+    fn test_accepted_shares_feed_the_hashrate_estimate() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let a = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let b = manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        assert_eq!(manager.estimated_hashrate(), 0.0);
+        assert_eq!(manager.with_session(&a.id, |s| s.hashrate.estimate(Instant::now())).unwrap(), None);
+
+        manager.record_submit_result(&a.id, &SubmitStatus::Rejected, 1000);
+        assert_eq!(manager.with_session(&a.id, |s| s.hashrate.estimate(Instant::now())).unwrap(), None);
+
+        manager.record_submit_result(&a.id, &SubmitStatus::Accepted, 1000);
+        manager.record_submit_result(&b.id, &SubmitStatus::Accepted, 1000);
+        for id in [&a.id, &b.id] {
+            assert!(manager.with_session(id, |s| s.hashrate.estimate(Instant::now())).unwrap().unwrap() > 0.0);
+        }
+        assert!(manager.estimated_hashrate() > 0.0);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let valid = SessionManagerConfig::default();
+        assert!(valid.validate().is_ok());
+
+        let zero = [
+            SessionManagerConfig { max_total: 0, max_per_ip: 0, ..valid },
+            SessionManagerConfig { max_per_ip: 0, ..valid },
+            SessionManagerConfig { messages_per_second: 0, ..valid },
+            SessionManagerConfig { submits_per_minute: 0, ..valid },
+        ];
+        for config in zero {
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains("must be greater than 0"), "{}", err);
+        }
+
+        let err = SessionManagerConfig { max_per_ip: 10, max_total: 5, ..valid }.validate().unwrap_err();
+        assert!(err.to_string().contains("exceeds server.max_connections"), "{}", err);
+    }
+
+    #[test]
+    fn test_reconfigure_applies_to_new_sessions() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 10, ..Default::default() });
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let first = manager.create_session(ip).unwrap();
+        assert!(manager.create_session(ip).is_none());
+
+        // An invalid config is refused and the old limits stay
+        let invalid = SessionManagerConfig { max_per_ip: 0, ..manager.config() };
+        assert!(manager.reconfigure(invalid).is_err());
+        assert_eq!(manager.config().max_per_ip, 1);
+
+        manager
+            .reconfigure(SessionManagerConfig { max_per_ip: 2, max_total: 10, submits_per_minute: 1, ..Default::default() })
+            .unwrap();
+        let second = manager.create_session(ip).unwrap();
+        assert!(manager.create_session(ip).is_none());
+
+        // The session created before keeps its submit limit; the new one gets the new limit
+        assert!(manager.check_submit_limit(&second.id));
+        assert!(!manager.check_submit_limit(&second.id));
+        assert!(manager.check_submit_limit(&first.id));
+        assert!(manager.check_submit_limit(&first.id));
+    }
+
+    #[test]
+    fn test_nonces_are_remembered_per_job_up_to_a_cap() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let session = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        manager.update_session(&session.id, |s| {
+            s.update_job("a".into(), Arc::from(&[0u8; 8][..]));
+            s.update_job("b".into(), Arc::from(&[1u8; 8][..]));
+        });
+
+        assert!(manager.record_nonce(&session.id, "a", [0; 4]));
+        assert!(!manager.record_nonce(&session.id, "a", [0; 4]));
+        assert!(manager.record_nonce(&session.id, "b", [0; 4]), "nonces are per job");
+
+        for i in 1..=MAX_NONCES_PER_JOB as u32 {
+            assert!(manager.record_nonce(&session.id, "a", i.to_le_bytes()));
+        }
+        let held = manager.with_session(&session.id, |s| s.recent_jobs[0].nonces.len()).unwrap();
+        assert_eq!(held, MAX_NONCES_PER_JOB);
+        // The oldest nonce was forgotten to make room
+        assert!(manager.record_nonce(&session.id, "a", [0; 4]));
+    }
+
+    #[test]
+    fn test_connection_rate_limit_and_recovery() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_connection_rate(3, 1);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let other: IpAddr = "198.51.100.2".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(manager.check_connection_rate(ip).is_ok());
+        }
+        let retry_after = manager.check_connection_rate(ip).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+        assert!(manager.check_connection_rate(other).is_ok());
+
+        std::thread::sleep(Duration::from_millis(1_100));
+        assert!(manager.check_connection_rate(ip).is_ok());
+    }
+
+    #[test]
+    fn test_subscribers_see_the_session_lifecycle() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_resume(Duration::from_millis(1), true);
+        let mut events = manager.subscribe();
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        let session = manager.create_session(ip).unwrap();
+        assert!(manager.set_ready(&session.id, "t".into(), 1, None, Some("blog-footer".into())));
+        manager.record_submit_result(&session.id, &SubmitStatus::Accepted, 10);
+        manager.record_submit_result(&session.id, &SubmitStatus::Stale, 10);
+        manager.remove_session(&session.id);
+
+        let idle = manager.create_session(ip).unwrap();
+        manager.update_session(&idle.id, |s| s.last_activity = Instant::now() - Duration::from_secs(60));
+        manager.cleanup_idle(Duration::from_secs(30));
+
+        let detached = manager.create_session(ip).unwrap();
+        manager.detach_session(&detached.id).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        manager.sweep_detached();
+
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            seen.push(match event {
+                SessionEvent::Created { ip: created_ip, .. } => {
+                    assert_eq!(created_ip, ip);
+                    "created".to_string()
+                }
+                SessionEvent::Ready { worker, .. } => format!("ready {}", worker.unwrap_or_default()),
+                SessionEvent::Submitted { status, .. } => format!("submitted {:?}", status),
+                SessionEvent::Closed { reason, .. } => format!("closed {}", reason),
+            });
+        }
+        assert_eq!(
+            seen,
+            [
+                "created",
+                "ready blog-footer",
+                "submitted Accepted",
+                "submitted Stale",
+                "closed disconnected",
+                "created",
+                "closed idle",
+                "created",
+                "closed expired",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_lags_without_blocking() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let events = manager.subscribe();
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // Nobody reads meanwhile; sessions are not held up
+        let rounds = SESSION_EVENT_CAPACITY;
+        for _ in 0..rounds {
+            let session = manager.create_session(ip).unwrap();
+            manager.remove_session(&session.id);
+        }
+        drop(manager);
+
+        let metrics = Arc::new(Metrics::new());
+        log_session_events(events, metrics.clone()).await;
+        let lagged = metrics.session_events_lagged.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(lagged, (2 * rounds - SESSION_EVENT_CAPACITY) as u64);
+    }
+
+    #[test]
+    fn test_ipv6_clients_are_limited_per_prefix() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 2, max_total: 100, ..Default::default() }).with_connection_rate(3, 60);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let first = manager.create_session(ip("2001:db8:1:1::1")).unwrap();
+        assert!(manager.create_session(ip("2001:db8:1:1::2")).is_some());
+        // A new interface identifier in the same /64 is the same client
+        assert!(manager.create_session(ip("2001:db8:1:1:abcd::3")).is_none());
+        assert!(manager.create_session(ip("2001:db8:1:2::1")).is_some());
+        // Sessions keep the full address for logs and metrics
+        assert_eq!(first.ip, ip("2001:db8:1:1::1"));
+
+        manager.remove_session(&first.id);
+        assert!(manager.create_session(ip("2001:db8:1:1::4")).is_some());
+
+        for i in 1..=3 {
+            assert!(manager.check_connection_rate(ip(&format!("2001:db8:9:9::{}", i))).is_ok());
+        }
+        assert!(manager.check_connection_rate(ip("2001:db8:9:9::ffff")).is_err());
+        assert!(manager.check_connection_rate(ip("2001:db8:9:a::1")).is_ok());
+
+        // A wider prefix groups more addresses
+        let wide = SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 100, ..Default::default() }).with_ipv6_prefix(48);
+        assert!(wide.create_session(ip("2001:db8:1:1::1")).is_some());
+        assert!(wide.create_session(ip("2001:db8:1:2::1")).is_none());
+    }
+
+    #[test]
+    fn test_idle_connection_limits_are_evicted() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_connection_rate(3, 1);
+        for i in 0..50u8 {
+            let _ = manager.check_connection_rate(IpAddr::from([198, 51, 100, i]));
+        }
+        manager.cleanup_connection_limits();
+        assert_eq!(manager.connect_limits.len(), 50);
+
+        std::thread::sleep(Duration::from_millis(1_100));
+        manager.cleanup_connection_limits();
+        assert!(manager.connect_limits.is_empty());
+    }
+
+    #[test]
+    fn test_submit_outcomes_update_counters() {
+        let mut session = Session::new("198.51.100.1".parse().unwrap());
+        let script = [
+            (SubmitStatus::Accepted, 100),
+            (SubmitStatus::Rejected, 100),
+            (SubmitStatus::Stale, 100),
+            (SubmitStatus::Error, 100),
+            (SubmitStatus::Accepted, 250),
+        ];
+        for (status, difficulty) in &script {
+            session.record_submit_result(status, *difficulty);
+        }
+        assert_eq!(session.accepted_submits, 2);
+        assert_eq!(session.rejected_submits, 1);
+        assert_eq!(session.stale_submits, 1);
+        assert_eq!(session.accepted_shares, 350);
+        assert!(session.last_accept_at.is_some());
+
+        // Achieved difficulty sums, remembers the best and saturates
+        session.record_achieved(400);
+        session.record_achieved(1_000);
+        assert_eq!((session.achieved_difficulty, session.best_difficulty), (1_400, 1_000));
+        session.record_achieved(u128::MAX);
+        assert_eq!((session.achieved_difficulty, session.best_difficulty), (u128::MAX, u128::MAX));
+    }
+
+    #[test]
+    fn test_cleanup_idle_removes_only_idle_sessions() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let idle = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        let active = manager.create_session("198.51.100.2".parse().unwrap()).unwrap();
+        manager.update_session(&idle.id, |s| s.last_activity = Instant::now() - Duration::from_secs(120));
+        manager.update_session(&active.id, |s| s.last_activity = Instant::now() - Duration::from_secs(120));
+        manager.update_session(&active.id, |s| s.touch());
+
+        assert_eq!(manager.cleanup_idle(Duration::from_secs(60)), 1);
+        assert!(manager.get_session(&idle.id).is_none());
+        assert!(manager.get_session(&active.id).is_some());
+        // The idle session's socket task, if any, is told to close
+        assert_eq!(idle.close_signal.reason(), Some("Idle timeout"));
+        assert!(!active.close_signal.is_closed());
+    }
+
+    #[test]
+    fn test_snapshot_while_sessions_churn() {
+        const SESSIONS: usize = 5000;
+        let manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: SESSIONS, max_total: SESSIONS * 2, ..Default::default() }));
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        for i in 0..SESSIONS {
+            let session = manager.create_session(ip).unwrap();
+            if i % 2 == 0 {
+                assert!(manager.set_ready(&session.id, "t".into(), 1, None, None));
+            }
+        }
+
+        // Creating and removing sessions alongside must not block on the readers
+        let churn = {
+            let manager = manager.clone();
+            std::thread::spawn(move || {
+                let other: IpAddr = "198.51.100.2".parse().unwrap();
+                for _ in 0..1000 {
+                    let session = manager.create_session(other).unwrap();
+                    manager.remove_session(&session.id);
+                }
+            })
+        };
+
+        let started = Instant::now();
+        for _ in 0..20 {
+            let snapshot = manager.snapshot();
+            assert!(snapshot.len() >= SESSIONS && snapshot.len() <= SESSIONS + 1);
+            let mut ready = 0;
+            manager.for_each_ready(|_| ready += 1);
+            assert_eq!(ready, SESSIONS / 2);
+        }
+        churn.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+        assert_eq!(manager.active_count(), SESSIONS);
+    }
+
+    #[test]
+    fn test_limiters_survive_session_reads() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, messages_per_second: 100, submits_per_minute: 3, ..Default::default() });
+        let session = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+
+        for _ in 0..3 {
+            // Snapshots must neither reset nor fork the limiter
+            assert!(manager.get_session(&session.id).is_some());
+            assert_eq!(manager.list_sessions().len(), 1);
+            assert!(manager.check_submit_limit(&session.id));
+        }
+        assert!(manager.get_session(&session.id).is_some());
+        assert!(!manager.check_submit_limit(&session.id));
+        assert!(manager.check_message_limit(&session.id));
+
+        manager.remove_session(&session.id);
+        assert!(!manager.check_submit_limit(&session.id));
+        assert!(manager.limits.is_empty());
+    }
+
+    fn resumable_manager(holds_ip_slot: bool) -> SessionManager {
+        SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 10, ..Default::default() }).with_resume(Duration::from_secs(60), holds_ip_slot)
+    }
+
+    #[test]
+    fn test_detached_session_resumes_with_its_counters() {
+        let manager = resumable_manager(true);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let session = manager.create_session(ip).unwrap();
+        manager.update_session(&session.id, |s| {
+            s.set_ready("t".into(), 2, None, None);
+            s.accepted_submits = 3;
+            s.update_job("job-1".into(), vec![7; 8].into());
+        });
+
+        let token = manager.detach_session(&session.id).unwrap();
+        assert_eq!(manager.active_count(), 0);
+        assert_eq!(manager.detached_count(), 1);
+        // Still holds its IP slot
+        assert!(manager.create_session(ip).is_none());
+
+        assert!(manager.resume_session("wrong", ip).is_none());
+        let resumed = manager.resume_session(&token, ip).unwrap();
+        assert_eq!(resumed.id, session.id);
+        assert_eq!(resumed.state, SessionState::Ready);
+        assert_eq!(resumed.accepted_submits, 3);
+        let job = resumed.current_job().unwrap();
+        assert_eq!(job.job_id, "job-1");
+        assert_eq!(*job.reserved_value, [7; 8]);
+        assert_eq!(manager.active_count(), 1);
+        assert!(manager.resume_session(&token, ip).is_none());
+
+        manager.remove_session(&session.id);
+        assert!(manager.create_session(ip).is_some());
+    }
+
+    #[test]
+    fn test_detached_session_can_release_ip_slot() {
+        let manager = resumable_manager(false);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let session = manager.create_session(ip).unwrap();
+        let token = manager.detach_session(&session.id).unwrap();
+
+        // The slot is free while detached, so resuming needs room again
+        let other = manager.create_session(ip).unwrap();
+        assert!(manager.resume_session(&token, ip).is_none());
+        manager.remove_session(&other.id);
+        assert!(manager.resume_session(&token, ip).is_some());
+    }
+
+    #[test]
+    fn test_expired_detached_sessions_are_swept() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 10, ..Default::default() }).with_resume(Duration::from_millis(20), true);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let session = manager.create_session(ip).unwrap();
+        let token = manager.detach_session(&session.id).unwrap();
+        assert_eq!(manager.sweep_detached(), 0);
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(manager.sweep_detached(), 1);
+        assert!(manager.resume_session(&token, ip).is_none());
+        assert!(manager.create_session(ip).is_some());
+    }
+
+    #[test]
+    fn test_detached_sessions_past_lifetime_are_swept() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 10, ..Default::default() })
+            .with_resume(Duration::from_secs(60), true)
+            .with_max_lifetime(Duration::from_secs(1));
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let first = manager.create_session(ip).unwrap();
+        let token = manager.detach_session(&first.id).unwrap();
+        assert_eq!(manager.sweep_detached(), 0);
+
+        // Well inside the resume grace, but past the lifetime
+        std::thread::sleep(Duration::from_millis(1_100));
+        assert!(manager.is_expired(&first));
+        assert_eq!(manager.sweep_detached(), 1);
+        assert!(manager.resume_session(&token, ip).is_none());
+        let second = manager.create_session(ip).unwrap();
+        assert!(!manager.is_expired(&second));
+    }
+
+    #[test]
+    fn test_detach_without_resumption_removes() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 10, ..Default::default() });
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let session = manager.create_session(ip).unwrap();
+        assert!(manager.detach_session(&session.id).is_none());
+        assert_eq!(manager.detached_count(), 0);
+        assert!(manager.create_session(ip).is_some());
+    }
+
+    #[test]
+    fn test_only_one_racer_resumes_a_session() {
+        for _ in 0..50 {
+            let manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_resume(Duration::from_secs(60), true));
+            let session = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+            let token = manager.detach_session(&session.id).unwrap();
+
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let racers: Vec<_> = (0..2u8)
+                .map(|i| {
+                    let (manager, barrier, token) = (manager.clone(), barrier.clone(), token.clone());
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        manager.resume_session(&token, IpAddr::from([198, 51, 100, 10 + i])).is_some()
+                    })
+                })
+                .collect();
+            let winners = racers.into_iter().map(|r| r.join().unwrap()).filter(|won| *won).count();
+            assert_eq!(winners, 1);
+            assert_eq!(manager.active_count(), 1);
+            assert_eq!(manager.detached_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_site_stats_roll_up_per_token() {
+        let metrics = Arc::new(Metrics::new());
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_metrics(metrics.clone());
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let ready = |site: &str| {
+            let session = manager.create_session(ip).unwrap();
+            assert!(manager.set_ready(&session.id, "t".into(), 1, Some(site.into()), None));
+            session.id
+        };
+
+        let a1 = ready("site-a");
+        let a2 = ready("site-a");
+        let b1 = ready("site-b");
+        manager.record_submit_result(&a1, &SubmitStatus::Accepted, 3000);
+        manager.record_submit_result(&a2, &SubmitStatus::Accepted, 3000);
+        manager.record_submit_result(&a2, &SubmitStatus::Stale, 3000);
+        manager.record_submit_result(&b1, &SubmitStatus::Accepted, 600);
+        manager.remove_session(&a2);
+
+        let stats = manager.site_stats();
+        assert_eq!(stats.len(), 2);
+        let (a, b) = (&stats[0], &stats[1]);
+        assert_eq!((a.site.as_str(), a.sessions, a.accepted_submits, a.accepted_shares), ("site-a", 1, 2, 6000));
+        assert_eq!((b.site.as_str(), b.sessions, b.accepted_submits, b.accepted_shares), ("site-b", 1, 1, 600));
+        assert_eq!(a.hashrate, 10.0);
+        assert_eq!(b.hashrate, 1.0);
+
+        // A site is kept while it has sessions, and forgotten once empty for the retention period
+        assert_eq!(manager.refresh_site_stats(Duration::ZERO), 0);
+        assert!(metrics.format_prometheus().contains(r#"coordinator_site_hashrate{site="site-a"} 10"#));
+        manager.remove_session(&b1);
+        assert_eq!(manager.refresh_site_stats(Duration::from_secs(3600)), 0);
+        assert_eq!(manager.refresh_site_stats(Duration::ZERO), 1);
+        assert_eq!(manager.site_stats().iter().map(|s| s.site.as_str()).collect::<Vec<_>>(), ["site-a"]);
+        assert!(!metrics.format_prometheus().contains(r#"site="site-b""#));
+    }
+
+    #[test]
+    fn test_in_place_reads_match_snapshots() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() });
+        let session = manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        assert_eq!(manager.state_of(&session.id), Some(SessionState::Connected));
+        assert!(manager.set_ready(&session.id, "t".into(), 3, None, None));
+        assert_eq!(manager.state_of(&session.id), Some(SessionState::Ready));
+
+        let reserved: Arc<[u8]> = vec![1; 8].into();
+        manager.update_session(&session.id, |s| s.update_job("job-1".into(), reserved.clone()));
+        let issued = manager.with_session(&session.id, |s| s.current_job().unwrap().reserved_value.clone()).unwrap();
+        assert!(Arc::ptr_eq(&issued, &reserved), "the reserved value is shared, not copied");
+        assert_eq!(manager.with_session(&session.id, |s| s.threads), manager.get_session(&session.id).map(|s| s.threads));
+
+        manager.remove_session(&session.id);
+        assert_eq!(manager.state_of(&session.id), None);
+        assert!(manager.with_session(&session.id, |_| ()).is_none());
+    }
+
+    #[test]
+    fn test_connection_rate_disabled() {
+        let manager = SessionManager::new(SessionManagerConfig { max_per_ip: 10, max_total: 10, ..Default::default() }).with_connection_rate(0, 60);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        for _ in 0..100 {
+            assert!(manager.check_connection_rate(ip).is_ok());
+        }
+        assert!(manager.connect_limits.is_empty());
+    }
+
+    #[test]
+    fn test_session_jobs_go_with_the_session() {
+        let jobs = Arc::new(JobManager::new(0));
+        let manager = resumable_manager(false).with_jobs(jobs.clone());
+        let template = crate::server::tests::test_template();
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // A detached session keeps its jobs for when it resumes
+        let detached = manager.create_session(ip).unwrap();
+        let job = jobs.create_job(&template, &detached.id, 1).unwrap();
+        manager.detach_session(&detached.id).unwrap();
+        assert!(jobs.get_job(&job.job_id).is_some());
+
+        let session = manager.create_session(ip).unwrap();
+        let job = jobs.create_job(&template, &session.id, 1).unwrap();
+        manager.remove_session(&session.id);
+        assert!(jobs.get_job(&job.job_id).is_none());
+        assert_eq!(jobs.session_job_count(&session.id), 0);
+    }
+}
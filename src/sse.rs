@@ -0,0 +1,211 @@
+use axum::{
+    Router,
+    routing::get,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+};
+use futures::stream;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Interval;
+use tracing::info;
+
+use crate::privacy;
+use crate::proxy;
+use crate::server::{self, AppState};
+use crate::session::CloseSignal;
+use crate::stats::CoordinatorStats;
+use crate::template::TemplateState;
+
+/// Interval between `stats` events
+const STATS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events stream of jobs and coordinator stats, for dashboards
+/// and miners that cannot hold a WebSocket open
+pub fn router() -> Router<AppState> {
+    Router::new().route("/jobs/stream", get(stream_jobs))
+}
+
+async fn stream_jobs(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let ip = proxy::resolve_client_ip(addr.ip(), &headers, &state.config.server.trusted_proxies);
+    if let Some(rejection) = server::admission_rejection(&state, ip) {
+        return rejection;
+    }
+
+    // A session holds the subscriber's slot in the per-IP and total limits
+    let session = match state.session_manager.create_session(ip) {
+        Some(s) => s,
+        None => return (StatusCode::TOO_MANY_REQUESTS, "Connection limit exceeded").into_response(),
+    };
+    state.metrics.inc_connections();
+    info!("Job stream opened: {} from {}", session.id, privacy::display_ip(&ip));
+
+    let mut template_rx = state.template_rx.clone();
+    // Send the current job, if any, straight away
+    template_rx.mark_changed();
+    let subscriber = Subscriber {
+        session_id: session.id,
+        close_signal: session.close_signal,
+        job_ids: Vec::new(),
+        template_rx,
+        stats_ticker: tokio::time::interval_at(tokio::time::Instant::now() + STATS_INTERVAL, STATS_INTERVAL),
+        state,
+    };
+
+    let events = stream::unfold(subscriber, |mut subscriber| async move {
+        let event = subscriber.next_event().await?;
+        Some((Ok::<_, Infallible>(event), subscriber))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// One open stream. Dropped when the client disconnects, which releases its
+/// session and the jobs created for it.
+struct Subscriber {
+    state: AppState,
+    session_id: String,
+    close_signal: CloseSignal,
+    /// Jobs handed out on this stream
+    job_ids: Vec<String>,
+    template_rx: watch::Receiver<Option<TemplateState>>,
+    stats_ticker: Interval,
+}
+
+impl Subscriber {
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            tokio::select! {
+                result = self.template_rx.changed() => {
+                    result.ok()?;
+                    if let Some(event) = self.job_event() {
+                        return Some(event);
+                    }
+                }
+                _ = self.stats_ticker.tick() => {
+                    self.state.session_manager.update_session(&self.session_id, |s| s.touch());
+                    let stats = CoordinatorStats::collect(&self.state);
+                    return Event::default().event("stats").json_data(stats).ok();
+                }
+                _ = self.close_signal.closed() => return None,
+                _ = self.state.shutdown.cancelled() => return None,
+            }
+        }
+    }
+
+    fn job_event(&mut self) -> Option<Event> {
+        let template = self.template_rx.borrow_and_update().clone()?;
+        let job = server::session_job(&self.state, &template, &self.session_id).ok()?;
+        self.state.metrics.inc_jobs();
+        self.state.session_manager.update_session(&self.session_id, |s| {
+            s.update_job(job.job_id.clone(), job.reserved_value.clone());
+        });
+        self.job_ids.push(job.job_id.clone());
+        let compact_target = self.state.session_manager.wants_compact_target(&self.session_id);
+        Event::default().event("job").json_data(server::job_message(job, compact_target)).ok()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for job_id in &self.job_ids {
+            self.state.job_manager.remove_job(job_id);
+        }
+        self.state.session_manager.remove_session(&self.session_id);
+        self.state.metrics.dec_connections();
+        info!("Job stream closed: {}", self.session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ServerMessage;
+    use crate::server::tests::{test_state, test_template};
+    use crate::session::{SessionManager, SessionManagerConfig};
+    use axum::body::{Body, BodyDataStream};
+    use axum::http::Request;
+    use futures::StreamExt;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn request() -> Request<Body> {
+        let mut request = Request::get("/v1/jobs/stream").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([198, 51, 100, 8], 5000))));
+        request
+    }
+
+    /// Read the next event as (name, data)
+    async fn next_event(body: &mut BodyDataStream) -> (String, String) {
+        let mut buffer = String::new();
+        while !buffer.contains("\n\n") {
+            let chunk = body.next().await.unwrap().unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let mut name = String::new();
+        let mut data = String::new();
+        for line in buffer.lines() {
+            if let Some(v) = line.strip_prefix("event: ") {
+                name = v.to_string();
+            } else if let Some(v) = line.strip_prefix("data: ") {
+                data = v.to_string();
+            }
+        }
+        (name, data)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_emits_job_and_stats_then_cleans_up() {
+        let (state, template_tx) = test_state();
+        let app = server::router(state.clone());
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.session_manager.active_count(), 1);
+        let mut body = response.into_body().into_data_stream();
+
+        template_tx.send(Some(test_template())).unwrap();
+        let (name, data) = next_event(&mut body).await;
+        assert_eq!(name, "job");
+        let job_id = match serde_json::from_str(&data).unwrap() {
+            ServerMessage::Job { job_id, height, .. } => {
+                assert_eq!(height, 100);
+                job_id
+            }
+            other => panic!("expected a job, got {:?}", other),
+        };
+        assert!(state.job_manager.get_job(&job_id).is_some());
+
+        let (name, data) = next_event(&mut body).await;
+        assert_eq!(name, "stats");
+        let stats: CoordinatorStats = serde_json::from_str(&data).unwrap();
+        assert_eq!(stats.template_height, Some(100));
+
+        drop(body);
+        assert_eq!(state.session_manager.active_count(), 0);
+        assert!(state.job_manager.get_job(&job_id).is_none());
+        assert_eq!(state.metrics.connections_active.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_honors_per_ip_limit() {
+        let (mut state, _template_tx) = test_state();
+        state.session_manager = Arc::new(SessionManager::new(SessionManagerConfig { max_per_ip: 1, max_total: 100, ..Default::default() }));
+        let app = server::router(state);
+
+        let first = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = app.oneshot(request()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+use crate::protocol::RandomxMode;
+use crate::server::AppState;
+use crate::session::SessionState;
+
+/// Snapshot served by the `/stats` endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoordinatorStats {
+    pub active_sessions: usize,
+    pub sessions_by_state: HashMap<SessionState, usize>,
+    /// Sessions past hello by the RandomX mode they reported
+    pub sessions_by_randomx_mode: HashMap<RandomxMode, usize>,
+    pub submissions_total: u64,
+    pub submissions_accepted: u64,
+    pub submissions_rejected: u64,
+    pub submissions_stale: u64,
+    pub jobs_created: u64,
+    pub template_height: Option<u64>,
+    pub template_age_ms: Option<u64>,
+    pub difficulty: Option<u64>,
+    /// Accepted work divided by uptime, in hashes per second
+    pub estimated_hashrate: f64,
+    /// RandomX flags submissions are hashed with
+    pub randomx_flags: Vec<String>,
+    pub uptime_secs: u64,
+}
+
+impl CoordinatorStats {
+    pub fn collect(state: &AppState) -> Self {
+        let metrics = &state.metrics;
+        let (template_height, template_age_ms, difficulty) = {
+            let template = state.template_rx.borrow();
+            match template.as_ref() {
+                Some(t) => (
+                    Some(t.height),
+                    Some(t.created_at.elapsed().as_millis() as u64),
+                    Some(t.difficulty),
+                ),
+                None => (None, None, None),
+            }
+        };
+
+        let uptime = state.started_at.elapsed();
+        let accepted_difficulty = metrics.accepted_difficulty.load(Ordering::Relaxed);
+        let estimated_hashrate = if uptime.as_secs_f64() > 0.0 {
+            accepted_difficulty as f64 / uptime.as_secs_f64()
+        } else {
+            0.0
+        };
+
+        Self {
+            active_sessions: state.session_manager.active_count(),
+            sessions_by_state: state.session_manager.count_by_state(),
+            sessions_by_randomx_mode: state.session_manager.count_by_randomx_mode(),
+            submissions_total: metrics.submissions_total.load(Ordering::Relaxed),
+            submissions_accepted: metrics.submissions_accepted.load(Ordering::Relaxed),
+            submissions_rejected: metrics.submissions_rejected.load(Ordering::Relaxed),
+            submissions_stale: metrics.submissions_stale.load(Ordering::Relaxed),
+            jobs_created: metrics.jobs_created.load(Ordering::Relaxed),
+            template_height,
+            template_age_ms,
+            difficulty,
+            estimated_hashrate,
+            randomx_flags: state.pow.randomx_flags().into_iter().map(String::from).collect(),
+            uptime_secs: uptime.as_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob;
+    use crate::rpc::BlockTemplate;
+    use crate::server::{self, tests::test_state};
+    use crate::template::TemplateState;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_stats_endpoint() {
+        let (state, template_tx) = test_state();
+        state.session_manager.create_session("198.51.100.1".parse().unwrap()).unwrap();
+        state.metrics.inc_submissions();
+        state.metrics.inc_stale();
+        template_tx
+            .send(Some(TemplateState::from_rpc(
+                BlockTemplate {
+                    blockhashing_blob: String::new(),
+                    blocktemplate_blob: blob::fixture::TEMPLATE_BLOB.into(),
+                    difficulty: 5000,
+                    expected_reward: 0,
+                    height: 42,
+                    prev_hash: String::new(),
+                    reserved_offset: blob::fixture::RESERVED_OFFSET,
+                    seed_hash: String::new(),
+                    next_seed_hash: String::new(),
+                    status: "OK".into(),
+                },
+                1,
+                8,
+            )
+            .unwrap()))
+            .unwrap();
+
+        let response = server::router(state)
+            .oneshot(Request::builder().uri("/stats").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let raw: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(raw["active_sessions"], 1);
+
+        let stats: CoordinatorStats = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats.sessions_by_state.get(&SessionState::Connected), Some(&1));
+        assert_eq!(stats.submissions_total, 1);
+        assert_eq!(stats.submissions_stale, 1);
+        assert_eq!(stats.template_height, Some(42));
+        assert_eq!(stats.difficulty, Some(5000));
+        assert!(stats.template_age_ms.is_some());
+        assert!(!stats.randomx_flags.iter().any(|flag| flag == "large_pages"));
+    }
+}
@@ -2,10 +2,17 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::watch;
 use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
 use tracing::{info, warn, error};
 
+use crate::blob::BlockLayout;
 use crate::config::Config;
-use crate::rpc::{MonerodClient, BlockTemplate, RpcError};
+use crate::health::DaemonStatus;
+use crate::jobs::{check_template, difficulty_to_target, NONCE_OFFSET, NONCE_SIZE};
+use crate::metrics::Metrics;
+use crate::rpc::{MonerodClient, RpcError};
+
+pub use crate::rpc::BlockTemplate;
 
 #[derive(Clone, Debug)]
 pub struct TemplateState {
@@ -16,14 +23,35 @@ pub struct TemplateState {
     pub blockhashing_blob: String,
     pub difficulty: u64,
     pub reserved_offset: usize,
-    pub reserve_size: u8,
+    pub reserve_size: u32,
     pub seed_hash: String,
+    /// Seed of the next epoch when monerod gives one, else empty; see
+    /// `upcoming_seed`
+    pub next_seed_hash: String,
     pub created_at: Instant,
+    /// `blocktemplate_blob` decoded once, shared by every job made from it
+    pub blob: Arc<Vec<u8>>,
+    /// Where jobs find the header and miner transaction in `blob`
+    pub layout: Arc<BlockLayout>,
+    /// Hex of the hashing blob before and after the merkle root, the same
+    /// for every job; see `hashing_hex`
+    pub hashing_hex_parts: Arc<(String, String)>,
+    /// Target for `difficulty`; see `set_difficulty`
+    pub block_target: [u8; 32],
 }
 
 impl TemplateState {
-    pub fn from_rpc(template: BlockTemplate, template_id: u64, reserve_size: u8) -> Self {
-        Self {
+    /// Fails if `blocktemplate_blob` is not a block monerod would accept,
+    /// since no hashing blob can be derived from it, or if its reserved
+    /// bytes cannot give each job its own work
+    pub fn from_rpc(template: BlockTemplate, template_id: u64, reserve_size: u32) -> Result<Self, String> {
+        let blob = hex::decode(&template.blocktemplate_blob).map_err(|_| "block template blob is not hex".to_string())?;
+        let layout = Arc::new(BlockLayout::parse(&blob)?);
+        let block_target = difficulty_to_target(template.difficulty);
+        if !template.blockhashing_blob.is_empty() && hex::encode(layout.hashing_blob(&blob)) != template.blockhashing_blob {
+            return Err("derived hashing blob differs from monerod's blockhashing_blob".into());
+        }
+        let state = Self {
             template_id,
             height: template.height,
             prev_hash: template.prev_hash,
@@ -33,19 +61,77 @@ impl TemplateState {
             reserved_offset: template.reserved_offset,
             reserve_size,
             seed_hash: template.seed_hash,
+            next_seed_hash: template.next_seed_hash,
             created_at: Instant::now(),
+            hashing_hex_parts: Arc::new(layout.hashing_hex_parts(&blob)),
+            blob: Arc::new(blob),
+            layout,
+            block_target,
+        };
+        check_template(&state).map_err(|e| e.to_string())?;
+        Ok(state)
+    }
+
+    /// What jobs would otherwise be made wrong from, checked before the
+    /// template is published: reserved bytes inside the blob and clear of
+    /// the nonce, both hashes 32 bytes of hex and a difficulty to mine at
+    pub fn validate(&self) -> Result<(), String> {
+        let start = self.reserved_offset;
+        let end = start.saturating_add(self.reserve_size as usize);
+        if end > self.blob.len() {
+            return Err(format!("reserved bytes {}..{} run past the {}-byte blob", start, end, self.blob.len()));
+        }
+        if start < NONCE_OFFSET + NONCE_SIZE && NONCE_OFFSET < end {
+            return Err(format!("reserved bytes {}..{} overlap the nonce", start, end));
+        }
+        for (name, hash) in [("prev_hash", &self.prev_hash), ("seed_hash", &self.seed_hash)] {
+            if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(format!("{} {:?} is not 32 bytes of hex", name, hash));
+            }
+        }
+        if self.difficulty == 0 {
+            return Err("difficulty is 0".into());
         }
+        Ok(())
+    }
+
+    /// Hex of a job's hashing blob, given its merkle root; only the root is
+    /// encoded per job
+    pub fn hashing_hex(&self, root: &[u8; 32]) -> String {
+        let (header, count) = &*self.hashing_hex_parts;
+        let mut root_hex = [0u8; 64];
+        hex::encode_to_slice(root, &mut root_hex).expect("64 hex digits for 32 bytes");
+        let mut out = String::with_capacity(header.len() + root_hex.len() + count.len());
+        out.push_str(header);
+        out.push_str(std::str::from_utf8(&root_hex).expect("hex is ascii"));
+        out.push_str(count);
+        out
+    }
+
+    /// Seed the chain switches to within the next few blocks, if monerod
+    /// says so and it is usable, so its VMs can be made ahead
+    pub fn upcoming_seed(&self) -> Option<&str> {
+        let seed = self.next_seed_hash.as_str();
+        let usable = seed.len() == 64 && seed.bytes().all(|b| b.is_ascii_hexdigit());
+        (usable && seed != self.seed_hash).then_some(seed)
+    }
+
+    /// Change the block difficulty, and the target that goes with it
+    pub fn set_difficulty(&mut self, difficulty: u64) {
+        self.difficulty = difficulty;
+        self.block_target = difficulty_to_target(difficulty);
     }
 }
 
 pub struct TemplateManager {
     client: Arc<MonerodClient>,
     wallet_address: String,
-    reserve_size: u8,
+    reserve_size: u32,
     refresh_interval: Duration,
     sender: watch::Sender<Option<TemplateState>>,
     receiver: watch::Receiver<Option<TemplateState>>,
     template_counter: u64,
+    daemon_status: Arc<DaemonStatus>,
 }
 
 impl TemplateManager {
@@ -65,6 +151,7 @@ impl TemplateManager {
             sender,
             receiver,
             template_counter: 0,
+            daemon_status: Arc::new(DaemonStatus::new()),
         })
     }
 
@@ -76,28 +163,39 @@ impl TemplateManager {
         self.client.clone()
     }
 
-    pub async fn run(&mut self, metrics: Arc<crate::metrics::Metrics>) {
+    pub fn daemon_status(&self) -> Arc<DaemonStatus> {
+        self.daemon_status.clone()
+    }
+
+    pub async fn run(&mut self, metrics: Arc<crate::metrics::Metrics>, shutdown: CancellationToken) {
         info!("Template manager starting");
         
-        if let Err(e) = self.refresh_template().await {
+        if let Err(e) = self.refresh_template(&metrics).await {
             error!("Initial template fetch failed: {}", e);
-        } else {
-            metrics.inc_templates();
         }
 
         let mut ticker = interval(self.refresh_interval);
         let mut last_height: u64 = 0;
 
         loop {
-            ticker.tick().await;
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.cancelled() => {
+                    info!("Template manager stopping");
+                    return;
+                }
+            }
 
             match self.client.get_info().await {
                 Ok(info) => {
+                    self.daemon_status.mark_ok();
+                    // Until a template for the new height is published,
+                    // every tick tries again
                     if info.height != last_height {
                         info!("New block at height {}", info.height);
-                        last_height = info.height;
-                        if self.refresh_template().await.is_ok() {
-                            metrics.inc_templates();
+                        match self.refresh_template(&metrics).await {
+                            Ok(()) => last_height = info.height,
+                            Err(e) => warn!("Template refresh failed: {}", e),
                         }
                     }
                 }
@@ -108,21 +206,139 @@ impl TemplateManager {
         }
     }
 
-    async fn refresh_template(&mut self) -> Result<(), RpcError> {
+    async fn refresh_template(&mut self, metrics: &Metrics) -> Result<(), RpcError> {
         let template = self
             .client
             .get_block_template(&self.wallet_address, self.reserve_size)
             .await?;
+        self.daemon_status.mark_ok();
 
         self.template_counter += 1;
         let state = TemplateState::from_rpc(template, self.template_counter, self.reserve_size);
-        
+        self.publish(state, metrics)
+    }
+
+    /// Send `state` to every job maker, unless it is unusable; then it is
+    /// logged, counted and dropped, and the previous template stays current
+    fn publish(&self, state: Result<TemplateState, String>, metrics: &Metrics) -> Result<(), RpcError> {
+        let state = match state.and_then(|state| state.validate().map(|()| state)) {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Dropping unusable block template: {}", e);
+                metrics.inc_templates_rejected();
+                return Err(RpcError::InvalidResponse(format!("unusable block template: {}", e)));
+            }
+        };
+
         info!(
             "New template: id={}, height={}, difficulty={}",
             state.template_id, state.height, state.difficulty
         );
 
+        metrics.inc_templates();
         let _ = self.sender.send(Some(state));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob;
+
+    fn rpc_template(blockhashing_blob: &str) -> BlockTemplate {
+        BlockTemplate {
+            blockhashing_blob: blockhashing_blob.into(),
+            blocktemplate_blob: blob::fixture::TEMPLATE_BLOB.into(),
+            difficulty: 1000,
+            expected_reward: 0,
+            height: 1,
+            prev_hash: String::new(),
+            reserved_offset: blob::fixture::RESERVED_OFFSET,
+            seed_hash: String::new(),
+            next_seed_hash: String::new(),
+            status: "OK".into(),
+        }
+    }
+
+    #[test]
+    fn test_from_rpc_checks_monerods_hashing_blob() {
+        assert!(TemplateState::from_rpc(rpc_template(blob::fixture::HASHING_BLOB), 1, 8).is_ok());
+        assert!(TemplateState::from_rpc(rpc_template(""), 1, 8).is_ok());
+        assert!(TemplateState::from_rpc(rpc_template(blob::fixture::HASHING_BLOB_RESERVED), 1, 8).is_err());
+    }
+
+    #[test]
+    fn test_from_rpc_refuses_unusable_reserved_bytes() {
+        // No reserve to vary, or a reserve that would overwrite the block header
+        assert!(TemplateState::from_rpc(rpc_template(""), 1, 0).is_err());
+        let mut template = rpc_template("");
+        template.reserved_offset = 10;
+        assert!(TemplateState::from_rpc(template, 1, 8).is_err());
+        let mut template = rpc_template("");
+        template.blocktemplate_blob.replace_range(..2, "zz");
+        assert!(TemplateState::from_rpc(template, 1, 8).is_err());
+    }
+
+    fn valid_state() -> TemplateState {
+        let mut template = rpc_template("");
+        template.prev_hash = "11".repeat(32);
+        template.seed_hash = "44".repeat(32);
+        TemplateState::from_rpc(template, 1, 8).unwrap()
+    }
+
+    #[test]
+    fn test_validate_refuses_corrupt_geometry() {
+        assert_eq!(valid_state().validate(), Ok(()));
+
+        type Corrupt = fn(&mut TemplateState);
+        let corruptions: [(&str, Corrupt); 6] = [
+            ("past the", |s| s.reserved_offset = s.blob.len() - 4),
+            ("overlap the nonce", |s| s.reserved_offset = NONCE_OFFSET - 4),
+            ("overlap the nonce", |s| s.reserved_offset = NONCE_OFFSET + NONCE_SIZE - 1),
+            ("prev_hash", |s| s.prev_hash.truncate(62)),
+            ("seed_hash", |s| s.seed_hash.replace_range(..2, "zz")),
+            ("difficulty is 0", |s| s.set_difficulty(0)),
+        ];
+        for (expected, corrupt) in corruptions {
+            let mut state = valid_state();
+            corrupt(&mut state);
+            let err = state.validate().unwrap_err();
+            assert!(err.contains(expected), "{}: {}", expected, err);
+        }
+
+        // Ending right before the nonce is fine
+        let mut state = valid_state();
+        state.reserved_offset = NONCE_OFFSET - 8;
+        assert_eq!(state.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_upcoming_seed_only_when_it_changes() {
+        let mut state = valid_state();
+        assert_eq!(state.upcoming_seed(), None);
+        state.next_seed_hash = state.seed_hash.clone();
+        assert_eq!(state.upcoming_seed(), None);
+        state.next_seed_hash = "zz".repeat(32);
+        assert_eq!(state.upcoming_seed(), None);
+        state.next_seed_hash = "55".repeat(32);
+        assert_eq!(state.upcoming_seed(), Some("55".repeat(32).as_str()));
+    }
+
+    #[test]
+    fn test_unusable_template_keeps_the_previous_one() {
+        let (state, _template_tx) = crate::server::tests::test_state();
+        let manager = TemplateManager::new(&state.config).unwrap();
+        let metrics = Metrics::new();
+        assert!(manager.publish(Ok(valid_state()), &metrics).is_ok());
+
+        let mut corrupt = valid_state();
+        corrupt.template_id = 2;
+        corrupt.seed_hash.clear();
+        assert!(manager.publish(Ok(corrupt), &metrics).is_err());
+        assert!(manager.publish(Err("derived hashing blob differs".into()), &metrics).is_err());
+        assert_eq!(manager.subscribe().borrow().as_ref().unwrap().template_id, 1);
+        assert_eq!(metrics.templates_received.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(metrics.templates_rejected.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+}
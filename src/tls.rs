@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{info, warn};
+
+use crate::config::TlsConfig;
+
+/// Load the certificate chain and private key, failing with a readable error
+/// if either file is missing or does not contain valid PEM.
+pub async fn load_rustls_config(tls: &TlsConfig) -> Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load TLS certificate '{}' / key '{}'",
+                tls.cert_path, tls.key_path
+            )
+        })
+}
+
+/// Reload the certificate from disk whenever the process receives SIGHUP.
+/// A failed reload keeps serving the previous certificate.
+pub fn spawn_reload_on_sighup(rustls: RustlsConfig, tls: TlsConfig) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to install SIGHUP handler, TLS reload disabled: {}", e);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            match rustls.reload_from_pem_file(&tls.cert_path, &tls.key_path).await {
+                Ok(()) => info!("TLS certificate reloaded from {}", tls.cert_path),
+                Err(e) => warn!("TLS certificate reload failed, keeping previous: {}", e),
+            }
+        }
+    });
+
+    #[cfg(not(unix))]
+    let _ = (rustls, tls);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("coordinator-tls-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pem_is_a_clean_error() {
+        let cert = write_temp("bad-cert.pem", "not a certificate");
+        let key = write_temp("bad-key.pem", "not a key");
+        let tls = TlsConfig {
+            cert_path: cert.to_string_lossy().into_owned(),
+            key_path: key.to_string_lossy().into_owned(),
+        };
+
+        let err = load_rustls_config(&tls).await.unwrap_err();
+        assert!(err.to_string().contains("Failed to load TLS certificate"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_files_are_a_clean_error() {
+        let tls = TlsConfig {
+            cert_path: "/nonexistent/cert.pem".into(),
+            key_path: "/nonexistent/key.pem".into(),
+        };
+        assert!(load_rustls_config(&tls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serves_https_with_self_signed_cert() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = write_temp("cert.pem", &generated.cert.pem());
+        let key = write_temp("key.pem", &generated.key_pair.serialize_pem());
+        let tls = TlsConfig {
+            cert_path: cert.to_string_lossy().into_owned(),
+            key_path: key.to_string_lossy().into_owned(),
+        };
+
+        let rustls = load_rustls_config(&tls).await.unwrap();
+        let app = Router::new().route("/health", get(|| async { "OK" }));
+        let handle = axum_server::Handle::new();
+        let server_handle = handle.clone();
+        tokio::spawn(async move {
+            axum_server::bind_rustls("127.0.0.1:0".parse().unwrap(), rustls)
+                .handle(server_handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+        let addr = handle.listening().await.unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(generated.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("OK"));
+
+        handle.shutdown();
+    }
+}
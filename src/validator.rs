@@ -1,106 +1,1323 @@
-use randomx_rs::{RandomXCache, RandomXFlag, RandomXVM};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::digest;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::fmt;
 use std::sync::Arc;
-use parking_lot::RwLock;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{oneshot, watch};
+use tokio_util::sync::CancellationToken;
 
-use crate::jobs::Job;
+use crate::blob::read_varint;
+use crate::jobs::{HashClass, Job, NONCE_OFFSET, NONCE_SIZE};
+use crate::metrics::Metrics;
+use crate::pow::PowVerifier;
+use crate::protocol::{ErrorCode, SubmitStatus};
+use crate::template::TemplateState;
 use crate::error::CoordinatorError;
 
+/// Why a submission could not be hashed
+#[derive(Debug, Error)]
+pub enum HashError {
+    /// RandomX's cache, dataset or a VM could not be allocated or
+    /// initialized, which steps verification down the ladder
+    #[error("RandomX unavailable: {0}")]
+    Init(CoordinatorError),
+    /// This hash could not be had for now, though another may; not the
+    /// miner's doing
+    #[error("Hash verification unavailable: {0}")]
+    Unavailable(CoordinatorError),
+    /// This hash went wrong, as with a seed that is not a RandomX key
+    #[error("{0}")]
+    Failed(CoordinatorError),
+    /// Nothing can hash until RandomX is retried, in about `retry_after`
+    #[error("Hash verification down, retrying RandomX in {retry_after:?}")]
+    Down { retry_after: Duration },
+}
+
+/// Rungs of the ladder hashing steps down as RandomX's memory runs short,
+/// highest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifierRung {
+    /// The verifier, mode and flags configured
+    Configured,
+    /// RandomX in light mode without large pages
+    Light,
+    /// monerod's calc_pow, no RandomX cache having been made
+    Rpc,
+    /// Submits refused while RandomX is retried
+    Down,
+}
+
+impl VerifierRung {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerifierRung::Configured => "configured",
+            VerifierRung::Light => "light",
+            VerifierRung::Rpc => "rpc",
+            VerifierRung::Down => "down",
+        }
+    }
+}
+
+/// First wait before RandomX is retried below the configured rung, doubled
+/// after each miss up to [`MAX_RETRY`]
+const MIN_RETRY: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(300);
+
+/// Where a verification's time went
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashTimings {
+    /// Waiting for a place in the verification queue, then for a VM
+    pub queue: Duration,
+    /// Making the seed's cache, or a VM or moving one onto it; zero when
+    /// both were ready
+    pub init: Duration,
+    /// The hash itself, by a VM or by monerod
+    pub hash: Duration,
+    /// Whether monerod hashed it
+    pub rpc: bool,
+    /// From asking to hashed, more than the rest by the handoffs between
+    pub total: Duration,
+}
+
+impl fmt::Display for HashTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} (queued {:?}, init {:?}, hash {:?}", self.total, self.queue, self.init, self.hash)?;
+        f.write_str(if self.rpc { " over RPC)" } else { ")" })
+    }
+}
+
+/// Hashes running or waiting, past which shares are refused, by default
+const DEFAULT_QUEUE_SIZE: usize = 64;
+
+/// Why a submission for a job was refused: its nonce, how its rebuilt block
+/// differs from the job's, or how its hash fell short
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ValidationError {
+    /// The nonce could not be written into the job's blob
+    #[error("{0}")]
+    BadNonce(String),
+    #[error("Blob is {actual} bytes, the job's block is {expected}")]
+    Length { expected: usize, actual: usize },
+    #[error("Reserved value mismatch")]
+    ReservedMismatch,
+    #[error("Nonce mismatch")]
+    NonceMismatch,
+    /// A byte outside the reserved value and nonce
+    #[error("Blob differs from the job's block at byte {offset}")]
+    BlobMismatch { offset: usize },
+    /// A header field unreadable, at odds with the job's template or, for
+    /// the timestamp, too far from the clock
+    #[error("Block header {field}: {problem}")]
+    Header { field: &'static str, problem: String },
+    /// The claimed result hash is not the hash of the nonce
+    #[error("Result hash is not the hash of the nonce")]
+    BadPow,
+    /// The hash misses the share target of a job asking for shares
+    #[error("Hash does not meet the share target")]
+    BelowShareTarget,
+    /// The hash misses the target of a job asking for blocks only
+    #[error("Hash does not meet the block target")]
+    BelowBlockTarget,
+    /// Neither a VM nor monerod could hash it; the reason is for the log
+    #[error("Hash verification unavailable")]
+    VmUnavailable(String),
+    #[error("{0}")]
+    HashFailed(String),
+    /// Nothing can hash for now; send the submit again after `retry_after_ms`
+    #[error("Hash verification down, retrying")]
+    VerifierDown { retry_after_ms: u64 },
+}
+
+impl ValidationError {
+    /// The variant's name, labelling the rejection counter
+    pub fn label(&self) -> &'static str {
+        match self {
+            ValidationError::BadNonce(_) => "bad_nonce",
+            ValidationError::Length { .. } => "length",
+            ValidationError::ReservedMismatch => "reserved_mismatch",
+            ValidationError::NonceMismatch => "nonce_mismatch",
+            ValidationError::BlobMismatch { .. } => "blob_mismatch",
+            ValidationError::Header { .. } => "header",
+            ValidationError::BadPow => "bad_pow",
+            ValidationError::BelowShareTarget => "below_share_target",
+            ValidationError::BelowBlockTarget => "below_block_target",
+            ValidationError::VmUnavailable(_) => "vm_unavailable",
+            ValidationError::HashFailed(_) => "hash_failed",
+            ValidationError::VerifierDown { .. } => "verifier_down",
+        }
+    }
+
+    /// Protocol error code for a submission failing this way
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ValidationError::BadNonce(_) | ValidationError::Length { .. } => ErrorCode::BadFormat,
+            ValidationError::Header { .. } => ErrorCode::BadJob,
+            ValidationError::BadPow => ErrorCode::BadPow,
+            ValidationError::VmUnavailable(_) | ValidationError::HashFailed(_) => ErrorCode::InternalError,
+            ValidationError::VerifierDown { .. } => ErrorCode::NotReady,
+            _ => ErrorCode::InvalidData,
+        }
+    }
+
+    /// How a submission failing this way is answered: a `submit_result`
+    /// with this status, or if None an error with [`Self::code`]
+    pub fn status(&self) -> Option<SubmitStatus> {
+        match self {
+            ValidationError::Header { .. } | ValidationError::BadPow | ValidationError::VerifierDown { .. } => None,
+            _ => Some(SubmitStatus::Rejected),
+        }
+    }
+
+    /// Whether failing this way counts towards a ban: the miner's doing,
+    /// not the template's or the coordinator's
+    pub fn is_offense(&self) -> bool {
+        !matches!(
+            self,
+            ValidationError::Header { .. }
+                | ValidationError::VmUnavailable(_)
+                | ValidationError::HashFailed(_)
+                | ValidationError::VerifierDown { .. }
+        )
+    }
+}
+
+impl From<HashError> for ValidationError {
+    fn from(e: HashError) -> Self {
+        match e {
+            HashError::Init(e) | HashError::Unavailable(e) => ValidationError::VmUnavailable(e.to_string()),
+            HashError::Failed(e) => ValidationError::HashFailed(e.to_string()),
+            HashError::Down { retry_after } => ValidationError::VerifierDown { retry_after_ms: retry_after.as_millis() as u64 },
+        }
+    }
+}
+
+/// What a valid submission's hash makes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationOutcome {
+    /// Meets the job's share target only
+    Share,
+    /// Meets the block target
+    Block,
+}
+
+/// A submission that passed validation, with what crediting or submitting
+/// it takes
+#[derive(Debug)]
+pub struct ValidSubmission {
+    pub outcome: ValidationOutcome,
+    pub hash: [u8; 32],
+    /// The job's block with the nonce written in, for monerod
+    pub block: Vec<u8>,
+    /// Where verifying took its time; None for a share credited on its
+    /// claimed hash
+    pub timings: Option<HashTimings>,
+}
+
+/// The fields leading a Monero block, and its hashing blob
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub major_version: u64,
+    pub minor_version: u64,
+    /// Unix seconds
+    pub timestamp: u64,
+    pub prev_id: [u8; 32],
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// Read the header at the start of `blob`; the error names the field
+    /// the blob ends in or garbles
+    pub fn parse(blob: &[u8]) -> Result<Self, ValidationError> {
+        let mut pos = 0;
+        let varint = |pos: &mut usize, field| {
+            read_varint(blob, pos).map_err(|problem| ValidationError::Header { field, problem })
+        };
+        let major_version = varint(&mut pos, "major_version")?;
+        let minor_version = varint(&mut pos, "minor_version")?;
+        let timestamp = varint(&mut pos, "timestamp")?;
+        let prev_id = header_bytes(blob, &mut pos, "prev_id")?;
+        let nonce = u32::from_le_bytes(header_bytes(blob, &mut pos, "nonce")?);
+        Ok(Self { major_version, minor_version, timestamp, prev_id, nonce })
+    }
+}
+
+fn header_bytes<const N: usize>(blob: &[u8], pos: &mut usize, field: &'static str) -> Result<[u8; N], ValidationError> {
+    let bytes = blob
+        .get(*pos..*pos + N)
+        .ok_or_else(|| ValidationError::Header { field, problem: "blob ends early".into() })?;
+    *pos += N;
+    Ok(bytes.try_into().expect("N bytes"))
+}
+
+/// Picks which shares claiming a result hash are hashed again
+pub struct ShareSampler {
+    percent: u8,
+    rng: parking_lot::Mutex<StdRng>,
+}
+
+impl ShareSampler {
+    /// Hash `percent` of claimed shares, chosen at random
+    pub fn new(percent: u8) -> Self {
+        Self::seeded(percent, rand::random())
+    }
+
+    pub fn seeded(percent: u8, seed: u64) -> Self {
+        Self { percent: percent.min(100), rng: parking_lot::Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+
+    /// Whether to hash a submission whose claimed hash makes it a block, or
+    /// not; claimed blocks always are
+    pub fn should_verify(&self, claims_block: bool) -> bool {
+        claims_block || self.percent == 100 || (self.percent > 0 && self.rng.lock().gen_range(0..100) < self.percent)
+    }
+}
+
+/// Hashes kept in the result cache
+const RESULT_CACHE_SIZE: usize = 4096;
+
+/// Hashes already computed, keyed by a digest of the seed and blob hashed,
+/// so a seed change can never return a hash made with another seed. The
+/// least recently used is dropped once full.
+struct ResultCache {
+    capacity: usize,
+    entries: parking_lot::Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    /// Bumped on every use, ordering entries by their last
+    tick: u64,
+    hashes: HashMap<[u8; 32], ([u8; 32], u64)>,
+    by_use: BTreeMap<u64, [u8; 32]>,
+}
+
+impl ResultCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, entries: parking_lot::Mutex::new(CacheEntries::default()) }
+    }
+
+    fn key(seed_hash: &str, blob: &[u8]) -> [u8; 32] {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&(seed_hash.len() as u64).to_le_bytes());
+        context.update(seed_hash.as_bytes());
+        context.update(blob);
+        let mut key = [0; 32];
+        key.copy_from_slice(context.finish().as_ref());
+        key
+    }
+
+    fn get(&self, key: &[u8; 32]) -> Option<[u8; 32]> {
+        let mut entries = self.entries.lock();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let (hash, used) = entries.hashes.get_mut(key)?;
+        let (hash, last) = (*hash, std::mem::replace(used, tick));
+        entries.by_use.remove(&last);
+        entries.by_use.insert(tick, *key);
+        Some(hash)
+    }
+
+    fn insert(&self, key: [u8; 32], hash: [u8; 32]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        entries.tick += 1;
+        let tick = entries.tick;
+        if let Some((_, last)) = entries.hashes.insert(key, (hash, tick)) {
+            entries.by_use.remove(&last);
+        } else if entries.hashes.len() > self.capacity {
+            if let Some((_, oldest)) = entries.by_use.pop_first() {
+                entries.hashes.remove(&oldest);
+            }
+        }
+        entries.by_use.insert(tick, key);
+    }
+}
+
+/// Hashes running on the blocking pool, one per VM, and those waiting for
+/// a VM, claimed blocks ahead of shares. Bounding it keeps a submit flood
+/// from piling up blocking tasks; shares past `capacity` are refused.
+struct HashQueue {
+    capacity: usize,
+    metrics: Option<Arc<Metrics>>,
+    state: parking_lot::Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    running: usize,
+    /// Keyed by (not a claimed block, arrival), so blocks come first
+    waiting: BTreeMap<(bool, u64), oneshot::Sender<()>>,
+    arrivals: u64,
+}
+
+impl HashQueue {
+    fn new(capacity: usize, metrics: Option<Arc<Metrics>>) -> Self {
+        Self { capacity, metrics, state: parking_lot::Mutex::new(QueueState::default()) }
+    }
+
+    /// Take a place for a hash, running now if one of `runners` is free
+    fn admit(self: &Arc<Self>, runners: usize, claims_block: bool) -> Option<QueueTicket> {
+        let mut state = self.state.lock();
+        let depth = state.running + state.waiting.len();
+        if depth >= self.capacity && !claims_block {
+            if let Some(metrics) = &self.metrics {
+                metrics.inc_verify_queue_full();
+            }
+            return None;
+        }
+        let waiting = if state.running < runners {
+            state.running += 1;
+            None
+        } else {
+            state.arrivals += 1;
+            let key = (!claims_block, state.arrivals);
+            let (tx, rx) = oneshot::channel();
+            state.waiting.insert(key, tx);
+            Some((key, rx))
+        };
+        self.set_depth(&state);
+        Some(QueueTicket { queue: Arc::clone(self), waiting })
+    }
+
+    fn set_depth(&self, state: &QueueState) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_verify_queue_depth(state.running + state.waiting.len());
+        }
+    }
+}
+
+/// A submission's place in the verification queue, given up when dropped
+pub struct QueueTicket {
+    queue: Arc<HashQueue>,
+    /// Set until a VM is handed over
+    waiting: Option<((bool, u64), oneshot::Receiver<()>)>,
+}
+
+impl QueueTicket {
+    /// Wait for a VM to be handed over, if none was free on admission
+    async fn ready(&mut self) {
+        if let Some((_, handed_over)) = &mut self.waiting {
+            let _ = handed_over.await;
+            self.waiting = None;
+        }
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock();
+        // Still waiting: leave the queue. Otherwise, even if handed a VM just
+        // as the wait was given up, pass it on to the next waiting
+        let left = self.waiting.as_ref().is_some_and(|(key, _)| state.waiting.remove(key).is_some());
+        if !left {
+            match state.waiting.pop_first() {
+                Some((_, next)) => {
+                    // A receiver already gone is dropping its ticket too,
+                    // and passes the VM on in turn
+                    let _ = next.send(());
+                }
+                None => state.running -= 1,
+            }
+        }
+        self.queue.set_depth(&state);
+    }
+}
+
+/// The checks, result cache, verification queue and stepping down around
+/// whichever [`PowVerifier`] hashes submissions, which each call is handed
 pub struct SubmissionValidator {
-    min_blob_len: usize,
-    vm: Arc<RwLock<Option<RandomXVM>>>,
-    current_seed_hash: Arc<RwLock<String>>,
+    /// Hashes once the verifier cannot make RandomX's memory, as monerod's
+    /// calc_pow does for the local one
+    fallback: Option<Arc<dyn PowVerifier>>,
+    /// Set once the verifier could not make RandomX's memory, after which
+    /// hashes go to the fallback, or are refused without one, until RandomX
+    /// is retried
+    local_failed: AtomicBool,
+    /// Until RandomX is next retried, below the configured rung
+    retry_after_ms: AtomicU64,
+    shares: ShareSampler,
+    results: ResultCache,
+    queue: Arc<HashQueue>,
+    metrics: Option<Arc<Metrics>>,
+    /// How far a block's timestamp may be from the clock; unchecked if None
+    timestamp_skew: Option<Duration>,
+    /// Submissions that reached validation
+    validations: AtomicU64,
 }
 
-// Safety: RandomXVM is protected by RwLock, so concurrent access is properly synchronized.
-// The RwLock ensures that only one thread can mutate at a time, and multiple threads can read safely.
-unsafe impl Send for SubmissionValidator {}
-unsafe impl Sync for SubmissionValidator {}
+impl Default for SubmissionValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl SubmissionValidator {
     pub fn new() -> Self {
         Self {
-            min_blob_len: 76,
-            vm: Arc::new(RwLock::new(None)),
-            current_seed_hash: Arc::new(RwLock::new(String::new())),
+            fallback: None,
+            local_failed: AtomicBool::new(false),
+            retry_after_ms: AtomicU64::new(MIN_RETRY.as_millis() as u64),
+            shares: ShareSampler::new(100),
+            results: ResultCache::new(RESULT_CACHE_SIZE),
+            queue: Arc::new(HashQueue::new(DEFAULT_QUEUE_SIZE, None)),
+            metrics: None,
+            timestamp_skew: None,
+            validations: AtomicU64::new(0),
         }
     }
 
-    /// Initialize or reinitialize the RandomX VM with a new seed hash
-    pub fn init_vm(&self, seed_hash: &str) -> Result<(), CoordinatorError> {
-        let mut current = self.current_seed_hash.write();
-        if *current == seed_hash {
-            return Ok(()); // Already initialized with this seed
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.queue = Arc::new(HashQueue::new(self.queue.capacity, Some(metrics.clone())));
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Refuse blocks timestamped more than `skew` before or after the
+    /// clock; zero leaves timestamps unchecked
+    pub fn with_timestamp_skew(mut self, skew: Duration) -> Self {
+        self.timestamp_skew = (!skew.is_zero()).then_some(skew);
+        self
+    }
+
+    /// Hash with `fallback` once the verifier cannot make RandomX's memory
+    pub fn with_fallback(mut self, fallback: Arc<dyn PowVerifier>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Let at most `size` hashes run or wait for a VM
+    pub fn with_queue(mut self, size: usize) -> Self {
+        self.queue = Arc::new(HashQueue::new(size, self.metrics.clone()));
+        self
+    }
+
+    /// Take only some claimed shares' hashes on trust
+    pub fn with_share_sampling(mut self, shares: ShareSampler) -> Self {
+        self.shares = shares;
+        self
+    }
+
+    /// Whether to hash a submission claiming a result hash, rather than
+    /// credit it on the claim; see [`ShareSampler::should_verify`]
+    pub fn should_verify(&self, claims_block: bool) -> bool {
+        self.shares.should_verify(claims_block)
+    }
+
+    /// The rung of the ladder hashes with `verifier` are on
+    pub fn rung(&self, verifier: &dyn PowVerifier) -> VerifierRung {
+        if self.local_failed.load(Ordering::Relaxed) {
+            if self.fallback.is_some() { VerifierRung::Rpc } else { VerifierRung::Down }
+        } else {
+            verifier.rung()
         }
+    }
 
-        let seed_bytes = hex::decode(seed_hash)
-            .map_err(|_| CoordinatorError::Validation("Invalid seed hash hex".into()))?;
+    /// Submissions validated so far
+    pub fn validations(&self) -> u64 {
+        self.validations.load(Ordering::Relaxed)
+    }
 
-        let flags = RandomXFlag::get_recommended_flags();
-        let cache = RandomXCache::new(flags, &seed_bytes)
-            .map_err(|e| CoordinatorError::Validation(format!("RandomX cache init failed: {}", e)))?;
-        
-        let vm = RandomXVM::new(flags, Some(cache), None)
-            .map_err(|e| CoordinatorError::Validation(format!("RandomX VM init failed: {}", e)))?;
+    /// Until RandomX is next retried, while hashes are refused
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_millis(self.retry_after_ms.load(Ordering::Relaxed))
+    }
 
-        let mut vm_lock = self.vm.write();
-        *vm_lock = Some(vm);
-        *current = seed_hash.to_string();
+    /// What hashes now: the fallback once `verifier` failed, if there is one
+    fn hasher<'a>(&'a self, verifier: &'a dyn PowVerifier) -> Option<&'a dyn PowVerifier> {
+        if !self.local_failed.load(Ordering::Relaxed) {
+            return Some(verifier);
+        }
+        self.fallback.as_deref()
+    }
 
-        tracing::info!("RandomX VM initialized with seed: {}", seed_hash);
-        Ok(())
+    /// Take a place in the verification queue for a hash with `verifier`,
+    /// ahead of shares if it claims a block; None if the queue is full
+    pub fn enqueue(&self, verifier: &dyn PowVerifier, claims_block: bool) -> Option<QueueTicket> {
+        let runners = self.hasher(verifier).map_or(1, |hasher| hasher.concurrency());
+        self.queue.admit(runners, claims_block)
     }
 
-    pub fn validate_submission(&self, blob: &[u8], job: &Job) -> Result<(), CoordinatorError> {
-        if blob.len() < self.min_blob_len {
-            return Err(CoordinatorError::Validation("Blob too short".into()));
+    /// Hash `blob`, the hashing blob of a block at `height`, for `seed_hash`
+    /// with `verifier` once `ticket` comes up, and say where the time went.
+    /// A seed and blob hashed before is answered from the result cache
+    /// without waiting. The verifier gives way to the fallback, if there is
+    /// one, once RandomX's memory cannot be had, and without one hashes are
+    /// refused until RandomX is retried.
+    pub async fn verify(
+        &self,
+        verifier: &dyn PowVerifier,
+        seed_hash: &str,
+        height: u64,
+        blob: Vec<u8>,
+        mut ticket: QueueTicket,
+    ) -> Result<([u8; 32], HashTimings), HashError> {
+        let started = Instant::now();
+        let mut timings = HashTimings::default();
+        let key = ResultCache::key(seed_hash, &blob);
+        let cached = self.results.get(&key);
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_randomx_result_cache(cached.is_some());
+        }
+        if let Some(hash) = cached {
+            timings.total = started.elapsed();
+            return Ok((hash, timings));
         }
 
-        // Verify reserved region still matches
-        let offset = job.reserved_offset;
-        let reserved = &job.reserved_value;
-        
-        if offset + reserved.len() > blob.len() {
-            return Err(CoordinatorError::Validation("Invalid blob structure".into()));
+        if self.rung(verifier) == VerifierRung::Down {
+            return Err(HashError::Down { retry_after: self.retry_after() });
         }
 
-        for (i, expected) in reserved.iter().enumerate() {
-            if blob[offset + i] != *expected {
-                return Err(CoordinatorError::Validation("Reserved value mismatch".into()));
+        // The ticket is held until the hash is done, so the next hash does
+        // not start before this one frees its VM
+        ticket.ready().await;
+        timings.queue = started.elapsed();
+        let hash = match &self.fallback {
+            Some(fallback) if self.local_failed.load(Ordering::Relaxed) => {
+                self.count_rpc_fallback();
+                fallback.verify(&blob, seed_hash, height, &mut timings).await
             }
+            fallback => match (verifier.verify(&blob, seed_hash, height, &mut timings).await, fallback) {
+                // Only RandomX failing to get its memory steps down; a hash
+                // failing for its own reasons is refused on its own
+                (Err(HashError::Init(e)), Some(fallback)) => {
+                    self.step_down_from_local(verifier, &e);
+                    self.count_rpc_fallback();
+                    fallback.verify(&blob, seed_hash, height, &mut timings).await
+                }
+                (Err(HashError::Init(e)), None) => {
+                    self.step_down_from_local(verifier, &e);
+                    Err(HashError::Init(e))
+                }
+                (hash, _) => hash,
+            },
+        }?;
+        drop(ticket);
+        self.results.insert(key, hash);
+        timings.total = started.elapsed();
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_verification(&timings);
         }
+        Ok((hash, timings))
+    }
 
-        Ok(())
+    /// Step down to the fallback, or to refusing hashes without it, the
+    /// first time the verifier cannot make RandomX's memory. Hashes do so
+    /// themselves; startup does when the self-test finds no memory.
+    pub fn step_down_from_local(&self, verifier: &dyn PowVerifier, e: &CoordinatorError) {
+        if self.local_failed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let rung = self.rung(verifier);
+        match rung {
+            VerifierRung::Rpc => tracing::warn!("RandomX unavailable ({}); hashing over monerod's calc_pow until it can be made again", e),
+            _ => tracing::warn!("RandomX unavailable ({}) and no RPC verifier; refusing submits until it can be made again", e),
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_verifier_steps(rung);
+        }
+    }
+
+    /// Try `verifier`'s RandomX memory again from below the configured
+    /// rung, and climb back as far as it allows. The rung then.
+    pub async fn retry_local(&self, verifier: &dyn PowVerifier) -> VerifierRung {
+        let from = self.rung(verifier);
+        if from == VerifierRung::Configured {
+            return from;
+        }
+        match verifier.recover().await {
+            Ok(()) => {
+                self.local_failed.store(false, Ordering::Relaxed);
+                let to = self.rung(verifier);
+                if to < from {
+                    tracing::info!("RandomX can be made again; verification back from {} to {}", from.as_str(), to.as_str());
+                    if let Some(metrics) = &self.metrics {
+                        metrics.inc_verifier_recoveries(to);
+                    }
+                }
+                to
+            }
+            Err(e) => {
+                tracing::debug!("RandomX still unavailable, staying {}: {}", from.as_str(), e);
+                from
+            }
+        }
+    }
+
+    fn count_rpc_fallback(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_randomx_rpc_fallbacks();
+        }
+    }
+
+    /// Check `verifier`'s RandomX against its test vector. None when hashes
+    /// go to the fallback, or the verifier runs no RandomX of its own to
+    /// test.
+    pub async fn self_test(&self, verifier: &dyn PowVerifier) -> Result<Option<Duration>, HashError> {
+        if self.local_failed.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        verifier.self_test().await
     }
 
-    /// Compute RandomX hash of the blob
-    pub fn compute_hash(&self, blob: &[u8]) -> Result<[u8; 32], CoordinatorError> {
-        let vm_lock = self.vm.read();
-        let vm = vm_lock.as_ref()
-            .ok_or_else(|| CoordinatorError::Validation("RandomX VM not initialized".into()))?;
+    /// Have `verifier` get ready for `seed_hash` ahead of the chain
+    /// switching to it. Hashes for the current seed carry on meanwhile.
+    pub async fn prewarm(&self, verifier: &dyn PowVerifier, seed_hash: &str) {
+        if self.local_failed.load(Ordering::Relaxed) {
+            return;
+        }
+        verifier.prewarm(seed_hash).await;
+    }
 
-        let hash = vm.calculate_hash(blob)
-            .map_err(|e| CoordinatorError::Validation(format!("Hash computation failed: {}", e)))?;
+    /// Check that `blob`, a block rebuilt for `job` with `nonce`, is the
+    /// job's block with only its reserved value and the nonce written in,
+    /// and that its header is sane
+    pub fn validate_submission(&self, blob: &[u8], job: &Job, nonce: u32) -> Result<(), ValidationError> {
+        self.validations.fetch_add(1, Ordering::Relaxed);
+        let template = &job.template_blob[..];
+        if blob.len() != template.len() {
+            return Err(ValidationError::Length { expected: template.len(), actual: blob.len() });
+        }
+        self.check_header(blob, job)?;
 
-        if hash.len() != 32 {
-            return Err(CoordinatorError::Validation(
-                format!("Unexpected hash length: expected 32, got {}", hash.len())
-            ));
+        // Reserved values running past the blob are clipped, as when written
+        let reserved_start = job.reserved_offset.min(blob.len());
+        let reserved_end = job.reserved_offset.saturating_add(job.reserved_value.len()).min(blob.len());
+        if blob[reserved_start..reserved_end] != job.reserved_value[..reserved_end - reserved_start] {
+            return Err(ValidationError::ReservedMismatch);
+        }
+        if blob.get(NONCE_OFFSET..NONCE_OFFSET + NONCE_SIZE) != Some(&nonce.to_le_bytes()[..]) {
+            return Err(ValidationError::NonceMismatch);
         }
 
-        let mut result = [0u8; 32];
-        result.copy_from_slice(&hash);
-        Ok(result)
+        let mut written = [(NONCE_OFFSET, NONCE_OFFSET + NONCE_SIZE), (reserved_start, reserved_end)];
+        written.sort_unstable();
+        let mut from = 0;
+        for (start, end) in written.into_iter().chain([(blob.len(), blob.len())]) {
+            let start = start.max(from);
+            if let Some(i) = blob[from..start].iter().zip(&template[from..start]).position(|(a, b)| a != b) {
+                return Err(ValidationError::BlobMismatch { offset: from + i });
+            }
+            from = from.max(end);
+        }
+        Ok(())
+    }
+
+    /// The header fields a block is judged by before its bytes: the major
+    /// version and previous block the template has, and a timestamp near
+    /// the clock
+    fn check_header(&self, blob: &[u8], job: &Job) -> Result<(), ValidationError> {
+        let header = BlockHeader::parse(blob)?;
+        let expected = BlockHeader::parse(&job.template_blob)?;
+        if header.major_version != expected.major_version {
+            return Err(ValidationError::Header {
+                field: "major_version",
+                problem: format!("{} where the template has {}", header.major_version, expected.major_version),
+            });
+        }
+        let prev_id = hex::encode(header.prev_id);
+        if !prev_id.eq_ignore_ascii_case(&job.prev_hash) {
+            return Err(ValidationError::Header {
+                field: "prev_id",
+                problem: format!("{} where the template builds on {}", prev_id, job.prev_hash),
+            });
+        }
+        if let Some(skew) = self.timestamp_skew {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            if header.timestamp.abs_diff(now) > skew.as_secs() {
+                return Err(ValidationError::Header {
+                    field: "timestamp",
+                    problem: format!("{} is more than {}s from the clock ({})", header.timestamp, skew.as_secs(), now),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check `nonce` for `job` end to end: rebuild its block and check it
+    /// with [`Self::validate_submission`], hash it with `verifier` once
+    /// `ticket` comes up, hold the hash against any `claimed` one, and class
+    /// it by the job's targets. Without a ticket the claimed hash is taken unhashed, for a
+    /// share the sampler lets through.
+    pub async fn validate_and_verify(
+        &self,
+        verifier: &dyn PowVerifier,
+        job: &Job,
+        nonce: &str,
+        claimed: Option<[u8; 32]>,
+        ticket: Option<QueueTicket>,
+    ) -> Result<ValidSubmission, ValidationError> {
+        let blob = job.apply_nonce(nonce).map_err(ValidationError::BadNonce)?;
+        let block = job.block_blob(nonce).map_err(ValidationError::BadNonce)?;
+        // apply_nonce took the nonce, so it parses
+        let mut nonce_bytes = [0u8; 4];
+        hex::decode_to_slice(nonce, &mut nonce_bytes).map_err(|_| ValidationError::BadNonce("Invalid nonce hex".into()))?;
+        self.validate_submission(&block, job, u32::from_le_bytes(nonce_bytes))?;
+
+        let (hash, timings) = match ticket {
+            Some(ticket) => {
+                let (hash, timings) = self.verify(verifier, &job.seed_hash, job.height, blob, ticket).await?;
+                (hash, Some(timings))
+            }
+            None => (claimed.expect("only claimed hashes go unverified"), None),
+        };
+        if claimed.is_some_and(|claimed| claimed != hash) {
+            return Err(ValidationError::BadPow);
+        }
+        let outcome = match job.classify(&hash) {
+            HashClass::BelowTarget if job.share_difficulty < job.difficulty => return Err(ValidationError::BelowShareTarget),
+            HashClass::BelowTarget => return Err(ValidationError::BelowBlockTarget),
+            HashClass::Share => ValidationOutcome::Share,
+            HashClass::Block => ValidationOutcome::Block,
+        };
+        Ok(ValidSubmission { outcome, hash, block, timings })
     }
 
     pub fn check_meets_target(&self, hash: &[u8; 32], target: &[u8; 32]) -> bool {
-        for i in (0..32).rev() {
-            if hash[i] < target[i] {
-                return true;
+        crate::jobs::meets_target(hash, target)
+    }
+}
+
+/// While hashes are below the configured rung, retry RandomX on a backoff,
+/// doubling the wait after each retry. The wait starts over only once the
+/// configured rung holds, so a dataset that fails again after a climb is
+/// not rebuilt every few seconds.
+pub async fn recover_verifier(validator: Arc<SubmissionValidator>, verifier: Arc<dyn PowVerifier>, shutdown: CancellationToken) {
+    let mut wait = MIN_RETRY;
+    loop {
+        validator.retry_after_ms.store(wait.as_millis() as u64, Ordering::Relaxed);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.cancelled() => return,
+        }
+        if validator.rung(verifier.as_ref()) == VerifierRung::Configured {
+            wait = MIN_RETRY;
+            continue;
+        }
+        validator.retry_local(verifier.as_ref()).await;
+        wait = (wait * 2).min(MAX_RETRY);
+    }
+}
+
+/// Have `verifier` prewarm each upcoming seed the templates announce, so
+/// the first submission after a seed change does not wait for its cache
+pub async fn prewarm_seeds(
+    validator: Arc<SubmissionValidator>,
+    verifier: Arc<dyn PowVerifier>,
+    mut templates: watch::Receiver<Option<TemplateState>>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let upcoming = templates
+            .borrow_and_update()
+            .as_ref()
+            .and_then(|t| t.upcoming_seed().map(str::to_string));
+        if let Some(seed_hash) = upcoming {
+            validator.prewarm(verifier.as_ref(), &seed_hash).await;
+        }
+        tokio::select! {
+            changed = templates.changed() => {
+                if changed.is_err() {
+                    return;
+                }
             }
-            if hash[i] > target[i] {
-                return false;
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RandomXFlags;
+    use crate::jobs::JobManager;
+    use crate::pow::FakeVerifier;
+    use crate::rpc::MonerodClient;
+    use crate::rpc_verifier::RpcVerifier;
+    use crate::server::tests::test_template;
+    use crate::vm_pool::{LocalVerifier, VmPool};
+    use randomx_rs::RandomXFlag;
+
+    const NONCE: u32 = 0x0403_0201;
+
+    fn job_and_block() -> (Job, Vec<u8>) {
+        let job = JobManager::new(10_000).create_job(&test_template(), "s", 1).unwrap();
+        let block = job.block_blob(&hex::encode(NONCE.to_le_bytes())).unwrap();
+        (job, block)
+    }
+
+    /// RandomX's VMs in `vms`, as the server hashes with them
+    fn local(vms: VmPool) -> Arc<dyn PowVerifier> {
+        Arc::new(LocalVerifier::new(vms))
+    }
+
+    /// monerod's calc_pow at `url`, one call at a time and up to
+    /// `per_second` a second
+    fn rpc(url: String, per_second: u32, metrics: &Arc<Metrics>) -> Arc<dyn PowVerifier> {
+        let client = Arc::new(MonerodClient::new(url, 1_000).unwrap());
+        Arc::new(RpcVerifier::new(client, 1, per_second).with_metrics(metrics.clone()))
+    }
+
+    /// Hash with `pow` and a share's place in the queue
+    async fn verify(validator: &SubmissionValidator, pow: &dyn PowVerifier, seed_hash: &str, blob: Vec<u8>) -> Result<[u8; 32], HashError> {
+        let ticket = validator.enqueue(pow, false).expect("queue full");
+        validator.verify(pow, seed_hash, 1, blob, ticket).await.map(|(hash, _)| hash)
+    }
+
+    #[test]
+    fn test_rebuilt_block_is_valid() {
+        let (job, block) = job_and_block();
+        assert_eq!(SubmissionValidator::new().validate_submission(&block, &job, NONCE), Ok(()));
+    }
+
+    #[test]
+    fn test_validator_is_send_and_sync() {
+        // RandomX caches, datasets and VMs are made and kept on their
+        // workers' threads, so nothing here needs an unsafe impl
+        fn send_and_sync<T: Send + Sync>() {}
+        send_and_sync::<SubmissionValidator>();
+    }
+
+    #[test]
+    fn test_each_region_reports_its_own_mismatch() {
+        let validator = SubmissionValidator::new();
+        let (job, block) = job_and_block();
+        let reserved = job.reserved_offset..job.reserved_offset + job.reserved_value.len();
+        let nonce = NONCE_OFFSET..NONCE_OFFSET + NONCE_SIZE;
+
+        for i in 0..block.len() {
+            let mut mutated = block.clone();
+            mutated[i] ^= 0x01;
+            // The major version and previous block are named as header fields
+            let field = match i {
+                0 => Some("major_version"),
+                7..=38 => Some("prev_id"),
+                _ => None,
+            };
+            if let Some(field) = field {
+                let result = validator.validate_submission(&mutated, &job, NONCE);
+                assert!(matches!(result, Err(ValidationError::Header { field: f, .. }) if f == field), "byte {}: {:?}", i, result);
+                continue;
             }
+            let expected = if reserved.contains(&i) {
+                ValidationError::ReservedMismatch
+            } else if nonce.contains(&i) {
+                ValidationError::NonceMismatch
+            } else {
+                ValidationError::BlobMismatch { offset: i }
+            };
+            assert_eq!(validator.validate_submission(&mutated, &job, NONCE), Err(expected), "byte {}", i);
         }
-        true
+        assert_eq!(validator.validate_submission(&block, &job, NONCE + 1), Err(ValidationError::NonceMismatch));
+    }
+
+    #[test]
+    fn test_share_sampling() {
+        let never = ShareSampler::seeded(0, 1);
+        assert!((0..100).all(|_| !never.should_verify(false)));
+        assert!(never.should_verify(true));
+        let always = ShareSampler::seeded(100, 1);
+        assert!((0..100).all(|_| always.should_verify(false)));
+
+        let picks = |sampler: &ShareSampler| (0..1000).map(|_| sampler.should_verify(false)).collect::<Vec<_>>();
+        let (a, b) = (ShareSampler::seeded(25, 7), ShareSampler::seeded(25, 7));
+        let verified = picks(&a);
+        assert_eq!(verified, picks(&b));
+        let count = verified.iter().filter(|&&v| v).count();
+        assert!((180..320).contains(&count), "verified {} of 1000", count);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_hash_comes_from_the_result_cache() {
+        let metrics = Arc::new(Metrics::new());
+        let validator = SubmissionValidator::new().with_metrics(metrics.clone());
+        let fake = FakeVerifier::new();
+        let (seed, other_seed) = ("00".repeat(32), "11".repeat(32));
+
+        verify(&validator, &fake, &seed, vec![1, 2, 3]).await.unwrap();
+        assert_eq!(verify(&validator, &fake, &seed, vec![1, 2, 3]).await.unwrap(), FakeVerifier::hash(&[1, 2, 3], &seed));
+        assert_eq!(fake.hashes(), 1);
+        // The same blob under another seed is hashed again
+        verify(&validator, &fake, &other_seed, vec![1, 2, 3]).await.unwrap();
+        verify(&validator, &fake, &seed, vec![1, 2, 4]).await.unwrap();
+        assert_eq!(fake.hashes(), 3);
+        assert_eq!(metrics.randomx_result_cache_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.randomx_result_cache_misses.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_verification_timings_add_up_and_reach_the_histograms() {
+        let metrics = Arc::new(Metrics::new());
+        let validator = SubmissionValidator::new().with_metrics(metrics.clone());
+        let hash_time = Duration::from_millis(100);
+        let fake = FakeVerifier::new().with_delay(hash_time).with_concurrency(1);
+        let seed = "00".repeat(32);
+
+        // The second hash waits for the first's VM
+        let first = validator.enqueue(&fake, false).unwrap();
+        let second = validator.enqueue(&fake, false).unwrap();
+        let (first, second) = tokio::join!(
+            validator.verify(&fake, &seed, 1, vec![1], first),
+            validator.verify(&fake, &seed, 1, vec![2], second),
+        );
+        let (_, first) = first.unwrap();
+        let (_, second) = second.unwrap();
+        for timings in [first, second] {
+            assert!(timings.hash >= hash_time, "{}", timings);
+            assert!(!timings.rpc);
+            let parts = timings.queue + timings.init + timings.hash;
+            assert!(parts <= timings.total && timings.total - parts < Duration::from_millis(50), "{}", timings);
+        }
+        assert!(first.queue.max(second.queue) >= hash_time - Duration::from_millis(10), "{} / {}", first, second);
+
+        assert_eq!(metrics.verify_queue_wait_seconds.count(), 2);
+        assert_eq!(metrics.verify_hash_seconds.count(), 2);
+        assert_eq!(metrics.rpc_pow_seconds.count(), 0);
+        // Fake hashes make no VM
+        assert_eq!(metrics.randomx_init_seconds.0.count(), 0);
+
+        // Nor is a cached hash observed
+        let ticket = validator.enqueue(&fake, false).unwrap();
+        let (_, cached) = validator.verify(&fake, &seed, 1, vec![1], ticket).await.unwrap();
+        assert_eq!((cached.queue, cached.hash), (Duration::ZERO, Duration::ZERO));
+        assert_eq!(metrics.verify_hash_seconds.count(), 2);
+        let text = metrics.format_prometheus();
+        assert!(text.contains("coordinator_verify_hash_seconds_count 2\n"), "{}", text);
+        assert!(text.contains("coordinator_randomx_vm_inits{kind=\"new\"} 0\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_full_queue_refuses_shares_and_lets_blocks_ahead() {
+        let metrics = Arc::new(Metrics::new());
+        let validator = Arc::new(SubmissionValidator::new().with_queue(2).with_metrics(metrics.clone()));
+        let fake = Arc::new(FakeVerifier::new().with_delay(Duration::from_millis(200)).with_concurrency(1));
+        let done = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let hash = |blob: u8, ticket: QueueTicket| {
+            let (validator, fake, done) = (validator.clone(), fake.clone(), done.clone());
+            tokio::spawn(async move {
+                validator.verify(fake.as_ref(), &"00".repeat(32), 1, vec![blob], ticket).await.unwrap();
+                done.lock().push(blob);
+            })
+        };
+
+        // One hashing, one waiting: the queue is full for shares
+        let running = hash(1, validator.enqueue(fake.as_ref(), false).unwrap());
+        let share = hash(2, validator.enqueue(fake.as_ref(), false).unwrap());
+        assert!(validator.enqueue(fake.as_ref(), false).is_none());
+        assert_eq!(metrics.verify_queue_full.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.verify_queue_depth.load(Ordering::Relaxed), 2);
+
+        // A claimed block is let in all the same, and hashed before the share
+        let block = hash(3, validator.enqueue(fake.as_ref(), true).unwrap());
+        for task in [running, block, share] {
+            task.await.unwrap();
+        }
+        assert_eq!(*done.lock(), [1, 3, 2]);
+        assert_eq!(fake.peak(), 1);
+
+        assert_eq!(metrics.verify_queue_depth.load(Ordering::Relaxed), 0);
+        let ticket = validator.enqueue(fake.as_ref(), false).expect("the queue drained");
+        assert!(validator.verify(fake.as_ref(), &"00".repeat(32), 1, vec![4], ticket).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_local_verifier_falls_back_to_rpc() {
+        let (url, calls) = crate::server::tests::fake_monerod([7; 32]).await;
+        let metrics = Arc::new(Metrics::new());
+        let vms = local(VmPool::new(1).with_metrics(metrics.clone()).refusing(|_, _| true));
+        let validator = SubmissionValidator::new().with_fallback(rpc(url, 100, &metrics)).with_metrics(metrics.clone());
+
+        // No cache can be made
+        assert_eq!(verify(&validator, vms.as_ref(), &"00".repeat(32), vec![1]).await.unwrap(), [7; 32]);
+        // Later hashes go straight to RPC
+        assert_eq!(verify(&validator, vms.as_ref(), &"00".repeat(32), vec![2]).await.unwrap(), [7; 32]);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.randomx_rpc_fallbacks.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.randomx_verifications_current.load(Ordering::Relaxed), 0);
+
+        // Without RPC the failure is the miner's to hear about, and later
+        // submits are refused until RandomX is retried
+        let alone = SubmissionValidator::new();
+        assert!(matches!(verify(&alone, vms.as_ref(), &"00".repeat(32), vec![1]).await, Err(HashError::Init(_))));
+        assert!(matches!(verify(&alone, vms.as_ref(), &"00".repeat(32), vec![2]).await, Err(HashError::Down { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_bad_seed_is_refused_without_stepping_down() {
+        let metrics = Arc::new(Metrics::new());
+        let validator = SubmissionValidator::new().with_metrics(metrics.clone());
+        let vms = local(VmPool::new(1));
+
+        // A seed that is not a RandomX key fails that submit alone
+        let err = verify(&validator, vms.as_ref(), "not hex", vec![1]).await.unwrap_err();
+        assert!(matches!(err, HashError::Failed(_)), "{:?}", err);
+        assert_eq!(ValidationError::from(err).status(), Some(SubmitStatus::Rejected));
+        assert_eq!(validator.rung(vms.as_ref()), VerifierRung::Configured);
+        assert_eq!(metrics.verifier_steps_light.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.verifier_steps_down.load(Ordering::Relaxed), 0);
+    }
+
+    /// RandomX allocations the validator asked for
+    type Asked = Arc<parking_lot::Mutex<Vec<(&'static str, RandomXFlag)>>>;
+
+    /// Record each allocation, refusing all while `refuse_all` is set and
+    /// those with large pages always, as on a host without huge pages
+    fn refusing(vms: VmPool, refuse_all: &Arc<AtomicBool>, asked: &Asked) -> Arc<dyn PowVerifier> {
+        let (refuse_all, asked) = (refuse_all.clone(), asked.clone());
+        local(vms.refusing(move |what, flags| {
+            asked.lock().push((what, flags));
+            refuse_all.load(Ordering::Relaxed) || flags.contains(RandomXFlag::FLAG_LARGE_PAGES)
+        }))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_allocation_failures_step_down_the_ladder() {
+        let metrics = Arc::new(Metrics::new());
+        let (refuse_all, asked) = (Arc::new(AtomicBool::new(true)), Asked::default());
+        let large_pages = RandomXFlags { large_pages: Some(true), ..Default::default() };
+        let vms = refusing(VmPool::new(1).with_flags(large_pages).with_metrics(metrics.clone()), &refuse_all, &asked);
+        let (validator, vms) = (SubmissionValidator::new().with_metrics(metrics.clone()), vms.as_ref());
+        let seed = "00".repeat(32);
+        assert_eq!(validator.rung(vms), VerifierRung::Configured);
+
+        // The cache is asked for as configured, then without large pages;
+        // with no RPC to fall back on, verification is then down
+        assert!(matches!(verify(&validator, vms, &seed, vec![1]).await, Err(HashError::Init(_))));
+        let tried = asked.lock().clone();
+        assert!(tried.iter().all(|(what, _)| *what == "cache"), "{:?}", tried);
+        assert!(tried[0].1.contains(RandomXFlag::FLAG_LARGE_PAGES), "{:?}", tried);
+        assert!(tried.len() > 1 && tried[1..].iter().all(|(_, flags)| !flags.contains(RandomXFlag::FLAG_LARGE_PAGES)), "{:?}", tried);
+        assert_eq!(validator.rung(vms), VerifierRung::Down);
+        assert_eq!(metrics.verifier_steps_light.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.verifier_steps_rpc.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.verifier_steps_down.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.verifier_rung.load(Ordering::Relaxed), VerifierRung::Down as u64);
+
+        // Refused at once, without asking RandomX, until a retry gets memory
+        asked.lock().clear();
+        assert!(matches!(verify(&validator, vms, &seed, vec![2]).await, Err(HashError::Down { .. })));
+        assert!(asked.lock().is_empty());
+        assert_eq!(validator.retry_local(vms).await, VerifierRung::Down);
+
+        // Memory comes back, but for large pages
+        refuse_all.store(false, Ordering::Relaxed);
+        assert_eq!(validator.retry_local(vms).await, VerifierRung::Light);
+        assert_eq!(metrics.verifier_recoveries.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.verifier_rung.load(Ordering::Relaxed), VerifierRung::Light as u64);
+        assert!(verify(&validator, vms, &seed, vec![3]).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_rpc_rung_climbs_back_once_randomx_can_be_made() {
+        let (url, calls) = crate::server::tests::fake_monerod([7; 32]).await;
+        let metrics = Arc::new(Metrics::new());
+        let (refuse_all, asked) = (Arc::new(AtomicBool::new(true)), Asked::default());
+        let vms = refusing(VmPool::new(1).with_metrics(metrics.clone()), &refuse_all, &asked);
+        let validator = SubmissionValidator::new().with_fallback(rpc(url, 100, &metrics)).with_metrics(metrics.clone());
+        let (vms, seed) = (vms.as_ref(), "00".repeat(32));
+
+        assert_eq!(verify(&validator, vms, &seed, vec![1]).await.unwrap(), [7; 32]);
+        assert_eq!(validator.rung(vms), VerifierRung::Rpc);
+        assert_eq!(metrics.verifier_steps_light.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.verifier_steps_rpc.load(Ordering::Relaxed), 1);
+
+        refuse_all.store(false, Ordering::Relaxed);
+        assert_eq!(validator.retry_local(vms).await, VerifierRung::Configured);
+        assert_eq!(metrics.verifier_rung.load(Ordering::Relaxed), VerifierRung::Configured as u64);
+        // Hashed by RandomX again, not monerod
+        assert_ne!(verify(&validator, vms, &seed, vec![2]).await.unwrap(), [7; 32]);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rpc_verifier_is_rate_limited() {
+        let (url, calls) = crate::server::tests::fake_monerod([7; 32]).await;
+        let metrics = Arc::new(Metrics::new());
+        let validator = SubmissionValidator::new().with_metrics(metrics.clone());
+        let rpc = rpc(url, 2, &metrics);
+        assert!(verify(&validator, rpc.as_ref(), &"00".repeat(32), vec![1]).await.is_ok());
+        assert!(verify(&validator, rpc.as_ref(), &"00".repeat(32), vec![2]).await.is_ok());
+        assert!(matches!(verify(&validator, rpc.as_ref(), &"00".repeat(32), vec![3]).await, Err(HashError::Unavailable(_))));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.rpc_pow_limited.load(Ordering::Relaxed), 1);
+        // Nothing local to self-test
+        assert_eq!(validator.self_test(rpc.as_ref()).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_dropped_tickets_give_their_place_back() {
+        let validator = SubmissionValidator::new().with_queue(2);
+        let vms = LocalVerifier::new(VmPool::new(1));
+        let running = validator.enqueue(&vms, false).unwrap();
+        let waiting = validator.enqueue(&vms, false).unwrap();
+        assert!(validator.enqueue(&vms, false).is_none());
+        drop(waiting);
+        let mut waiting = validator.enqueue(&vms, false).unwrap();
+        // The VM passes to the one waiting
+        drop(running);
+        assert!(waiting.waiting.as_mut().unwrap().1.try_recv().is_ok());
+        assert!(validator.enqueue(&vms, false).is_some());
+    }
+
+    #[test]
+    fn test_result_cache_drops_the_least_recently_used() {
+        let cache = ResultCache::new(2);
+        let (a, b, c) = ([1; 32], [2; 32], [3; 32]);
+        cache.insert(a, a);
+        cache.insert(b, b);
+        assert_eq!(cache.get(&a), Some(a));
+        cache.insert(c, c);
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&a), Some(a));
+        assert_eq!(cache.get(&c), Some(c));
+        assert_ne!(ResultCache::key("ab", b"c"), ResultCache::key("a", b"bc"));
+    }
+
+    #[test]
+    fn test_each_failure_maps_to_its_answer() {
+        use ErrorCode::*;
+        let header = ValidationError::Header { field: "timestamp", problem: String::new() };
+        // Variant, label, code, answered as a rejection (else an error), an offense
+        let table = [
+            (ValidationError::BadNonce(String::new()), "bad_nonce", BadFormat, true, true),
+            (ValidationError::Length { expected: 1, actual: 2 }, "length", BadFormat, true, true),
+            (ValidationError::ReservedMismatch, "reserved_mismatch", InvalidData, true, true),
+            (ValidationError::NonceMismatch, "nonce_mismatch", InvalidData, true, true),
+            (ValidationError::BlobMismatch { offset: 0 }, "blob_mismatch", InvalidData, true, true),
+            (header, "header", BadJob, false, false),
+            (ValidationError::BadPow, "bad_pow", BadPow, false, true),
+            (ValidationError::BelowShareTarget, "below_share_target", InvalidData, true, true),
+            (ValidationError::BelowBlockTarget, "below_block_target", InvalidData, true, true),
+            (ValidationError::VmUnavailable(String::new()), "vm_unavailable", InternalError, true, false),
+            (ValidationError::HashFailed(String::new()), "hash_failed", InternalError, true, false),
+            (ValidationError::VerifierDown { retry_after_ms: 0 }, "verifier_down", NotReady, false, false),
+        ];
+        for (e, label, code, rejected, offense) in table {
+            assert_eq!(e.label(), label);
+            assert_eq!(e.code(), code, "{}", label);
+            assert_eq!(e.status(), rejected.then_some(SubmitStatus::Rejected), "{}", label);
+            assert_eq!(e.is_offense(), offense, "{}", label);
+        }
+        // What the miner reads when verification fails on our side
+        assert_eq!(ValidationError::VmUnavailable("no memory".into()).to_string(), "Hash verification unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_validate_and_verify_classes_hashes() {
+        let (validator, fake) = (SubmissionValidator::new(), FakeVerifier::new());
+        let mut template = test_template();
+        template.set_difficulty(1000);
+        let jobs = JobManager::new(10_000);
+        let share_job = jobs.create_job(&template, "s", 2).unwrap();
+        let blocks_job = jobs.create_job(&template, "s", 1000).unwrap();
+
+        // The first nonce whose hash meets the share target but not the block's
+        let (nonce, hash) = (0u32..)
+            .map(|n| hex::encode(n.to_le_bytes()))
+            .map(|nonce| {
+                let hash = FakeVerifier::hash(&share_job.apply_nonce(&nonce).unwrap(), &share_job.seed_hash);
+                (nonce, hash)
+            })
+            .find(|(_, hash)| share_job.classify(hash) == HashClass::Share)
+            .unwrap();
+        let ticket = validator.enqueue(&fake, false);
+        let valid = validator.validate_and_verify(&fake, &share_job, &nonce, None, ticket).await.unwrap();
+        assert_eq!((valid.outcome, valid.hash), (ValidationOutcome::Share, hash));
+        assert_eq!(valid.block, share_job.block_blob(&nonce).unwrap());
+        assert!(valid.timings.is_some());
+
+        // A claim taken unhashed is classed as it stands
+        let mut share = [0xff; 32];
+        share[31] = 0;
+        let valid = validator.validate_and_verify(&fake, &share_job, &nonce, Some(share), None).await.unwrap();
+        assert_eq!(valid.outcome, ValidationOutcome::Share);
+        assert!(valid.timings.is_none());
+        for (job, expected) in [(&share_job, ValidationError::BelowShareTarget), (&blocks_job, ValidationError::BelowBlockTarget)] {
+            let missed = validator.validate_and_verify(&fake, job, &nonce, Some([0xff; 32]), None).await;
+            assert_eq!(missed.unwrap_err(), expected);
+        }
+
+        // A claim that was hashed must be the hash
+        let ticket = validator.enqueue(&fake, false);
+        let bad_pow = validator.validate_and_verify(&fake, &blocks_job, &nonce, Some([1; 32]), ticket).await;
+        assert_eq!(bad_pow.unwrap_err(), ValidationError::BadPow);
+        let bad_nonce = validator.validate_and_verify(&fake, &blocks_job, "zz", None, validator.enqueue(&fake, false)).await;
+        assert!(matches!(bad_nonce, Err(ValidationError::BadNonce(_))), "{:?}", bad_nonce);
+    }
+
+    #[test]
+    fn test_length_must_match_the_template() {
+        let validator = SubmissionValidator::new();
+        let (job, block) = job_and_block();
+        let expected = block.len();
+        let short = validator.validate_submission(&block[..expected - 1], &job, NONCE).unwrap_err();
+        assert_eq!(short, ValidationError::Length { expected, actual: expected - 1 });
+        assert_eq!(short.code(), ErrorCode::BadFormat);
+
+        let mut long = block.clone();
+        long.push(0);
+        assert!(matches!(validator.validate_submission(&long, &job, NONCE), Err(ValidationError::Length { .. })));
+        assert_eq!(ValidationError::ReservedMismatch.code(), ErrorCode::InvalidData);
+    }
+
+    /// Mainnet's genesis block header: versions 1/0, timestamp 0, no
+    /// previous block and nonce 10000
+    const GENESIS_HEADER: &str = concat!("010000", "0000000000000000000000000000000000000000000000000000000000000000", "10270000");
+
+    #[test]
+    fn test_block_header_parses_mainnet_and_template_headers() {
+        let genesis = BlockHeader::parse(&hex::decode(GENESIS_HEADER).unwrap()).unwrap();
+        assert_eq!(
+            genesis,
+            BlockHeader { major_version: 1, minor_version: 0, timestamp: 0, prev_id: [0; 32], nonce: 10_000 }
+        );
+
+        let template = BlockHeader::parse(&hex::decode(crate::blob::fixture::TEMPLATE_BLOB).unwrap()).unwrap();
+        assert_eq!((template.major_version, template.minor_version), (16, 16));
+        assert_eq!(template.timestamp, crate::blob::fixture::TIMESTAMP);
+        assert_eq!(hex::encode(template.prev_id), crate::blob::fixture::PREV_HASH);
+        assert_eq!(template.nonce, 0);
+    }
+
+    #[test]
+    fn test_block_header_names_the_field_a_truncated_blob_ends_in() {
+        let header = hex::decode(&crate::blob::fixture::TEMPLATE_BLOB[..86]).unwrap();
+        assert!(BlockHeader::parse(&header).is_ok());
+        for len in 0..header.len() {
+            let field = match len {
+                0 => "major_version",
+                1 => "minor_version",
+                2..=6 => "timestamp",
+                7..=38 => "prev_id",
+                _ => "nonce",
+            };
+            match BlockHeader::parse(&header[..len]) {
+                Err(ValidationError::Header { field: actual, .. }) => assert_eq!(actual, field, "{} bytes", len),
+                other => panic!("{} bytes parsed as {:?}", len, other),
+            }
+        }
+        // A varint that never ends
+        assert!(matches!(BlockHeader::parse(&[0x80; 16]), Err(ValidationError::Header { field: "major_version", .. })));
+    }
+
+    #[test]
+    fn test_header_timestamp_must_be_near_the_clock() {
+        let (job, block) = job_and_block();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let age = now - crate::blob::fixture::TIMESTAMP;
+        let within = SubmissionValidator::new().with_timestamp_skew(Duration::from_secs(age + 60));
+        assert_eq!(within.validate_submission(&block, &job, NONCE), Ok(()));
+
+        let skewed = SubmissionValidator::new().with_timestamp_skew(Duration::from_secs(age - 60));
+        let err = skewed.validate_submission(&block, &job, NONCE).unwrap_err();
+        assert!(matches!(err, ValidationError::Header { field: "timestamp", .. }), "{:?}", err);
+        assert_eq!(err.code(), ErrorCode::BadJob);
     }
 }
@@ -0,0 +1,185 @@
+use std::time::{Duration, Instant};
+
+use crate::config::VarDiffConfig;
+use crate::protocol::RandomxMode;
+
+/// Weight of the newest share interval in the moving average
+const EMA_ALPHA: f64 = 0.3;
+
+/// Largest factor one retarget may move the difficulty by, either way
+const MAX_STEP: f64 = 4.0;
+
+/// Estimates within this fraction of the target leave the difficulty alone
+const DEADBAND: f64 = 0.1;
+
+/// Share difficulty controller for one session. Aims for one accepted share
+/// every `target_share_secs` by tracking a moving average of share intervals.
+///
+/// Every method takes the current time, so callers (and tests) own the clock.
+#[derive(Debug, Clone)]
+pub struct VarDiff {
+    difficulty: u64,
+    /// Moving average of seconds between accepted shares
+    ema_interval: Option<f64>,
+    last_share: Instant,
+    last_retarget: Instant,
+}
+
+impl VarDiff {
+    pub fn new(config: &VarDiffConfig, now: Instant) -> Self {
+        Self::starting_at(config.initial_difficulty, config, now)
+    }
+
+    /// Start at the difficulty configured for clients running RandomX in `mode`
+    pub fn for_mode(config: &VarDiffConfig, mode: RandomxMode, now: Instant) -> Self {
+        let initial = match mode {
+            RandomxMode::Fast => config.initial_difficulty_fast,
+            RandomxMode::Light => config.initial_difficulty_light,
+            RandomxMode::Unknown => None,
+        };
+        Self::starting_at(initial.unwrap_or(config.initial_difficulty), config, now)
+    }
+
+    fn starting_at(initial: u64, config: &VarDiffConfig, now: Instant) -> Self {
+        Self {
+            difficulty: initial.clamp(config.min_difficulty, config.max_difficulty.max(config.min_difficulty)),
+            ema_interval: None,
+            last_share: now,
+            last_retarget: now,
+        }
+    }
+
+    pub fn difficulty(&self) -> u64 {
+        self.difficulty
+    }
+
+    pub fn record_share(&mut self, now: Instant) {
+        let interval = now.saturating_duration_since(self.last_share).as_secs_f64();
+        self.ema_interval = Some(match self.ema_interval {
+            Some(ema) => ema + EMA_ALPHA * (interval - ema),
+            None => interval,
+        });
+        self.last_share = now;
+    }
+
+    /// Recompute the difficulty if a retarget interval has passed. Returns
+    /// the new difficulty when it changed.
+    pub fn retarget(&mut self, config: &VarDiffConfig, now: Instant) -> Option<u64> {
+        if now.saturating_duration_since(self.last_retarget) < Duration::from_secs(config.retarget_secs) {
+            return None;
+        }
+        self.last_retarget = now;
+
+        // A miner that has gone quiet is slower than its average says
+        let since_last = now.saturating_duration_since(self.last_share).as_secs_f64();
+        let interval = self.ema_interval.unwrap_or(since_last).max(since_last).max(0.001);
+
+        let ratio = config.target_share_secs as f64 / interval;
+        if (ratio - 1.0).abs() <= DEADBAND {
+            return None;
+        }
+        let ratio = ratio.clamp(1.0 / MAX_STEP, MAX_STEP);
+        let max = config.max_difficulty.max(config.min_difficulty);
+        let next = ((self.difficulty as f64 * ratio).round() as u64).clamp(config.min_difficulty.max(1), max);
+        if next == self.difficulty {
+            return None;
+        }
+
+        self.difficulty = next;
+        // Intervals measured at the old difficulty no longer apply
+        self.ema_interval = self.ema_interval.map(|ema| ema * ratio);
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> VarDiffConfig {
+        VarDiffConfig {
+            enable: true,
+            initial_difficulty: 10_000,
+            initial_difficulty_fast: None,
+            initial_difficulty_light: None,
+            min_difficulty: 100,
+            max_difficulty: 100_000_000,
+            target_share_secs: 30,
+            retarget_secs: 60,
+        }
+    }
+
+    /// Simulate a miner with a fixed hashrate for `minutes`: it finds a share
+    /// every `difficulty / hashrate` seconds. Returns the final difficulty.
+    fn simulate(hashrate: f64, minutes: u64) -> u64 {
+        let config = config();
+        let start = Instant::now();
+        let mut vardiff = VarDiff::new(&config, start);
+        let mut next_share = start + Duration::from_secs_f64(vardiff.difficulty() as f64 / hashrate);
+
+        for second in 1..=minutes * 60 {
+            let now = start + Duration::from_secs(second);
+            while next_share <= now {
+                vardiff.record_share(next_share);
+                next_share += Duration::from_secs_f64(vardiff.difficulty() as f64 / hashrate);
+            }
+            if vardiff.retarget(&config, now).is_some() {
+                // The new difficulty applies from the next job on
+                next_share = now + Duration::from_secs_f64(vardiff.difficulty() as f64 / hashrate);
+            }
+        }
+        vardiff.difficulty()
+    }
+
+    fn assert_near_target(hashrate: f64, difficulty: u64) {
+        let share_secs = difficulty as f64 / hashrate;
+        assert!((20.0..=45.0).contains(&share_secs), "one share every {:.1}s at difficulty {}", share_secs, difficulty);
+    }
+
+    #[test]
+    fn test_fast_submitter_converges_up() {
+        // 10 kH/s: the initial difficulty yields a share every second
+        let difficulty = simulate(10_000.0, 30);
+        assert_near_target(10_000.0, difficulty);
+    }
+
+    #[test]
+    fn test_slow_submitter_converges_down() {
+        // 20 H/s: the initial difficulty yields a share every ~8 minutes
+        let difficulty = simulate(20.0, 60);
+        assert_near_target(20.0, difficulty);
+    }
+
+    #[test]
+    fn test_steps_are_limited_and_clamped() {
+        let config = config();
+        let start = Instant::now();
+        let mut vardiff = VarDiff::new(&config, start);
+
+        // Shares every 100 ms want a 300x increase; one step allows 4x
+        for i in 1..=600 {
+            vardiff.record_share(start + Duration::from_millis(100 * i));
+        }
+        assert_eq!(vardiff.retarget(&config, start + Duration::from_secs(30)), None, "not due yet");
+        assert_eq!(vardiff.retarget(&config, start + Duration::from_secs(60)), Some(40_000));
+
+        // A silent miner is walked down, but never below the minimum
+        let mut now = start + Duration::from_secs(60);
+        for _ in 0..20 {
+            now += Duration::from_secs(3600);
+            vardiff.retarget(&config, now);
+        }
+        assert_eq!(vardiff.difficulty(), config.min_difficulty);
+    }
+
+    #[test]
+    fn test_starting_difficulty_follows_randomx_mode() {
+        let by_mode = VarDiffConfig { initial_difficulty_light: Some(500), initial_difficulty_fast: Some(1_000_000_000), ..config() };
+        let now = Instant::now();
+        assert_eq!(VarDiff::for_mode(&by_mode, RandomxMode::Light, now).difficulty(), 500);
+        // Still clamped to the configured range
+        assert_eq!(VarDiff::for_mode(&by_mode, RandomxMode::Fast, now).difficulty(), by_mode.max_difficulty);
+        assert_eq!(VarDiff::for_mode(&by_mode, RandomxMode::Unknown, now).difficulty(), by_mode.initial_difficulty);
+        assert_eq!(VarDiff::for_mode(&config(), RandomxMode::Light, now).difficulty(), by_mode.initial_difficulty);
+    }
+}
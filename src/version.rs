@@ -0,0 +1,77 @@
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{PROTOCOL_VERSION, SUBPROTOCOL_V1};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Set by build.rs; "unknown" when git was not available at build time
+pub const GIT_COMMIT: &str = match option_env!("MWC_GIT_COMMIT") {
+    Some(commit) => commit,
+    None => "unknown",
+};
+
+pub const BUILD_TIMESTAMP: &str = match option_env!("MWC_BUILD_TIMESTAMP") {
+    Some(timestamp) => timestamp,
+    None => "unknown",
+};
+
+/// Body of the `/version` endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub build_timestamp: String,
+    pub protocol_version: u8,
+    pub subprotocol: String,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        Self {
+            version: VERSION.to_string(),
+            git_commit: GIT_COMMIT.to_string(),
+            build_timestamp: BUILD_TIMESTAMP.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            subprotocol: SUBPROTOCOL_V1.to_string(),
+        }
+    }
+}
+
+/// One-line build description for the startup log
+pub fn summary() -> String {
+    format!(
+        "{} (commit {}, built {}, protocol v{})",
+        VERSION, GIT_COMMIT, BUILD_TIMESTAMP, PROTOCOL_VERSION
+    )
+}
+
+pub async fn version_handler() -> Json<VersionInfo> {
+    Json(VersionInfo::current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{self, tests::test_state};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_version_endpoint_reports_cargo_version() {
+        let (state, _template_tx) = test_state();
+        let response = server::router(state)
+            .oneshot(Request::builder().uri("/version").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: VersionInfo = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert!(!info.git_commit.is_empty());
+        assert!(!info.build_timestamp.is_empty());
+    }
+}
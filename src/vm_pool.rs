@@ -0,0 +1,1295 @@
+//! RandomX VMs for verifying submissions, so several hashes can run at once.
+//!
+//! Each VM lives on a worker thread of its own, up to the pool's size, and so
+//! does everything it reads. RandomX itself would let threads share one
+//! read-only cache and dataset, but randomx-rs's types are not `Send`, so
+//! every worker makes its own, on its own thread, and never hands them on. A hash checks out a worker and sends it the seed and
+//! blob over a channel; the hash comes back the same way. Light mode thus
+//! needs [`CACHE_MB`] per worker for its cache, and a scratchpad on top.
+//! Workers are started on first use. When the seed changes each worker makes
+//! the new seed's cache the first time it is sent a hash for it, and moves
+//! its VM onto it.
+//!
+//! In fast mode each worker also builds a [`DATASET_MB`] dataset from its
+//! cache for the current seed, which is why the config allows a single
+//! worker in fast mode. That takes tens of seconds, so a thread of
+//! its own takes idle workers out of the pool one at a time to build theirs,
+//! while the rest hash in light mode. Each VM is remade on the dataset the
+//! next time its worker hashes. If a dataset cannot be built the pool stays
+//! in light mode.
+//!
+//! The next seed's cache can be made ahead with [`VmPool::prewarm`] before the
+//! chain switches to it, by each idle worker in turn and any not yet started.
+//! The first hash for that seed then finds it made instead of making it while
+//! submissions wait.
+//!
+//! After a switch the previous seed is kept for a grace period, so hashes for
+//! jobs issued just before it do not make its cache again, and each hash
+//! prefers an idle worker whose VM is already on its seed. Around a switch a
+//! worker thus holds two caches.
+//!
+//! The RandomX flags are RandomX's choice for the CPU unless set otherwise.
+//! Should making a cache, dataset or VM fail with them, it is tried again
+//! with fewer: without large pages, then with JIT in secure mode, then
+//! without JIT. Flags that made it work are kept for later ones.
+//!
+//! Short of memory, the pool steps down from hashing as configured to light
+//! mode without large pages: once a cache cannot be made with the flags
+//! asked for, or a dataset cannot be built, no more datasets are built and
+//! caches are made without large pages. [`VmPool::recover`] tries the
+//! configured flags and mode again.
+
+use futures::future::BoxFuture;
+use parking_lot::{Condvar, Mutex};
+use randomx_rs::{RandomXCache, RandomXDataset, RandomXFlag, RandomXVM};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use crate::config::{RandomXFlags, VerifyMode};
+use crate::error::CoordinatorError;
+use crate::metrics::Metrics;
+use crate::pow::PowVerifier;
+use crate::validator::{HashError, HashTimings, VerifierRung};
+
+/// Memory of a light-mode cache, which each worker makes for itself
+pub const CACHE_MB: usize = 256;
+/// Memory of a fast-mode dataset, which each worker builds for itself, on
+/// top of the cache
+pub const DATASET_MB: usize = 2080;
+/// Scratchpad and program state each VM adds, roughly
+pub const VM_MB: usize = 2;
+/// Most VMs made by default, however many cores there are
+pub const MAX_DEFAULT_VMS: usize = 4;
+/// How long the previous seed is kept after a switch, unless set
+const DEFAULT_PREVIOUS_SEED_GRACE: Duration = Duration::from_secs(10);
+
+/// RandomX's first published test vector: a key, an input, and the hash of
+/// the input with a VM for that key
+const SELF_TEST_KEY: &[u8] = b"test key 000";
+const SELF_TEST_INPUT: &[u8] = b"This is a test";
+const SELF_TEST_HASH: &str = "639183aae1bf4c9a35884cb46b09cad9175f04efd7684e7262a0ac1c2f0b4e3f";
+
+/// VMs when `randomx.vms` is unset: one per core the process may use, at
+/// most [`MAX_DEFAULT_VMS`]
+pub fn default_size() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_DEFAULT_VMS)
+}
+
+/// Which held seed a hash was done with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SeedSlot {
+    Current,
+    Previous,
+}
+
+/// Asks RandomX for the memory of caches and datasets. Tests have it refuse
+/// some, as a host short of memory would.
+#[derive(Clone, Default)]
+struct Allocator {
+    /// Says whether to fail making a "cache" or "dataset" with some flags
+    #[cfg(test)]
+    refuse: Option<Arc<dyn Fn(&'static str, RandomXFlag) -> bool + Send + Sync>>,
+}
+
+impl Allocator {
+    fn cache(&self, flags: RandomXFlag, key: &[u8]) -> Result<RandomXCache, String> {
+        #[cfg(test)]
+        self.refused("cache", flags)?;
+        RandomXCache::new(flags, key).map_err(|e| e.to_string())
+    }
+
+    fn dataset(&self, flags: RandomXFlag, cache: RandomXCache) -> Result<RandomXDataset, String> {
+        #[cfg(test)]
+        self.refused("dataset", flags)?;
+        RandomXDataset::new(flags, cache, 0).map_err(|e| e.to_string())
+    }
+
+    #[cfg(test)]
+    fn refused(&self, what: &'static str, flags: RandomXFlag) -> Result<(), String> {
+        match &self.refuse {
+            Some(refuse) if refuse(what, flags) => Err(format!("{} allocation refused", what)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The seeds the pool holds, whose caches workers keep
+#[derive(Default)]
+struct Seeds {
+    current: Option<Arc<str>>,
+    /// The seed before the current one, kept until the instant given for
+    /// submissions on jobs issued just before the switch
+    previous: Option<(Arc<str>, Instant)>,
+    /// Made ahead of the seed change, taken over by the first hash for it
+    next: Option<Arc<str>>,
+    /// Seed whose cache is being made ahead
+    prewarming: Option<Arc<str>>,
+}
+
+impl Seeds {
+    /// Seeds whose caches workers keep; they drop any other
+    fn held(&self) -> Vec<Arc<str>> {
+        let previous = self.previous.as_ref().map(|(seed, _)| seed);
+        [self.current.as_ref(), previous, self.next.as_ref(), self.prewarming.as_ref()]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    fn holds(&self, seed_hash: &str) -> bool {
+        self.held().iter().any(|seed| &**seed == seed_hash)
+    }
+
+    fn is_current(&self, seed_hash: &str) -> bool {
+        self.current.as_deref() == Some(seed_hash)
+    }
+
+    /// Which held seed `seed_hash` is, taking over the one made ahead if that
+    /// is the seed. Drops the previous seed once its time is up.
+    fn find(&mut self, seed_hash: &str, now: Instant, grace: Duration) -> Option<(SeedSlot, Arc<str>)> {
+        if self.previous.as_ref().is_some_and(|(_, until)| now >= *until) {
+            self.previous = None;
+        }
+        if let Some(seed) = self.current.as_ref().filter(|seed| &***seed == seed_hash) {
+            return Some((SeedSlot::Current, seed.clone()));
+        }
+        if let Some((seed, _)) = self.previous.as_ref().filter(|(seed, _)| &**seed == seed_hash) {
+            return Some((SeedSlot::Previous, seed.clone()));
+        }
+        if self.next.as_deref() == Some(seed_hash) {
+            let seed = self.next.take().expect("checked above");
+            tracing::info!("RandomX switched to the prewarmed seed: {}", seed);
+            self.rotate(seed.clone(), now + grace);
+            return Some((SeedSlot::Current, seed));
+        }
+        None
+    }
+
+    /// Make `seed` current, keeping the seed it replaces until `until`
+    fn rotate(&mut self, seed: Arc<str>, until: Instant) {
+        self.previous = self.current.replace(seed).map(|seed| (seed, until));
+    }
+}
+
+/// What workers make caches, datasets and VMs with, shared with the pool
+#[derive(Clone)]
+struct WorkerContext {
+    flags: Arc<AtomicU32>,
+    light_only: Arc<AtomicBool>,
+    alloc: Allocator,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl WorkerContext {
+    /// Make the cache for `seed_hash` with the flags in use, or else step
+    /// down to light mode without large pages and make it there, with fewer
+    /// flags still if need be. A seed that is not hex fails without asking
+    /// RandomX for anything.
+    fn make_cache(&self, seed_hash: &str) -> Result<RandomXCache, HashError> {
+        let seed_bytes = hex::decode(seed_hash)
+            .map_err(|_| HashError::Failed(CoordinatorError::Validation("Invalid seed hash hex".into())))?;
+        let flags = RandomXFlag::from_bits_truncate(self.flags.load(Ordering::Relaxed));
+        let cache = match (!self.light_only.load(Ordering::Relaxed)).then(|| self.alloc.cache(flags, &seed_bytes)) {
+            Some(Ok(cache)) => cache,
+            refused => {
+                if let Some(Err(e)) = refused {
+                    step_down_to_light(&self.light_only, &self.flags, self.metrics.as_deref(), &format!("cache: {}", e));
+                }
+                init_with_fallback(&self.flags, "cache", |flags| self.alloc.cache(flags, &seed_bytes))
+                    .map_err(|e| HashError::Init(CoordinatorError::Validation(format!("RandomX cache init failed: {}", e))))?
+            }
+        };
+        tracing::info!("RandomX cache initialized with seed: {}", seed_hash);
+        Ok(cache)
+    }
+}
+
+/// A seed's cache as a worker holds it, with its dataset once built
+struct WorkerCache {
+    seed: Arc<str>,
+    cache: RandomXCache,
+    /// Set once built, in fast mode, and only for the current seed
+    dataset: Option<RandomXDataset>,
+}
+
+/// A worker's VM
+struct PooledVm {
+    /// Seed of the cache the VM was last given
+    seed_hash: Arc<str>,
+    /// Whether the VM hashes from the dataset
+    fast: bool,
+    vm: RandomXVM,
+}
+
+/// All the RandomX a worker thread holds, made on it and never leaving it
+#[derive(Default)]
+struct WorkerState {
+    caches: Vec<WorkerCache>,
+    vm: Option<PooledVm>,
+}
+
+impl WorkerState {
+    /// Where `seed`'s cache is among the worker's, made now if it has none,
+    /// adding the time that took to `init`
+    fn cache(&mut self, context: &WorkerContext, seed: &Arc<str>, init: &mut Duration) -> Result<usize, HashError> {
+        if let Some(i) = self.caches.iter().position(|held| held.seed == *seed) {
+            return Ok(i);
+        }
+        let started = Instant::now();
+        let cache = context.make_cache(seed);
+        *init += started.elapsed();
+        self.caches.push(WorkerCache { seed: seed.clone(), cache: cache?, dataset: None });
+        Ok(self.caches.len() - 1)
+    }
+}
+
+/// Work for a worker, with the seeds the pool holds, whose caches it keeps
+struct WorkerRequest {
+    held: Vec<Arc<str>>,
+    work: Work,
+}
+
+enum Work {
+    /// Hash `blob` with a VM on `seed`'s cache
+    Hash {
+        seed: Arc<str>,
+        blob: Vec<u8>,
+        timings: HashTimings,
+        reply: mpsc::SyncSender<(Result<[u8; 32], HashError>, HashTimings)>,
+    },
+    /// Make `seed`'s cache ahead of the chain switching to it
+    Prewarm { seed: Arc<str>, reply: mpsc::SyncSender<Result<(), HashError>> },
+    /// Build the dataset for `seed`'s cache, dropping any other
+    BuildDataset { seed: Arc<str>, reply: mpsc::SyncSender<Result<(), HashError>> },
+}
+
+/// A worker thread as the pool hands it out: where to send it work, and
+/// what it was last left with
+struct Worker {
+    id: u64,
+    requests: mpsc::Sender<WorkerRequest>,
+    /// The seed its VM is on, if it has one
+    seed_hash: Option<Arc<str>>,
+    /// The seed it built a dataset for, if any
+    dataset: Option<Arc<str>>,
+}
+
+impl Worker {
+    /// Send the worker `work` and wait for its reply; None if its thread is
+    /// gone
+    fn ask<T>(&self, held: Vec<Arc<str>>, work: impl FnOnce(mpsc::SyncSender<T>) -> Work) -> Option<T> {
+        let (reply, replied) = mpsc::sync_channel(1);
+        self.requests.send(WorkerRequest { held, work: work(reply) }).ok()?;
+        replied.recv().ok()
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    idle: Vec<Worker>,
+    /// Slots checked out, with or without a worker
+    in_use: usize,
+    /// Most slots ever checked out at once
+    #[cfg(test)]
+    peak_in_use: usize,
+}
+
+pub struct VmPool {
+    size: usize,
+    /// Bits of the flags to make caches, datasets and VMs with, fewer once
+    /// some failed
+    flags: Arc<AtomicU32>,
+    /// The flags asked for, which [`VmPool::recover`] goes back to
+    configured: RandomXFlag,
+    mode: VerifyMode,
+    /// Set once stepped down to light mode without large pages
+    light_only: Arc<AtomicBool>,
+    alloc: Allocator,
+    seeds: Mutex<Seeds>,
+    /// How long the previous seed is kept after a switch
+    previous_seed_grace: Duration,
+    /// Whether hashes are done from a dataset right now
+    fast: AtomicBool,
+    /// Set while a thread is building datasets on the workers
+    building: AtomicBool,
+    metrics: Option<Arc<Metrics>>,
+    slots: Mutex<Slots>,
+    returned: Condvar,
+    /// Numbers the workers, so each is visited once when going round them
+    started: AtomicU64,
+}
+
+/// A checked out slot, given back when dropped
+struct Slot<'a> {
+    pool: &'a VmPool,
+    /// None until a worker is started for the slot, or if its thread is gone
+    worker: Option<Worker>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut slots = self.pool.slots.lock();
+        slots.in_use -= 1;
+        slots.idle.extend(self.worker.take());
+        self.pool.returned.notify_one();
+    }
+}
+
+impl VmPool {
+    pub fn new(size: usize) -> Self {
+        let flags = RandomXFlag::get_recommended_flags();
+        Self {
+            size: size.max(1),
+            flags: Arc::new(AtomicU32::new(flags.bits())),
+            configured: flags,
+            mode: VerifyMode::Light,
+            light_only: Arc::new(AtomicBool::new(false)),
+            alloc: Allocator::default(),
+            seeds: Mutex::new(Seeds::default()),
+            previous_seed_grace: DEFAULT_PREVIOUS_SEED_GRACE,
+            fast: AtomicBool::new(false),
+            building: AtomicBool::new(false),
+            metrics: None,
+            slots: Mutex::new(Slots::default()),
+            returned: Condvar::new(),
+            started: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size.max(1);
+        self
+    }
+
+    /// Build a dataset for each seed in fast mode
+    pub fn with_mode(mut self, mode: VerifyMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Force the RandomX flags `settings` sets on or off
+    pub fn with_flags(mut self, settings: RandomXFlags) -> Self {
+        self.configured = choose_flags(settings, RandomXFlag::get_recommended_flags());
+        self.flags = Arc::new(AtomicU32::new(self.configured.bits()));
+        self
+    }
+
+    /// Fail the allocations `refuse` says no to, given "cache" or "dataset"
+    /// and the flags, as a host short of memory would
+    #[cfg(test)]
+    pub(crate) fn refusing(mut self, refuse: impl Fn(&'static str, RandomXFlag) -> bool + Send + Sync + 'static) -> Self {
+        self.alloc.refuse = Some(Arc::new(refuse));
+        self
+    }
+
+    /// Keep the previous seed for `grace` after a switch
+    pub fn with_previous_seed_grace(mut self, grace: Duration) -> Self {
+        self.previous_seed_grace = grace;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The mode asked for, which hashes are done in once it is ready
+    pub fn mode(&self) -> VerifyMode {
+        self.mode
+    }
+
+    /// Whether the pool stepped down to light mode without large pages
+    pub fn is_light_only(&self) -> bool {
+        self.light_only.load(Ordering::Relaxed)
+    }
+
+    /// Whether datasets are built: in fast mode, unless stepped down
+    fn builds_datasets(&self) -> bool {
+        self.mode == VerifyMode::Fast && !self.is_light_only()
+    }
+
+    /// Whether hashes are done from a dataset right now
+    pub fn is_fast(&self) -> bool {
+        self.fast.load(Ordering::Relaxed)
+    }
+
+    /// The RandomX flags VMs are made with now, full memory among them once
+    /// hashing from a dataset
+    pub fn flags(&self) -> RandomXFlag {
+        let flags = RandomXFlag::from_bits_truncate(self.flags.load(Ordering::Relaxed));
+        if self.is_fast() { flags | RandomXFlag::FLAG_FULL_MEM } else { flags }
+    }
+
+    /// Memory the pool takes once every worker is started: a cache and a VM
+    /// each, and a dataset each in fast mode
+    pub fn memory_mb(&self) -> usize {
+        let dataset = if self.mode == VerifyMode::Fast { DATASET_MB } else { 0 };
+        self.size * (CACHE_MB + dataset + VM_MB)
+    }
+
+    /// Hash `blob` with a VM for `seed_hash`, waiting for one to be free.
+    /// Blocks for as long as that and the hash take, which are added to
+    /// `timings`.
+    pub fn hash(self: &Arc<Self>, seed_hash: &str, blob: &[u8], timings: &mut HashTimings) -> Result<[u8; 32], HashError> {
+        // Caught before the pool switches to the seed
+        if hex::decode(seed_hash).is_err() {
+            return Err(HashError::Failed(CoordinatorError::Validation("Invalid seed hash hex".into())));
+        }
+        let (seed_slot, seed) = self.resolve(seed_hash);
+        let waiting = Instant::now();
+        let mut slot = self.checkout(&seed);
+        timings.queue += waiting.elapsed();
+        let worker = match slot.worker.take() {
+            Some(worker) => worker,
+            None => self.start_worker()?,
+        };
+
+        // A worker whose thread is gone is dropped with the slot, and
+        // another started in its place next time
+        let held = self.seeds.lock().held();
+        let request = |reply| Work::Hash { seed: seed.clone(), blob: blob.to_vec(), timings: *timings, reply };
+        let (hash, worked) = worker
+            .ask(held, request)
+            .ok_or_else(|| HashError::Failed(CoordinatorError::Validation("RandomX worker thread exited".into())))?;
+        *timings = worked;
+        if hash.is_ok() {
+            self.count_verification(seed_slot);
+        }
+        let current = self.seeds.lock().current.clone();
+        let lacks_dataset = self.builds_datasets() && hash.is_ok() && worker.dataset != current;
+        slot.worker = Some(Worker { seed_hash: hash.is_ok().then_some(seed), ..worker });
+        drop(slot);
+        if lacks_dataset {
+            self.build_datasets();
+        }
+        hash
+    }
+
+    /// Start a worker thread, which makes its caches and VM as work comes in
+    fn start_worker(&self) -> Result<Worker, HashError> {
+        let (requests, incoming) = mpsc::channel();
+        let context = WorkerContext {
+            flags: self.flags.clone(),
+            light_only: self.light_only.clone(),
+            alloc: self.alloc.clone(),
+            metrics: self.metrics.clone(),
+        };
+        std::thread::Builder::new()
+            .name("randomx-vm".into())
+            .spawn(move || run_worker(incoming, context))
+            .map_err(|e| {
+                HashError::Unavailable(CoordinatorError::Validation(format!("RandomX worker thread failed to start: {}", e)))
+            })?;
+        let id = self.started.fetch_add(1, Ordering::Relaxed);
+        Ok(Worker { id, requests, seed_hash: None, dataset: None })
+    }
+
+    /// Hash RandomX's test vector with a light VM made with the pool's flags,
+    /// apart from the pool, and return how long that took. A wrong hash means
+    /// this build of RandomX, or its flags on this host, cannot be trusted.
+    pub fn self_test(&self) -> Result<Duration, HashError> {
+        let unavailable = |e: String| HashError::Init(CoordinatorError::Validation(e));
+        let started = Instant::now();
+        let cache = init_with_fallback(&self.flags, "cache", |flags| RandomXCache::new(flags, SELF_TEST_KEY))
+            .map_err(|e| unavailable(format!("RandomX cache init failed: {}", e)))?;
+        let vm = init_with_fallback(&self.flags, "VM", |flags| RandomXVM::new(flags, Some(cache.clone()), None))
+            .map_err(|e| unavailable(format!("RandomX VM init failed: {}", e)))?;
+        let hash = vm
+            .calculate_hash(SELF_TEST_INPUT)
+            .map_err(|e| HashError::Failed(CoordinatorError::Validation(format!("Hash computation failed: {}", e))))?;
+        if hex::encode(&hash) != SELF_TEST_HASH {
+            return Err(HashError::Failed(CoordinatorError::Validation(format!(
+                "RandomX hashed the test vector to {}, expected {}, with {:?}",
+                hex::encode(&hash),
+                SELF_TEST_HASH,
+                RandomXFlag::from_bits_truncate(self.flags.load(Ordering::Relaxed)),
+            ))));
+        }
+        Ok(started.elapsed())
+    }
+
+    /// Find `seed_hash` among the seeds held, or switch to it
+    fn resolve(&self, seed_hash: &str) -> (SeedSlot, Arc<str>) {
+        let mut seeds = self.seeds.lock();
+        let now = Instant::now();
+        let before = seeds.current.clone();
+        let (slot, seed) = match seeds.find(seed_hash, now, self.previous_seed_grace) {
+            Some(found) => found,
+            None => {
+                let seed: Arc<str> = seed_hash.into();
+                seeds.rotate(seed.clone(), now + self.previous_seed_grace);
+                (SeedSlot::Current, seed)
+            }
+        };
+        // No worker has the new seed's dataset yet
+        if seeds.current != before {
+            set_fast(&self.fast, self.metrics.as_deref(), false);
+        }
+        (slot, seed)
+    }
+
+    /// Count a hash against the seed it was done with
+    fn count_verification(&self, slot: SeedSlot) {
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_randomx_verifications(slot == SeedSlot::Previous);
+        }
+    }
+
+    /// Make the cache for `seed_hash` ahead of the chain switching to it, on
+    /// each idle worker in turn and on any not started yet, while the rest
+    /// carry on hashing. Blocks until they are done; datasets are built once
+    /// the seed is current.
+    pub fn prewarm(&self, seed_hash: &str) {
+        let seed: Arc<str> = seed_hash.into();
+        {
+            let mut seeds = self.seeds.lock();
+            if seeds.holds(seed_hash) {
+                return;
+            }
+            seeds.prewarming = Some(seed.clone());
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_randomx_prewarms_started();
+        }
+        tracing::info!("RandomX prewarming the next seed: {}", seed_hash);
+
+        let mut visited = Vec::new();
+        let mut made = Ok(());
+        while let Some(mut slot) = self.take_idle(|worker| !visited.contains(&worker.id)).or_else(|| self.take_room()) {
+            let worker = match slot.worker.take().map_or_else(|| self.start_worker(), Ok) {
+                Ok(worker) => worker,
+                Err(e) => {
+                    made = Err(e);
+                    break;
+                }
+            };
+            visited.push(worker.id);
+            let held = self.seeds.lock().held();
+            match worker.ask(held, |reply| Work::Prewarm { seed: seed.clone(), reply }) {
+                Some(Ok(())) => slot.worker = Some(worker),
+                Some(Err(e)) => {
+                    slot.worker = Some(worker);
+                    made = Err(e);
+                    break;
+                }
+                None => {}
+            }
+        }
+
+        let mut seeds = self.seeds.lock();
+        seeds.prewarming = None;
+        if let Err(e) = made {
+            tracing::warn!("RandomX prewarm for seed {} failed: {}", seed_hash, e);
+            return;
+        }
+        // A hash for the seed may have switched to it meanwhile
+        if !seeds.holds(seed_hash) {
+            seeds.next = Some(seed);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.inc_randomx_prewarms_completed();
+        }
+    }
+
+    /// Build the current seed's dataset on each idle worker lacking it, one
+    /// at a time, on a thread of its own, unless one is at it already
+    fn build_datasets(self: &Arc<Self>) {
+        if self.building.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let pool = Arc::clone(self);
+        let spawned = std::thread::Builder::new().name("randomx-dataset".into()).spawn(move || loop {
+            pool.build_on_idle_workers();
+            pool.building.store(false, Ordering::Release);
+            // A worker handed back after the last look would otherwise wait
+            // for the next seed
+            if !pool.wants_dataset() || pool.building.swap(true, Ordering::AcqRel) {
+                return;
+            }
+        });
+        if let Err(e) = spawned {
+            self.building.store(false, Ordering::Release);
+            dataset_failed(self.metrics.as_deref(), &e.to_string());
+        }
+    }
+
+    /// Whether an idle worker lacks the current seed's dataset, which it
+    /// should build
+    fn wants_dataset(&self) -> bool {
+        let current = self.seeds.lock().current.clone();
+        self.builds_datasets() && self.slots.lock().idle.iter().any(|worker| worker.dataset != current)
+    }
+
+    /// Have each idle worker build the current seed's dataset in turn, until
+    /// none lacks it, or stop at the first that cannot be built
+    fn build_on_idle_workers(&self) {
+        while self.builds_datasets() {
+            let Some(seed) = self.seeds.lock().current.clone() else {
+                return;
+            };
+            let Some(mut slot) = self.take_idle(|worker| worker.dataset.as_ref() != Some(&seed)) else {
+                return;
+            };
+            let worker = slot.worker.as_mut().expect("taken idle");
+            let held = self.seeds.lock().held();
+            let started = Instant::now();
+            match worker.ask(held, |reply| Work::BuildDataset { seed: seed.clone(), reply }) {
+                Some(Ok(())) => {
+                    worker.dataset = Some(seed.clone());
+                    dataset_ready(self.metrics.as_deref(), &seed, started.elapsed());
+                    if self.seeds.lock().is_current(&seed) {
+                        set_fast(&self.fast, self.metrics.as_deref(), true);
+                    }
+                }
+                Some(Err(e)) => {
+                    dataset_failed(self.metrics.as_deref(), &e.to_string());
+                    step_down_to_light(&self.light_only, &self.flags, self.metrics.as_deref(), &format!("dataset: {}", e));
+                    return;
+                }
+                None => slot.worker = None,
+            }
+        }
+    }
+
+    /// Climb back up once RandomX's memory can be had again: a cache made
+    /// with the configured flags brings them back, and the configured mode
+    /// with datasets for the current seed; failing that, one made in light
+    /// mode without large pages brings back that rung. Whether the pool is
+    /// back as configured, or an error if neither cache could be made.
+    pub fn recover(self: &Arc<Self>) -> Result<bool, CoordinatorError> {
+        if self.alloc.cache(self.configured, SELF_TEST_KEY).is_ok() {
+            self.flags.store(self.configured.bits(), Ordering::Relaxed);
+            self.light_only.store(false, Ordering::Relaxed);
+            if self.wants_dataset() {
+                self.build_datasets();
+            }
+            return Ok(true);
+        }
+        // Tried apart from the pool's flags, which only change if it works
+        let light = AtomicU32::new((self.configured - RandomXFlag::FLAG_LARGE_PAGES).bits());
+        init_with_fallback(&light, "cache", |flags| self.alloc.cache(flags, SELF_TEST_KEY))
+            .map_err(|e| CoordinatorError::Validation(format!("RandomX cache init failed: {}", e)))?;
+        self.flags.store(light.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.light_only.store(true, Ordering::Relaxed);
+        Ok(false)
+    }
+
+    /// Wait for a slot: an idle worker, preferably one whose VM is already
+    /// on `seed_hash`, or room to start one
+    fn checkout(&self, seed_hash: &str) -> Slot<'_> {
+        let mut slots = self.slots.lock();
+        while slots.idle.is_empty() && slots.in_use >= self.size {
+            self.returned.wait(&mut slots);
+        }
+        slots.in_use += 1;
+        #[cfg(test)]
+        {
+            slots.peak_in_use = slots.peak_in_use.max(slots.in_use);
+        }
+        let keyed = slots.idle.iter().rposition(|worker| worker.seed_hash.as_deref() == Some(seed_hash));
+        let worker = match keyed {
+            Some(i) => Some(slots.idle.swap_remove(i)),
+            None => slots.idle.pop(),
+        };
+        Slot { pool: self, worker }
+    }
+
+    /// Take an idle worker that `pick` chooses out of the pool, without
+    /// waiting for one; None if none is idle
+    fn take_idle(&self, pick: impl Fn(&Worker) -> bool) -> Option<Slot<'_>> {
+        let mut slots = self.slots.lock();
+        let i = slots.idle.iter().position(pick)?;
+        slots.in_use += 1;
+        #[cfg(test)]
+        {
+            slots.peak_in_use = slots.peak_in_use.max(slots.in_use);
+        }
+        let worker = slots.idle.swap_remove(i);
+        Some(Slot { pool: self, worker: Some(worker) })
+    }
+
+    /// Take a slot to start a worker in, if the pool has fewer than its size
+    /// and none is checked out to start one already
+    fn take_room(&self) -> Option<Slot<'_>> {
+        let mut slots = self.slots.lock();
+        if slots.idle.len() + slots.in_use >= self.size {
+            return None;
+        }
+        slots.in_use += 1;
+        #[cfg(test)]
+        {
+            slots.peak_in_use = slots.peak_in_use.max(slots.in_use);
+        }
+        Some(Slot { pool: self, worker: None })
+    }
+
+    /// Take a slot for `time`, as a hash for `seed_hash` would, going through
+    /// the same choice of seed without asking a worker for anything
+    #[cfg(test)]
+    pub(crate) fn fake_hash(&self, seed_hash: &str, time: Duration) {
+        self.fake_hash_timed(seed_hash, time, &mut HashTimings::default());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fake_hash_timed(&self, seed_hash: &str, time: Duration, timings: &mut HashTimings) {
+        let (seed_slot, seed) = self.resolve(seed_hash);
+        self.count_verification(seed_slot);
+        let waiting = Instant::now();
+        let _slot = self.checkout(&seed);
+        timings.queue += waiting.elapsed();
+        let hashing = Instant::now();
+        std::thread::sleep(time);
+        timings.hash += hashing.elapsed();
+    }
+
+    /// Report a dataset built in `took`, as the build thread does, without
+    /// building one
+    #[cfg(test)]
+    pub(crate) fn signal_dataset_ready(&self, took: Duration) {
+        dataset_ready(self.metrics.as_deref(), "test", took);
+        set_fast(&self.fast, self.metrics.as_deref(), true);
+    }
+}
+
+/// RandomX's VMs as a [`PowVerifier`], hashing on tokio's blocking pool:
+/// making a VM, and even a light-mode hash, would otherwise stall every
+/// connection sharing the executor thread
+pub struct LocalVerifier {
+    vms: Arc<VmPool>,
+}
+
+impl LocalVerifier {
+    pub fn new(vms: VmPool) -> Self {
+        Self { vms: Arc::new(vms) }
+    }
+
+    /// Run `task` with the pool on tokio's blocking pool
+    async fn blocking<T: Send + 'static>(&self, what: &str, task: impl FnOnce(&Arc<VmPool>) -> T + Send + 'static) -> Result<T, CoordinatorError> {
+        let vms = Arc::clone(&self.vms);
+        tokio::task::spawn_blocking(move || task(&vms))
+            .await
+            .map_err(|e| CoordinatorError::Validation(format!("{} task failed: {}", what, e)))
+    }
+}
+
+impl PowVerifier for LocalVerifier {
+    fn verify<'a>(
+        &'a self,
+        blob: &'a [u8],
+        seed_hash: &'a str,
+        _height: u64,
+        timings: &'a mut HashTimings,
+    ) -> BoxFuture<'a, Result<[u8; 32], HashError>> {
+        let (seed_hash, blob, mut worked) = (seed_hash.to_string(), blob.to_vec(), *timings);
+        Box::pin(async move {
+            let (hash, worked) = self
+                .blocking("Hash", move |vms| (vms.hash(&seed_hash, &blob, &mut worked), worked))
+                .await
+                .map_err(HashError::Failed)?;
+            *timings = worked;
+            hash
+        })
+    }
+
+    fn concurrency(&self) -> usize {
+        self.vms.size()
+    }
+
+    fn rung(&self) -> VerifierRung {
+        if self.vms.is_light_only() { VerifierRung::Light } else { VerifierRung::Configured }
+    }
+
+    fn recover(&self) -> BoxFuture<'_, Result<(), CoordinatorError>> {
+        Box::pin(async move { self.blocking("RandomX retry", |vms| vms.recover()).await?.map(|_| ()) })
+    }
+
+    fn self_test(&self) -> BoxFuture<'_, Result<Option<Duration>, HashError>> {
+        Box::pin(async move { self.blocking("Self-test", |vms| vms.self_test()).await.map_err(HashError::Failed)?.map(Some) })
+    }
+
+    fn prewarm<'a>(&'a self, seed_hash: &'a str) -> BoxFuture<'a, ()> {
+        let seed_hash = seed_hash.to_string();
+        Box::pin(async move {
+            let _ = self.blocking("Prewarm", move |vms| vms.prewarm(&seed_hash)).await;
+        })
+    }
+
+    fn randomx_flags(&self) -> Vec<&'static str> {
+        flag_names(self.vms.flags())
+    }
+}
+
+/// Do what the pool sends until it lets go of the worker, with caches,
+/// datasets and a VM made on this thread and kept on it, so none is ever
+/// used from two threads
+fn run_worker(requests: mpsc::Receiver<WorkerRequest>, context: WorkerContext) {
+    let mut state = WorkerState::default();
+    for WorkerRequest { held, work } in requests {
+        // A VM still on a dropped seed's cache keeps it until moved off
+        state.caches.retain(|cache| held.contains(&cache.seed));
+        // Only a pool that went away stops waiting for the reply
+        match work {
+            Work::Hash { seed, blob, mut timings, reply } => {
+                let hash = hash_on_worker(&mut state, &context, seed, &blob, &mut timings);
+                let _ = reply.send((hash, timings));
+            }
+            Work::Prewarm { seed, reply } => {
+                let made = state.cache(&context, &seed, &mut Duration::default()).map(|_| ());
+                let _ = reply.send(made);
+            }
+            Work::BuildDataset { seed, reply } => {
+                let _ = reply.send(build_dataset(&mut state, &context, &seed));
+            }
+        }
+    }
+}
+
+/// Hash `blob` with the worker's VM, making `seed`'s cache, and moving the
+/// VM onto it or making it first, as needed
+fn hash_on_worker(
+    state: &mut WorkerState,
+    context: &WorkerContext,
+    seed: Arc<str>,
+    blob: &[u8],
+    timings: &mut HashTimings,
+) -> Result<[u8; 32], HashError> {
+    let made_before = state.caches.len();
+    let i = state.cache(context, &seed, &mut timings.init)?;
+    if state.caches.len() > made_before {
+        if let Some(metrics) = &context.metrics {
+            metrics.inc_randomx_inline_cache_inits();
+        }
+    }
+    let WorkerState { caches, vm } = state;
+    let WorkerCache { cache, dataset, .. } = &caches[i];
+    let fast = dataset.is_some();
+    let started = Instant::now();
+    let pooled = match vm.take() {
+        Some(pooled) if pooled.seed_hash == seed && pooled.fast == fast => pooled,
+        // A light VM moves onto the new cache; anything else is made again
+        Some(mut pooled) if !pooled.fast && !fast => {
+            pooled.vm.reinit_cache(cache.clone()).map_err(|e| {
+                HashError::Init(CoordinatorError::Validation(format!("RandomX VM re-key failed: {}", e)))
+            })?;
+            timings.init += started.elapsed();
+            count_vm_init(context.metrics.as_deref(), true);
+            pooled.seed_hash = seed;
+            pooled
+        }
+        _ => {
+            let made = init_with_fallback(&context.flags, "VM", |flags| match dataset {
+                Some(dataset) => RandomXVM::new(flags | RandomXFlag::FLAG_FULL_MEM, None, Some(dataset.clone())),
+                None => RandomXVM::new(flags, Some(cache.clone()), None),
+            })
+            .map_err(|e| HashError::Init(CoordinatorError::Validation(format!("RandomX VM init failed: {}", e))))?;
+            timings.init += started.elapsed();
+            count_vm_init(context.metrics.as_deref(), false);
+            PooledVm { seed_hash: seed, fast, vm: made }
+        }
+    };
+    let hashing = Instant::now();
+    let hash = pooled.vm.calculate_hash(blob);
+    timings.hash += hashing.elapsed();
+    *vm = Some(pooled);
+
+    let hash = hash.map_err(|e| HashError::Failed(CoordinatorError::Validation(format!("Hash computation failed: {}", e))))?;
+    hash.try_into().map_err(|hash: Vec<u8>| {
+        HashError::Failed(CoordinatorError::Validation(format!("Unexpected hash length: expected 32, got {}", hash.len())))
+    })
+}
+
+/// Build the dataset for `seed`'s cache, making the cache first if the
+/// worker has none. Datasets for other seeds are dropped, so a worker holds
+/// one at most, besides any its VM is still on.
+fn build_dataset(state: &mut WorkerState, context: &WorkerContext, seed: &Arc<str>) -> Result<(), HashError> {
+    let i = state.cache(context, seed, &mut Duration::default())?;
+    for (j, other) in state.caches.iter_mut().enumerate() {
+        if j != i {
+            other.dataset = None;
+        }
+    }
+    let held = &mut state.caches[i];
+    if held.dataset.is_none() {
+        let dataset = init_with_fallback(&context.flags, "dataset", |flags| context.alloc.dataset(flags, held.cache.clone()))
+            .map_err(|e| HashError::Init(CoordinatorError::Validation(format!("RandomX dataset init failed: {}", e))))?;
+        held.dataset = Some(dataset);
+    }
+    Ok(())
+}
+
+fn count_vm_init(metrics: Option<&Metrics>, rekey: bool) {
+    if let Some(metrics) = metrics {
+        metrics.inc_randomx_vm_inits(rekey);
+    }
+}
+
+/// Step down to light mode without large pages, if not there already:
+/// build no more datasets, and make caches and VMs without large pages
+fn step_down_to_light(light_only: &AtomicBool, flags: &AtomicU32, metrics: Option<&Metrics>, why: &str) {
+    flags.fetch_and(!RandomXFlag::FLAG_LARGE_PAGES.bits(), Ordering::Relaxed);
+    if !light_only.swap(true, Ordering::Relaxed) {
+        tracing::warn!("RandomX stepping down to light mode without large pages: {}", why);
+        if let Some(metrics) = metrics {
+            metrics.inc_verifier_steps(VerifierRung::Light);
+        }
+    }
+}
+
+/// `recommended` flags, with each flag `settings` sets forced on or off
+fn choose_flags(settings: RandomXFlags, recommended: RandomXFlag) -> RandomXFlag {
+    let mut flags = recommended;
+    for (setting, flag) in [
+        (settings.large_pages, RandomXFlag::FLAG_LARGE_PAGES),
+        (settings.jit, RandomXFlag::FLAG_JIT),
+        (settings.secure, RandomXFlag::FLAG_SECURE),
+        (settings.hard_aes, RandomXFlag::FLAG_HARD_AES),
+    ] {
+        if let Some(on) = setting {
+            flags.set(flag, on);
+        }
+    }
+    // Secure mode only changes how JIT pages are mapped
+    if !flags.contains(RandomXFlag::FLAG_JIT) {
+        flags.remove(RandomXFlag::FLAG_SECURE);
+    }
+    flags
+}
+
+/// The flags to try after `flags` failed: without large pages, then JIT in
+/// secure mode, then without JIT; None once there is nothing left to drop
+fn fewer_flags(flags: RandomXFlag) -> Option<RandomXFlag> {
+    if flags.contains(RandomXFlag::FLAG_LARGE_PAGES) {
+        Some(flags - RandomXFlag::FLAG_LARGE_PAGES)
+    } else if flags.contains(RandomXFlag::FLAG_JIT) && !flags.contains(RandomXFlag::FLAG_SECURE) {
+        Some(flags | RandomXFlag::FLAG_SECURE)
+    } else if flags.contains(RandomXFlag::FLAG_JIT) {
+        Some(flags - RandomXFlag::FLAG_JIT - RandomXFlag::FLAG_SECURE)
+    } else {
+        None
+    }
+}
+
+/// Make `what` with the pool's `flags`, trying fewer as each fails. Flags
+/// that work are kept for what is made later; if none do, the first error
+/// is returned and the flags are left as they were, the failure likely
+/// being something else.
+fn init_with_fallback<T, E: Display>(flags: &AtomicU32, what: &str, init: impl Fn(RandomXFlag) -> Result<T, E>) -> Result<T, E> {
+    let first = RandomXFlag::from_bits_truncate(flags.load(Ordering::Relaxed));
+    let error = match init(first) {
+        Ok(made) => return Ok(made),
+        Err(e) => e,
+    };
+    let mut tried = first;
+    while let Some(fewer) = fewer_flags(tried) {
+        tracing::warn!("RandomX {} failed with {:?} ({}); trying {:?}", what, tried, error, fewer);
+        tried = fewer;
+        if let Ok(made) = init(fewer) {
+            tracing::warn!("RandomX {} made with {:?}, which is used from now on", what, fewer);
+            flags.store(fewer.bits(), Ordering::Relaxed);
+            return Ok(made);
+        }
+    }
+    Err(error)
+}
+
+/// Names of the RandomX flags set in `flags`, for logs and `/stats`
+pub fn flag_names(flags: RandomXFlag) -> Vec<&'static str> {
+    [
+        (RandomXFlag::FLAG_LARGE_PAGES, "large_pages"),
+        (RandomXFlag::FLAG_HARD_AES, "hard_aes"),
+        (RandomXFlag::FLAG_FULL_MEM, "full_mem"),
+        (RandomXFlag::FLAG_JIT, "jit"),
+        (RandomXFlag::FLAG_SECURE, "secure"),
+        (RandomXFlag::FLAG_ARGON2_SSSE3, "argon2_ssse3"),
+        (RandomXFlag::FLAG_ARGON2_AVX2, "argon2_avx2"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| name)
+    .collect()
+}
+
+fn set_fast(fast: &AtomicBool, metrics: Option<&Metrics>, value: bool) {
+    fast.store(value, Ordering::Relaxed);
+    if let Some(metrics) = metrics {
+        metrics.set_randomx_fast(value);
+    }
+}
+
+fn dataset_ready(metrics: Option<&Metrics>, seed_hash: &str, took: Duration) {
+    tracing::info!("RandomX dataset for seed {} ready after {:?}", seed_hash, took);
+    if let Some(metrics) = metrics {
+        metrics.set_randomx_dataset_init(took);
+    }
+}
+
+fn dataset_failed(metrics: Option<&Metrics>, error: &str) {
+    tracing::warn!("RandomX dataset could not be built, verifying in light mode: {}", error);
+    if let Some(metrics) = metrics {
+        metrics.inc_randomx_dataset_failures();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_more_than_size_slots_at_once() {
+        let pool = Arc::new(VmPool::new(2));
+        // With one slot held here, the hashes below get the other one in turn
+        let held = pool.checkout("00");
+        let holders: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || pool.fake_hash("00", Duration::from_millis(10)))
+            })
+            .collect();
+        for holder in holders {
+            holder.join().unwrap();
+        }
+        drop(held);
+        let slots = pool.slots.lock();
+        assert_eq!(slots.peak_in_use, 2);
+        assert_eq!(slots.in_use, 0);
+    }
+
+    #[test]
+    fn test_bad_seed_fails_the_hash() {
+        let pool = Arc::new(VmPool::new(1));
+        assert!(matches!(pool.hash("not hex", &[0; 76], &mut HashTimings::default()), Err(HashError::Failed(_))));
+        assert_eq!(pool.slots.lock().in_use, 0);
+        assert_eq!(VmPool::new(0).size(), 1);
+        assert_eq!(pool.memory_mb(), CACHE_MB + VM_MB);
+    }
+
+    #[test]
+    fn test_fast_mode_starts_light_until_the_dataset_is_ready() {
+        let metrics = Arc::new(Metrics::new());
+        let pool = VmPool::new(2).with_mode(VerifyMode::Fast).with_metrics(metrics.clone());
+        assert_eq!(pool.memory_mb(), 2 * (CACHE_MB + DATASET_MB + VM_MB));
+        assert!(!pool.is_fast());
+
+        dataset_failed(pool.metrics.as_deref(), "no memory");
+        assert!(!pool.is_fast());
+        assert!(metrics.format_prometheus().contains("coordinator_randomx_dataset_failures 1\n"));
+
+        pool.signal_dataset_ready(Duration::from_secs(30));
+        assert!(pool.is_fast());
+        let output = metrics.format_prometheus();
+        assert!(output.contains("coordinator_randomx_mode{mode=\"fast\"} 1\n"));
+        assert!(output.contains("coordinator_randomx_mode{mode=\"light\"} 0\n"));
+        assert!(output.contains("coordinator_randomx_dataset_init_seconds 30\n"));
+    }
+
+    #[test]
+    fn test_self_test_hashes_the_reference_vector() {
+        // Light mode, as the self-test always is, so cheap enough to run here
+        let took = VmPool::new(1).self_test().unwrap();
+        assert!(took > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_concurrent_hashes_across_two_seeds_agree() {
+        let metrics = Arc::new(Metrics::new());
+        let pool = Arc::new(VmPool::new(3).with_metrics(metrics.clone()).with_previous_seed_grace(Duration::from_secs(60)));
+        let seeds = ["00".repeat(32), "11".repeat(32)];
+        let blob = |i: usize| vec![i as u8; 76];
+        // One at a time first, which moves the one VM from the first seed
+        // onto the second
+        let expected: Vec<Vec<[u8; 32]>> = seeds
+            .iter()
+            .map(|seed| (0..4).map(|i| pool.hash(seed, &blob(i), &mut HashTimings::default()).unwrap()).collect())
+            .collect();
+        assert_ne!(expected[0][0], expected[1][0]);
+
+        // Then from more threads than workers, alternating seeds, so VMs are
+        // moved back and forth between caches while others hash
+        let hashers: Vec<_> = (0..6)
+            .map(|thread| {
+                let (pool, seeds, expected) = (pool.clone(), seeds.clone(), expected.clone());
+                std::thread::spawn(move || {
+                    for round in 0..8 {
+                        let (seed, i) = ((thread + round) % 2, (thread * round) % 4);
+                        let hash = pool.hash(&seeds[seed], &blob(i), &mut HashTimings::default()).unwrap();
+                        assert_eq!(hash, expected[seed][i], "thread {} round {}", thread, round);
+                    }
+                })
+            })
+            .collect();
+        for hasher in hashers {
+            hasher.join().unwrap();
+        }
+
+        let slots = pool.slots.lock();
+        assert_eq!(slots.in_use, 0);
+        assert!(slots.idle.len() <= 3);
+        let made = metrics.randomx_vm_inits_new.load(Ordering::Relaxed);
+        assert!((1..=3).contains(&made), "{} VMs made", made);
+        assert!(metrics.randomx_vm_inits_rekey.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn test_flag_settings_override_the_recommendation() {
+        let recommended = RandomXFlag::FLAG_HARD_AES | RandomXFlag::FLAG_JIT | RandomXFlag::FLAG_ARGON2_AVX2;
+        assert_eq!(choose_flags(RandomXFlags::default(), recommended), recommended);
+
+        let tuned = RandomXFlags { large_pages: Some(true), secure: Some(true), ..Default::default() };
+        assert_eq!(
+            choose_flags(tuned, recommended),
+            recommended | RandomXFlag::FLAG_LARGE_PAGES | RandomXFlag::FLAG_SECURE
+        );
+        // Secure mode goes with JIT
+        let interpreted = RandomXFlags { jit: Some(false), secure: Some(true), hard_aes: Some(false), ..Default::default() };
+        assert_eq!(choose_flags(interpreted, recommended), RandomXFlag::FLAG_ARGON2_AVX2);
+        assert_eq!(flag_names(recommended), ["hard_aes", "jit", "argon2_avx2"]);
+    }
+
+    #[test]
+    fn test_failed_init_falls_back_to_fewer_flags() {
+        let wanted = RandomXFlag::FLAG_LARGE_PAGES | RandomXFlag::FLAG_JIT;
+        let flags = AtomicU32::new(wanted.bits());
+        // No large pages on this host
+        let tried = Mutex::new(Vec::new());
+        let made = init_with_fallback(&flags, "cache", |flags| {
+            tried.lock().push(flags);
+            if flags.contains(RandomXFlag::FLAG_LARGE_PAGES) { Err("no huge pages") } else { Ok(flags) }
+        });
+        assert_eq!(made, Ok(RandomXFlag::FLAG_JIT));
+        assert_eq!(flags.load(Ordering::Relaxed), RandomXFlag::FLAG_JIT.bits());
+        assert_eq!(*tried.lock(), [wanted, RandomXFlag::FLAG_JIT]);
+
+        // Nothing works: every step is tried and the flags are kept
+        tried.lock().clear();
+        let made = init_with_fallback(&flags, "cache", |flags| {
+            tried.lock().push(flags);
+            Err::<(), _>("out of memory")
+        });
+        assert_eq!(made, Err("out of memory"));
+        assert_eq!(flags.load(Ordering::Relaxed), RandomXFlag::FLAG_JIT.bits());
+        let secure = RandomXFlag::FLAG_JIT | RandomXFlag::FLAG_SECURE;
+        assert_eq!(*tried.lock(), [RandomXFlag::FLAG_JIT, secure, RandomXFlag::FLAG_DEFAULT]);
+    }
+
+    #[test]
+    fn test_alternating_seeds_keep_both() {
+        let metrics = Arc::new(Metrics::new());
+        let pool = VmPool::new(1).with_metrics(metrics.clone());
+        // Submissions on jobs from both sides of a seed change, interleaved
+        for seed in ["aa", "bb"].repeat(10) {
+            pool.fake_hash(seed, Duration::ZERO);
+        }
+        assert!(pool.seeds.lock().is_current("bb"));
+        assert!(pool.seeds.lock().holds("aa"));
+        assert_eq!(metrics.randomx_verifications_current.load(Ordering::Relaxed), 11);
+        assert_eq!(metrics.randomx_verifications_previous.load(Ordering::Relaxed), 9);
+
+        // A third seed retires the first, which comes back as a new one
+        pool.fake_hash("cc", Duration::ZERO);
+        assert!(!pool.seeds.lock().holds("aa"));
+        pool.fake_hash("aa", Duration::ZERO);
+        assert!(pool.seeds.lock().is_current("aa"));
+        assert_eq!(pool.seeds.lock().held().len(), 2);
+        assert_eq!(metrics.randomx_verifications_previous.load(Ordering::Relaxed), 9);
+    }
+
+    #[test]
+    fn test_previous_seed_is_dropped_after_the_grace() {
+        let pool = VmPool::new(1).with_previous_seed_grace(Duration::from_millis(50));
+        pool.fake_hash("aa", Duration::ZERO);
+        pool.fake_hash("bb", Duration::ZERO);
+        assert!(pool.seeds.lock().holds("aa"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        pool.fake_hash("bb", Duration::ZERO);
+        assert!(!pool.seeds.lock().holds("aa"));
+        assert!(pool.seeds.lock().is_current("bb"));
+    }
+
+    #[test]
+    fn test_refused_dataset_steps_down_to_light() {
+        let metrics = Arc::new(Metrics::new());
+        let datasets = Arc::new(AtomicU32::new(0));
+        let asked = datasets.clone();
+        let pool = VmPool::new(1).with_mode(VerifyMode::Fast).with_metrics(metrics.clone()).refusing(move |what, _| {
+            if what == "dataset" {
+                asked.fetch_add(1, Ordering::Relaxed);
+            }
+            what == "dataset"
+        });
+        let pool = Arc::new(pool);
+        pool.hash(&"00".repeat(32), &[0; 76], &mut HashTimings::default()).unwrap();
+
+        // The dataset is built, and refused, on the worker, asked by a thread
+        // of its own
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while metrics.verifier_steps_light.load(Ordering::Relaxed) == 0 {
+            assert!(Instant::now() < deadline, "never stepped down");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pool.is_light_only());
+        assert_eq!(metrics.randomx_dataset_failures.load(Ordering::Relaxed), 1);
+
+        // The next seed is hashed in light mode without asking for a dataset
+        let refused = datasets.load(Ordering::Relaxed);
+        pool.hash(&"11".repeat(32), &[0; 76], &mut HashTimings::default()).unwrap();
+        assert_eq!(datasets.load(Ordering::Relaxed), refused);
+        assert!(!pool.is_fast());
+    }
+
+    #[test]
+    fn test_rekeying_while_hashes_are_in_flight() {
+        let metrics = Arc::new(Metrics::new());
+        // No grace, so each switch drops the seed before it while hashes for
+        // it may still be on their way to a worker
+        let pool = Arc::new(VmPool::new(2).with_metrics(metrics.clone()).with_previous_seed_grace(Duration::ZERO));
+        let seeds = ["00".repeat(32), "11".repeat(32), "22".repeat(32)];
+        let blob = |i: usize| vec![i as u8; 76];
+        // Worked out here, apart from the pool and its workers
+        let expected: Vec<Vec<[u8; 32]>> = seeds
+            .iter()
+            .map(|seed| {
+                let cache = RandomXCache::new(pool.flags(), &hex::decode(seed).unwrap()).unwrap();
+                let vm = RandomXVM::new(pool.flags(), Some(cache), None).unwrap();
+                (0..2).map(|i| vm.calculate_hash(&blob(i)).unwrap().try_into().unwrap()).collect()
+            })
+            .collect();
+
+        let prewarmer = {
+            let (pool, seeds) = (pool.clone(), seeds.clone());
+            std::thread::spawn(move || {
+                for round in 0..6 {
+                    pool.prewarm(&seeds[round % 3]);
+                }
+            })
+        };
+        let hashers: Vec<_> = (0..4)
+            .map(|thread| {
+                let (pool, seeds, expected) = (pool.clone(), seeds.clone(), expected.clone());
+                std::thread::spawn(move || {
+                    for round in 0..4 {
+                        let (seed, i) = ((thread + round) % 3, round % 2);
+                        let hash = pool.hash(&seeds[seed], &blob(i), &mut HashTimings::default()).unwrap();
+                        assert_eq!(hash, expected[seed][i], "thread {} round {}", thread, round);
+                    }
+                })
+            })
+            .collect();
+        for hasher in hashers {
+            hasher.join().unwrap();
+        }
+        prewarmer.join().unwrap();
+
+        let slots = pool.slots.lock();
+        assert_eq!(slots.in_use, 0);
+        assert!(slots.idle.len() <= 2);
+        assert!(metrics.randomx_inline_cache_inits.load(Ordering::Relaxed) >= 1);
+    }
+}